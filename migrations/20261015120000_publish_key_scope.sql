@@ -0,0 +1,2 @@
+ALTER TABLE publish_keys ADD COLUMN scope varchar(64);
+ALTER TABLE publish_keys ADD COLUMN expires_at int;
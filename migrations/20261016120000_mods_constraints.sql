@@ -0,0 +1,19 @@
+-- Databases from before migrations existed may have a mods table without the unique constraint,
+-- so the table is rebuilt keeping a single copy of each version
+CREATE TABLE mods_new (
+    id varchar(64) NOT NULL,
+
+    major int NOT NULL CHECK (major >= 0),
+    minor int NOT NULL CHECK (minor >= 0),
+    patch int NOT NULL CHECK (patch >= 0)
+);
+
+INSERT INTO mods_new (id, major, minor, patch)
+    SELECT DISTINCT id, major, minor, patch FROM mods
+    WHERE major >= 0 AND minor >= 0 AND patch >= 0;
+
+DROP TABLE mods;
+ALTER TABLE mods_new RENAME TO mods;
+
+-- Ordered like resolving walks versions so it covers those queries too
+CREATE UNIQUE INDEX mods_id_version ON mods (id, major DESC, minor DESC, patch DESC);
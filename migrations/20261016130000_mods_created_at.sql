@@ -0,0 +1 @@
+ALTER TABLE mods ADD COLUMN created_at int;
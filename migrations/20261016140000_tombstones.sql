@@ -0,0 +1,12 @@
+-- Versions that were deleted, so they can't be published again with different contents
+CREATE TABLE IF NOT EXISTS tombstones (
+    id varchar(64) NOT NULL,
+
+    major int NOT NULL,
+    minor int NOT NULL,
+    patch int NOT NULL,
+
+    deleted_at int NOT NULL,
+
+    UNIQUE(id, major, minor, patch)
+)
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at int NOT NULL,
+
+    -- Publish key user, or "admin"
+    actor varchar(128) NOT NULL,
+    action varchar(32) NOT NULL,
+    package varchar(64),
+    version varchar(64),
+    ip varchar(64)
+)
@@ -0,0 +1,4 @@
+-- Left empty for versions uploaded before these were recorded
+ALTER TABLE mods ADD COLUMN size int;
+ALTER TABLE mods ADD COLUMN sha256 varchar(64);
+ALTER TABLE mods ADD COLUMN downloads int NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- What publishers say about their versions, for frontends to show
+ALTER TABLE mods ADD COLUMN name varchar(128);
+ALTER TABLE mods ADD COLUMN description text;
+ALTER TABLE mods ADD COLUMN author varchar(128);
+ALTER TABLE mods ADD COLUMN website varchar(512);
+-- Beat Saber version the version is made for
+ALTER TABLE mods ADD COLUMN game_version varchar(32);
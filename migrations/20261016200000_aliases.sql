@@ -0,0 +1,6 @@
+-- Renamed packages, requests for the old id are served from the new one
+CREATE TABLE IF NOT EXISTS aliases (
+    old_id varchar(64) PRIMARY KEY NOT NULL,
+    new_id varchar(64) NOT NULL,
+    created_at int NOT NULL
+)
@@ -0,0 +1,13 @@
+-- Human readable documents publishers attach to their versions, in markdown
+CREATE TABLE IF NOT EXISTS readmes (
+    id varchar(64) NOT NULL,
+
+    major int NOT NULL,
+    minor int NOT NULL,
+    patch int NOT NULL,
+
+    contents text NOT NULL,
+    updated_at int NOT NULL,
+
+    UNIQUE(id, major, minor, patch)
+)
@@ -0,0 +1,15 @@
+-- Extra files published alongside a version, like debug symbols
+CREATE TABLE IF NOT EXISTS artifacts (
+    id varchar(64) NOT NULL,
+
+    major int NOT NULL,
+    minor int NOT NULL,
+    patch int NOT NULL,
+
+    name varchar(64) NOT NULL,
+    size int NOT NULL,
+    sha256 char(64) NOT NULL,
+    created_at int NOT NULL,
+
+    UNIQUE(id, major, minor, patch, name)
+)
@@ -0,0 +1,25 @@
+-- Game versions the index knows about, uploads can only be tagged with these
+CREATE TABLE IF NOT EXISTS game_versions (
+    version varchar(32) PRIMARY KEY NOT NULL,
+    created_at int NOT NULL
+);
+
+-- Game versions each version is made for, versions without any are taken to work on all of them
+CREATE TABLE IF NOT EXISTS mod_game_versions (
+    id varchar(64) NOT NULL,
+
+    major int NOT NULL,
+    minor int NOT NULL,
+    patch int NOT NULL,
+
+    game_version varchar(32) NOT NULL,
+
+    UNIQUE(id, major, minor, patch, game_version)
+);
+
+-- Versions tagged through their metadata keep their tag
+INSERT OR IGNORE INTO mod_game_versions (id, major, minor, patch, game_version)
+SELECT id, major, minor, patch, game_version FROM mods WHERE game_version IS NOT NULL;
+
+INSERT OR IGNORE INTO game_versions (version, created_at)
+SELECT DISTINCT game_version, strftime('%s', 'now') FROM mods WHERE game_version IS NOT NULL;
@@ -0,0 +1,2 @@
+-- Bytes the file takes up in storage, smaller than size if it's compressed
+ALTER TABLE mods ADD COLUMN stored_size int;
@@ -0,0 +1,6 @@
+-- Build metadata of the version, empty for versions without any. Whether several builds of a
+-- version can be published is up to the config, so the index only keeps each build unique
+ALTER TABLE mods ADD COLUMN build varchar(64) NOT NULL DEFAULT '';
+
+DROP INDEX mods_id_version;
+CREATE UNIQUE INDEX mods_id_version ON mods (id, major DESC, minor DESC, patch DESC, build);
@@ -0,0 +1,11 @@
+-- Everything that changed what the index lists, in order, so mirrors can sync incrementally.
+-- Changes past the retention are pruned in the background, except the latest
+CREATE TABLE IF NOT EXISTS changes (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    action varchar(32) NOT NULL,
+    id varchar(64) NOT NULL,
+    version varchar(64) NOT NULL,
+    created_at int NOT NULL
+);
+
+CREATE INDEX changes_created_at ON changes (created_at);
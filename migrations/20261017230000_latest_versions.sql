@@ -0,0 +1,13 @@
+-- The newest version of each package that isn't yanked, kept up to date along with the versions
+-- so the newest doesn't have to be looked for among all of them
+CREATE TABLE latest_versions (
+    id varchar(64) NOT NULL PRIMARY KEY,
+    major int NOT NULL,
+    minor int NOT NULL,
+    patch int NOT NULL,
+    build varchar(64) NOT NULL DEFAULT ''
+);
+
+INSERT INTO latest_versions (id, major, minor, patch, build)
+SELECT id, major, minor, patch, build FROM mods m WHERE rowid = (SELECT n.rowid FROM mods n
+WHERE n.id = m.id AND n.yanked_at IS NULL ORDER BY n.major DESC, n.minor DESC, n.patch DESC, n.rowid DESC LIMIT 1);
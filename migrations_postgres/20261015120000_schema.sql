@@ -0,0 +1,30 @@
+-- The schema the SQLite migrations up to this one build, in one go since there are no older
+-- PostgreSQL databases to bring along. Every migration added after it gets one of the same
+-- version in both directories.
+--
+-- Integers are all bigint, which is what the index reads them as. Strings are text, since SQLite
+-- doesn't hold them to the varchar lengths its migrations give. Ids compare bytewise as they do
+-- in SQLite so everything is listed in the same order whatever the database's locale
+
+CREATE TABLE mods (
+    -- What SQLite calls the row's id, the order versions were added in
+    rowid bigserial NOT NULL,
+    id text COLLATE "C" NOT NULL,
+
+    major bigint NOT NULL,
+    minor bigint NOT NULL,
+    patch bigint NOT NULL,
+
+    UNIQUE (id, major, minor, patch)
+);
+
+CREATE UNIQUE INDEX mods_rowid ON mods (rowid);
+
+CREATE TABLE publish_keys (
+    pw text COLLATE "C" NOT NULL,
+    "user" text COLLATE "C" NOT NULL,
+    scope text,
+    expires_at bigint,
+
+    UNIQUE (pw, "user")
+);
@@ -0,0 +1,10 @@
+-- There are no PostgreSQL databases from before migrations, so the table only needs the
+-- constraints SQLite rebuilds its table for
+ALTER TABLE mods
+    ADD CHECK (major >= 0),
+    ADD CHECK (minor >= 0),
+    ADD CHECK (patch >= 0),
+    DROP CONSTRAINT mods_id_major_minor_patch_key;
+
+-- Ordered like resolving walks versions so it covers those queries too
+CREATE UNIQUE INDEX mods_id_version ON mods (id, major DESC, minor DESC, patch DESC);
@@ -0,0 +1,12 @@
+-- Versions that were deleted, so they can't be published again with different contents
+CREATE TABLE IF NOT EXISTS tombstones (
+    id text COLLATE "C" NOT NULL,
+
+    major bigint NOT NULL,
+    minor bigint NOT NULL,
+    patch bigint NOT NULL,
+
+    deleted_at bigint NOT NULL,
+
+    UNIQUE (id, major, minor, patch)
+);
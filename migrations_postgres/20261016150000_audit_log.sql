@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id bigserial PRIMARY KEY,
+    created_at bigint NOT NULL,
+
+    -- Publish key user, or "admin"
+    actor text NOT NULL,
+    action text NOT NULL,
+    package text,
+    version text,
+    ip text
+);
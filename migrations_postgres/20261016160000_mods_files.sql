@@ -0,0 +1,4 @@
+-- Left empty for versions uploaded before these were recorded
+ALTER TABLE mods ADD COLUMN size bigint;
+ALTER TABLE mods ADD COLUMN sha256 text;
+ALTER TABLE mods ADD COLUMN downloads bigint NOT NULL DEFAULT 0;
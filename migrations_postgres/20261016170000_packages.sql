@@ -0,0 +1,6 @@
+-- Settings of packages as a whole, packages without a row are public
+CREATE TABLE IF NOT EXISTS packages (
+    id text COLLATE "C" PRIMARY KEY NOT NULL,
+
+    visibility text NOT NULL DEFAULT 'public' CHECK (visibility IN ('public', 'private'))
+)
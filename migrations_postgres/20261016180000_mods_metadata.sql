@@ -0,0 +1,7 @@
+-- What publishers say about their versions, for frontends to show
+ALTER TABLE mods ADD COLUMN name text;
+ALTER TABLE mods ADD COLUMN description text;
+ALTER TABLE mods ADD COLUMN author text;
+ALTER TABLE mods ADD COLUMN website text;
+-- Beat Saber version the version is made for
+ALTER TABLE mods ADD COLUMN game_version text;
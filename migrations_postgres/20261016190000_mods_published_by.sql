@@ -0,0 +1,5 @@
+-- Publish key user or admin that uploaded each version, unknown for older versions
+ALTER TABLE mods ADD COLUMN published_by text;
+CREATE INDEX mods_published_by ON mods (published_by);
+-- Yanked versions are left out of resolves but can still be downloaded
+ALTER TABLE mods ADD COLUMN yanked_at bigint;
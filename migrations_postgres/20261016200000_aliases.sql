@@ -0,0 +1,6 @@
+-- Renamed packages, requests for the old id are served from the new one
+CREATE TABLE IF NOT EXISTS aliases (
+    old_id text COLLATE "C" PRIMARY KEY NOT NULL,
+    new_id text COLLATE "C" NOT NULL,
+    created_at bigint NOT NULL
+)
@@ -0,0 +1,13 @@
+-- Human readable documents publishers attach to their versions, in markdown
+CREATE TABLE IF NOT EXISTS readmes (
+    id text COLLATE "C" NOT NULL,
+
+    major bigint NOT NULL,
+    minor bigint NOT NULL,
+    patch bigint NOT NULL,
+
+    contents text NOT NULL,
+    updated_at bigint NOT NULL,
+
+    UNIQUE(id, major, minor, patch)
+)
@@ -0,0 +1,15 @@
+-- Extra files published alongside a version, like debug symbols
+CREATE TABLE IF NOT EXISTS artifacts (
+    id text COLLATE "C" NOT NULL,
+
+    major bigint NOT NULL,
+    minor bigint NOT NULL,
+    patch bigint NOT NULL,
+
+    name text COLLATE "C" NOT NULL,
+    size bigint NOT NULL,
+    sha256 text NOT NULL,
+    created_at bigint NOT NULL,
+
+    UNIQUE(id, major, minor, patch, name)
+)
@@ -0,0 +1,28 @@
+-- Game versions the index knows about, uploads can only be tagged with these
+CREATE TABLE IF NOT EXISTS game_versions (
+    version text COLLATE "C" PRIMARY KEY NOT NULL,
+    created_at bigint NOT NULL
+);
+
+-- Game versions each version is made for, versions without any are taken to work on all of them
+CREATE TABLE IF NOT EXISTS mod_game_versions (
+    id text COLLATE "C" NOT NULL,
+
+    major bigint NOT NULL,
+    minor bigint NOT NULL,
+    patch bigint NOT NULL,
+
+    game_version text COLLATE "C" NOT NULL,
+
+    UNIQUE(id, major, minor, patch, game_version)
+);
+
+-- Versions tagged through their metadata keep their tag
+INSERT INTO mod_game_versions (id, major, minor, patch, game_version)
+SELECT id, major, minor, patch, game_version FROM mods WHERE game_version IS NOT NULL
+ON CONFLICT DO NOTHING;
+
+INSERT INTO game_versions (version, created_at)
+SELECT DISTINCT game_version, CAST(EXTRACT(EPOCH FROM now()) AS bigint) FROM mods
+WHERE game_version IS NOT NULL
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,6 @@
+-- Packages pulled for good, resolves, downloads and uploads of them are answered with 410 Gone
+CREATE TABLE IF NOT EXISTS blocked_packages (
+    id text COLLATE "C" PRIMARY KEY NOT NULL,
+    reason text NOT NULL,
+    created_at bigint NOT NULL
+)
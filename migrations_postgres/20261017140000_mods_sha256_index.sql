@@ -0,0 +1,2 @@
+-- Finds versions with the same file, so identical uploads can share it
+CREATE INDEX mods_sha256 ON mods (sha256);
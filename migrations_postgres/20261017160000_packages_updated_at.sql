@@ -0,0 +1,3 @@
+-- When a package last changed in a way its versions' own timestamps don't show, like a version
+-- being deleted
+ALTER TABLE packages ADD COLUMN updated_at bigint;
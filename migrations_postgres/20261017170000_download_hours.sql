@@ -0,0 +1,11 @@
+-- Downloads of each package by the hour since the epoch, for rankings over recent windows. Hours
+-- past the retention are pruned in the background
+CREATE TABLE IF NOT EXISTS download_hours (
+    id text COLLATE "C" NOT NULL,
+    hour bigint NOT NULL,
+    downloads bigint NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (id, hour)
+);
+
+CREATE INDEX download_hours_hour ON download_hours (hour);
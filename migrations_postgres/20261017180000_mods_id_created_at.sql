@@ -0,0 +1,2 @@
+-- Counts a package's recent uploads for the per-package upload limit
+CREATE INDEX mods_id_created_at ON mods (id, created_at);
@@ -0,0 +1,11 @@
+-- Everything that changed what the index lists, in order, so mirrors can sync incrementally.
+-- Changes past the retention are pruned in the background, except the latest
+CREATE TABLE IF NOT EXISTS changes (
+    seq bigserial PRIMARY KEY,
+    action text NOT NULL,
+    id text COLLATE "C" NOT NULL,
+    version text NOT NULL,
+    created_at bigint NOT NULL
+);
+
+CREATE INDEX changes_created_at ON changes (created_at);
@@ -0,0 +1,3 @@
+-- Modloader a version is made for and the loader versions it works with
+ALTER TABLE mods ADD COLUMN loader text;
+ALTER TABLE mods ADD COLUMN loader_version_req text;
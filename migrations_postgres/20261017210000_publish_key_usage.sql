@@ -0,0 +1,6 @@
+-- When keys were added, last used and disabled for going unused, so stale keys can be disabled
+ALTER TABLE publish_keys ADD COLUMN created_at bigint;
+ALTER TABLE publish_keys ADD COLUMN last_used_at bigint;
+ALTER TABLE publish_keys ADD COLUMN disabled_at bigint;
+-- Keys from before count as added now, so they get a chance to be used before being disabled
+UPDATE publish_keys SET created_at = CAST(EXTRACT(EPOCH FROM now()) AS bigint);
@@ -0,0 +1,5 @@
+-- Whether an admin let a package be listed, for instances holding new packages for approval.
+-- Packages from before are approved, as is every package without a row
+ALTER TABLE packages ADD COLUMN approval text NOT NULL DEFAULT 'approved' CHECK (approval IN ('approved', 'pending', 'rejected'));
+-- The user whose key first published a package held for approval
+ALTER TABLE packages ADD COLUMN owner text;
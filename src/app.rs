@@ -0,0 +1,150 @@
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tower_service::Service;
+use warp::{Filter, filters::BoxedFilter, reply::Response};
+
+use crate::{
+    config::{Config, StorageKind},
+    db,
+    file_repo::FileRepo,
+    mirror::Mirror,
+    ratelimit::ClientAddr,
+    routes,
+    s3::S3Storage,
+    state::AppState,
+    storage::{LocalStorage, Storage},
+};
+
+/// Opens the storage the config points to, the cache isn't swept until `spawn_sweeper` is called
+pub fn file_repo(config: &'static Config) -> anyhow::Result<&'static FileRepo> {
+    let storage: Box<dyn Storage> = match config.storage {
+        StorageKind::Local => Box::new(LocalStorage::new(config.downloads_path.clone())),
+        StorageKind::S3 => Box::new(S3Storage::new(
+            config
+                .s3
+                .as_ref()
+                .context("S3 storage needs an `s3` config")?,
+        )?),
+    };
+    Ok(Box::leak(Box::new(FileRepo::new(
+        storage,
+        Duration::from_secs(config.missing_file_ttl),
+        config.cache_ttl_secs.map(Duration::from_secs),
+        Duration::from_secs(config.storage_timeout),
+        config.cache_on_upload,
+        config.compress_storage,
+    ))))
+}
+
+/// Connects to the database and storage the config points to and builds the routes over them,
+/// returning the state they share as well. Its background tasks run until [`serve`] shuts down
+pub async fn build(
+    config: &'static Config,
+) -> anyhow::Result<(BoxedFilter<(Response,)>, &'static AppState)> {
+    let file_repo = file_repo(config)?;
+
+    let pool = db::connect(config).await?;
+
+    if config.warm_cache {
+        let mods = db::Mod::all(pool).await?;
+        file_repo.warm(&mods).await?;
+    }
+
+    let mirror = Mirror::from_config(config)?;
+
+    let state = AppState::new(config, pool, file_repo, mirror).leak();
+    state.spawn_tasks();
+    Ok((routes::handler(state).boxed(), state))
+}
+
+/// Completes on Ctrl-C, or on SIGTERM as sent by service managers where there's such a thing
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("couldn't listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("couldn't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// Serves the routes on `addr` until `shutdown` completes, then waits for open connections to
+/// finish and stops the state's background tasks. Returns the address actually bound, which
+/// differs from `addr` if its port is 0
+pub async fn serve(
+    routes: BoxedFilter<(Response,)>,
+    state: &'static AppState,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let graceful = GracefulShutdown::new();
+        let mut shutdown = std::pin::pin!(shutdown);
+
+        // warp doesn't expose the peer address anymore, so we accept connections ourselves and
+        // pass it down to the filters as a request extension
+        loop {
+            let (stream, addr) = tokio::select! {
+                conn = listener.accept() => match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            let service = warp::service(routes.clone());
+            let service = warp::hyper::service::service_fn(move |mut req| {
+                req.extensions_mut().insert(ClientAddr(addr));
+                service.clone().call(req)
+            });
+
+            let conn = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let conn = graceful.watch(conn);
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    tracing::debug!("{}", e);
+                }
+            });
+        }
+
+        drop(listener);
+        tracing::info!("shutting down, waiting on {} connections", graceful.count());
+        graceful.shutdown().await;
+        state.stop_tasks().await;
+    });
+
+    Ok((addr, server))
+}
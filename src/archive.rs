@@ -0,0 +1,398 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{TryStreamExt, stream::BoxStream};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::AnyPool;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::{
+    db::{Change, Mod, PublishKey, Upload},
+    file_repo::FileRepo,
+    storage::{Storage, file_key, parse_key},
+};
+
+const BLOCK_SIZE: usize = 512;
+
+const MANIFEST: &str = "manifest.json";
+/// Directory files are kept under in the archive, laid out as in storage
+const FILES_PREFIX: &str = "files/";
+
+/// What the index knew about at export time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub mods: Vec<Upload>,
+    pub publish_keys: Vec<ExportedKey>,
+}
+
+/// A publish key, hashed so the archive doesn't leak it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedKey {
+    pub user: String,
+    pub pw_sha256: String,
+    pub scope: Option<String>,
+    pub expires_at: Option<i64>,
+}
+
+impl From<PublishKey> for ExportedKey {
+    fn from(key: PublishKey) -> Self {
+        Self {
+            user: key.user,
+            pw_sha256: hex::encode(Sha256::digest(key.pw.as_bytes())),
+            scope: key.scope,
+            expires_at: key.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub added: Vec<Mod>,
+    /// Versions that were already there
+    pub skipped: Vec<Mod>,
+    /// Versions listed in the manifest without a file in the archive
+    pub missing: Vec<Mod>,
+    /// Users whose keys can't be restored since only their hash was exported
+    pub keys_not_restored: Vec<String>,
+}
+
+/// Writes the whole index to a tar archive at `path`, one file at a time. The archive is written
+/// next to it first so a concurrent download never sees it half written. Files are read past the
+/// cache, which would otherwise end up holding all of them
+pub async fn export(path: &Path, pool: &AnyPool, file_repo: &FileRepo) -> anyhow::Result<()> {
+    let manifest = Manifest {
+        mods: Mod::uploads(pool).await?,
+        publish_keys: PublishKey::all(pool)
+            .await?
+            .into_iter()
+            .map(ExportedKey::from)
+            .collect(),
+    };
+
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}.partial", crate::logging::uuid_v4()));
+    let result = async {
+        let mut tar = TarWriter::new(File::create(&partial).await?);
+        tar.append(MANIFEST, &serde_json::to_vec_pretty(&manifest)?)
+            .await?;
+        for m in &manifest.mods {
+            match file_repo.get_uncached(&m.id, &m.version).await {
+                Ok(contents) => {
+                    let name = format!("{}{}", FILES_PREFIX, file_key(&m.id, &m.version));
+                    tar.append(&name, &contents).await?;
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    tracing::warn!("{} {} has no file to export", m.id, m.version);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tar.finish().await?;
+
+        fs::rename(&partial, path).await?;
+        anyhow::Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        fs::remove_file(&partial).await.ok();
+    }
+    result
+}
+
+/// Contents of an archive made by [`export`]
+pub struct Archive {
+    manifest: Manifest,
+    files: HashMap<(String, Version), Bytes>,
+}
+
+impl Archive {
+    pub fn parse(archive: &Bytes) -> anyhow::Result<Archive> {
+        let mut manifest = None;
+        let mut files = HashMap::new();
+        for (name, contents) in read_tar(archive)? {
+            if name == MANIFEST {
+                manifest = Some(serde_json::from_slice(&contents)?);
+            } else if let Some(key) = name.strip_prefix(FILES_PREFIX).and_then(parse_key) {
+                files.insert(key, contents);
+            }
+        }
+
+        Ok(Archive {
+            manifest: manifest.ok_or_else(|| anyhow::anyhow!("the archive has no manifest"))?,
+            files,
+        })
+    }
+}
+
+/// Restores an archive, leaving versions that already exist alone
+pub async fn import(
+    archive: Archive,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> anyhow::Result<ImportReport> {
+    let Archive {
+        manifest,
+        mut files,
+    } = archive;
+
+    let mut report = ImportReport {
+        keys_not_restored: manifest.publish_keys.into_iter().map(|k| k.user).collect(),
+        ..Default::default()
+    };
+    for upload in manifest.mods {
+        let m = Mod {
+            id: upload.id,
+            version: upload.version,
+        };
+        if Mod::versions(&m.id, pool).await?.contains(&m.version) {
+            report.skipped.push(m);
+            continue;
+        }
+        let Some(contents) = files.remove(&(m.id.clone(), m.version.clone())) else {
+            report.missing.push(m);
+            continue;
+        };
+
+        // File first, so the version is never listed without it
+        storage
+            .write_file(&m.id, &m.version, contents.clone())
+            .await?;
+        storage.forget(&m.id, &m.version).await;
+        if Mod::insert_at(&m.id, &m.version, upload.created_at, true, pool).await? {
+            Mod::record_file(&m.id, &m.version, &contents, pool).await?;
+            let stored_size = storage.stored_size(&m.id, &m.version).await?;
+            Mod::record_stored_size(&m.id, &m.version, stored_size, pool).await?;
+            Change::record("upload", &m.id, &m.version, pool).await?;
+            report.added.push(m);
+        } else {
+            report.skipped.push(m);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Writes a ustar archive
+struct TarWriter {
+    out: BufWriter<File>,
+}
+
+impl TarWriter {
+    fn new(file: File) -> Self {
+        Self {
+            out: BufWriter::new(file),
+        }
+    }
+
+    async fn append(&mut self, name: &str, contents: &[u8]) -> Result<()> {
+        self.out.write_all(&header(name, contents.len())?).await?;
+        self.out.write_all(contents).await?;
+        self.out
+            .write_all(&[0; BLOCK_SIZE][..padding(contents.len())])
+            .await
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        self.out.write_all(&[0; BLOCK_SIZE * 2]).await?;
+        self.out.flush().await?;
+        self.out.into_inner().sync_all().await
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+pub fn header(name: &str, size: usize) -> Result<[u8; BLOCK_SIZE]> {
+    // Names longer than the name field are split at a slash into the prefix field
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        name.char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && name.len() - i - 1 <= 100)
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is too long", name)))?
+    };
+
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// An entry of a ustar archive, as told by its header
+struct Entry {
+    name: String,
+    size: u64,
+    /// Only regular files matter
+    is_file: bool,
+}
+
+/// Parses a header block, `None` for the zeroed blocks ending the archive
+fn parse_header(header: &[u8]) -> Result<Option<Entry>> {
+    if header.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+
+    let checksum: u32 = header[..148]
+        .iter()
+        .chain(&[b' '; 8])
+        .chain(&header[156..])
+        .map(|&b| u32::from(b))
+        .sum();
+    if octal(&header[148..156]) != Some(u64::from(checksum)) {
+        return Err(invalid("bad tar header checksum"));
+    }
+
+    let size = octal(&header[124..136]).ok_or_else(|| invalid("bad tar entry size"))?;
+    let name = field(&header[..100]);
+    let name = match field(&header[345..500]) {
+        "" => name.to_owned(),
+        prefix => format!("{}/{}", prefix, name),
+    };
+    Ok(Some(Entry {
+        name,
+        size,
+        is_file: matches!(header[156], b'0' | 0),
+    }))
+}
+
+/// Reads the regular files of a ustar archive
+fn read_tar(archive: &Bytes) -> Result<Vec<(String, Bytes)>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let Some(entry) = parse_header(&archive[offset..offset + BLOCK_SIZE])? else {
+            break;
+        };
+
+        let size = entry.size as usize;
+        let start = offset + BLOCK_SIZE;
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= archive.len())
+            .ok_or_else(|| invalid("truncated tar archive"))?;
+        if entry.is_file {
+            entries.push((entry.name, archive.slice(start..end)));
+        }
+
+        offset = end + padding(size);
+    }
+
+    Ok(entries)
+}
+
+/// A regular file of an archive read by [`TarReader`], without its contents if they were too
+/// large to read
+pub struct TarFile {
+    pub name: String,
+    pub contents: Option<Bytes>,
+}
+
+/// Reads the regular files of a ustar archive as it streams in, holding at most one file in
+/// memory at a time
+pub struct TarReader {
+    stream: BoxStream<'static, Result<Bytes>>,
+    buf: BytesMut,
+}
+
+impl TarReader {
+    pub fn new(stream: BoxStream<'static, Result<Bytes>>) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Reads until `len` bytes are buffered, returning false if the stream ends first
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        while self.buf.len() < len {
+            match self.stream.try_next().await? {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads `len` bytes, or drops them if `keep` is false
+    async fn take(&mut self, len: u64, keep: bool) -> Result<Option<Bytes>> {
+        if keep {
+            if !self.fill(len as usize).await? {
+                return Err(invalid("truncated tar archive"));
+            }
+            return Ok(Some(self.buf.split_to(len as usize).freeze()));
+        }
+
+        let mut left = len;
+        while left > 0 {
+            if self.buf.is_empty() && !self.fill(1).await? {
+                return Err(invalid("truncated tar archive"));
+            }
+            let dropped = left.min(self.buf.len() as u64);
+            self.buf.advance(dropped as usize);
+            left -= dropped;
+        }
+        Ok(None)
+    }
+
+    /// The next regular file, reading its contents if they're at most `max_size` bytes
+    pub async fn next_file(&mut self, max_size: u64) -> Result<Option<TarFile>> {
+        loop {
+            // Archives missing the blocks that end them end all the same
+            if !self.fill(BLOCK_SIZE).await? {
+                return Ok(None);
+            }
+            let Some(entry) = parse_header(&self.buf.split_to(BLOCK_SIZE))? else {
+                return Ok(None);
+            };
+
+            let keep = entry.is_file && entry.size <= max_size;
+            let contents = self.take(entry.size, keep).await?;
+            self.take(padding(entry.size as usize) as u64, false)
+                .await?;
+            if entry.is_file {
+                return Ok(Some(TarFile {
+                    name: entry.name,
+                    contents,
+                }));
+            }
+        }
+    }
+}
+
+fn field(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..len]).unwrap_or_default()
+}
+
+fn octal(bytes: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(bytes).ok()?;
+    u64::from_str_radix(digits.trim_matches(|c| c == ' ' || c == '\0'), 8).ok()
+}
@@ -0,0 +1,117 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use sqlx::AnyPool;
+use tokio::{fs, sync::Mutex, task::JoinHandle};
+
+use crate::{config::BackupConfig, datetime::DateTime};
+
+const PREFIX: &str = "index-";
+const SUFFIX: &str = ".db";
+
+/// Copies the database into timestamped files, one backup at a time
+pub struct Backups {
+    config: &'static BackupConfig,
+    running: Mutex<()>,
+}
+
+impl Backups {
+    pub fn new(config: &'static BackupConfig) -> Self {
+        Self {
+            config,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Backs up every `interval_hours` if scheduled backups are enabled, the first one being
+    /// right away
+    pub fn spawn(&'static self, pool: &'static AnyPool) -> Option<JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let period = Duration::from_secs(self.config.interval_hours.max(1) * 3600);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match self.run(pool).await {
+                    Ok(path) => tracing::info!("backed the database up to {}", path.display()),
+                    Err(e) => tracing::error!("couldn't back the database up: {:#}", e),
+                }
+            }
+        }))
+    }
+
+    /// Backs the database up, then removes backups past the number to keep. Returns the path of
+    /// the new backup
+    pub async fn run(&self, pool: &AnyPool) -> anyhow::Result<PathBuf> {
+        let _running = self.running.lock().await;
+        fs::create_dir_all(&self.config.dir)
+            .await
+            .with_context(|| format!("couldn't create {}", self.config.dir.display()))?;
+
+        // Names only go down to the millisecond, so wait out one taken by the previous backup
+        let mut path = self.config.dir.join(file_name(SystemTime::now()));
+        while fs::try_exists(&path).await? {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            path = self.config.dir.join(file_name(SystemTime::now()));
+        }
+
+        // Copies a consistent snapshot from a read transaction, so writers carry on meanwhile
+        let target = path.to_str().context("backup path isn't UTF-8")?;
+        sqlx::query("VACUUM INTO ?")
+            .bind(target)
+            .execute(pool)
+            .await?;
+
+        if self.config.keep > 0 {
+            prune(&self.config.dir, self.config.keep).await?;
+        }
+        Ok(path)
+    }
+}
+
+/// `index-YYYYMMDDTHHMMSS.mmmZ.db`, which sorts the same as the times it's made from
+fn file_name(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let t = DateTime::from_unix(since_epoch.as_secs() as i64);
+    format!(
+        "{}{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z{}",
+        PREFIX,
+        t.year,
+        t.month,
+        t.day,
+        t.hour,
+        t.minute,
+        t.second,
+        since_epoch.subsec_millis(),
+        SUFFIX
+    )
+}
+
+/// Removes all but the newest `keep` backups in the directory
+async fn prune(dir: &Path, keep: usize) -> anyhow::Result<()> {
+    let mut backups = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if name
+            .to_str()
+            .is_some_and(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+        {
+            backups.push(entry.path());
+        }
+    }
+
+    backups.sort();
+    let stale = backups.len().saturating_sub(keep);
+    for path in &backups[..stale] {
+        fs::remove_file(path)
+            .await
+            .with_context(|| format!("couldn't remove {}", path.display()))?;
+    }
+    Ok(())
+}
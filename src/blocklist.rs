@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use sqlx::AnyPool;
+use tokio::sync::RwLock;
+
+use crate::db::Block;
+
+/// Blocked package ids and their reasons, loaded from the database on first use and again after
+/// every change, so looking a package up doesn't hit the database
+#[derive(Default)]
+pub struct Blocklist {
+    blocked: RwLock<Option<HashMap<String, String>>>,
+}
+
+impl Blocklist {
+    /// Why the package is blocked, if it is
+    pub async fn reason(&self, id: &str, pool: &AnyPool) -> sqlx::Result<Option<String>> {
+        if let Some(blocked) = &*self.blocked.read().await {
+            return Ok(blocked.get(id).cloned());
+        }
+
+        let mut blocked = self.blocked.write().await;
+        if blocked.is_none() {
+            let loaded = Block::all(pool).await?;
+            *blocked = Some(
+                loaded
+                    .into_iter()
+                    .map(|b| (b.id.into(), b.reason))
+                    .collect(),
+            );
+        }
+        Ok(blocked.as_ref().and_then(|b| b.get(id).cloned()))
+    }
+
+    pub async fn block(&self, block: &Block, pool: &AnyPool) -> sqlx::Result<()> {
+        let mut blocked = self.blocked.write().await;
+        let result = block.insert(pool).await;
+        *blocked = None;
+        result
+    }
+
+    /// Returns false if the package wasn't blocked
+    pub async fn unblock(&self, id: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let mut blocked = self.blocked.write().await;
+        let result = Block::delete(id, pool).await;
+        *blocked = None;
+        result
+    }
+}
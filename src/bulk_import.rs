@@ -0,0 +1,143 @@
+use std::{
+    collections::VecDeque,
+    io::Result,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use semver::Version;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{archive::TarReader, db::Mod, storage::key_path};
+
+/// A file to import, or why it can't be read
+pub struct ImportFile {
+    pub name: String,
+    pub contents: std::result::Result<Bytes, String>,
+}
+
+/// Where a bulk import reads files from, one at a time
+pub enum Source {
+    Tar(TarReader),
+    /// Files left to read, relative to `root`
+    Dir {
+        root: PathBuf,
+        names: VecDeque<String>,
+    },
+}
+
+impl Source {
+    /// Lists the files of a directory laid out as `{id}/{version}` or `{id}-{version}.qmod`,
+    /// sorted by name. Anything else, like symlinks, is left out
+    pub async fn dir(root: PathBuf) -> Result<Source> {
+        let mut names = VecDeque::new();
+        for (name, is_dir) in list(&root).await? {
+            if !is_dir {
+                names.push_back(name);
+                continue;
+            }
+            for (version, is_dir) in list(&root.join(&name)).await? {
+                if !is_dir {
+                    names.push_back(format!("{}/{}", name, version));
+                }
+            }
+        }
+        Ok(Source::Dir { root, names })
+    }
+
+    /// The next file, read if it's at most `max_size` bytes
+    pub async fn next(&mut self, max_size: u64) -> Result<Option<ImportFile>> {
+        let too_large = || format!("larger than {} bytes", max_size);
+        match self {
+            Source::Tar(tar) => Ok(tar.next_file(max_size).await?.map(|file| ImportFile {
+                name: file.name,
+                contents: file.contents.ok_or_else(too_large),
+            })),
+            Source::Dir { root, names } => {
+                let Some(name) = names.pop_front() else {
+                    return Ok(None);
+                };
+                let path = key_path(root, &name);
+                let contents = match fs::metadata(&path).await {
+                    Ok(metadata) if metadata.len() > max_size => Err(too_large()),
+                    Ok(_) => fs::read(&path)
+                        .await
+                        .map(Bytes::from)
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                Ok(Some(ImportFile { name, contents }))
+            }
+        }
+    }
+}
+
+/// Names of the regular files and directories in `dir`, and whether they're directories
+async fn list(dir: &Path) -> Result<Vec<(String, bool)>> {
+    let mut entries = Vec::new();
+    let mut read = fs::read_dir(dir).await?;
+    while let Some(entry) = read.next_entry().await? {
+        let file_type = entry.file_type().await?;
+        if !file_type.is_file() && !file_type.is_dir() {
+            continue;
+        }
+        // Names that aren't UTF-8 can't name a package anyway
+        if let Ok(name) = entry.file_name().into_string() {
+            entries.push((name, file_type.is_dir()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Package and version a file is for, from a name like `{id}/{version}` or
+/// `{id}-{version}.qmod`. Ids can have dashes too, the first split leaving a valid version wins
+pub fn parse_name(name: &str) -> std::result::Result<(String, Version), String> {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    let (id, version) = match name.split_once('/') {
+        Some((id, version)) => {
+            let version = version.strip_suffix(".qmod").unwrap_or(version);
+            let version = Version::parse(version)
+                .map_err(|e| format!("invalid version {}: {}", version, e))?;
+            (id, version)
+        }
+        None => {
+            let stem = name
+                .strip_suffix(".qmod")
+                .ok_or("expected `{id}/{version}` or `{id}-{version}.qmod`")?;
+            stem.match_indices('-')
+                .find_map(|(i, _)| Some((&stem[..i], Version::parse(&stem[i + 1..]).ok()?)))
+                .ok_or_else(|| format!("no valid version in {}", name))?
+        }
+    };
+    if !is_valid_id(id) {
+        return Err(format!("invalid package id {}", id));
+    }
+    Ok((id.to_lowercase(), version))
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BulkImportReport {
+    pub created: Vec<Mod>,
+    /// Versions that were already published
+    pub conflicted: Vec<NotImported>,
+    pub failed: Vec<NotImported>,
+    /// Why the import stopped before the last file, if it did. Files before it were imported
+    pub stopped: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotImported {
+    /// Name of the file in the archive or directory
+    pub name: String,
+    pub reason: String,
+}
@@ -0,0 +1,300 @@
+use std::{fmt, path::PathBuf};
+
+use anyhow::Context;
+use bytes::Bytes;
+use clap::{ArgGroup, Parser};
+use semver::Version;
+use serde::Serialize;
+use sqlx::AnyPool;
+
+use crate::{
+    db::{AuditEntry, Change, Mod, PublishKey},
+    storage::Storage,
+    verify::{Verifier, VerifyReport},
+};
+
+/// Recorded as the actor in the audit log for changes made from the command line
+const CLI_ACTOR: &str = "cli";
+
+#[derive(Debug, Parser)]
+#[command(about)]
+struct Args {
+    /// Path to the config file
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Print results as JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+    /// Leave migrating the database to other instances
+    #[arg(long, global = true)]
+    skip_migrations: bool,
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+    /// Config to serve with, the way the server used to be started
+    #[arg(hide = true)]
+    serve_config: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Subcommand {
+    /// Serve the index, the default
+    Serve { config: Option<String> },
+    /// Add a publish key
+    AddKey {
+        #[arg(long)]
+        user: String,
+        #[arg(long)]
+        pw: String,
+    },
+    /// Delete a user's keys, or a single key
+    #[command(group(ArgGroup::new("key").required(true)))]
+    DeleteKey {
+        #[arg(long, group = "key")]
+        user: Option<String>,
+        #[arg(long, group = "key")]
+        pw: Option<String>,
+    },
+    /// List every version
+    List,
+    /// Publish a file as a version
+    ImportFile {
+        id: String,
+        version: Version,
+        path: PathBuf,
+    },
+    /// Check files against their recorded hashes
+    Verify,
+}
+
+/// What the binary was asked to do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    AddKey {
+        user: String,
+        pw: String,
+    },
+    DeleteKey(KeySelector),
+    List,
+    ImportFile {
+        id: String,
+        version: Version,
+        path: PathBuf,
+    },
+    Verify,
+}
+
+/// Which keys to delete
+#[derive(Debug, PartialEq)]
+pub enum KeySelector {
+    User(String),
+    Pw(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub config: String,
+    /// Print results as JSON instead of text
+    pub json: bool,
+    /// Leave migrating the database to other instances, whatever the config says
+    pub skip_migrations: bool,
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parses the arguments, including the binary name. A lone argument that isn't a command is
+    /// taken as the config path to serve with, like before there were commands
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, clap::Error> {
+        let args = Args::try_parse_from(args)?;
+        let mut config = args.config.or(args.serve_config);
+        let command = match args.command {
+            None => Command::Serve,
+            Some(Subcommand::Serve { config: path }) => {
+                config = path.or(config);
+                Command::Serve
+            }
+            Some(Subcommand::AddKey { user, pw }) => Command::AddKey { user, pw },
+            Some(Subcommand::DeleteKey { user, pw }) => match (user, pw) {
+                (Some(user), _) => Command::DeleteKey(KeySelector::User(user)),
+                (_, Some(pw)) => Command::DeleteKey(KeySelector::Pw(pw)),
+                // The group makes one of them required
+                (None, None) => unreachable!(),
+            },
+            Some(Subcommand::List) => Command::List,
+            Some(Subcommand::ImportFile { id, version, path }) => {
+                Command::ImportFile { id, version, path }
+            }
+            Some(Subcommand::Verify) => Command::Verify,
+        };
+        Ok(Cli {
+            config: config.unwrap_or_else(|| "config.json".to_owned()),
+            json: args.json,
+            skip_migrations: args.skip_migrations,
+            command,
+        })
+    }
+}
+
+/// Prints a command's outcome, as JSON if asked to
+pub fn print(output: &(impl Serialize + fmt::Display), json: bool) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(output)?);
+    } else {
+        println!("{}", output);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyAdded {
+    pub user: String,
+}
+
+impl fmt::Display for KeyAdded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "added a key for {}", self.user)
+    }
+}
+
+pub async fn add_key(user: &str, pw: &str, pool: &AnyPool) -> anyhow::Result<KeyAdded> {
+    let key = PublishKey {
+        pw: pw.trim().to_owned(),
+        user: user.trim().to_owned(),
+        scope: None,
+        expires_at: None,
+        last_used_at: None,
+        disabled_at: None,
+    };
+    if key.pw.is_empty() || key.user.is_empty() {
+        anyhow::bail!("the user and password can't be empty");
+    }
+    if !key.insert(pool).await? {
+        anyhow::bail!("that key exists already");
+    }
+    AuditEntry::insert(CLI_ACTOR, "add_key", None, None, None, pool).await?;
+    Ok(KeyAdded { user: key.user })
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyDeleted {
+    pub deleted: bool,
+}
+
+impl fmt::Display for KeyDeleted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.deleted {
+            true => write!(f, "deleted"),
+            false => write!(f, "no such key"),
+        }
+    }
+}
+
+pub async fn delete_key(selector: &KeySelector, pool: &AnyPool) -> anyhow::Result<KeyDeleted> {
+    let deleted = match selector {
+        KeySelector::User(user) => PublishKey::delete_user(user, pool).await?,
+        KeySelector::Pw(pw) => PublishKey::delete_pw(pw, pool).await?,
+    };
+    if deleted {
+        AuditEntry::insert(CLI_ACTOR, "delete_key", None, None, None, pool).await?;
+    }
+    Ok(KeyDeleted { deleted })
+}
+
+/// Every version, by package
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct Listing(pub Vec<Mod>);
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<_> = self
+            .0
+            .iter()
+            .map(|m| format!("{} {}", m.id, m.version))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+pub async fn list(pool: &AnyPool) -> anyhow::Result<Listing> {
+    Ok(Listing(Mod::all(pool).await?))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Imported {
+    #[serde(flatten)]
+    pub m: Mod,
+    pub sha256: Option<String>,
+}
+
+impl fmt::Display for Imported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "imported {} {}", self.m.id, self.m.version)
+    }
+}
+
+/// Publishes the file as the version, the same way an upload would
+pub async fn import_file(
+    id: &str,
+    ver: &Version,
+    path: &PathBuf,
+    build_variants: bool,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> anyhow::Result<Imported> {
+    let contents = Bytes::from(
+        tokio::fs::read(path)
+            .await
+            .with_context(|| format!("couldn't read {}", path.display()))?,
+    );
+    if !Mod::insert(id, ver, build_variants, pool).await? {
+        anyhow::bail!("{} {} exists already", id, ver);
+    }
+    Mod::record_publisher(id, ver, CLI_ACTOR, pool).await?;
+    Mod::record_file(id, ver, &contents, pool).await?;
+    if let Err(e) = storage.write_file(id, ver, contents).await {
+        // Leave the version free to be imported again rather than listed without a file
+        Mod::delete(id, ver, pool).await?;
+        return Err(e.into());
+    }
+    Mod::record_stored_size(id, ver, storage.stored_size(id, ver).await?, pool).await?;
+    AuditEntry::insert(CLI_ACTOR, "upload", Some(id), Some(ver), None, pool).await?;
+    Change::record("upload", id, ver, pool).await?;
+
+    let published = Mod::get(id, ver, pool)
+        .await?
+        .context("version deleted while importing")?;
+    Ok(Imported {
+        m: Mod {
+            id: published.id,
+            version: published.version,
+        },
+        sha256: published.sha256,
+    })
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} mismatched, {} missing, {} without a hash",
+            self.mismatched.len(),
+            self.missing.len(),
+            self.unhashed.len()
+        )?;
+        for mismatch in &self.mismatched {
+            write!(f, "\nmismatched {} {}", mismatch.m.id, mismatch.m.version)?;
+        }
+        for m in &self.missing {
+            write!(f, "\nmissing {} {}", m.id, m.version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every file against its recorded hash, leaving mismatched files where they are
+pub async fn verify(pool: &AnyPool, storage: &dyn Storage) -> anyhow::Result<VerifyReport> {
+    let verifier = Verifier::default();
+    verifier.run(false, pool, storage).await?;
+    Ok(verifier.status().report)
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+
+use bytes::Bytes;
+use semver::{Version, VersionReq};
+use serde::de::DeserializeOwned;
+use warp::http::{Method, StatusCode};
+
+use crate::{
+    http_client::{Endpoint, uri_encode},
+    types::{Mod, PublishKey},
+};
+
+/// An index responded with an unexpected status, callers can downcast to it to tell them apart
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    /// Body of the response, usually a JSON error
+    pub body: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "index responded with {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Talks to an index over HTTP, one connection per request
+#[derive(Debug)]
+pub struct IndexClient {
+    endpoint: Endpoint,
+    /// Publish or admin key sent along with every request
+    auth: Option<String>,
+}
+
+impl IndexClient {
+    pub fn new(base_url: &str, auth: Option<&str>) -> anyhow::Result<IndexClient> {
+        Ok(IndexClient {
+            endpoint: Endpoint::parse(base_url)?,
+            auth: auth.map(str::to_owned),
+        })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> anyhow::Result<(StatusCode, Bytes)> {
+        let mut headers = Vec::new();
+        if let Some(auth) = &self.auth {
+            headers.push(("authorization", auth.as_str()));
+        }
+        if let Some(content_type) = content_type {
+            headers.push(("content-type", content_type));
+        }
+        let uri = format!("{}{}", self.endpoint.path(), path);
+        Ok(self.endpoint.send(&method, &uri, &headers, body).await?)
+    }
+
+    /// Sends the request and fails unless the response has one of the `expected` statuses
+    async fn expect(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: Option<&str>,
+        body: &[u8],
+        expected: &[StatusCode],
+    ) -> anyhow::Result<(StatusCode, Bytes)> {
+        let (status, body) = self.send(method, path, content_type, body).await?;
+        if !expected.contains(&status) {
+            return Err(StatusError {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            }
+            .into());
+        }
+        Ok((status, body))
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let (_, body) = self
+            .expect(Method::GET, path, None, b"", &[StatusCode::OK])
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Ids of every package
+    pub async fn list(&self) -> anyhow::Result<Vec<String>> {
+        self.get_json("/").await
+    }
+
+    /// Versions of the package matching `req`, newest first. A `limit` of 0 returns all of them
+    pub async fn resolve(
+        &self,
+        id: &str,
+        req: &VersionReq,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Mod>> {
+        let path = format!(
+            "/{}?req={}&limit={}",
+            uri_encode(id),
+            uri_encode(&req.to_string()),
+            limit
+        );
+        // A single version is returned as is, or not found
+        if limit == 1 {
+            let (status, body) = self
+                .expect(
+                    Method::GET,
+                    &path,
+                    None,
+                    b"",
+                    &[StatusCode::OK, StatusCode::NOT_FOUND],
+                )
+                .await?;
+            return match status {
+                StatusCode::OK => Ok(vec![serde_json::from_slice(&body)?]),
+                _ => Ok(Vec::new()),
+            };
+        }
+        self.get_json(&path).await
+    }
+
+    /// Contents of the version's file
+    pub async fn download(&self, id: &str, ver: &Version) -> anyhow::Result<Bytes> {
+        let path = format!("/{}/{}", uri_encode(id), ver);
+        let (_, body) = self
+            .expect(Method::GET, &path, None, b"", &[StatusCode::OK])
+            .await?;
+        Ok(body)
+    }
+
+    /// Publishes a version, succeeding as well if it already exists with the same contents
+    pub async fn upload(&self, id: &str, ver: &Version, contents: &[u8]) -> anyhow::Result<Mod> {
+        let path = format!("/{}/{}", uri_encode(id), ver);
+        let (_, body) = self
+            .expect(
+                Method::POST,
+                &path,
+                Some("application/octet-stream"),
+                contents,
+                &[StatusCode::CREATED, StatusCode::OK],
+            )
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Deletes a version, needs an admin key
+    pub async fn delete(&self, id: &str, ver: &Version) -> anyhow::Result<()> {
+        let path = format!("/{}/{}", uri_encode(id), ver);
+        self.expect(Method::DELETE, &path, None, b"", &[StatusCode::OK])
+            .await?;
+        Ok(())
+    }
+
+    /// Adds a publish key, needs an admin key
+    pub async fn add_key(&self, key: &PublishKey) -> anyhow::Result<()> {
+        self.expect(
+            Method::POST,
+            "/publish_key",
+            Some("application/json"),
+            &serde_json::to_vec(key)?,
+            &[StatusCode::CREATED],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes a publish key by its password, needs an admin key
+    pub async fn delete_key(&self, pw: &str) -> anyhow::Result<()> {
+        self.expect(
+            Method::POST,
+            "/delete_key",
+            Some("application/json"),
+            &serde_json::to_vec(&serde_json::json!({ "pw": pw }))?,
+            &[StatusCode::OK],
+        )
+        .await?;
+        Ok(())
+    }
+}
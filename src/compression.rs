@@ -0,0 +1,228 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::{
+    Compression, GzBuilder,
+    read::GzDecoder,
+    write::{self, DeflateEncoder, GzEncoder},
+};
+use futures::{Stream, TryStreamExt};
+use serde::Serialize;
+use warp::{
+    Filter, Rejection, Reply,
+    http::{
+        HeaderValue,
+        header::{CONTENT_ENCODING, CONTENT_TYPE, VARY},
+    },
+    reply::Response,
+};
+
+use crate::{
+    config::Config,
+    errors::{ApiError, TryExt},
+};
+
+/// Local file header signature every zip archive starts with
+pub const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// Subfield in the gzip header of files compressed at rest, so they can't be mistaken for a gzip
+/// file someone uploaded
+const STORED_SUBFIELD: &[u8] = b"QI";
+
+/// Gzips a file to keep in storage, unless it's a zip or compressing it doesn't make it smaller
+pub fn compress_stored(contents: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+    if contents.starts_with(ZIP_MAGIC) {
+        return Ok(None);
+    }
+    let mut extra = STORED_SUBFIELD.to_vec();
+    extra.extend_from_slice(&[0, 0]);
+    let mut encoder = GzBuilder::new()
+        .extra(extra)
+        .write(Vec::new(), Compression::default());
+    encoder.write_all(contents)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() < contents.len()).then_some(compressed))
+}
+
+/// Whether a file in storage was gzipped by [`compress_stored`]
+pub fn is_compressed_stored(contents: &[u8]) -> bool {
+    // Magic, deflate, FEXTRA set, then the extra field after the 10 byte header and its length
+    contents.len() > 14
+        && contents[..3] == [0x1f, 0x8b, 0x08]
+        && contents[3] & 0x04 != 0
+        && &contents[12..14] == STORED_SUBFIELD
+}
+
+/// Undoes [`compress_stored`], handing back files stored as they were untouched
+pub fn decompress_stored(contents: Bytes) -> std::io::Result<Bytes> {
+    if !is_compressed_stored(&contents) {
+        return Ok(contents);
+    }
+    let mut decompressed = Vec::new();
+    GzDecoder::new(contents.as_ref()).read_to_end(&mut decompressed)?;
+    Ok(decompressed.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    /// Picks the preferred encoding from an `Accept-Encoding` header value, favouring gzip
+    fn negotiate(header: &str) -> Encoding {
+        let mut best = (Encoding::Identity, 0.0);
+        for item in header.split(',') {
+            let mut parts = item.split(';');
+            let encoding = match parts.next().map(str::trim) {
+                Some(e) if e.eq_ignore_ascii_case("gzip") || e == "*" => Encoding::Gzip,
+                Some(e) if e.eq_ignore_ascii_case("deflate") => Encoding::Deflate,
+                _ => continue,
+            };
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // q=0 means the client won't take that encoding at all
+            if q <= 0.0 {
+                continue;
+            }
+
+            if q > best.1 || (q == best.1 && encoding == Encoding::Gzip) {
+                best = (encoding, q);
+            }
+        }
+        best.0
+    }
+}
+
+/// Extracts the encoding to compress responses with, always identity if compression is disabled
+pub fn accept_encoding(
+    config: &'static Config,
+) -> impl Filter<Extract = (Encoding,), Error = Rejection> + Send + Sync + Clone + 'static {
+    warp::header::optional("Accept-Encoding").map(move |header: Option<HeaderValue>| {
+        match header.as_ref().and_then(|h| h.to_str().ok()) {
+            Some(header) if config.compression => Encoding::negotiate(header),
+            _ => Encoding::Identity,
+        }
+    })
+}
+
+/// Serializes a JSON reply, compressing it with the given encoding
+pub fn json<T: Serialize>(value: &T, encoding: Encoding) -> Result<Response, ApiError> {
+    json_body(serde_json::to_vec(value).or_ise()?, encoding)
+}
+
+/// A reply of JSON serialized already, compressed with the given encoding
+pub fn json_body(body: Vec<u8>, encoding: Encoding) -> Result<Response, ApiError> {
+    compressed(body, "application/json", encoding)
+}
+
+/// A plain text reply with a line for each item, for scripts without a JSON parser at hand
+pub fn text_lines<T: fmt::Display>(
+    lines: impl IntoIterator<Item = T>,
+    encoding: Encoding,
+) -> Result<Response, ApiError> {
+    let mut body = String::new();
+    for line in lines {
+        writeln!(body, "{}", line).or_ise()?;
+    }
+    compressed(body.into_bytes(), "text/plain; charset=utf-8", encoding)
+}
+
+/// A reply of the body compressed with the given encoding
+fn compressed(
+    body: Vec<u8>,
+    content_type: &'static str,
+    encoding: Encoding,
+) -> Result<Response, ApiError> {
+    let (body, content_encoding) = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).or_ise()?;
+            (encoder.finish().or_ise()?, Some("gzip"))
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body).or_ise()?;
+            (encoder.finish().or_ise()?, Some("deflate"))
+        }
+        Encoding::Identity => (body, None),
+    };
+
+    let mut response = body.into_response();
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(content_encoding) = content_encoding {
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+    }
+    Ok(response)
+}
+
+/// Encoding of a request body from its `Content-Encoding`, only gzip is understood
+pub fn content_encoding(header: Option<&HeaderValue>) -> Result<Encoding, ApiError> {
+    let Some(header) = header else {
+        return Ok(Encoding::Identity);
+    };
+    let encoding = String::from_utf8_lossy(header.as_bytes())
+        .trim()
+        .to_ascii_lowercase();
+    match encoding.as_str() {
+        "" | "identity" => Ok(Encoding::Identity),
+        "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+        _ => Err(ApiError::UnsupportedEncoding { encoding }),
+    }
+}
+
+/// Where a request body is decompressed to, refusing to grow past `limit`
+struct Capped {
+    contents: Vec<u8>,
+    limit: u64,
+    exceeded: bool,
+}
+
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.contents.len() + buf.len()) as u64 > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("decompressed body is too large"));
+        }
+        self.contents.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses a gzipped request body as it streams in. It's refused as soon as it decompresses
+/// to more than `limit` bytes, so a small body can't expand to fill the memory
+pub async fn gunzip_body(
+    mut body: impl Stream<Item = io::Result<Bytes>> + Unpin,
+    limit: u64,
+) -> Result<Bytes, ApiError> {
+    let mut decoder = write::GzDecoder::new(Capped {
+        contents: Vec::new(),
+        limit,
+        exceeded: false,
+    });
+    let decoded = async {
+        while let Some(chunk) = body.try_next().await? {
+            decoder.write_all(&chunk)?;
+        }
+        decoder.try_finish()
+    }
+    .await;
+
+    match decoded {
+        Ok(()) => Ok(decoder.finish().or_ise()?.contents.into()),
+        Err(_) if decoder.get_ref().exceeded => Err(ApiError::PayloadTooLarge { limit }),
+        Err(e) => Err(ApiError::BadRequest {
+            reason: format!("invalid gzip body: {}", e),
+        }),
+    }
+}
@@ -1,8 +1,12 @@
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use tokio::fs;
 
+use crate::db::sqlite_path;
+use crate::forwarded::Cidr;
+use crate::http_client::Endpoint;
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
@@ -10,13 +14,487 @@ pub struct Config {
     pub database_url: String,
     pub downloads_path: PathBuf,
     pub log_level: Option<String>,
-    pub admin_keys: HashSet<String>,
+    /// How log lines are written
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Admin keys and what they're allowed to do, a plain list gives every key full rights
+    pub admin_keys: AdminKeys,
+    /// Whether to start without any admin key, which leaves keys and the index unmanageable
+    #[serde(default)]
+    pub allow_no_admin: bool,
+    /// Failed authentication attempts allowed per client within the failure window
+    #[serde(default = "default_auth_max_failures")]
+    pub auth_max_failures: u32,
+    /// Length of the failure window in seconds
+    #[serde(default = "default_auth_failure_window")]
+    pub auth_failure_window: u64,
+    /// Trusts whatever peer a request comes from as a proxy, but only the last hop it adds to
+    /// `X-Forwarded-For`. Deprecated, list the proxies in `trusted-proxies` instead
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// Addresses or CIDR ranges of the proxies in front of the index. Only requests from these
+    /// have their `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers followed
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Requests allowed per client per minute, rate limiting is disabled if absent
+    pub rate_limit_per_minute: Option<u32>,
+    /// Requests a client can make in a burst, defaults to the per minute limit
+    pub rate_limit_burst: Option<u32>,
+    /// Origins allowed to make cross-origin requests, `"*"` allows any origin and no CORS
+    /// headers are sent if absent
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Most versions a single resolve can ask for, `limit=0` still returns every version
+    #[serde(default = "default_max_resolve_limit")]
+    pub max_resolve_limit: usize,
+    /// Whether to compress JSON responses for clients that accept it
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// How long in seconds a missing file is remembered as such before checking the disk again
+    #[serde(default = "default_missing_file_ttl")]
+    pub missing_file_ttl: u64,
+    /// Seconds files stay cached in memory before they're read from storage again, they stay
+    /// until deleted if absent
+    pub cache_ttl_secs: Option<u64>,
+    /// Whether uploaded files are cached right away, otherwise they're cached once downloaded
+    #[serde(default)]
+    pub cache_on_upload: bool,
+    /// Seconds list and resolve responses are cached in memory, also sent as their `max-age`
+    #[serde(default = "default_response_cache_ttl")]
+    pub response_cache_ttl_secs: u64,
+    /// Most list and resolve responses cached at once, none are if 0
+    #[serde(default = "default_response_cache_entries")]
+    pub response_cache_max_entries: usize,
+    /// Seconds download counts and key uses are kept in memory before they're written to the
+    /// database together
+    #[serde(default = "default_write_behind_secs")]
+    pub write_behind_secs: u64,
+    /// Download counts and key uses kept in memory after which they're written without waiting
+    #[serde(default = "default_write_behind_max_pending")]
+    pub write_behind_max_pending: usize,
+    /// Requests taking longer than this many milliseconds are logged as warnings, none are if
+    /// absent
+    pub slow_request_ms: Option<u64>,
+    /// Whether to preload files and check them against the database at startup
+    #[serde(default)]
+    pub warm_cache: bool,
+    /// Whether to gzip files before storing them, zips are stored as they are. Files stored either
+    /// way are read back no matter what this is set to
+    #[serde(default)]
+    pub compress_storage: bool,
+    /// Where qmod files are kept, `downloads-path` is used for local storage
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// Bucket used for S3 storage
+    pub s3: Option<S3Config>,
+    /// How long in seconds to wait on storage to read a file before giving up
+    #[serde(default = "default_storage_timeout")]
+    pub storage_timeout: u64,
+    /// How long in seconds to wait for the database to be unlocked before giving up
+    #[serde(default = "default_busy_timeout")]
+    pub busy_timeout: u64,
+    /// Whether to leave migrating the database to other instances, for ones sharing it that
+    /// shouldn't change its schema. Also set by `--skip-migrations`
+    #[serde(default)]
+    pub skip_migrations: bool,
+    /// Most database connections kept open at once
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    /// Index to look up packages and versions missing here in, keeping downloads found there
+    pub upstream_url: Option<String>,
+    /// How long in seconds to wait on the upstream index before giving up
+    #[serde(default = "default_upstream_timeout")]
+    pub upstream_timeout: u64,
+    /// Header telling a proxy in front of the index to serve downloads itself, like nginx's
+    /// `X-Accel-Redirect` or `X-Sendfile`. Downloads are read and sent by the index if absent
+    pub sendfile_header: Option<String>,
+    /// Prepended to `{id}/{major}/{minor}/{patch}` in the sendfile header, an internal location
+    /// for nginx or the absolute downloads path for `X-Sendfile`
+    #[serde(default)]
+    pub sendfile_prefix: String,
+    /// URL the index is reachable at, to give absolute download links. They're relative to the
+    /// root of the index if absent
+    pub public_base_url: Option<String>,
+    /// Most bytes a single package's versions can take up, uploads past it are refused
+    pub max_package_bytes: Option<u64>,
+    /// Most bytes all packages together can take up, uploads past it are refused
+    pub max_total_bytes: Option<u64>,
+    /// Most new versions of a single package that can be published within an hour, uploads past
+    /// it are refused until the oldest of them is an hour old. Uploads with an admin key don't
+    /// count against it
+    pub max_uploads_per_package_per_hour: Option<u64>,
+    /// Largest major, minor or patch number a new version can have
+    #[serde(default = "default_max_version_number")]
+    pub max_version_number: u64,
+    /// Most bytes a `multipart/form-data` upload can be, all parts together
+    #[serde(default = "default_max_form_bytes")]
+    pub max_form_bytes: u64,
+    /// Most bytes a single part of a `multipart/form-data` upload can be
+    #[serde(default = "default_max_form_part_bytes")]
+    pub max_form_part_bytes: u64,
+    /// Most bytes an upload sent with `Content-Encoding: gzip` can decompress to
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_decompressed_bytes: u64,
+    /// Most bytes an archive restored through `/admin/import` can be, it's held in memory whole
+    #[serde(default = "default_max_import_bytes")]
+    pub max_import_bytes: u64,
+    /// Whether builds of a version differing only in build metadata, like `1.2.0+abc`, can be
+    /// published alongside each other. Publishing another build of a version conflicts if not
+    #[serde(default)]
+    pub allow_build_variants: bool,
+    /// Whether packages first published by a publish key are held for an admin to approve,
+    /// hidden from everyone but admins and the key's user until then
+    #[serde(default)]
+    pub require_approval_for_new_packages: bool,
+    /// Whether to check every file against its recorded hash in the background at startup
+    #[serde(default)]
+    pub verify_on_start: bool,
+    /// Whether to start refusing writes, can be toggled at runtime
+    #[serde(default)]
+    pub read_only: bool,
+    /// Days downloads are kept counted by the hour, the longest window `/popular` can rank over
+    #[serde(default = "default_download_history_days")]
+    pub download_history_days: u64,
+    /// Days changes are kept for `/changes`, mirrors further behind have to sync everything again
+    #[serde(default = "default_change_retention_days")]
+    pub change_retention_days: u64,
+    /// Publish keys unused for this many days are disabled until an admin enables them again.
+    /// Keys are kept enabled if absent
+    #[serde(default)]
+    pub key_expiry_days: Option<u64>,
+    /// Versions published longer ago than this many hours are only deleted once the delete is
+    /// repeated with the token the first one answered with. Any version can be deleted at once if
+    /// absent
+    pub delete_protection_hours: Option<u64>,
+    /// Directory `POST /admin/bulk_import` can read files from, only archives can be imported if
+    /// absent
+    pub bulk_import_dir: Option<PathBuf>,
+    /// Where to keep copies of the database, `POST /admin/backup` is only served if set
+    pub backup: Option<BackupConfig>,
+    /// Tidying up of the downloads directory, only done for local storage
+    #[serde(default)]
+    pub gc: GcConfig,
+    /// Whether to serve the qpackages compatible routes under `/qpm`
+    #[serde(default)]
+    pub qpm_compat: bool,
+}
+
+/// What an admin key is allowed to do, ordered from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminRole {
+    /// Can delete versions, but not manage keys or the index itself
+    Moderator,
+    Full,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "AdminKeysConfig")]
+pub struct AdminKeys(HashMap<String, AdminRole>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AdminKeysConfig {
+    Full(HashSet<String>),
+    Roles(HashMap<String, AdminRole>),
+}
+
+impl From<AdminKeysConfig> for AdminKeys {
+    fn from(config: AdminKeysConfig) -> Self {
+        match config {
+            AdminKeysConfig::Full(keys) => {
+                Self(keys.into_iter().map(|k| (k, AdminRole::Full)).collect())
+            }
+            AdminKeysConfig::Roles(roles) => Self(roles),
+        }
+    }
+}
+
+impl FromIterator<(String, AdminRole)> for AdminKeys {
+    fn from_iter<I: IntoIterator<Item = (String, AdminRole)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl AdminKeys {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, AdminRole)> {
+        self.0.iter().map(|(k, role)| (k.as_str(), *role))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Local,
+    S3,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Config {
+    /// Base URL of the S3 API, buckets are addressed path-style under it
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupConfig {
+    /// Whether to back the database up on a schedule, on top of when asked to
+    #[serde(default)]
+    pub enabled: bool,
+    pub dir: PathBuf,
+    #[serde(default = "default_backup_interval_hours")]
+    pub interval_hours: u64,
+    /// Backups kept, older ones are removed after each backup. 0 keeps them all
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct GcConfig {
+    /// How often to tidy up, only when asked to if 0
+    pub interval_hours: u64,
+    /// Whether to delete files no version points to instead of moving them under `quarantine/`
+    pub delete_orphans: bool,
+    /// Files and directories modified more recently are left alone, so uploads in progress
+    /// aren't raced
+    pub min_age_minutes: u64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval_hours: 24,
+            delete_orphans: false,
+            min_age_minutes: 10,
+        }
+    }
+}
+
+fn default_auth_max_failures() -> u32 {
+    10
+}
+
+fn default_auth_failure_window() -> u64 {
+    300
+}
+
+fn default_max_resolve_limit() -> usize {
+    100
+}
+
+fn default_max_form_bytes() -> u64 {
+    128 * 1024 * 1024
+}
+
+fn default_max_form_part_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_download_history_days() -> u64 {
+    30
+}
+
+fn default_max_version_number() -> u64 {
+    1_000_000_000
+}
+
+fn default_change_retention_days() -> u64 {
+    30
+}
+
+fn default_write_behind_secs() -> u64 {
+    5
+}
+
+fn default_write_behind_max_pending() -> usize {
+    1000
+}
+
+fn default_max_decompressed_bytes() -> u64 {
+    128 * 1024 * 1024
+}
+
+fn default_max_import_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_compression() -> bool {
+    true
+}
+
+fn default_missing_file_ttl() -> u64 {
+    60
+}
+
+fn default_response_cache_ttl() -> u64 {
+    30
+}
+
+fn default_response_cache_entries() -> usize {
+    1024
+}
+
+fn default_busy_timeout() -> u64 {
+    5
+}
+
+fn default_storage_timeout() -> u64 {
+    30
+}
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_upstream_timeout() -> u64 {
+    10
+}
+
+fn default_backup_interval_hours() -> u64 {
+    24
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
 }
 
 impl Config {
-    pub async fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<&'static Self> {
+    pub async fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let contents = fs::read_to_string(path).await?;
-        let config = serde_json::from_str(&contents)?;
-        Ok(Box::leak(Box::new(config)))
+        Ok(serde_json::from_str(&contents)?)
     }
+
+    /// Checks the paths are writable and the URLs valid, so a bad config fails at startup rather
+    /// than on the first request needing them. Lists every problem found, not just the first
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.max_version_number > i64::MAX as u64 {
+            problems.push(format!("max-version-number can be at most {}", i64::MAX));
+        }
+        if self.rate_limit_per_minute == Some(0) {
+            problems.push("rate-limit-per-minute can't be 0".to_owned());
+        }
+        if self.rate_limit_burst == Some(0) {
+            problems.push("rate-limit-burst can't be 0".to_owned());
+        }
+        if self.max_uploads_per_package_per_hour == Some(0) {
+            problems.push("max-uploads-per-package-per-hour can't be 0".to_owned());
+        }
+        if self.download_history_days == 0 {
+            problems.push("download-history-days can't be 0".to_owned());
+        }
+        if self.change_retention_days == 0 {
+            problems.push("change-retention-days can't be 0".to_owned());
+        }
+        if self.write_behind_secs == 0 {
+            problems.push("write-behind-secs can't be 0".to_owned());
+        }
+        if self.write_behind_max_pending == 0 {
+            problems.push("write-behind-max-pending can't be 0".to_owned());
+        }
+        if self.key_expiry_days == Some(0) {
+            problems.push("key-expiry-days can't be 0".to_owned());
+        }
+        if self.storage_timeout == 0 {
+            problems.push("storage-timeout can't be 0".to_owned());
+        }
+        if self.admin_keys.iter().next().is_none() && !self.allow_no_admin {
+            problems.push("admin-keys is empty, set allow-no-admin to start anyway".to_owned());
+        }
+
+        if self.storage == StorageKind::Local
+            && let Err(e) = check_writable(&self.downloads_path)
+        {
+            problems.push(format!("downloads-path {}", e));
+        }
+        if let Some(dir) = database_dir(&self.database_url)
+            && let Err(e) = check_writable(&dir)
+        {
+            problems.push(format!("database-url directory {}", e));
+        }
+        if let Some(backup) = &self.backup
+            && let Err(e) = check_writable(&backup.dir)
+        {
+            problems.push(format!("backup dir {}", e));
+        }
+
+        for cidr in &self.trusted_proxies {
+            if let Err(e) = Cidr::parse(cidr) {
+                problems.push(format!("trusted-proxies entry {} is invalid: {}", cidr, e));
+            }
+        }
+
+        let urls = [
+            ("upstream-url", self.upstream_url.as_deref()),
+            ("public-base-url", self.public_base_url.as_deref()),
+            (
+                "s3 endpoint",
+                self.s3.as_ref().map(|s3| s3.endpoint.as_str()),
+            ),
+        ];
+        for (name, url) in urls {
+            if let Some(url) = url
+                && let Err(e) = Endpoint::parse(url)
+            {
+                problems.push(format!("{} is invalid: {}", name, e));
+            }
+        }
+        if self.compress_storage && self.sendfile_header.is_some() {
+            problems.push(
+                "compress-storage can't be used with sendfile-header, the proxy would send files compressed"
+                    .to_owned(),
+            );
+        }
+        if self.storage == StorageKind::S3 && self.s3.is_none() {
+            problems.push("storage is s3 but there's no s3 section".to_owned());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("invalid config:\n  - {}", problems.join("\n  - "))
+        }
+    }
+}
+
+/// Directory the SQLite database file is in, none for in-memory databases
+fn database_dir(url: &str) -> Option<PathBuf> {
+    let path = sqlite_path(url);
+    if path.as_os_str().is_empty() || path.starts_with(":memory:") {
+        return None;
+    }
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => Some(dir.to_owned()),
+        _ => Some(PathBuf::from(".")),
+    }
+}
+
+/// Creates the directory if needed, then writes and removes a file in it
+fn check_writable(dir: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("{} can't be created: {}", dir.display(), e))?;
+    let probe = dir.join(".write-probe");
+    std::fs::write(&probe, b"").map_err(|e| format!("{} isn't writable: {}", dir.display(), e))?;
+    std::fs::remove_file(&probe).map_err(|e| format!("{} isn't writable: {}", dir.display(), e))
 }
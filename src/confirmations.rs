@@ -0,0 +1,55 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use semver::Version;
+use tokio::sync::Mutex;
+
+use crate::logging::uuid_v4;
+
+/// One-time tokens confirming the deletion of a version, kept in memory for a short while
+pub struct DeleteConfirmations {
+    ttl: Duration,
+    pending: Mutex<HashMap<(String, Version), (String, Instant)>>,
+}
+
+impl DeleteConfirmations {
+    pub fn new(ttl: Duration) -> DeleteConfirmations {
+        DeleteConfirmations {
+            ttl,
+            pending: Default::default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issues a token for deleting the version, replacing any earlier one
+    pub async fn issue(&self, id: &str, ver: &Version) -> String {
+        let mut pending = self.pending.lock().await;
+        // Forget about tokens that expired so the map doesn't grow forever
+        pending.retain(|_, (_, issued)| issued.elapsed() < self.ttl);
+
+        let token = uuid_v4();
+        pending.insert(
+            (id.to_owned(), ver.clone()),
+            (token.clone(), Instant::now()),
+        );
+        token
+    }
+
+    /// Whether the token was issued for the version and hasn't expired. A matching token is used
+    /// up even if it expired, a wrong one leaves the version's token alone
+    pub async fn redeem(&self, id: &str, ver: &Version, token: &str) -> bool {
+        let mut pending = self.pending.lock().await;
+        let key = (id.to_owned(), ver.clone());
+        if pending.get(&key).is_none_or(|(issued, _)| issued != token) {
+            return false;
+        }
+        pending
+            .remove(&key)
+            .is_some_and(|(_, issued)| issued.elapsed() < self.ttl)
+    }
+}
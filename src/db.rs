@@ -1,97 +1,2265 @@
 #![allow(clippy::toplevel_ref_arg)]
 
-use futures::{future, StreamExt, TryStreamExt};
-use semver::{Version, VersionReq};
+use futures::{StreamExt, TryStreamExt, future};
+use semver::{BuildMetadata, Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sha2::{Digest, Sha256};
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPoolOptions};
+use sqlx::migrate::{MigrateError, Migrator};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::{PgConnectOptions, PgDatabaseError};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::{AnyConnection, AnyPool};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::Deref;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs;
 
-#[tracing::instrument(level = "info")]
-pub async fn connect(url: &str) -> anyhow::Result<&'static SqlitePool> {
-    if let Some(dir) = Path::new(url).parent() {
+use crate::config::Config;
+use crate::file_repo::FileRepo;
+use crate::types::version_string;
+pub use crate::types::{Mod, PublishKey};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+/// The same schema for PostgreSQL. Every migration added to `migrations` gets one here too
+#[cfg(feature = "postgres")]
+static PG_MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+/// Attempts made at a write before giving up on the database being busy
+const RETRY_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for each one after it and jittered by up to as much again
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Attempts made at migrating before giving up on another instance migrating the same database
+const MIGRATE_ATTEMPTS: u32 = 5;
+
+/// Whether the error is the database being locked by someone else for longer than the busy
+/// timeout, or no connection freeing up in time
+pub fn is_busy(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut => true,
+        #[cfg(feature = "postgres")]
+        sqlx::Error::Database(e) if e.try_downcast_ref::<PgDatabaseError>().is_some() => {
+            // Serialization failures, deadlocks and running out of `lock_timeout`
+            matches!(e.code().as_deref(), Some("40001" | "40P01" | "55P03"))
+        }
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // SQLITE_BUSY and SQLITE_LOCKED, ignoring extended codes
+            .is_some_and(|code| matches!(code & 0xff, 5 | 6)),
+        _ => false,
+    }
+}
+
+/// Whether the error is a write breaking a uniqueness, check or foreign key constraint
+pub fn is_constraint_violation(e: &sqlx::Error) -> bool {
+    match e {
+        #[cfg(feature = "postgres")]
+        sqlx::Error::Database(e) if e.try_downcast_ref::<PgDatabaseError>().is_some() => {
+            // Integrity constraint violations
+            e.code().is_some_and(|code| code.starts_with("23"))
+        }
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // SQLITE_CONSTRAINT, ignoring extended codes
+            .is_some_and(|code| code & 0xff == 19),
+        _ => false,
+    }
+}
+
+/// Runs a query again when it fails on the database being busy, so short write bursts don't
+/// fail requests. The error is returned as is once out of attempts
+pub async fn retry<T, F, Fut>(mut query: F) -> sqlx::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = sqlx::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match query().await {
+            Err(e) if attempt < RETRY_ATTEMPTS && is_busy(&e) => {
+                let delay = backoff(attempt);
+                tracing::debug!("database busy, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before trying again after `attempt` attempts
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_DELAY * 2u32.pow(attempt - 1);
+    let mut jitter = [0; 4];
+    getrandom::getrandom(&mut jitter).expect("no randomness available");
+    delay + delay.mul_f64(u32::from_le_bytes(jitter) as f64 / u32::MAX as f64)
+}
+
+/// Whether the database URL is that of a PostgreSQL database, anything else is taken for SQLite
+pub fn is_postgres_url(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+/// Whether the pool is connected to PostgreSQL rather than SQLite
+pub fn is_postgres(pool: &AnyPool) -> bool {
+    match pool.any_kind() {
+        AnyKind::Sqlite => false,
+        #[cfg(feature = "postgres")]
+        AnyKind::Postgres => true,
+    }
+}
+
+/// Migrations for the kind of database the pool is connected to
+fn migrator(pool: &AnyPool) -> &'static Migrator {
+    match pool.any_kind() {
+        AnyKind::Sqlite => &MIGRATOR,
+        #[cfg(feature = "postgres")]
+        AnyKind::Postgres => &PG_MIGRATOR,
+    }
+}
+
+/// Brings the schema up to date. PostgreSQL migrations take an advisory lock, but SQLite has
+/// none to take, so an instance starting alongside another one can fail on a migration the other
+/// one applied first. Running them again then skips whatever the other one got done
+async fn migrate(pool: &AnyPool) -> anyhow::Result<()> {
+    let mut attempt = 1;
+    loop {
+        let before = applied_migrations(pool).await?.len();
+        let e = match migrator(pool).run(pool).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let raced = match &e {
+            MigrateError::Execute(e) if is_busy(e) => true,
+            _ => applied_migrations(pool).await?.len() != before,
+        };
+        if !raced || attempt == MIGRATE_ATTEMPTS {
+            return Err(e.into());
+        }
+        let delay = backoff(attempt);
+        tracing::info!(
+            "database being migrated elsewhere, retrying in {:?}: {}",
+            delay,
+            e
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Migrations recorded as applied, oldest first
+async fn applied_migrations(pool: &AnyPool) -> sqlx::Result<Vec<AppliedMigration>> {
+    // Only there once something has been migrated
+    if !table_exists("_sqlx_migrations", pool).await? {
+        return Ok(Vec::new());
+    }
+    sqlx::query_as(
+        "SELECT version, description, checksum, CAST(installed_on AS TEXT) AS installed_on \
+         FROM _sqlx_migrations ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(sqlx::FromRow)]
+struct AppliedMigration {
+    version: i64,
+    description: String,
+    checksum: Vec<u8>,
+    installed_on: Option<String>,
+}
+
+/// A migration and whether it's been applied, for `GET /admin/migrations`
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    /// Hex SHA-384 of the migration, the one it was applied with if it was
+    pub checksum: String,
+    /// When it was applied, as the database wrote it
+    pub installed_on: Option<String>,
+    pub state: MigrationState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Applied,
+    /// Not applied yet, left to the next instance starting that migrates
+    Pending,
+    /// Applied from a different version of the file than the one built in
+    Modified,
+    /// Applied by a newer build that knows of migrations this one doesn't
+    Unknown,
+}
+
+impl MigrationStatus {
+    /// Every migration built in and every one applied, by version
+    pub async fn list(pool: &AnyPool) -> sqlx::Result<Vec<Self>> {
+        let mut applied: BTreeMap<_, _> = applied_migrations(pool)
+            .await?
+            .into_iter()
+            .map(|m| (m.version, m))
+            .collect();
+        let mut statuses = Vec::new();
+        for migration in migrator(pool).iter() {
+            if migration.migration_type.is_down_migration() {
+                continue;
+            }
+            let status = match applied.remove(&migration.version) {
+                Some(m) => Self {
+                    state: if m.checksum == *migration.checksum {
+                        MigrationState::Applied
+                    } else {
+                        MigrationState::Modified
+                    },
+                    ..m.into()
+                },
+                None => Self {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                    checksum: hex::encode(&migration.checksum),
+                    installed_on: None,
+                    state: MigrationState::Pending,
+                },
+            };
+            statuses.push(status);
+        }
+        statuses.extend(applied.into_values().map(Self::from));
+        statuses.sort_by_key(|status| status.version);
+        Ok(statuses)
+    }
+}
+
+impl From<AppliedMigration> for MigrationStatus {
+    fn from(m: AppliedMigration) -> Self {
+        Self {
+            version: m.version,
+            description: m.description,
+            checksum: hex::encode(m.checksum),
+            installed_on: m.installed_on,
+            state: MigrationState::Unknown,
+        }
+    }
+}
+
+#[tracing::instrument(level = "info", skip(config), fields(url = %without_password(&config.database_url)))]
+pub async fn connect(config: &Config) -> anyhow::Result<&'static AnyPool> {
+    let pool = if is_postgres_url(&config.database_url) {
+        connect_postgres(config).await?
+    } else {
+        connect_sqlite(config).await?
+    };
+
+    // Left to another instance, this one only says what it would be missing
+    if config.skip_migrations {
+        let pending = MigrationStatus::list(&pool)
+            .await?
+            .into_iter()
+            .filter(|status| status.state == MigrationState::Pending)
+            .count();
+        if pending > 0 {
+            tracing::warn!(
+                "{} migrations aren't applied and skip-migrations is set, the schema may be too \
+                 old for this version",
+                pending
+            );
+        }
+        return Ok(&*Box::leak(Box::new(pool)));
+    }
+
+    let legacy = legacy_rows(&pool).await?;
+    for (id, major, minor, patch) in &legacy.duplicates {
+        tracing::warn!(
+            "{} {}.{}.{} is in the database more than once, only one will be kept",
+            id,
+            major,
+            minor,
+            patch
+        );
+    }
+    for (id, major, minor, patch) in &legacy.negative {
+        tracing::warn!(
+            "{} {}.{}.{} has a negative version component and will be dropped",
+            id,
+            major,
+            minor,
+            patch
+        );
+    }
+
+    migrate(&pool).await?;
+    for (table, (id, major, minor, patch)) in wrapped_versions(&pool).await? {
+        tracing::warn!(
+            "{} {}.{}.{} in {} has a negative version component, likely a version number too \
+             large to store that wrapped around, and can't be addressed",
+            id,
+            major,
+            minor,
+            patch,
+            table
+        );
+    }
+    Ok(&*Box::leak(Box::new(pool)))
+}
+
+/// The URL with any password left out, so it can be logged
+fn without_password(url: &str) -> std::borrow::Cow<'_, str> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.into();
+    };
+    match rest.split_once('@') {
+        Some((user, host)) if user.contains(':') => {
+            let user = user.split_once(':').map_or(user, |(user, _)| user);
+            format!("{}://{}:***@{}", scheme, user, host).into()
+        }
+        _ => url.into(),
+    }
+}
+
+/// File a `sqlite:` URL points to, handed over as a path rather than formatted back into a URL.
+/// Query parameters are dropped, as is the slash in front of a Windows drive letter in URLs like
+/// `sqlite:///C:/index/index.db`
+pub fn sqlite_path(url: &str) -> &Path {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .unwrap_or(url);
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => &path[1..],
+        _ => path,
+    };
+    Path::new(path)
+}
+
+async fn connect_sqlite(config: &Config) -> anyhow::Result<AnyPool> {
+    let path = sqlite_path(&config.database_url);
+    if let Some(dir) = path.parent() {
         fs::create_dir_all(dir).await?;
     }
-    if fs::metadata(url).await.is_err() {
-        fs::write(url, b"").await?;
+    // WAL lets readers carry on while something is being written, and waiting on the lock for a
+    // bit beats failing right away under concurrent writes
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(Duration::from_secs(config.busy_timeout))
+        .foreign_keys(true);
+    let pool = AnyPoolOptions::new()
+        .max_connections(config.max_connections)
+        .connect_with(AnyConnectOptions::from(options))
+        .await?;
+    Ok(pool)
+}
+
+/// Connects to a server shared with other instances, and likely other applications. Connections
+/// are opened as needed up to `max-connections` and closed once idle for a while rather than all
+/// held open. Waiting on a lock gives up after the busy timeout, as waiting on SQLite's lock does
+#[cfg(feature = "postgres")]
+async fn connect_postgres(config: &Config) -> anyhow::Result<AnyPool> {
+    // A lock timeout of 0 waits forever, where SQLite's busy timeout of 0 doesn't wait at all
+    let lock_timeout = config.busy_timeout.saturating_mul(1000).max(1);
+    let options = PgConnectOptions::from_str(&config.database_url)
+        .map_err(|e| anyhow::anyhow!("bad database URL: {}", e))?
+        .application_name("bs-quest-index")
+        .options([("lock_timeout", format!("{}ms", lock_timeout))]);
+    let pool = AnyPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(1)
+        .idle_timeout(Duration::from_secs(10 * 60))
+        .max_lifetime(Duration::from_secs(30 * 60))
+        .connect_with(AnyConnectOptions::from(options))
+        .await?;
+    Ok(pool)
+}
+
+/// Says the driver isn't built in instead of creating a file named after the URL
+#[cfg(not(feature = "postgres"))]
+async fn connect_postgres(_config: &Config) -> anyhow::Result<AnyPool> {
+    anyhow::bail!("PostgreSQL databases need the index built with the `postgres` feature")
+}
+
+/// Whether the table exists, for those migrations may not have created yet
+async fn table_exists(name: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+    let sql = if is_postgres(pool) {
+        "SELECT CAST(table_name AS text) FROM information_schema.tables
+        WHERE table_schema = current_schema() AND table_name = $1"
+    } else {
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = $1"
+    };
+    let found: Option<(String,)> = sqlx::query_as(sql).bind(name).fetch_optional(pool).await?;
+    Ok(found.is_some())
+}
+
+/// Whether the index exists, for those migrations may not have created yet
+async fn index_exists(name: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+    let sql = if is_postgres(pool) {
+        "SELECT CAST(indexname AS text) FROM pg_indexes
+        WHERE schemaname = current_schema() AND indexname = $1"
+    } else {
+        "SELECT name FROM sqlite_master WHERE type = 'index' AND name = $1"
+    };
+    let found: Option<(String,)> = sqlx::query_as(sql).bind(name).fetch_optional(pool).await?;
+    Ok(found.is_some())
+}
+
+type LegacyRow = (String, i64, i64, i64);
+
+/// Rows an older database may have that the current schema doesn't allow
+#[derive(Debug, Default, PartialEq)]
+pub struct LegacyRows {
+    pub duplicates: Vec<LegacyRow>,
+    pub negative: Vec<LegacyRow>,
+}
+
+/// Finds the rows migrations will drop because of the constraints on the mods table
+pub async fn legacy_rows(pool: &AnyPool) -> sqlx::Result<LegacyRows> {
+    // This runs before migrations so the schema may not be the current one, or not exist at all
+    if !table_exists("mods", pool).await? {
+        return Ok(LegacyRows::default());
+    }
+    // Builds of a version are told apart once the unique index is there, and that index only
+    // exists once the constraints migration has dropped the rows anyway
+    if index_exists("mods_id_version", pool).await? {
+        return Ok(LegacyRows::default());
+    }
+
+    let duplicates = sqlx::query_as(
+        "SELECT id, major, minor, patch FROM mods GROUP BY id, major, minor, patch \
+         HAVING COUNT(*) > 1 ORDER BY id, major, minor, patch",
+    )
+    .fetch_all(pool)
+    .await?;
+    let negative = sqlx::query_as(
+        "SELECT id, major, minor, patch FROM mods WHERE major < 0 OR minor < 0 OR patch < 0 \
+         ORDER BY id, major, minor, patch",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(LegacyRows {
+        duplicates,
+        negative,
+    })
+}
+
+/// A version's numbers as they're stored. Columns are signed, so numbers past `i64::MAX` are
+/// refused rather than wrapping around to negative ones
+fn version_parts(ver: &Version) -> sqlx::Result<(i64, i64, i64)> {
+    let part = |n: u64| {
+        i64::try_from(n).map_err(|_| {
+            sqlx::Error::Protocol(format!("version number {} is too large to store", n))
+        })
+    };
+    Ok((part(ver.major)?, part(ver.minor)?, part(ver.patch)?))
+}
+
+/// Tables keyed by version that, unlike mods, have no constraint against negative numbers
+const VERSIONED_TABLES: [&str; 4] = ["tombstones", "readmes", "artifacts", "mod_game_versions"];
+
+/// Rows of the versioned tables with a negative version number, left behind by numbers too large
+/// to store wrapping around before they were refused
+pub async fn wrapped_versions(pool: &AnyPool) -> sqlx::Result<Vec<(&'static str, LegacyRow)>> {
+    let mut found = Vec::new();
+    for table in VERSIONED_TABLES {
+        let rows: Vec<LegacyRow> = sqlx::query_as(&format!(
+            "SELECT DISTINCT id, major, minor, patch FROM {} \
+             WHERE major < 0 OR minor < 0 OR patch < 0 ORDER BY id, major, minor, patch",
+            table
+        ))
+        .fetch_all(pool)
+        .await?;
+        found.extend(rows.into_iter().map(|row| (table, row)));
+    }
+    Ok(found)
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// What a publisher said about a version, all of it optional
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+    /// Game version the version is made for, also one of its tags. Versions without any tag are
+    /// resolved whatever game version is asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_version: Option<String>,
+    /// Modloader the version is made for, like `scotland2`. Versions without one are resolved
+    /// whatever loader is asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loader: Option<String>,
+    /// Versions of the loader the version works with, as a semver requirement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loader_version_req: Option<String>,
+}
+
+impl Metadata {
+    /// Longest a field can be
+    const MAX_LEN: usize = 4096;
+
+    /// Checks no field is unreasonably long, naming the first one that is
+    pub fn check(&self) -> Result<(), &'static str> {
+        let fields = [
+            ("name", &self.name),
+            ("description", &self.description),
+            ("author", &self.author),
+            ("website", &self.website),
+            ("game_version", &self.game_version),
+            ("loader", &self.loader),
+            ("loader_version_req", &self.loader_version_req),
+        ];
+        for (field, value) in fields {
+            if value.as_ref().is_some_and(|v| v.len() > Self::MAX_LEN) {
+                return Err(field);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A version along with its metadata, as resolved
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Resolved {
+    #[serde(flatten)]
+    pub m: Mod,
+    /// Where the version can be downloaded from, left out where responses don't say
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+impl From<Mod> for Resolved {
+    fn from(m: Mod) -> Self {
+        Self {
+            m,
+            download_url: None,
+            meta: Metadata::default(),
+        }
+    }
+}
+
+impl From<Published> for Resolved {
+    fn from(p: Published) -> Self {
+        Self {
+            m: Mod {
+                id: p.id,
+                version: p.version,
+            },
+            download_url: None,
+            meta: p.meta,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DbResolved {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    name: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    website: Option<String>,
+    game_version: Option<String>,
+    loader: Option<String>,
+    loader_version_req: Option<String>,
+}
+
+impl From<DbResolved> for Resolved {
+    fn from(db: DbResolved) -> Self {
+        Self {
+            m: Mod {
+                id: db.id,
+                version: db_version(db.major, db.minor, db.patch, &db.build),
+            },
+            download_url: None,
+            meta: Metadata {
+                name: db.name,
+                description: db.description,
+                author: db.author,
+                website: db.website,
+                game_version: db.game_version,
+                loader: db.loader,
+                loader_version_req: db.loader_version_req,
+            },
+        }
+    }
+}
+
+/// A package id, lowercased so looking a package up doesn't depend on how its id is typed. Parsing
+/// one refuses ids that can't name a package, see [`PackageId::check`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct PackageId(String);
+
+/// Names Windows keeps for devices, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+impl From<String> for PackageId {
+    fn from(id: String) -> Self {
+        Self(id.to_lowercase())
+    }
+}
+
+impl From<PackageId> for String {
+    fn from(id: PackageId) -> Self {
+        id.0
+    }
+}
+
+impl FromStr for PackageId {
+    type Err = String;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let id = id.to_lowercase();
+        PackageId::check(&id)?;
+        Ok(Self(id))
+    }
+}
+
+impl<'de> Deserialize<'de> for PackageId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl PackageId {
+    /// Why an id can't name a package, if it can't. Ids end up as directory names, so anything
+    /// that would be read as a path of its own is refused
+    pub fn check(id: &str) -> Result<(), String> {
+        if id.is_empty() {
+            return Err("package ids can't be empty".to_owned());
+        }
+        if id.len() > FileRepo::max_id_len() {
+            return Err(format!(
+                "package ids can be at most {} bytes long",
+                FileRepo::max_id_len()
+            ));
+        }
+        if id == "." || id == ".." {
+            return Err(format!("{} isn't a package id", id));
+        }
+        if id.chars().any(|c| c == '/' || c == '\\' || c.is_control()) {
+            return Err(
+                "package ids can't contain slashes, backslashes or control characters".to_owned(),
+            );
+        }
+        let stem = id.split('.').next().unwrap_or(id);
+        if RESERVED_NAMES
+            .iter()
+            .any(|name| stem.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("{} is a reserved name", id));
+        }
+        Ok(())
+    }
+}
+
+impl Deref for PackageId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DbMod {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+}
+
+/// A named file published alongside a version, like debug symbols
+#[derive(Debug, Clone, Serialize, PartialEq, sqlx::FromRow)]
+pub struct Artifact {
+    pub name: String,
+    pub size: i64,
+    pub sha256: String,
+    pub created_at: i64,
+}
+
+/// A version and when it was uploaded
+#[derive(Debug, PartialEq)]
+pub struct Release {
+    pub id: String,
+    pub version: Version,
+    pub created_at: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbRelease {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    created_at: i64,
+}
+
+impl From<DbRelease> for Release {
+    fn from(db_release: DbRelease) -> Self {
+        Self {
+            id: db_release.id,
+            version: db_version(
+                db_release.major,
+                db_release.minor,
+                db_release.patch,
+                &db_release.build,
+            ),
+            created_at: db_release.created_at,
+        }
+    }
+}
+
+/// A version and when it was uploaded, if that's known
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Upload {
+    pub id: String,
+    pub version: Version,
+    pub created_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbUpload {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    created_at: Option<i64>,
+}
+
+impl From<DbUpload> for Upload {
+    fn from(db_upload: DbUpload) -> Self {
+        Self {
+            id: db_upload.id,
+            version: db_version(
+                db_upload.major,
+                db_upload.minor,
+                db_upload.patch,
+                &db_upload.build,
+            ),
+            created_at: db_upload.created_at,
+        }
+    }
+}
+
+/// A version uploaded by a given publisher
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Publication {
+    pub id: String,
+    pub version: Version,
+    pub created_at: Option<i64>,
+    pub yanked_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbPublication {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    created_at: Option<i64>,
+    yanked_at: Option<i64>,
+}
+
+impl From<DbPublication> for Publication {
+    fn from(db_publication: DbPublication) -> Self {
+        Self {
+            id: db_publication.id,
+            version: db_version(
+                db_publication.major,
+                db_publication.minor,
+                db_publication.patch,
+                &db_publication.build,
+            ),
+            created_at: db_publication.created_at,
+            yanked_at: db_publication.yanked_at,
+        }
+    }
+}
+
+/// Everything known about a single version, for clients to look at before downloading it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct VersionInfo {
+    pub id: String,
+    #[serde(with = "version_string")]
+    pub version: Version,
+    pub sha256: Option<String>,
+    pub size: Option<i64>,
+    pub created_at: Option<i64>,
+    /// Yanked versions aren't resolved, but can still be downloaded
+    pub yanked: bool,
+    pub yanked_at: Option<i64>,
+    pub downloads: i64,
+    /// Game versions the version is tagged as made for
+    pub game_versions: Vec<String>,
+    /// Where the version can be downloaded from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbVersionInfo {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    sha256: Option<String>,
+    size: Option<i64>,
+    created_at: Option<i64>,
+    yanked_at: Option<i64>,
+    downloads: i64,
+
+    name: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    website: Option<String>,
+    game_version: Option<String>,
+    loader: Option<String>,
+    loader_version_req: Option<String>,
+}
+
+impl From<DbVersionInfo> for VersionInfo {
+    fn from(db_info: DbVersionInfo) -> Self {
+        Self {
+            id: db_info.id,
+            version: db_version(db_info.major, db_info.minor, db_info.patch, &db_info.build),
+            sha256: db_info.sha256,
+            size: db_info.size,
+            created_at: db_info.created_at,
+            yanked: db_info.yanked_at.is_some(),
+            yanked_at: db_info.yanked_at,
+            downloads: db_info.downloads,
+            game_versions: Vec::new(),
+            download_url: None,
+            meta: Metadata {
+                name: db_info.name,
+                description: db_info.description,
+                author: db_info.author,
+                website: db_info.website,
+                game_version: db_info.game_version,
+                loader: db_info.loader,
+                loader_version_req: db_info.loader_version_req,
+            },
+        }
+    }
+}
+
+/// Who can see a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// Only listed for and served to admins and keys covering the package
+    Private,
+}
+
+impl Visibility {
+    fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        }
+    }
+
+    pub async fn of(id: &str, pool: &AnyPool) -> sqlx::Result<Self> {
+        let found: Option<String> =
+            sqlx::query_scalar("SELECT visibility FROM packages WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(match found.as_deref() {
+            Some("private") => Visibility::Private,
+            _ => Visibility::Public,
+        })
+    }
+
+    /// Also counts as the package changing, since it shows up or disappears
+    pub async fn set(self, id: &str, pool: &AnyPool) -> sqlx::Result<()> {
+        let visibility = self.as_str();
+        sqlx::query(
+            "INSERT INTO packages (id, visibility, updated_at) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE
+            SET visibility = excluded.visibility, updated_at = excluded.updated_at",
+        )
+        .bind(id)
+        .bind(visibility)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Whether an admin let a new package be listed, when that's required
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Approval {
+    #[default]
+    Approved,
+    /// Only listed for and served to admins and its owner, until an admin approves it
+    Pending,
+    /// Hidden from everyone but admins, and blocked
+    Rejected,
+}
+
+impl Approval {
+    /// How the package is approved, and the user holding it if it's pending
+    pub async fn of(id: &str, pool: &AnyPool) -> sqlx::Result<(Self, Option<String>)> {
+        let found: Option<(String, Option<String>)> =
+            sqlx::query_as("SELECT approval, owner FROM packages WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(match found {
+            Some((approval, owner)) => (
+                match approval.as_str() {
+                    "pending" => Approval::Pending,
+                    "rejected" => Approval::Rejected,
+                    _ => Approval::Approved,
+                },
+                owner,
+            ),
+            None => (Approval::Approved, None),
+        })
+    }
+
+    /// Holds a new package for approval, for `owner` to keep publishing to meanwhile
+    pub async fn hold(id: &str, owner: &str, pool: &AnyPool) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO packages (id, approval, owner, updated_at) VALUES ($1, 'pending', $2, $3)
+            ON CONFLICT (id) DO UPDATE
+            SET approval = excluded.approval, owner = excluded.owner, updated_at = excluded.updated_at",
+        )
+        .bind(id)
+        .bind(owner)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Settles a pending package, false if it wasn't pending. Also counts as the package
+    /// changing, since it shows up or stays hidden for good
+    pub async fn settle(self, id: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let approval = match self {
+            Approval::Approved => "approved",
+            Approval::Pending => "pending",
+            Approval::Rejected => "rejected",
+        };
+        let settled = sqlx::query(
+            "UPDATE packages SET approval = $1, updated_at = $2 WHERE id = $3 AND approval = 'pending'",
+        )
+        .bind(approval)
+        .bind(unix_now())
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(settled.rows_affected() > 0)
+    }
+}
+
+/// A renamed package, its old id is served from the new one
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Alias {
+    pub from: PackageId,
+    pub to: PackageId,
+}
+
+impl Alias {
+    /// The id a package was renamed to, if it was
+    pub async fn target(id: &str, pool: &AnyPool) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar("SELECT new_id FROM aliases WHERE old_id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Whether some other id is renamed to this one
+    pub async fn is_target(id: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let found: Option<String> =
+            sqlx::query_scalar("SELECT old_id FROM aliases WHERE new_id = $1 LIMIT 1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(found.is_some())
+    }
+
+    /// Adds the alias, pointing it somewhere else if the old id already had one
+    pub async fn insert(&self, pool: &AnyPool) -> sqlx::Result<()> {
+        let from: &str = &self.from;
+        let to: &str = &self.to;
+        let now = unix_now();
+        sqlx::query(
+            "INSERT INTO aliases (old_id, new_id, created_at) VALUES ($1, $2, $3)
+            ON CONFLICT (old_id) DO UPDATE SET new_id = excluded.new_id, created_at = excluded.created_at",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        // The old id resolves to what the new one does from now on
+        Mod::touch(to, pool).await
+    }
+}
+
+/// A package pulled for good, along with why
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Block {
+    pub id: PackageId,
+    pub reason: String,
+}
+
+impl Block {
+    pub async fn all(pool: &AnyPool) -> sqlx::Result<Vec<Self>> {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT id, reason FROM blocked_packages")
+            .fetch_all(pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, reason)| Block {
+                id: id.into(),
+                reason,
+            })
+            .collect())
+    }
+
+    /// Blocks the package, replacing the reason if it already was
+    pub async fn insert(&self, pool: &AnyPool) -> sqlx::Result<()> {
+        let id: &str = &self.id;
+        let now = unix_now();
+        sqlx::query(
+            "INSERT INTO blocked_packages (id, reason, created_at) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE SET reason = excluded.reason",
+        )
+        .bind(id)
+        .bind(&self.reason)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Unblocks the package, returns false if it wasn't blocked
+    pub async fn delete(id: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let affected = sqlx::query("DELETE FROM blocked_packages WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(affected.rows_affected() > 0)
+    }
+}
+
+/// Game versions mods can be made for
+pub struct GameVersion;
+
+impl GameVersion {
+    /// Every known game version, oldest first
+    pub async fn all(pool: &AnyPool) -> sqlx::Result<Vec<String>> {
+        let mut versions: Vec<String> = sqlx::query_scalar("SELECT version FROM game_versions")
+            .fetch_all(pool)
+            .await?;
+        // Compared as semver where possible, so 1.10.0 comes after 1.9.0
+        versions.sort_by_cached_key(|v| (Version::parse(v).ok(), v.clone()));
+        Ok(versions)
+    }
+
+    pub async fn exists(version: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let found = sqlx::query("SELECT version FROM game_versions WHERE version = $1")
+            .bind(version)
+            .fetch_optional(pool)
+            .await?;
+        Ok(found.is_some())
+    }
+
+    /// Adds a game version, returns false if it was already known
+    pub async fn insert(version: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let now = unix_now();
+        let affected = sqlx::query(
+            "INSERT INTO game_versions (version, created_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(version)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        Ok(affected.rows_affected() > 0)
+    }
+}
+
+/// A package ranked by its downloads within a recent window, for `/popular`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Popular {
+    pub id: String,
+    /// Downloads within the window
+    pub downloads: i64,
+    pub all_time_downloads: i64,
+}
+
+/// Disk used by a package's files. Versions whose size isn't known don't count
+#[derive(Debug, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Usage {
+    pub versions: i64,
+    pub bytes: i64,
+    /// What the files take up in storage, less than `bytes` if some are compressed
+    pub stored_bytes: i64,
+}
+
+/// Everything known about a version, for snapshots of the whole index
+#[derive(Debug, PartialEq)]
+pub struct Published {
+    pub id: String,
+    pub version: Version,
+    pub created_at: Option<i64>,
+    pub size: Option<i64>,
+    pub sha256: Option<String>,
+    pub downloads: i64,
+    pub meta: Metadata,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbPublished {
+    id: String,
+
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+
+    created_at: Option<i64>,
+    size: Option<i64>,
+    sha256: Option<String>,
+    downloads: i64,
+
+    name: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    website: Option<String>,
+    game_version: Option<String>,
+    loader: Option<String>,
+    loader_version_req: Option<String>,
+}
+
+impl From<DbPublished> for Published {
+    fn from(db_published: DbPublished) -> Self {
+        Self {
+            id: db_published.id,
+            version: db_version(
+                db_published.major,
+                db_published.minor,
+                db_published.patch,
+                &db_published.build,
+            ),
+            created_at: db_published.created_at,
+            size: db_published.size,
+            sha256: db_published.sha256,
+            downloads: db_published.downloads,
+            meta: Metadata {
+                name: db_published.name,
+                description: db_published.description,
+                author: db_published.author,
+                website: db_published.website,
+                game_version: db_published.game_version,
+                loader: db_published.loader,
+                loader_version_req: db_published.loader_version_req,
+            },
+        }
+    }
+}
+
+impl From<DbMod> for Mod {
+    fn from(db_mod: DbMod) -> Self {
+        Self {
+            id: db_mod.id,
+            version: db_version(db_mod.major, db_mod.minor, db_mod.patch, &db_mod.build),
+        }
+    }
+}
+#[derive(sqlx::FromRow)]
+struct DbPublishKey {
+    pw: String,
+    user: String,
+    scope: Option<String>,
+    expires_at: Option<i64>,
+    last_used_at: Option<i64>,
+    disabled_at: Option<i64>,
+}
+
+impl From<DbPublishKey> for PublishKey {
+    fn from(db_key: DbPublishKey) -> Self {
+        Self {
+            pw: db_key.pw,
+            user: db_key.user,
+            scope: db_key.scope,
+            expires_at: db_key.expires_at,
+            last_used_at: db_key.last_used_at,
+            disabled_at: db_key.disabled_at,
+        }
+    }
+}
+
+/// A publish key as admins see it, without the key itself
+#[derive(Debug, Serialize, PartialEq, sqlx::FromRow)]
+pub struct KeyInfo {
+    pub user: String,
+    pub scope: Option<String>,
+    pub expires_at: Option<i64>,
+    pub created_at: Option<i64>,
+    /// To the hour, keys aren't marked used on every request
+    pub last_used_at: Option<i64>,
+    pub disabled: bool,
+    pub disabled_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbKeyInfo {
+    user: String,
+    scope: Option<String>,
+    expires_at: Option<i64>,
+    created_at: Option<i64>,
+    last_used_at: Option<i64>,
+    disabled_at: Option<i64>,
+}
+
+impl From<DbKeyInfo> for KeyInfo {
+    fn from(db: DbKeyInfo) -> Self {
+        Self {
+            user: db.user,
+            scope: db.scope,
+            expires_at: db.expires_at,
+            created_at: db.created_at,
+            last_used_at: db.last_used_at,
+            disabled: db.disabled_at.is_some(),
+            disabled_at: db.disabled_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SimpleDbMod {
+    id: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbVersion {
+    major: i64,
+    minor: i64,
+    patch: i64,
+    build: String,
+}
+
+impl From<DbVersion> for Version {
+    fn from(db_ver: DbVersion) -> Self {
+        db_version(db_ver.major, db_ver.minor, db_ver.patch, &db_ver.build)
+    }
+}
+
+/// A version from its columns, build metadata the database somehow holds that isn't valid is
+/// left out
+fn db_version(major: i64, minor: i64, patch: i64, build: &str) -> Version {
+    let mut version = Version::new(major as u64, minor as u64, patch as u64);
+    version.build = BuildMetadata::new(build).unwrap_or(BuildMetadata::EMPTY);
+    version
+}
+
+#[derive(sqlx::FromRow)]
+struct DbCount {
+    count: i64,
+}
+
+impl Mod {
+    /// Public packages, only those with a version for the game version if one is given
+    pub async fn list(game: Option<&str>, pool: &AnyPool) -> sqlx::Result<Vec<String>> {
+        sqlx::query_as::<_, SimpleDbMod>(
+            "SELECT DISTINCT id FROM mods WHERE id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            AND id NOT IN (SELECT old_id FROM aliases)
+            AND ($1 IS NULL OR id IN (SELECT id FROM mods WHERE COALESCE((SELECT MAX(CASE WHEN g.game_version = $1 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1))
+            ORDER BY id",
+        )
+        .bind(game)
+        .fetch(pool)
+        .map_ok(|r| r.id)
+        .try_collect()
+        .await
+    }
+
+    pub async fn all(pool: &AnyPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as::<_, DbMod>(
+            "SELECT id, major, minor, patch, build FROM mods ORDER BY id, major DESC, minor DESC, patch DESC, rowid DESC",
+        )
+        .fetch(pool)
+        .map_ok(Self::from)
+        .try_collect()
+        .await
+    }
+
+    /// Every version along with the hash of its file, if it was recorded
+    pub async fn checksums(pool: &AnyPool) -> sqlx::Result<Vec<(Self, Option<String>)>> {
+        sqlx::query_as::<_, (String, i64, i64, i64, String, Option<String>)>(
+            "SELECT id, major, minor, patch, build, sha256 FROM mods
+            ORDER BY id, major, minor, patch, build",
+        )
+        .fetch(pool)
+        .map_ok(|(id, major, minor, patch, build, sha256)| {
+            let m = Self {
+                id,
+                version: db_version(major, minor, patch, &build),
+            };
+            (m, sha256)
+        })
+        .try_collect()
+        .await
+    }
+
+    /// Every version along with when it was uploaded, in the order they were added
+    pub async fn uploads(pool: &AnyPool) -> sqlx::Result<Vec<Upload>> {
+        sqlx::query_as::<_, DbUpload>(
+            "SELECT id, major, minor, patch, build, created_at FROM mods ORDER BY rowid",
+        )
+        .fetch(pool)
+        .map_ok(Upload::from)
+        .try_collect()
+        .await
+    }
+
+    /// Most recent uploads of public packages, of a single package if given. Versions from before
+    /// upload times were recorded are left out
+    pub async fn recent(
+        package: Option<&str>,
+        limit: u32,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Vec<Release>> {
+        match package {
+            Some(id) => {
+                sqlx::query_as::<_, DbRelease>(
+                    "SELECT id, major, minor, patch, build, created_at FROM mods
+                    WHERE created_at IS NOT NULL AND id = $1
+                    AND id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+                    ORDER BY created_at DESC, rowid DESC LIMIT $2",
+                )
+                .bind(id)
+                .bind(i64::from(limit))
+                .fetch(pool)
+                .map_ok(Release::from)
+                .try_collect()
+                .await
+            }
+            None => {
+                sqlx::query_as::<_, DbRelease>(
+                    "SELECT id, major, minor, patch, build, created_at FROM mods
+                    WHERE created_at IS NOT NULL
+                    AND id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+                    ORDER BY created_at DESC, rowid DESC LIMIT $1",
+                )
+                .bind(i64::from(limit))
+                .fetch(pool)
+                .map_ok(Release::from)
+                .try_collect()
+                .await
+            }
+        }
+    }
+
+    /// Every version of every public package, by id then newest first
+    pub async fn published(pool: &AnyPool) -> sqlx::Result<Vec<Published>> {
+        sqlx::query_as::<_, DbPublished>(
+            "SELECT id, major, minor, patch, build, created_at, size, sha256, downloads,
+            name, description, author, website, game_version, loader, loader_version_req FROM mods
+            WHERE id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            ORDER BY id, major DESC, minor DESC, patch DESC, rowid DESC",
+        )
+        .fetch(pool)
+        .map_ok(Published::from)
+        .try_collect()
+        .await
+    }
+
+    /// Everything known about a single version
+    pub async fn get(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<Option<Published>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let found = sqlx::query_as::<_, DbPublished>(
+            "SELECT id, major, minor, patch, build, created_at, size, sha256, downloads,
+            name, description, author, website, game_version, loader, loader_version_req FROM mods
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND build=$5",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.map(Published::from))
+    }
+
+    /// Everything known about the version, only if it was stored with exactly this version
+    /// string, build metadata included
+    pub async fn get_exact(
+        id: &str,
+        ver: &Version,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Option<VersionInfo>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let found = sqlx::query_as::<_, DbVersionInfo>(
+            "SELECT id, major, minor, patch, build, sha256, size, created_at, yanked_at, downloads,
+            name, description, author, website, game_version, loader, loader_version_req FROM mods
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND build=$5",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .fetch_optional(pool)
+        .await?;
+        let Some(found) = found else {
+            return Ok(None);
+        };
+
+        let mut info = VersionInfo::from(found);
+        info.game_versions = sqlx::query_scalar(
+            "SELECT game_version FROM mod_game_versions
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 ORDER BY game_version",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .fetch_all(pool)
+        .await?;
+        Ok(Some(info))
+    }
+
+    /// A version as stored, the build asked for if there is one and otherwise the first one of
+    /// its builds uploaded, so versions can be looked up without their build metadata
+    pub async fn find(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<Option<Published>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let found = sqlx::query_as::<_, DbPublished>(
+            "SELECT id, major, minor, patch, build, created_at, size, sha256, downloads,
+            name, description, author, website, game_version, loader, loader_version_req FROM mods
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4
+            ORDER BY CASE WHEN build=$5 THEN 0 ELSE 1 END, rowid LIMIT 1",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.map(Published::from))
+    }
+
+    /// Disk used by each public package
+    pub async fn usage(pool: &AnyPool) -> sqlx::Result<BTreeMap<String, Usage>> {
+        sqlx::query_as::<_, (String, i64, i64, i64)>(
+            "SELECT id, COUNT(*), CAST(COALESCE(SUM(size), 0) AS BIGINT),
+                CAST(COALESCE(SUM(COALESCE(stored_size, size)), 0) AS BIGINT)
+            FROM mods WHERE id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved') GROUP BY id",
+        )
+        .fetch(pool)
+        .map_ok(|(id, versions, bytes, stored_bytes)| {
+            let usage = Usage {
+                versions,
+                bytes,
+                stored_bytes,
+            };
+            (id, usage)
+        })
+        .try_collect()
+        .await
+    }
+
+    /// How many versions of the package were published after the given time, and when the
+    /// earliest of them was
+    pub async fn recent_uploads(
+        id: &str,
+        after: i64,
+        pool: &AnyPool,
+    ) -> sqlx::Result<(i64, Option<i64>)> {
+        sqlx::query_as(
+            "SELECT COUNT(*), CAST(MIN(created_at) AS BIGINT) FROM mods
+            WHERE id = $1 AND created_at > $2",
+        )
+        .bind(id)
+        .bind(after)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Disk used by one package, or all of them
+    pub async fn package_usage(id: Option<&str>, pool: &AnyPool) -> sqlx::Result<Usage> {
+        sqlx::query_as::<_, Usage>(
+            "SELECT COUNT(*) AS versions, CAST(COALESCE(SUM(size), 0) AS BIGINT) AS bytes,
+                CAST(COALESCE(SUM(COALESCE(stored_size, size)), 0) AS BIGINT) AS stored_bytes
+            FROM mods WHERE $1 IS NULL OR id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// When the package last changed, as far as resolving it goes: a version was published,
+    /// yanked or deleted, or its metadata, file or aliases changed. `None` if it never had a version with a known
+    /// publish date
+    pub async fn updated_at(id: &str, pool: &AnyPool) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar(
+            "SELECT CAST(MAX(changed_at) AS BIGINT) FROM (
+                SELECT created_at AS changed_at FROM mods WHERE id = $1
+                UNION ALL SELECT yanked_at FROM mods WHERE id = $1
+                UNION ALL SELECT updated_at FROM packages WHERE id = $1
+            ) AS changes",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Records that the package changed now, for changes [`Mod::updated_at`] can't tell from the
+    /// versions themselves
+    async fn touch(id: &str, pool: &AnyPool) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO packages (id, updated_at) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET updated_at = excluded.updated_at",
+        )
+        .bind(id)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Changes whenever the package list could: with every change recorded for `/changes`, and
+    /// whenever a package is renamed or its visibility changes. Only looks at the latest change
+    /// and the small per-package tables, never at the versions themselves
+    pub async fn list_fingerprint(pool: &AnyPool) -> sqlx::Result<String> {
+        let (seq, aliases, private, updated_at): (i64, i64, i64, i64) = sqlx::query_as(
+            "SELECT
+                (SELECT CAST(COALESCE(MAX(seq), 0) AS BIGINT) FROM changes),
+                (SELECT COUNT(*) FROM aliases),
+                (SELECT COUNT(*) FROM packages WHERE visibility = 'private'),
+                (SELECT CAST(COALESCE(MAX(updated_at), 0) AS BIGINT) FROM packages)",
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(format!("{}-{}-{}-{}", seq, aliases, private, updated_at))
+    }
+
+    /// Changes whenever a version is added or removed, or downloaded. Downloads not written yet
+    /// are passed in, so writing them doesn't change it
+    pub async fn generation(pending_downloads: i64, pool: &AnyPool) -> sqlx::Result<String> {
+        let (count, last, downloads): (i64, i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(MAX(rowid), 0), CAST(COALESCE(SUM(downloads), 0) AS BIGINT)
+            FROM mods",
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(format!(
+            "{}-{}-{}",
+            count,
+            last,
+            downloads + pending_downloads
+        ))
+    }
+
+    pub async fn list_paged(
+        game: Option<&str>,
+        pool: &AnyPool,
+        limit: u32,
+        offset: u32,
+    ) -> sqlx::Result<Vec<String>> {
+        sqlx::query_as::<_, SimpleDbMod>(
+            "SELECT DISTINCT id FROM mods WHERE id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            AND id NOT IN (SELECT old_id FROM aliases)
+            AND ($1 IS NULL OR id IN (SELECT id FROM mods WHERE COALESCE((SELECT MAX(CASE WHEN g.game_version = $1 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1))
+            ORDER BY id LIMIT $2 OFFSET $3",
+        )
+        .bind(game)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
+        .fetch(pool)
+        .map_ok(|r| r.id)
+        .try_collect()
+        .await
+    }
+
+    pub async fn count(game: Option<&str>, pool: &AnyPool) -> sqlx::Result<u32> {
+        let count = sqlx::query_as::<_, DbCount>(
+            "SELECT COUNT(DISTINCT id) AS count FROM mods WHERE id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            AND id NOT IN (SELECT old_id FROM aliases)
+            AND ($1 IS NULL OR id IN (SELECT id FROM mods WHERE COALESCE((SELECT MAX(CASE WHEN g.game_version = $1 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1))",
+        )
+        .bind(game)
+        .fetch_one(pool)
+        .await?;
+        Ok(count.count as u32)
+    }
+
+    /// Lists every version of a package from newest to oldest
+    pub async fn versions(id: &str, pool: &AnyPool) -> sqlx::Result<Vec<Version>> {
+        sqlx::query_as::<_, DbVersion>(
+            "SELECT major, minor, patch, build FROM mods WHERE id = $1 ORDER BY major DESC, minor DESC, patch DESC, rowid DESC",
+        )
+        .bind(id)
+        .fetch(pool)
+        .map_ok(Version::from)
+        .try_collect()
+        .await
+    }
+
+    /// Inserts a version, returning false if it's already there. Unless `build_variants` is set
+    /// the version also isn't inserted when another build of it is there
+    pub async fn insert(
+        id: &str,
+        ver: &Version,
+        build_variants: bool,
+        pool: &AnyPool,
+    ) -> sqlx::Result<bool> {
+        Self::insert_at(id, ver, Some(unix_now()), build_variants, pool).await
+    }
+
+    /// Inserts a version uploaded at the given time, if it's known
+    pub async fn insert_at(
+        id: &str,
+        ver: &Version,
+        created_at: Option<i64>,
+        build_variants: bool,
+        pool: &AnyPool,
+    ) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let affected = retry(|| async {
+            let mut tx = pool.begin().await?;
+            let affected = sqlx::query(
+                "INSERT INTO mods (id, major, minor, patch, build, created_at)
+                SELECT $1, $2, $3, $4, $5, $6 WHERE $7 OR NOT EXISTS
+                (SELECT 1 FROM mods WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4)
+                ON CONFLICT DO NOTHING",
+            )
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .bind(ver.build.as_str())
+            .bind(created_at)
+            .bind(build_variants)
+            .execute(&mut tx)
+            .await?;
+            if affected.rows_affected() > 0 {
+                Self::refresh_latest(id, &mut tx).await?;
+            }
+            tx.commit().await?;
+            Ok(affected)
+        })
+        .await?;
+
+        if affected.rows_affected() == 0 {
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Replaces the metadata of a version, returning whether the version exists
+    pub async fn set_metadata(
+        id: &str,
+        ver: &Version,
+        meta: &Metadata,
+        pool: &AnyPool,
+    ) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let affected = sqlx::query(
+            "UPDATE mods SET name=$1, description=$2, author=$3, website=$4, game_version=$5,
+            loader=$6, loader_version_req=$7
+            WHERE id=$8 AND major=$9 AND minor=$10 AND patch=$11 AND build=$12",
+        )
+        .bind(&meta.name)
+        .bind(&meta.description)
+        .bind(&meta.author)
+        .bind(&meta.website)
+        .bind(&meta.game_version)
+        .bind(&meta.loader)
+        .bind(&meta.loader_version_req)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        if affected.rows_affected() == 0 {
+            return Ok(false);
+        }
+        Self::touch(id, pool).await?;
+
+        // The game version in the metadata is one of the version's tags
+        if let Some(game_version) = &meta.game_version {
+            Self::tag_game_versions(id, ver, std::slice::from_ref(game_version), pool).await?;
+        }
+        Ok(true)
+    }
+
+    /// Marks a version as made for the given game versions, on top of any it was already for
+    pub async fn tag_game_versions(
+        id: &str,
+        ver: &Version,
+        game_versions: &[String],
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        for game_version in game_versions {
+            sqlx::query(
+                "INSERT INTO mod_game_versions (id, major, minor, patch, game_version)
+                VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+            )
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .bind(game_version)
+            .execute(pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Records who uploaded a version
+    pub async fn record_publisher(
+        id: &str,
+        ver: &Version,
+        user: &str,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        sqlx::query(
+            "UPDATE mods SET published_by=$1
+            WHERE id=$2 AND major=$3 AND minor=$4 AND patch=$5 AND build=$6",
+        )
+        .bind(user)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Versions a user uploaded, newest first
+    pub async fn published_by(
+        user: &str,
+        limit: u32,
+        offset: u32,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Vec<Publication>> {
+        sqlx::query_as::<_, DbPublication>(
+            "SELECT id, major, minor, patch, build, created_at, yanked_at FROM mods WHERE published_by = $1
+            ORDER BY created_at DESC, rowid DESC LIMIT $2 OFFSET $3",
+        )
+        .bind(user)
+        .bind(i64::from(limit))
+        .bind(i64::from(offset))
+        .fetch(pool)
+        .map_ok(Publication::from)
+        .try_collect()
+        .await
+    }
+
+    pub async fn count_published_by(user: &str, pool: &AnyPool) -> sqlx::Result<u32> {
+        let count = sqlx::query_as::<_, DbCount>(
+            "SELECT COUNT(*) AS count FROM mods WHERE published_by = $1",
+        )
+        .bind(user)
+        .fetch_one(pool)
+        .await?;
+        Ok(count.count as u32)
+    }
+
+    /// Yanks every version a user uploaded that isn't yanked already, returning them
+    pub async fn yank_published_by(user: &str, pool: &AnyPool) -> sqlx::Result<Vec<Mod>> {
+        let now = unix_now();
+        let mut tx = pool.begin().await?;
+        let yanked: Vec<Mod> = sqlx::query_as::<_, DbMod>(
+            "SELECT id, major, minor, patch, build FROM mods WHERE published_by = $1 AND yanked_at IS NULL
+            ORDER BY id, major, minor, patch",
+        )
+        .bind(user)
+        .fetch(&mut tx)
+        .map_ok(Mod::from)
+        .try_collect()
+        .await?;
+        sqlx::query("UPDATE mods SET yanked_at = $1 WHERE published_by = $2 AND yanked_at IS NULL")
+            .bind(now)
+            .bind(user)
+            .execute(&mut tx)
+            .await?;
+        // Sorted by id, so each package comes up once in a row
+        let mut ids: Vec<&str> = yanked.iter().map(|m| m.id.as_str()).collect();
+        ids.dedup();
+        for id in ids {
+            Self::refresh_latest(id, &mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(yanked)
+    }
+
+    /// Yanks every version of a package that isn't yanked already, returning them
+    pub async fn yank_package(id: &str, pool: &AnyPool) -> sqlx::Result<Vec<Version>> {
+        let now = unix_now();
+        let mut tx = pool.begin().await?;
+        let yanked = sqlx::query_as::<_, DbMod>(
+            "SELECT id, major, minor, patch, build FROM mods WHERE id = $1 AND yanked_at IS NULL
+            ORDER BY major, minor, patch",
+        )
+        .bind(id)
+        .fetch(&mut tx)
+        .map_ok(|m| Mod::from(m).version)
+        .try_collect()
+        .await?;
+        sqlx::query("UPDATE mods SET yanked_at = $1 WHERE id = $2 AND yanked_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        Self::refresh_latest(id, &mut tx).await?;
+        tx.commit().await?;
+        Ok(yanked)
+    }
+
+    /// Moves everything stored under the ids in `from` under `id`, in one transaction. Their
+    /// versions must not be under `id` already. Where both have a row only kept once per package,
+    /// or once per version for stale rows of deleted versions, `id`'s is kept, though a package
+    /// stays private if either was. Download counts are added up. Changes and the audit log are
+    /// history and keep the ids they had
+    pub async fn merge_ids(from: &[String], id: &str, pool: &AnyPool) -> sqlx::Result<()> {
+        let mut tx = pool.begin().await?;
+        for old in from {
+            for table in ["tombstones", "readmes", "mod_game_versions", "artifacts"] {
+                let same_name = match table {
+                    "artifacts" => " AND t.name = artifacts.name",
+                    _ => "",
+                };
+                sqlx::query(&format!(
+                    "DELETE FROM {table} WHERE id = $1 AND EXISTS (SELECT 1 FROM {table} t
+                    WHERE t.id = $2 AND t.major = {table}.major AND t.minor = {table}.minor
+                    AND t.patch = {table}.patch{same_name})"
+                ))
+                .bind(old)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+
+            sqlx::query(
+                "UPDATE packages SET visibility = 'private' WHERE id = $1
+                AND EXISTS (SELECT 1 FROM packages p WHERE p.id = $2 AND p.visibility = 'private')",
+            )
+            .bind(id)
+            .bind(old)
+            .execute(&mut tx)
+            .await?;
+            for (table, column) in [
+                ("packages", "id"),
+                ("blocked_packages", "id"),
+                ("aliases", "old_id"),
+            ] {
+                sqlx::query(&format!(
+                    "DELETE FROM {table} WHERE {column} = $1
+                    AND EXISTS (SELECT 1 FROM {table} t WHERE t.{column} = $2)"
+                ))
+                .bind(old)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+            }
+
+            sqlx::query(
+                "UPDATE download_hours SET downloads = downloads + (SELECT h.downloads
+                FROM download_hours h WHERE h.id = $1 AND h.hour = download_hours.hour)
+                WHERE id = $2 AND hour IN (SELECT hour FROM download_hours WHERE id = $1)",
+            )
+            .bind(old)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+            sqlx::query(
+                "DELETE FROM download_hours WHERE id = $1
+                AND hour IN (SELECT hour FROM download_hours WHERE id = $2)",
+            )
+            .bind(old)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+            for (table, column) in [
+                ("mods", "id"),
+                ("tombstones", "id"),
+                ("readmes", "id"),
+                ("mod_game_versions", "id"),
+                ("artifacts", "id"),
+                ("packages", "id"),
+                ("blocked_packages", "id"),
+                ("aliases", "old_id"),
+                ("aliases", "new_id"),
+                ("download_hours", "id"),
+            ] {
+                sqlx::query(&format!(
+                    "UPDATE {table} SET {column} = $1 WHERE {column} = $2"
+                ))
+                .bind(id)
+                .bind(old)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        // Aliases from another casing to the id, added to work around it, now lead nowhere
+        sqlx::query("DELETE FROM aliases WHERE old_id = $1 AND new_id = $1")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        for old in from {
+            Self::refresh_latest(old, &mut tx).await?;
+        }
+        Self::refresh_latest(id, &mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Hash of a version's file, `None` if there's no such version and `Some(None)` if it was
+    /// stored before hashes were recorded
+    pub async fn sha256(
+        id: &str,
+        ver: &Version,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Option<Option<String>>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let found: Option<(Option<String>,)> = sqlx::query_as(
+            "SELECT sha256 FROM mods WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND build=$5",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.map(|(sha256,)| sha256))
     }
 
-    let pool = SqlitePool::connect(&format!("sqlite://{}", url)).await?;
-    sqlx::migrate!("./migrations").run(&pool).await?;
-    Ok(&*Box::leak(Box::new(pool)))
-}
+    /// Records the size of a file found in storage, unless it's already known
+    pub async fn record_size(
+        id: &str,
+        ver: &Version,
+        size: u64,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+        let size = size as i64;
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub struct Mod {
-    pub id: String,
-    pub version: Version,
-}
+        sqlx::query(
+            "UPDATE mods SET size=$1
+            WHERE size IS NULL AND id=$2 AND major=$3 AND minor=$4 AND patch=$5 AND build=$6",
+        )
+        .bind(size)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
 
-struct DbMod {
-    id: String,
+    /// Another version whose file has the given hash, to share it with
+    pub async fn with_sha256(
+        sha256: &str,
+        except_id: &str,
+        except_ver: &Version,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Option<Mod>> {
+        let (major, minor, patch) = version_parts(except_ver)?;
 
-    major: i64,
-    minor: i64,
-    patch: i64,
-}
+        let found = sqlx::query_as::<_, DbMod>(
+            "SELECT id, major, minor, patch, build FROM mods
+            WHERE sha256 = $1
+            AND NOT (id = $2 AND major = $3 AND minor = $4 AND patch = $5 AND build = $6)
+            ORDER BY rowid LIMIT 1",
+        )
+        .bind(sha256)
+        .bind(except_id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(except_ver.build.as_str())
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.map(Mod::from))
+    }
 
-impl From<DbMod> for Mod {
-    fn from(db_mod: DbMod) -> Self {
-        Self {
-            id: db_mod.id,
-            version: Version::new(
-                db_mod.major as u64,
-                db_mod.minor as u64,
-                db_mod.patch as u64,
-            ),
-        }
+    /// Records the bytes a version's file takes up in storage
+    pub async fn record_stored_size(
+        id: &str,
+        ver: &Version,
+        stored_size: u64,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+        let stored_size = stored_size as i64;
+
+        sqlx::query(
+            "UPDATE mods SET stored_size=$1
+            WHERE id=$2 AND major=$3 AND minor=$4 AND patch=$5 AND build=$6",
+        )
+        .bind(stored_size)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        Ok(())
     }
-}
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub struct PublishKey {
-    pub pw: String,
-    pub user: String,
-}
 
-struct DbPublishKey {
-    pw: String,
-    user: String,
-}
+    /// Records the size and hash of the file a version was stored with
+    pub async fn record_file(
+        id: &str,
+        ver: &Version,
+        contents: &[u8],
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
 
-impl From<DbPublishKey> for PublishKey {
-    fn from(db_key: DbPublishKey) -> Self {
-        Self {
-            pw: db_key.pw,
-            user: db_key.user,
-        }
+        let size = contents.len() as i64;
+        let sha256 = hex::encode(Sha256::digest(contents));
+
+        sqlx::query(
+            "UPDATE mods SET size=$1, sha256=$2, stored_size=NULL
+            WHERE id=$3 AND major=$4 AND minor=$5 AND patch=$6 AND build=$7",
+        )
+        .bind(size)
+        .bind(sha256)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        // Recorded after the version was inserted, maybe a second later
+        Mod::touch(id, pool).await
     }
-}
 
-struct SimpleDbMod {
-    id: String,
-}
+    pub async fn add_downloads(
+        id: &str,
+        ver: &Version,
+        count: i64,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
 
-impl Mod {
-    pub async fn list(pool: &SqlitePool) -> sqlx::Result<Vec<String>> {
-        sqlx::query_as!(SimpleDbMod, "SELECT DISTINCT id FROM mods")
-            .fetch(pool)
-            .map_ok(|r| r.id)
-            .try_collect()
-            .await
+        sqlx::query(
+            "UPDATE mods SET downloads = downloads + $1
+            WHERE id=$2 AND major=$3 AND minor=$4 AND patch=$5 AND build=$6",
+        )
+        .bind(count)
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(ver.build.as_str())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Counts downloads in the hour they were in as well, for rankings over recent windows
+    pub async fn add_download_hours(
+        id: &str,
+        hour: i64,
+        count: i64,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO download_hours (id, hour, downloads) VALUES ($1, $2, $3)
+            ON CONFLICT (id, hour) DO UPDATE SET downloads = download_hours.downloads + $3",
+        )
+        .bind(id)
+        .bind(hour)
+        .bind(count)
+        .execute(pool)
+        .await?;
+        Ok(())
     }
 
-    pub async fn insert(id: &str, ver: &Version, pool: &SqlitePool) -> sqlx::Result<bool> {
-        let major = ver.major as i64;
-        let minor = ver.minor as i64;
-        let patch = ver.patch as i64;
+    /// Downloads of every version of a package
+    pub async fn downloads(id: &str, pool: &AnyPool) -> sqlx::Result<i64> {
+        sqlx::query_scalar(
+            "SELECT CAST(COALESCE(SUM(downloads), 0) AS BIGINT) FROM mods WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
 
-        let affected = sqlx::query!(
-            "INSERT OR IGNORE INTO mods (id, major, minor, patch) VALUES (?, ?, ?, ?)",
+    /// Public packages with the most downloads since the start of `since_hour`, ties broken by
+    /// id. Packages without any version left aren't ranked
+    pub async fn popular(
+        since_hour: i64,
+        limit: u32,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Vec<Popular>> {
+        sqlx::query_as::<_, (String, i64, i64)>(
+            "SELECT h.id, CAST(SUM(h.downloads) AS BIGINT) AS recent,
+                (SELECT CAST(COALESCE(SUM(m.downloads), 0) AS BIGINT) FROM mods m WHERE m.id = h.id)
+            FROM download_hours h
+            WHERE h.hour >= $1 AND h.id IN (SELECT id FROM mods)
+                AND h.id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            GROUP BY h.id ORDER BY recent DESC, h.id LIMIT $2",
+        )
+        .bind(since_hour)
+        .bind(limit as i64)
+        .fetch(pool)
+        .map_ok(|(id, downloads, all_time_downloads)| Popular {
             id,
-            major,
-            minor,
-            patch
+            downloads,
+            all_time_downloads,
+        })
+        .try_collect()
+        .await
+    }
+
+    /// Forgets the hourly download counts from before `before_hour`, returning how many went
+    pub async fn prune_download_hours(before_hour: i64, pool: &AnyPool) -> sqlx::Result<u64> {
+        let pruned = sqlx::query("DELETE FROM download_hours WHERE hour < $1")
+            .bind(before_hour)
+            .execute(pool)
+            .await?;
+        Ok(pruned.rows_affected())
+    }
+
+    pub async fn delete(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        // The latest version goes back to the newest one left in the same transaction
+        let affected = retry(|| async {
+            let mut tx = pool.begin().await?;
+            let affected = sqlx::query(
+                "DELETE FROM mods WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND build=$5",
+            )
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .bind(ver.build.as_str())
+            .execute(&mut tx)
+            .await?;
+            if affected.rows_affected() > 0 {
+                Self::refresh_latest(id, &mut tx).await?;
+            }
+            tx.commit().await?;
+            Ok(affected)
+        })
+        .await?;
+        if affected.rows_affected() > 0 {
+            Self::touch(id, pool).await?;
+        }
+
+        // Documents and artifacts only make sense alongside their version, which other builds of
+        // it share
+        let remaining: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM mods WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .fetch_one(pool)
+        .await?;
+        if remaining > 0 {
+            return Ok(affected.rows_affected() > 0);
+        }
+        sqlx::query("DELETE FROM readmes WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4")
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .execute(pool)
+            .await?;
+        sqlx::query("DELETE FROM artifacts WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4")
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            "DELETE FROM mod_game_versions WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4",
         )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
         .execute(pool)
         .await?;
 
@@ -102,18 +2270,183 @@ impl Mod {
         }
     }
 
-    pub async fn delete(id: &str, ver: &Version, pool: &SqlitePool) -> sqlx::Result<bool> {
-        let major = ver.major as i64;
-        let minor = ver.minor as i64;
-        let patch = ver.patch as i64;
+    /// The readme attached to a version, if there is one
+    pub async fn readme(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<Option<String>> {
+        let (major, minor, patch) = version_parts(ver)?;
 
-        let affected = sqlx::query!(
-            "DELETE FROM mods WHERE id=? AND major=? AND minor=? AND patch=?",
-            id,
-            major,
-            minor,
-            patch
+        sqlx::query_scalar(
+            "SELECT contents FROM readmes WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Attaches a readme to a version, replacing any it had
+    pub async fn set_readme(
+        id: &str,
+        ver: &Version,
+        contents: &str,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+        let now = unix_now();
+
+        sqlx::query(
+            "INSERT INTO readmes (id, major, minor, patch, contents, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (id, major, minor, patch) DO UPDATE
+            SET contents = excluded.contents, updated_at = excluded.updated_at",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(contents)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The named artifacts published alongside a version, by name
+    pub async fn artifacts(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<Vec<Artifact>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        sqlx::query_as::<_, Artifact>(
+            "SELECT name, size, sha256, created_at FROM artifacts
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 ORDER BY name",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn artifact(
+        id: &str,
+        ver: &Version,
+        name: &str,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Option<Artifact>> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        sqlx::query_as::<_, Artifact>(
+            "SELECT name, size, sha256, created_at FROM artifacts
+            WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND name=$5",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Records an artifact of a version, returns false if it already has one by that name
+    pub async fn add_artifact(
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: &[u8],
+        pool: &AnyPool,
+    ) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+        let size = contents.len() as i64;
+        let sha256 = hex::encode(Sha256::digest(contents));
+        let now = unix_now();
+
+        let affected = retry(|| {
+            sqlx::query(
+                "INSERT INTO artifacts (id, major, minor, patch, name, size, sha256, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT DO NOTHING",
+            )
+            .bind(id)
+            .bind(major)
+            .bind(minor)
+            .bind(patch)
+            .bind(name)
+            .bind(size)
+            .bind(&sha256)
+            .bind(now)
+            .execute(pool)
+        })
+        .await?;
+        Ok(affected.rows_affected() > 0)
+    }
+
+    pub async fn remove_artifact(
+        id: &str,
+        ver: &Version,
+        name: &str,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        sqlx::query(
+            "DELETE FROM artifacts WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4 AND name=$5",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(name)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Remembers a version was deleted so it can't be published again
+    pub async fn tombstone(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<()> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let now = unix_now();
+
+        sqlx::query(
+            "INSERT INTO tombstones (id, major, minor, patch, deleted_at) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (id, major, minor, patch) DO UPDATE SET deleted_at = excluded.deleted_at",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .bind(now)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_tombstoned(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let found = sqlx::query(
+            "SELECT deleted_at FROM tombstones WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4",
+        )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
+        .fetch_optional(pool)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    pub async fn clear_tombstone(id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<bool> {
+        let (major, minor, patch) = version_parts(ver)?;
+
+        let affected = sqlx::query(
+            "DELETE FROM tombstones WHERE id=$1 AND major=$2 AND minor=$3 AND patch=$4",
         )
+        .bind(id)
+        .bind(major)
+        .bind(minor)
+        .bind(patch)
         .execute(pool)
         .await?;
 
@@ -124,60 +2457,154 @@ impl Mod {
         }
     }
 
+    /// Points `latest_versions` at the newest version of a package that isn't yanked, or drops
+    /// its row if there's none left. Run in the transaction changing the versions
+    async fn refresh_latest(id: &str, conn: &mut AnyConnection) -> sqlx::Result<()> {
+        sqlx::query("DELETE FROM latest_versions WHERE id = $1")
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(
+            "INSERT INTO latest_versions (id, major, minor, patch, build)
+            SELECT id, major, minor, patch, build FROM mods WHERE id = $1 AND yanked_at IS NULL
+            ORDER BY major DESC, minor DESC, patch DESC, rowid DESC LIMIT 1",
+        )
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
     pub async fn resolve_one(
         id: &str,
         req: &VersionReq,
-        pool: &SqlitePool,
-    ) -> sqlx::Result<Option<Self>> {
-        sqlx::query_as!(
-            DbMod,
-            "SELECT * FROM mods WHERE id = ? ORDER BY major DESC, minor DESC, patch DESC",
-            id
+        game: Option<&str>,
+        loader: Option<&str>,
+        pool: &AnyPool,
+        offset: usize,
+    ) -> sqlx::Result<Option<Resolved>> {
+        // Any version does, so it's the latest one unless some have to be left out
+        if req.comparators.is_empty() && game.is_none() && loader.is_none() && offset == 0 {
+            return sqlx::query_as::<_, DbResolved>(
+                "SELECT m.id, m.major, m.minor, m.patch, m.build, name, description, author, website,
+                game_version, loader, loader_version_req
+                FROM latest_versions l JOIN mods m ON m.id = l.id AND m.major = l.major
+                AND m.minor = l.minor AND m.patch = l.patch AND m.build = l.build
+                WHERE l.id = $1",
+            )
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .map(|m| m.map(Resolved::from));
+        }
+
+        sqlx::query_as::<_, DbResolved>(
+            "SELECT id, major, minor, patch, build, name, description, author, website, game_version,
+            loader, loader_version_req
+            FROM mods WHERE id = $1 AND yanked_at IS NULL AND ($2 IS NULL OR COALESCE((SELECT MAX(CASE WHEN g.game_version = $2 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1)
+            AND ($3 IS NULL OR loader IS NULL OR loader = $3)
+            ORDER BY major DESC, minor DESC, patch DESC, rowid DESC",
         )
+        .bind(id)
+        .bind(game)
+        .bind(loader)
         .fetch(pool)
         .try_filter_map(move |m| Self::tfm_fn(m, req))
+        .skip(offset)
         .next()
         .await
         .transpose()
     }
 
-    pub async fn resolve_all(
+    /// Matching versions past `offset`, at most `limit` of them unless it's 0, along with how
+    /// many versions match in total
+    pub async fn resolve_counted(
         id: &str,
         req: &VersionReq,
-        pool: &SqlitePool,
-    ) -> sqlx::Result<Vec<Self>> {
-        sqlx::query_as!(
-            DbMod,
-            "SELECT * FROM mods WHERE id = ? ORDER BY major DESC, minor DESC, patch DESC",
-            id
+        game: Option<&str>,
+        loader: Option<&str>,
+        pool: &AnyPool,
+        limit: usize,
+        offset: usize,
+    ) -> sqlx::Result<(Vec<Resolved>, u64)> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let mut versions = sqlx::query_as::<_, DbResolved>(
+            "SELECT id, major, minor, patch, build, name, description, author, website, game_version,
+            loader, loader_version_req
+            FROM mods WHERE id = $1 AND yanked_at IS NULL AND ($2 IS NULL OR COALESCE((SELECT MAX(CASE WHEN g.game_version = $2 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1)
+            AND ($3 IS NULL OR loader IS NULL OR loader = $3)
+            ORDER BY major DESC, minor DESC, patch DESC, rowid DESC",
         )
+        .bind(id)
+        .bind(game)
+        .bind(loader)
         .fetch(pool)
-        .try_filter_map(move |m| Self::tfm_fn(m, req))
-        .try_collect()
-        .await
+        .try_filter_map(move |m| Self::tfm_fn(m, req));
+
+        // Every version matches, so the database can count them without reading past the page
+        if req.comparators.is_empty() {
+            let total = Self::count_matching(id, req, game, loader, pool).await?;
+            let versions = versions.skip(offset).take(limit).try_collect().await?;
+            return Ok((versions, total));
+        }
+
+        let mut page = Vec::new();
+        let mut total = 0;
+        while let Some(m) = versions.try_next().await? {
+            if total >= offset as u64 && page.len() < limit {
+                page.push(m);
+            }
+            total += 1;
+        }
+        Ok((page, total))
     }
 
-    pub async fn resolve_n(
+    /// How many versions match the requirement
+    pub async fn count_matching(
         id: &str,
         req: &VersionReq,
-        pool: &SqlitePool,
-        n: usize,
-    ) -> sqlx::Result<Vec<Self>> {
-        sqlx::query_as!(
-            DbMod,
-            "SELECT * FROM mods WHERE id = ? ORDER BY major DESC, minor DESC, patch DESC",
-            id
+        game: Option<&str>,
+        loader: Option<&str>,
+        pool: &AnyPool,
+    ) -> sqlx::Result<u64> {
+        // Requirements are matched here rather than in SQL
+        if !req.comparators.is_empty() {
+            return sqlx::query_as::<_, DbResolved>(
+                "SELECT id, major, minor, patch, build, name, description, author, website, game_version,
+                loader, loader_version_req
+                FROM mods WHERE id = $1 AND yanked_at IS NULL AND ($2 IS NULL OR COALESCE((SELECT MAX(CASE WHEN g.game_version = $2 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1)
+            AND ($3 IS NULL OR loader IS NULL OR loader = $3)
+                ORDER BY major DESC, minor DESC, patch DESC, rowid DESC",
+            )
+            .bind(id)
+            .bind(game)
+            .bind(loader)
+            .fetch(pool)
+            .try_filter_map(move |m| Self::tfm_fn(m, req))
+            .try_fold(0, |count, _| future::ready(Ok(count + 1)))
+            .await;
+        }
+
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*)
+            FROM mods WHERE id = $1 AND yanked_at IS NULL AND ($2 IS NULL OR COALESCE((SELECT MAX(CASE WHEN g.game_version = $2 THEN 1 ELSE 0 END) FROM mod_game_versions g
+            WHERE g.id = mods.id AND g.major = mods.major AND g.minor = mods.minor AND g.patch = mods.patch), 1) = 1)
+            AND ($3 IS NULL OR loader IS NULL OR loader = $3)",
         )
-        .fetch(pool)
-        .try_filter_map(move |m| Self::tfm_fn(m, req))
-        .take(n)
-        .try_collect()
-        .await
+        .bind(id)
+        .bind(game)
+        .bind(loader)
+        .fetch_one(pool)
+        .await?;
+        Ok(count as u64)
     }
 
-    fn tfm_fn(m: DbMod, req: &VersionReq) -> future::Ready<sqlx::Result<Option<Self>>> {
-        let m = Self::from(m);
-        if req.matches(&m.version) {
+    fn tfm_fn(m: DbResolved, req: &VersionReq) -> future::Ready<sqlx::Result<Option<Resolved>>> {
+        let m = Resolved::from(m);
+        if req.matches(&m.m.version) {
             future::ready(sqlx::Result::Ok(Some(m)))
         } else {
             future::ready(sqlx::Result::Ok(None))
@@ -185,18 +2612,173 @@ impl Mod {
     }
 }
 
+/// Something an admin or publisher did
+#[derive(Debug, Serialize, PartialEq, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: i64,
+    pub actor: String,
+    pub action: String,
+    pub package: Option<String>,
+    pub version: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl AuditEntry {
+    pub async fn insert(
+        actor: &str,
+        action: &str,
+        package: Option<&str>,
+        version: Option<&Version>,
+        ip: Option<IpAddr>,
+        pool: &AnyPool,
+    ) -> sqlx::Result<()> {
+        let now = unix_now();
+        let version = version.map(Version::to_string);
+        let ip = ip.map(|ip| ip.to_string());
+
+        sqlx::query(
+            "INSERT INTO audit_log (created_at, actor, action, package, version, ip)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(now)
+        .bind(actor)
+        .bind(action)
+        .bind(package)
+        .bind(version)
+        .bind(ip)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Entries newest first, starting before the given entry id if any
+    pub async fn list(limit: u32, before: Option<i64>, pool: &AnyPool) -> sqlx::Result<Vec<Self>> {
+        let before = before.unwrap_or(i64::MAX);
+        sqlx::query_as::<_, AuditEntry>(
+            "SELECT id, created_at, actor, action, package, version, ip FROM audit_log
+            WHERE id < $1 ORDER BY id DESC LIMIT $2",
+        )
+        .bind(before)
+        .bind(i64::from(limit))
+        .fetch_all(pool)
+        .await
+    }
+}
+
+/// A version published, deleted, yanked or given new metadata, for mirrors following `/changes`
+#[derive(Debug, Serialize, PartialEq, sqlx::FromRow)]
+pub struct Change {
+    pub seq: i64,
+    /// `upload`, `delete`, `yank` or `metadata`
+    pub action: String,
+    pub id: String,
+    pub version: String,
+    pub timestamp: i64,
+}
+
+impl Change {
+    pub async fn record(action: &str, id: &str, ver: &Version, pool: &AnyPool) -> sqlx::Result<()> {
+        sqlx::query(
+            "INSERT INTO changes (action, id, version, created_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(action)
+        .bind(id)
+        .bind(ver.to_string())
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The earliest and latest sequence numbers kept, none before anything changed
+    pub async fn bounds(pool: &AnyPool) -> sqlx::Result<Option<(i64, i64)>> {
+        let (earliest, latest): (Option<i64>, Option<i64>) = sqlx::query_as(
+            "SELECT CAST(MIN(seq) AS BIGINT), CAST(MAX(seq) AS BIGINT) FROM changes",
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(earliest.zip(latest))
+    }
+
+    /// Changes to public packages after `since` up to `until`, in order
+    pub async fn between(
+        since: i64,
+        until: i64,
+        limit: u32,
+        pool: &AnyPool,
+    ) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as::<_, Change>(
+            "SELECT seq, action, id, version, created_at AS timestamp FROM changes
+            WHERE seq > $1 AND seq <= $2
+                AND id NOT IN (SELECT id FROM packages WHERE visibility = 'private' OR approval <> 'approved')
+            ORDER BY seq LIMIT $3",
+        )
+        .bind(since)
+        .bind(until)
+        .bind(i64::from(limit))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Forgets changes made before the given time. The latest is always kept, so what the feed
+    /// is up to is never lost
+    pub async fn prune(before: i64, pool: &AnyPool) -> sqlx::Result<u64> {
+        let pruned = sqlx::query(
+            "DELETE FROM changes WHERE created_at < $1
+                AND seq < (SELECT MAX(seq) FROM changes)",
+        )
+        .bind(before)
+        .execute(pool)
+        .await?;
+        Ok(pruned.rows_affected())
+    }
+}
+
 impl PublishKey {
     fn tfm_fn(m: DbPublishKey) -> future::Ready<sqlx::Result<Option<Self>>> {
         future::ready(sqlx::Result::Ok(Some(Self::from(m))))
     }
 
-    pub async fn insert(user: &str, pw: &str, pool: &SqlitePool) -> sqlx::Result<bool> {
-        let affected = sqlx::query!(
-            "INSERT OR IGNORE INTO publish_keys (pw, user) VALUES (?, ?)",
-            pw,
-            user,
-        )
-        .execute(pool)
+    /// Whether the key was disabled for going unused
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= unix_now(),
+            None => false,
+        }
+    }
+
+    pub fn covers(&self, id: &str) -> bool {
+        match self.scope.as_deref() {
+            None => true,
+            Some(scope) => {
+                // Scopes from before ids were lowercased may not be
+                let scope = scope.to_lowercase();
+                match scope.strip_suffix('*') {
+                    Some(prefix) => id.starts_with(prefix),
+                    None => scope == id,
+                }
+            }
+        }
+    }
+
+    pub async fn insert(&self, pool: &AnyPool) -> sqlx::Result<bool> {
+        let affected = retry(|| {
+            sqlx::query(
+                r#"INSERT INTO publish_keys (pw, "user", scope, expires_at, created_at)
+                VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING"#,
+            )
+            .bind(&self.pw)
+            .bind(&self.user)
+            .bind(&self.scope)
+            .bind(self.expires_at)
+            .bind(unix_now())
+            .execute(pool)
+        })
         .await?;
 
         if affected.rows_affected() == 0 {
@@ -206,8 +2788,77 @@ impl PublishKey {
         }
     }
 
-    pub async fn resolve_one(key: &str, pool: &SqlitePool) -> sqlx::Result<Option<Self>> {
-        sqlx::query_as!(DbPublishKey, "SELECT * FROM publish_keys WHERE pw = ?", key)
+    pub async fn all(pool: &AnyPool) -> sqlx::Result<Vec<Self>> {
+        sqlx::query_as::<_, DbPublishKey>(r#"SELECT * FROM publish_keys ORDER BY "user", pw"#)
+            .fetch(pool)
+            .map_ok(Self::from)
+            .try_collect()
+            .await
+    }
+
+    /// Every key without the keys themselves, for admins to see which are still in use
+    pub async fn list(pool: &AnyPool) -> sqlx::Result<Vec<KeyInfo>> {
+        sqlx::query_as::<_, DbKeyInfo>(
+            r#"SELECT "user", scope, expires_at, created_at, last_used_at, disabled_at
+            FROM publish_keys ORDER BY "user", created_at"#,
+        )
+        .fetch(pool)
+        .map_ok(KeyInfo::from)
+        .try_collect()
+        .await
+    }
+
+    /// Whether the key was last used long enough ago to be marked used again. Keys are used on
+    /// every authenticated request, so when isn't kept more precisely than to the hour
+    pub fn is_used_recently(&self) -> bool {
+        self.last_used_at
+            .is_some_and(|used| unix_now() - used < 3600)
+    }
+
+    pub async fn mark_used(pw: &str, user: &str, at: i64, pool: &AnyPool) -> sqlx::Result<()> {
+        sqlx::query(r#"UPDATE publish_keys SET last_used_at=$1 WHERE pw=$2 AND "user"=$3"#)
+            .bind(at)
+            .bind(pw)
+            .bind(user)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Disables keys that weren't used since `before`, going by when they were added for those
+    /// never used. Returns how many were
+    pub async fn disable_unused(before: i64, pool: &AnyPool) -> sqlx::Result<u64> {
+        let affected = retry(|| {
+            sqlx::query(
+                "UPDATE publish_keys SET disabled_at=$1
+                WHERE disabled_at IS NULL AND COALESCE(last_used_at, created_at) < $2",
+            )
+            .bind(unix_now())
+            .bind(before)
+            .execute(pool)
+        })
+        .await?;
+        Ok(affected.rows_affected())
+    }
+
+    /// Enables a user's keys again, counting it as a use so they aren't disabled again right
+    /// away. Returns how many keys the user has
+    pub async fn enable_user(user: &str, pool: &AnyPool) -> sqlx::Result<u64> {
+        let affected = retry(|| {
+            sqlx::query(
+                r#"UPDATE publish_keys SET disabled_at=NULL, last_used_at=$1 WHERE "user"=$2"#,
+            )
+            .bind(unix_now())
+            .bind(user)
+            .execute(pool)
+        })
+        .await?;
+        Ok(affected.rows_affected())
+    }
+
+    pub async fn resolve_one(key: &str, pool: &AnyPool) -> sqlx::Result<Option<Self>> {
+        sqlx::query_as::<_, DbPublishKey>("SELECT * FROM publish_keys WHERE pw = $1")
+            .bind(key)
             .fetch(pool)
             .try_filter_map(Self::tfm_fn)
             .next()
@@ -215,10 +2866,13 @@ impl PublishKey {
             .transpose()
     }
 
-    pub async fn delete_user(user: &str, pool: &SqlitePool) -> sqlx::Result<bool> {
-        let affected = sqlx::query!("DELETE FROM publish_keys WHERE user=?", user)
-            .execute(pool)
-            .await?;
+    pub async fn delete_user(user: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let affected = retry(|| {
+            sqlx::query(r#"DELETE FROM publish_keys WHERE "user"=$1"#)
+                .bind(user)
+                .execute(pool)
+        })
+        .await?;
 
         if affected.rows_affected() == 0 {
             Ok(false)
@@ -227,10 +2881,13 @@ impl PublishKey {
         }
     }
 
-    pub async fn delete_pw(pw: &str, pool: &SqlitePool) -> sqlx::Result<bool> {
-        let affected = sqlx::query!("DELETE FROM publish_keys WHERE pw=?", pw)
-            .execute(pool)
-            .await?;
+    pub async fn delete_pw(pw: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+        let affected = retry(|| {
+            sqlx::query("DELETE FROM publish_keys WHERE pw=$1")
+                .bind(pw)
+                .execute(pool)
+        })
+        .await?;
 
         if affected.rows_affected() == 0 {
             Ok(false)
@@ -1,67 +1,469 @@
-use std::fmt::Display;
+use std::{
+    any::Any,
+    fmt::{self, Display},
+    io,
+    time::Duration,
+};
 use warp::{
-    http::StatusCode,
-    reject::{Reject, Rejection},
     Reply,
+    http::{
+        HeaderValue, Method, StatusCode,
+        header::{ALLOW, RETRY_AFTER},
+    },
+    reject::{Reject, Rejection},
+    reply::Response,
 };
 
+use crate::datetime::DateTime;
+
+/// Everything a request can fail with, each answered with its own status and a JSON body whose
+/// `error` field tells them apart
 #[derive(Debug)]
-pub struct InternalServerError;
-impl Reject for InternalServerError {}
+pub enum ApiError {
+    /// `what` names the kind of resource that's missing
+    NotFound {
+        what: &'static str,
+    },
+    /// A resource that existed but was deliberately removed
+    Gone {
+        what: &'static str,
+    },
+    /// A package an admin pulled, clients shouldn't retry
+    Blocked {
+        reason: String,
+    },
+    BadRequest {
+        reason: String,
+    },
+    /// A query parameter that couldn't be parsed or isn't allowed
+    Invalid {
+        field: String,
+        message: String,
+    },
+    /// A well-formed body whose contents aren't acceptable
+    Unprocessable {
+        reason: String,
+    },
+    Unauthorized,
+    Forbidden,
+    TooManyRequests {
+        retry_after: Duration,
+    },
+    /// Too many versions of a package were published lately, `count` of the `limit` allowed
+    UploadLimited {
+        count: u64,
+        limit: u64,
+        retry_after: Duration,
+    },
+    /// The database is too busy to answer, clients should try again shortly
+    ServiceUnavailable {
+        retry_after: Duration,
+    },
+    /// A write clashing with what's already stored
+    Conflict {
+        detail: String,
+    },
+    /// Publishing a version that's already there with another file, or while another upload of it
+    /// is still storing its file. Says what's there, so clients can tell those apart
+    VersionConflict {
+        /// The version as published, which may be another build of the one uploaded
+        version: String,
+        sha256: Option<String>,
+        published_at: Option<i64>,
+        file_present: bool,
+        hash_matches: bool,
+        in_progress: bool,
+    },
+    /// Deleting a version old enough to be protected, repeating the delete with the token
+    /// confirms it
+    ConfirmationRequired {
+        token: String,
+        expires_in: Duration,
+    },
+    /// An `If-Match` that doesn't match the version as stored, `sha256` is what it should have
+    /// been, if the version exists and its hash is known
+    PreconditionFailed {
+        sha256: Option<String>,
+    },
+    /// Asking `/changes` for changes that were already pruned, clients have to sync everything
+    /// again. `earliest` is the first change still kept
+    ResyncRequired {
+        earliest: i64,
+    },
+    /// Publishing under a package id that was renamed
+    Renamed {
+        to: String,
+    },
+    /// A write while the index is read-only
+    ReadOnly,
+    /// Storage took too long to read a file
+    StorageTimeout,
+    /// An upload that would take the index past a storage quota
+    InsufficientStorage {
+        /// `package` or `total`
+        quota: &'static str,
+        limit: u64,
+    },
+    /// A request body over the size allowed for it
+    PayloadTooLarge {
+        limit: u64,
+    },
+    MethodNotAllowed {
+        allow: &'static [Method],
+    },
+    /// A request body sent with a `Content-Encoding` that isn't accepted for it
+    UnsupportedEncoding {
+        encoding: String,
+    },
+    /// Logged when answered, clients only learn something went wrong
+    Internal(anyhow::Error),
+}
+impl Reject for ApiError {}
 
+/// Method not allowed and not found are rejected as their own types, see
+/// [`ApiError::into_rejection`]
 #[derive(Debug)]
-pub struct NotFound;
-impl Reject for NotFound {}
+struct NotAllowed(ApiError);
+impl Reject for NotAllowed {}
 
 #[derive(Debug)]
-pub struct Unauthorized;
-impl Reject for Unauthorized {}
+struct Missing(ApiError);
+impl Reject for Missing {}
+
+impl ApiError {
+    /// Rejections of every route matching the path are combined, and only the first of a type
+    /// can be found again. Method not allowed and not found are rejected as their own types, so
+    /// any other error a route ran into is answered before them, which rejecting with the error
+    /// as is wouldn't do
+    pub fn into_rejection(self) -> Rejection {
+        match self {
+            ApiError::MethodNotAllowed { .. } => warp::reject::custom(NotAllowed(self)),
+            ApiError::NotFound { .. } => warp::reject::custom(Missing(self)),
+            e => warp::reject::custom(e),
+        }
+    }
+}
+
+/// A short description, for reports listing what went wrong with each of several things. Internal
+/// errors aren't described
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NotFound { what } => write!(f, "no such {}", what),
+            ApiError::Gone { what } => write!(f, "the {} was deleted", what),
+            ApiError::Blocked { reason } => write!(f, "the package is blocked: {}", reason),
+            ApiError::BadRequest { reason } | ApiError::Unprocessable { reason } => {
+                f.write_str(reason)
+            }
+            ApiError::Invalid { field, message } => write!(f, "{}: {}", field, message),
+            ApiError::Unauthorized => f.write_str("unauthorized"),
+            ApiError::Forbidden => f.write_str("forbidden"),
+            ApiError::TooManyRequests { .. } => f.write_str("too many requests"),
+            ApiError::UploadLimited { count, limit, .. } => write!(
+                f,
+                "{} versions of the package were published in the last hour, the limit is {}",
+                count, limit
+            ),
+            ApiError::ServiceUnavailable { .. } => f.write_str("the database is busy"),
+            ApiError::Conflict { detail } => f.write_str(detail),
+            ApiError::VersionConflict {
+                in_progress: true, ..
+            } => f.write_str("the version is being published"),
+            ApiError::VersionConflict { version, .. } => {
+                write!(f, "{} was already published with another file", version)
+            }
+            ApiError::ConfirmationRequired { .. } => f.write_str("the delete must be confirmed"),
+            ApiError::PreconditionFailed { .. } => f.write_str("the hash doesn't match"),
+            ApiError::ResyncRequired { earliest } => write!(
+                f,
+                "changes before {} were pruned, fetch /index.json to sync everything again",
+                earliest
+            ),
+            ApiError::Renamed { to } => write!(f, "the package was renamed to {}", to),
+            ApiError::ReadOnly => f.write_str("the index is read-only"),
+            ApiError::StorageTimeout => f.write_str("storage took too long to answer"),
+            ApiError::InsufficientStorage { quota, limit } => {
+                write!(f, "the {} quota of {} bytes is used up", quota, limit)
+            }
+            ApiError::PayloadTooLarge { limit } => write!(f, "larger than {} bytes", limit),
+            ApiError::MethodNotAllowed { .. } => f.write_str("method not allowed"),
+            ApiError::UnsupportedEncoding { encoding } => {
+                write!(f, "bodies encoded with {} aren't supported", encoding)
+            }
+            ApiError::Internal(_) => f.write_str("internal error"),
+        }
+    }
+}
+
+/// How long clients are told to wait when the database is busy
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Database errors that are the client's to deal with rather than ours
+fn classify_db(e: &sqlx::Error) -> Option<ApiError> {
+    if crate::db::is_busy(e) {
+        tracing::warn!("{}", e);
+        Some(ApiError::ServiceUnavailable {
+            retry_after: BUSY_RETRY_AFTER,
+        })
+    } else if crate::db::is_constraint_violation(e) {
+        tracing::info!("{}", e);
+        Some(ApiError::Conflict {
+            detail: "already exists".to_owned(),
+        })
+    } else {
+        None
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        classify_db(&e).unwrap_or_else(|| ApiError::Internal(e.into()))
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            tracing::info!("{}", e);
+            ApiError::NotFound { what: "file" }
+        } else if e.kind() == io::ErrorKind::InvalidFilename {
+            tracing::info!("{}", e);
+            ApiError::Unprocessable {
+                reason: e.to_string(),
+            }
+        } else if e.kind() == io::ErrorKind::TimedOut {
+            tracing::warn!("{}", e);
+            ApiError::StorageTimeout
+        } else {
+            ApiError::Internal(e.into())
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
 
 pub trait TryExt<T> {
-    fn or_ise(self) -> Result<T, Rejection>;
-    fn or_nf(self) -> Result<T, Rejection>;
+    fn or_ise(self) -> Result<T, ApiError>;
+    fn or_nf(self, what: &'static str) -> Result<T, ApiError>;
 }
 
-impl<T, E: Display> TryExt<T> for Result<T, E> {
-    fn or_ise(self) -> Result<T, Rejection> {
+impl<T, E: Display + 'static> TryExt<T> for Result<T, E> {
+    fn or_ise(self) -> Result<T, ApiError> {
         self.map_err(|e| {
-            tracing::error!("{}", e);
-            warp::reject::custom(InternalServerError)
+            (&e as &dyn Any)
+                .downcast_ref::<sqlx::Error>()
+                .and_then(classify_db)
+                .unwrap_or_else(|| ApiError::Internal(anyhow::anyhow!("{}", e)))
         })
     }
 
-    fn or_nf(self) -> Result<T, Rejection> {
+    fn or_nf(self, what: &'static str) -> Result<T, ApiError> {
         self.map_err(|e| {
             tracing::info!("{}", e);
-            warp::reject::custom(NotFound)
+            ApiError::NotFound { what }
         })
     }
 }
 
 impl<T> TryExt<T> for Option<T> {
-    fn or_ise(self) -> Result<T, Rejection> {
-        self.ok_or_else(|| warp::reject::custom(InternalServerError))
+    fn or_ise(self) -> Result<T, ApiError> {
+        self.ok_or_else(|| ApiError::Internal(anyhow::anyhow!("missing value")))
     }
 
-    fn or_nf(self) -> Result<T, Rejection> {
-        self.ok_or_else(|| warp::reject::custom(NotFound))
+    fn or_nf(self, what: &'static str) -> Result<T, ApiError> {
+        self.ok_or_else(|| ApiError::NotFound { what })
     }
 }
 
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
-    if err.is_not_found() || err.find::<NotFound>().is_some() {
-        Ok(warp::reply::with_status("Not Found", StatusCode::NOT_FOUND))
-    } else if err.find::<InternalServerError>().is_some() {
-        Ok(warp::reply::with_status(
-            "Internal Server Error",
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    } else if err.find::<Unauthorized>().is_some() {
-        Ok(warp::reply::with_status(
-            "Unauthorized",
-            StatusCode::UNAUTHORIZED,
-        ))
-    } else {
-        Err(err)
+/// Rounds up so clients never retry too early
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+impl ApiError {
+    pub fn to_response(&self) -> Response {
+        let (status, body) = match self {
+            ApiError::NotFound { what } => (
+                StatusCode::NOT_FOUND,
+                serde_json::json!({"error": "not_found", "resource": what}),
+            ),
+            ApiError::Gone { what } => (
+                StatusCode::GONE,
+                serde_json::json!({"error": "gone", "resource": what}),
+            ),
+            ApiError::Blocked { reason } => (
+                StatusCode::GONE,
+                serde_json::json!({"error": "gone", "resource": "package", "reason": reason}),
+            ),
+            ApiError::BadRequest { reason } => (
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "bad_request", "reason": reason}),
+            ),
+            ApiError::Invalid { field, message } => (
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "bad_request", "parameter": field, "reason": message}),
+            ),
+            ApiError::Unprocessable { reason } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                serde_json::json!({"error": "unprocessable", "reason": reason}),
+            ),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                serde_json::json!({"error": "unauthorized"}),
+            ),
+            ApiError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                serde_json::json!({"error": "forbidden"}),
+            ),
+            ApiError::TooManyRequests { retry_after } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::json!({
+                    "error": "Too Many Requests",
+                    "retry_after": retry_after_secs(*retry_after),
+                }),
+            ),
+            ApiError::UploadLimited {
+                count,
+                limit,
+                retry_after,
+            } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::json!({
+                    "error": "upload_limited",
+                    "count": count,
+                    "limit": limit,
+                    "retry_after": retry_after_secs(*retry_after),
+                }),
+            ),
+            ApiError::ServiceUnavailable { retry_after } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({
+                    "error": "Service Unavailable",
+                    "retry_after": retry_after_secs(*retry_after),
+                }),
+            ),
+            ApiError::Conflict { detail } => (
+                StatusCode::CONFLICT,
+                serde_json::json!({"error": "conflict", "detail": detail}),
+            ),
+            ApiError::VersionConflict {
+                version,
+                sha256,
+                published_at,
+                file_present,
+                hash_matches,
+                in_progress,
+            } => (
+                StatusCode::CONFLICT,
+                serde_json::json!({
+                    "error": "conflict",
+                    "version": version,
+                    "sha256": sha256,
+                    "published_at": published_at.map(|at| DateTime::from_unix(at).rfc3339()),
+                    "file_present": file_present,
+                    "hash_matches": hash_matches,
+                    "in_progress": in_progress,
+                }),
+            ),
+            ApiError::ConfirmationRequired { token, expires_in } => (
+                StatusCode::CONFLICT,
+                serde_json::json!({
+                    "error": "confirmation_required",
+                    "token": token,
+                    "expires_in": expires_in.as_secs(),
+                }),
+            ),
+            ApiError::PreconditionFailed { sha256 } => (
+                StatusCode::PRECONDITION_FAILED,
+                serde_json::json!({"error": "precondition_failed", "sha256": sha256}),
+            ),
+            ApiError::ResyncRequired { earliest } => (
+                StatusCode::GONE,
+                serde_json::json!({
+                    "error": "resync_required",
+                    "earliest": earliest,
+                    "resync": "/index.json",
+                }),
+            ),
+            ApiError::Renamed { to } => (
+                StatusCode::CONFLICT,
+                serde_json::json!({"error": "renamed", "to": to}),
+            ),
+            ApiError::ReadOnly => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({"error": "read_only"}),
+            ),
+            ApiError::StorageTimeout => (
+                StatusCode::GATEWAY_TIMEOUT,
+                serde_json::json!({"error": "storage_timeout"}),
+            ),
+            ApiError::InsufficientStorage { quota, limit } => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                serde_json::json!({"error": "insufficient_storage", "quota": quota, "limit": limit}),
+            ),
+            ApiError::PayloadTooLarge { limit } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                serde_json::json!({"error": "payload_too_large", "limit": limit}),
+            ),
+            ApiError::MethodNotAllowed { allow } => (
+                StatusCode::METHOD_NOT_ALLOWED,
+                serde_json::json!({
+                    "error": "method_not_allowed",
+                    "allow": allow.iter().map(Method::as_str).collect::<Vec<_>>(),
+                }),
+            ),
+            ApiError::UnsupportedEncoding { encoding } => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                serde_json::json!({"error": "unsupported_encoding", "encoding": encoding}),
+            ),
+            ApiError::Internal(e) => {
+                tracing::error!("{:#}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    serde_json::json!({"error": "internal"}),
+                )
+            }
+        };
+
+        let mut response =
+            warp::reply::with_status(warp::reply::json(&body), status).into_response();
+        let headers = response.headers_mut();
+        match self {
+            ApiError::TooManyRequests { retry_after }
+            | ApiError::UploadLimited { retry_after, .. }
+            | ApiError::ServiceUnavailable { retry_after } => {
+                headers.insert(RETRY_AFTER, retry_after_secs(*retry_after).into());
+            }
+            ApiError::MethodNotAllowed { allow } => {
+                let allow: Vec<_> = allow.iter().map(Method::as_str).collect();
+                if let Ok(allow) = HeaderValue::from_str(&allow.join(", ")) {
+                    headers.insert(ALLOW, allow);
+                }
+            }
+            _ => {}
+        }
+        response
     }
 }
+
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    let route = ApiError::NotFound { what: "route" };
+    let error = if err.is_not_found() {
+        &route
+    } else if let Some(error) = err.find::<ApiError>() {
+        error
+    } else if let Some(NotAllowed(error)) = err.find() {
+        // Takes precedence over not found since a route matching the path may have looked the
+        // path up as a package before the method was checked
+        error
+    } else if let Some(Missing(error)) = err.find() {
+        error
+    } else {
+        return Err(err);
+    };
+    Ok(error.to_response())
+}
@@ -0,0 +1,65 @@
+use std::{fmt::Write, time::SystemTime};
+
+use crate::{datetime::DateTime, db::Release, http_client::uri_encode};
+
+/// Renders recent uploads as an Atom feed, of a single package if given. Links are relative to
+/// `base`, which feed readers need to be a full URL
+pub fn atom(releases: &[Release], package: Option<&str>, base: &str) -> String {
+    let (id, title, link) = match package {
+        Some(package) => (
+            format!("urn:bs-quest-index:feed:{}", uri_encode(package)),
+            format!("{} uploads", package),
+            format!("{}/feed.atom?package={}", base, uri_encode(package)),
+        ),
+        None => (
+            "urn:bs-quest-index:feed".to_owned(),
+            "Uploads".to_owned(),
+            format!("{}/feed.atom", base),
+        ),
+    };
+    // The feed changed when its latest entry was added
+    let updated = match releases.first() {
+        Some(release) => DateTime::from_unix(release.created_at),
+        None => DateTime::from_system_time(SystemTime::now()),
+    };
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(xml, "  <id>{}</id>", escape(&id));
+    let _ = writeln!(xml, "  <title>{}</title>", escape(&title));
+    let _ = writeln!(xml, "  <updated>{}</updated>", updated.rfc3339());
+    xml.push_str("  <author><name>bs-quest-index</name></author>\n");
+    let _ = writeln!(xml, "  <link rel=\"self\" href=\"{}\"/>", escape(&link));
+
+    for release in releases {
+        let name = format!("{} {}", release.id, release.version);
+        let id = format!(
+            "urn:bs-quest-index:{}:{}",
+            uri_encode(&release.id),
+            release.version
+        );
+        let href = format!("{}/{}/{}", base, uri_encode(&release.id), release.version);
+
+        xml.push_str("  <entry>\n");
+        let _ = writeln!(xml, "    <id>{}</id>", escape(&id));
+        let _ = writeln!(xml, "    <title>{}</title>", escape(&name));
+        let _ = writeln!(
+            xml,
+            "    <updated>{}</updated>",
+            DateTime::from_unix(release.created_at).rfc3339()
+        );
+        let _ = writeln!(xml, "    <link href=\"{}\"/>", escape(&href));
+        xml.push_str("  </entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
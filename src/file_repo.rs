@@ -1,58 +1,467 @@
-use std::{collections::HashMap, io::Result, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::{self, ErrorKind, Result},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
+use async_trait::async_trait;
 use bytes::Bytes;
 use semver::Version;
-use tokio::{fs, sync::RwLock};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{OwnedMutexGuard, RwLock},
+    task::JoinHandle,
+};
+use tracing::Span;
 
+use crate::{
+    compression::{compress_stored, decompress_stored},
+    db::Mod,
+    storage::{MAX_NAME_LEN, Purged, Scan, Storage, file_key},
+};
+
+/// Most files remembered as missing at once
+const MAX_MISSING: usize = 1024;
+/// Largest file preloaded into the cache when warming it
+const WARM_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Discrepancies between the database and the files in storage
+#[derive(Debug, Default)]
+pub struct WarmReport {
+    /// Mods with no file in storage
+    pub missing: Vec<Mod>,
+    /// Keys in storage with no mod
+    pub orphaned: Vec<String>,
+}
+
+type FileLock = Arc<tokio::sync::Mutex<()>>;
+
+/// What's in the file cache, and how it's been used since the process started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: u64,
+    /// Size of the cached files, decompressed
+    pub bytes: u64,
+    /// Reads answered from the cache, and ones that went to storage
+    pub hits: u64,
+    pub misses: u64,
+    /// Files dropped from the cache because they expired
+    pub evictions: u64,
+}
+
+/// A cached file, and when it was read
+struct Cached {
+    contents: Bytes,
+    since: Instant,
+}
+
+impl Cached {
+    fn new(contents: Bytes) -> Self {
+        Self {
+            contents,
+            since: Instant::now(),
+        }
+    }
+}
+
+/// In-memory cache in front of a storage backend
 pub struct FileRepo {
-    path: PathBuf,
+    storage: Box<dyn Storage>,
     // TODO: Synchronize
-    cache: RwLock<HashMap<(String, Version), String>>,
+    cache: RwLock<HashMap<(String, Version), Cached>>,
+    /// How long files stay cached, forever if `None`
+    cache_ttl: Option<Duration>,
+    /// How long reading a file from storage may take, so a stuck backend fails requests rather
+    /// than holding them, and the cache, forever
+    read_timeout: Duration,
+    /// Files recently found to be missing, and when
+    missing: RwLock<HashMap<(String, Version), Instant>>,
+    missing_ttl: Duration,
+    /// Held while a file is written or deleted, so changes to the same file don't interleave
+    writing: Mutex<HashMap<(String, Version), FileLock>>,
+    /// Reads answered from the cache, and ones that went to storage
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    /// Whether written files are cached right away, rather than once they're first read
+    cache_on_upload: bool,
+    /// Whether files are gzipped before they're written. Compressed files are read back either way
+    compress: bool,
 }
 
 impl FileRepo {
-    pub fn new(path: PathBuf) -> FileRepo {
+    pub fn new(
+        storage: Box<dyn Storage>,
+        missing_ttl: Duration,
+        cache_ttl: Option<Duration>,
+        read_timeout: Duration,
+        cache_on_upload: bool,
+        compress: bool,
+    ) -> FileRepo {
         FileRepo {
-            path,
+            storage,
             cache: Default::default(),
+            cache_ttl,
+            read_timeout,
+            missing: Default::default(),
+            missing_ttl,
+            writing: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            evictions: Default::default(),
+            cache_on_upload,
+            compress,
+        }
+    }
+
+    /// Longest package id files can be stored under, since the id is a directory name in local
+    /// storage
+    pub const fn max_id_len() -> usize {
+        MAX_NAME_LEN
+    }
+
+    pub async fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.read().await;
+        CacheStats {
+            entries: cache.len() as u64,
+            bytes: cache.values().map(|c| c.contents.len() as u64).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Waits for whoever is changing the file to be done, then holds it until the guard is
+    /// dropped
+    async fn lock(&self, key: &(String, Version)) -> OwnedMutexGuard<()> {
+        let lock = self
+            .writing
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Forgets about the lock of a file once nobody else holds or waits on it
+    fn unlock(&self, key: &(String, Version), guard: OwnedMutexGuard<()>) {
+        let mut writing = self.writing.lock().unwrap();
+        drop(guard);
+        if writing
+            .get(key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            writing.remove(key);
         }
     }
 
-    pub async fn get_file(&self, id: String, ver: Version) -> Result<String> {
-        if let Some(o) = self.cache.read().await.get(&(id.clone(), ver.clone())) {
-            return Ok(o.clone());
+    fn is_fresh(&self, cached: &Cached) -> bool {
+        self.cache_ttl
+            .is_none_or(|ttl| cached.since.elapsed() < ttl)
+    }
+
+    /// Drops expired files and misses, returning how many files were dropped
+    pub async fn sweep(&self) -> usize {
+        self.missing
+            .write()
+            .await
+            .retain(|_, since| since.elapsed() < self.missing_ttl);
+
+        let mut cache = self.cache.write().await;
+        let before = cache.len();
+        cache.retain(|_, cached| self.is_fresh(cached));
+        let dropped = before - cache.len();
+        self.evictions.fetch_add(dropped as u64, Ordering::Relaxed);
+        dropped
+    }
+
+    /// Sweeps every minute, if files expire at all
+    pub fn spawn_sweeper(&'static self) -> Option<JoinHandle<()>> {
+        self.cache_ttl?;
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let dropped = self.sweep().await;
+                if dropped > 0 {
+                    tracing::debug!("dropped {} expired files from the cache", dropped);
+                }
+            }
+        }))
+    }
+
+    /// Preloads the files of the given mods into the cache, and reports the ones that are missing
+    /// along with files no mod refers to
+    pub async fn warm(&self, mods: &[Mod]) -> Result<WarmReport> {
+        let scan = self.storage.scan().await?;
+        let sizes: HashMap<_, _> = scan
+            .files
+            .iter()
+            .map(|f| ((&f.id, &f.version), f.size))
+            .collect();
+        let expected: HashSet<_> = mods.iter().map(|m| (&m.id, &m.version)).collect();
+
+        let mut report = WarmReport {
+            orphaned: scan.skipped.clone(),
+            ..Default::default()
+        };
+        for f in &scan.files {
+            if !expected.contains(&(&f.id, &f.version)) {
+                report.orphaned.push(file_key(&f.id, &f.version));
+            }
+        }
+
+        let mut cache = self.cache.write().await;
+        for m in mods {
+            let Some(&size) = sizes.get(&(&m.id, &m.version)) else {
+                report.missing.push(m.clone());
+                continue;
+            };
+
+            if size <= WARM_MAX_SIZE {
+                let contents = decompress_stored(self.storage.get_file(&m.id, &m.version).await?)?;
+                cache.insert((m.id.clone(), m.version.clone()), Cached::new(contents));
+            }
+        }
+
+        for m in &report.missing {
+            tracing::warn!("{} {} has no file in storage", m.id, m.version);
+        }
+        for key in &report.orphaned {
+            tracing::warn!("{} doesn't belong to any mod", key);
+        }
+        Ok(report)
+    }
+
+    /// Stores a file identical to the one of `from` by linking to it, writing it if the storage
+    /// can't link files. Deleting either version later leaves the other's file in place
+    pub async fn link_or_write(
+        &self,
+        from: &Mod,
+        id: &str,
+        ver: &Version,
+        contents: Bytes,
+    ) -> Result<()> {
+        let key = (id.to_owned(), ver.clone());
+        let guard = self.lock(&key).await;
+        let linked = self
+            .storage
+            .link_file(&from.id, &from.version, id, ver)
+            .await;
+        self.unlock(&key, guard);
+
+        match linked {
+            Ok(()) => {
+                self.missing.write().await.remove(&key);
+                let mut cache = self.cache.write().await;
+                match self.cache_on_upload {
+                    true => cache.insert(key, Cached::new(contents)),
+                    false => cache.remove(&key),
+                };
+                Ok(())
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "couldn't link {} {} to {} {}, writing it instead: {}",
+                    id,
+                    ver,
+                    from.id,
+                    from.version,
+                    e
+                );
+                self.write_file(id, ver, contents).await
+            }
+        }
+    }
+
+    /// Reads a file like [`Storage::get_file`] but without caching it, for reads of every file
+    /// like exports that would otherwise pull the whole index into memory
+    pub async fn get_uncached(&self, id: &str, ver: &Version) -> Result<Bytes> {
+        let key = (id.to_owned(), ver.clone());
+        if let Some(cached) = self.cache.read().await.get(&key)
+            && self.is_fresh(cached)
+        {
+            return Ok(cached.contents.clone());
+        }
+        decompress_stored(self.read(self.storage.get_file(id, ver)).await?)
+    }
+
+    /// Fails with [`ErrorKind::TimedOut`] if storage takes longer than the read timeout
+    async fn read<T>(&self, read: impl Future<Output = Result<T>>) -> Result<T> {
+        tokio::time::timeout(self.read_timeout, read)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("storage took over {:?} to read", self.read_timeout),
+                ))
+            })
+    }
+
+    async fn remember_missing(&self, key: (String, Version)) {
+        let mut missing = self.missing.write().await;
+        if missing.len() >= MAX_MISSING {
+            missing.retain(|_, since| since.elapsed() < self.missing_ttl);
+        }
+        if missing.len() >= MAX_MISSING {
+            let oldest = missing
+                .iter()
+                .min_by_key(|(_, since)| **since)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                missing.remove(&oldest);
+            }
+        }
+        missing.insert(key, Instant::now());
+    }
+}
+
+#[async_trait]
+impl Storage for FileRepo {
+    async fn get_file(&self, id: &str, ver: &Version) -> Result<Bytes> {
+        let key = (id.to_owned(), ver.clone());
+        if let Some(cached) = self.cache.read().await.get(&key)
+            && self.is_fresh(cached)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            // Only spans with a `cache` field, like downloads', keep it
+            Span::current().record("cache", "hit");
+            return Ok(cached.contents.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Span::current().record("cache", "miss");
+        if let Some(since) = self.missing.read().await.get(&key)
+            && since.elapsed() < self.missing_ttl
+        {
+            return Err(ErrorKind::NotFound.into());
         }
 
         // lock to ensure no other thread is reading
         let mut cache = self.cache.write().await;
 
-        let contents: String = fs::read_to_string(
-            self.path
-                .join(&id)
-                .join(format!("{}/{}/{}", &ver.major, &ver.minor, &ver.patch)),
-        )
-        .await?;
+        let contents = match self.read(self.storage.get_file(id, ver)).await {
+            Ok(contents) => decompress_stored(contents)?,
+            Err(e) => {
+                if e.kind() == ErrorKind::NotFound {
+                    self.remember_missing(key).await;
+                }
+                return Err(e);
+            }
+        };
 
-        cache.insert((id.clone(), ver.clone()), contents.clone());
+        cache.insert(key, Cached::new(contents.clone()));
         Ok(contents)
     }
 
-    pub async fn write_file(&self, id: String, ver: Version, contents: Bytes) -> Result<()> {
-        self.cache.write().await.insert(
-            (id.clone(), ver.clone()),
-            String::from_utf8_lossy(&contents).into(),
-        );
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> Result<()> {
+        let key = (id.to_owned(), ver.clone());
+        let guard = self.lock(&key).await;
+
+        let stored = match self.compress {
+            true => compress_stored(&contents)
+                .map(|compressed| compressed.map_or_else(|| contents.clone(), Bytes::from)),
+            false => Ok(contents.clone()),
+        };
+        let result = match stored {
+            Ok(stored) => self.storage.write_file(id, ver, stored).await,
+            Err(e) => Err(e),
+        };
+        self.missing.write().await.remove(&key);
+        // Whatever was cached is stale now, and uploads are mostly not downloaded soon after
+        let mut cache = self.cache.write().await;
+        match result {
+            Ok(()) if self.cache_on_upload => cache.insert(key.clone(), Cached::new(contents)),
+            _ => cache.remove(&key),
+        };
+        drop(cache);
+
+        self.unlock(&key, guard);
+        result
+    }
+
+    async fn delete_file(&self, id: &str, ver: &Version) -> Result<()> {
+        let key = (id.to_owned(), ver.clone());
+        let guard = self.lock(&key).await;
 
-        let dir = self
-            .path
-            .join(id)
-            .join(format!("{}/{}", ver.major, ver.minor));
-        fs::create_dir_all(&dir).await?;
+        self.cache.write().await.remove(&key);
+        let result = self.storage.delete_file(id, ver).await;
 
-        let file = dir.join(ver.patch.to_string());
-        fs::write(file, contents).await?;
+        self.unlock(&key, guard);
+        result
+    }
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> Result<bool> {
+        // Whoever asks wants to know about the storage, which the cache may not agree with
+        self.storage.file_exists(id, ver).await
+    }
+
+    async fn scan(&self) -> Result<Scan> {
+        self.storage.scan().await
+    }
+
+    async fn sha256(&self, id: &str, ver: &Version) -> Result<String> {
+        // Hashes were recorded before compressing, so compressed files are hashed decompressed
+        let contents = decompress_stored(self.storage.get_file(id, ver).await?)?;
+        Ok(hex::encode(Sha256::digest(contents)))
+    }
+
+    async fn stored_size(&self, id: &str, ver: &Version) -> Result<u64> {
+        self.storage.stored_size(id, ver).await
+    }
+
+    // Artifacts are rarely downloaded, so they aren't cached
+    async fn get_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<Bytes> {
+        self.read(self.storage.get_artifact(id, ver, name)).await
+    }
+
+    async fn write_artifact(
+        &self,
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: Bytes,
+    ) -> Result<()> {
+        self.storage.write_artifact(id, ver, name, contents).await
+    }
+
+    async fn delete_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<()> {
+        self.storage.delete_artifact(id, ver, name).await
+    }
+
+    async fn quarantine(&self, id: &str, ver: &Version) -> Result<()> {
+        let result = self.storage.quarantine(id, ver).await;
+        self.forget(id, ver).await;
+        result
+    }
+
+    async fn forget(&self, id: &str, ver: &Version) {
+        let key = (id.to_owned(), ver.clone());
+        self.missing.write().await.remove(&key);
+        self.cache.write().await.remove(&key);
+    }
+
+    async fn purge(&self, id: Option<&str>) -> Purged {
+        let mut missing = self.missing.write().await;
+        let mut cache = self.cache.write().await;
+        let mut purged = Purged::default();
 
-        Ok(())
+        let matches = |(cached_id, _): &(String, Version)| id.is_none_or(|id| cached_id == id);
+        missing.retain(|key, _| !matches(key));
+        cache.retain(|key, cached| {
+            if !matches(key) {
+                return true;
+            }
+            purged.entries += 1;
+            purged.bytes += cached.contents.len() as u64;
+            false
+        });
+        purged
     }
 }
 
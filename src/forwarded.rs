@@ -0,0 +1,163 @@
+use std::{convert::Infallible, net::IpAddr, sync::Arc};
+
+use warp::{
+    Filter,
+    http::{HeaderMap, header::HOST},
+};
+
+use crate::{config::Config, ratelimit::ClientAddr};
+
+/// A range of addresses like `10.0.0.0/8`, a bare address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{} isn't an IP address", addr))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(|| {
+                    format!("{} isn't a prefix length between 0 and {}", prefix, bits)
+                })?,
+            None => bits,
+        };
+        Ok(Cidr {
+            addr: addr.to_canonical(),
+            prefix,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        // A /0 shifts every bit out, which `checked_shr` refuses for IPv6
+        let shift = u32::from(bits - self.prefix);
+        net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+/// Who a request came from and how they reached the index. The forwarding headers are only
+/// believed when the peer is a trusted proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Unknown if the request didn't come through the server, like in tests
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`, from `X-Forwarded-Proto`
+    pub scheme: Option<String>,
+    /// From `X-Forwarded-Host`, or the `Host` the proxy passed on
+    pub host: Option<String>,
+}
+
+impl ClientInfo {
+    /// `trust_peer` trusts whatever peer the request came from, but not the hops it reports
+    fn new(
+        peer: Option<IpAddr>,
+        headers: &HeaderMap,
+        trust_peer: bool,
+        trusted: impl Fn(IpAddr) -> bool,
+    ) -> Self {
+        let peer = peer.map(|ip| ip.to_canonical());
+        let Some(mut ip) = peer.filter(|&ip| trust_peer || trusted(ip)) else {
+            return ClientInfo {
+                ip: peer,
+                ..Default::default()
+            };
+        };
+
+        // Each proxy appends the address it got the request from, so walking back from the
+        // right the first one that isn't a trusted proxy is the client. Anything left of it could
+        // have been made up by the client
+        let hops = values(headers, "X-Forwarded-For").collect::<Vec<_>>();
+        for hop in hops.into_iter().rev() {
+            let Ok(hop) = hop.parse::<IpAddr>() else {
+                break;
+            };
+            ip = hop.to_canonical();
+            if !trusted(ip) {
+                break;
+            }
+        }
+
+        let scheme = values(headers, "X-Forwarded-Proto")
+            .next()
+            .map(str::to_ascii_lowercase)
+            .filter(|scheme| scheme == "http" || scheme == "https");
+        let host = values(headers, "X-Forwarded-Host")
+            .next()
+            .or_else(|| headers.get(HOST)?.to_str().ok())
+            .filter(|host| is_valid_host(host))
+            .map(str::to_owned);
+        ClientInfo {
+            ip: Some(ip),
+            scheme,
+            host,
+        }
+    }
+
+    /// Scheme and host the client used, if a trusted proxy told both
+    pub fn base_url(&self) -> Option<String> {
+        Some(format!(
+            "{}://{}",
+            self.scheme.as_ref()?,
+            self.host.as_ref()?
+        ))
+    }
+}
+
+/// Comma separated values of every `name` header, in order
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Hosts end up in URLs, so only names, addresses and ports are let through
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'))
+}
+
+/// Derives the [`ClientInfo`] of a request, following the forwarding headers of trusted proxies
+pub fn client_info(
+    config: &'static Config,
+) -> impl Filter<Extract = (ClientInfo,), Error = Infallible> + Send + Sync + Clone + 'static {
+    // Validated at startup, so nothing is skipped here
+    let proxies: Arc<[Cidr]> = config
+        .trusted_proxies
+        .iter()
+        .filter_map(|cidr| Cidr::parse(cidr).ok())
+        .collect();
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::header::headers_cloned())
+        .map(move |addr: Option<ClientAddr>, headers: HeaderMap| {
+            let trusted = |ip| proxies.iter().any(|cidr: &Cidr| cidr.contains(ip));
+            ClientInfo::new(
+                addr.map(|a| a.0.ip()),
+                &headers,
+                config.trust_forwarded_for,
+                trusted,
+            )
+        })
+}
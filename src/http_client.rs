@@ -0,0 +1,236 @@
+use std::io::{Error, ErrorKind, Result};
+
+use bytes::Bytes;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpStream,
+};
+use warp::http::{Method, StatusCode, Uri};
+
+/// Base URL of a server we make requests to
+#[derive(Debug)]
+pub struct Endpoint {
+    https: bool,
+    host: String,
+    port: u16,
+    /// Value of the `Host` header
+    authority: String,
+    /// Path requests are made under, without a trailing slash
+    path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> anyhow::Result<Endpoint> {
+        let uri: Uri = url.parse()?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => anyhow::bail!("{} isn't an http or https URL", url),
+        };
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow::anyhow!("{} has no host", url))?;
+
+        Ok(Endpoint {
+            https,
+            host: authority.host().to_owned(),
+            port: authority.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            authority: authority.to_string(),
+            path: uri.path().trim_end_matches('/').to_owned(),
+        })
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Sends a request over a fresh connection, `uri` is the full path and query. The `host`,
+    /// `content-length` and `connection` headers are added
+    pub async fn send(
+        &self,
+        method: &Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<(StatusCode, Bytes)> {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nhost: {}\r\ncontent-length: {}\r\nconnection: close\r\n",
+            method,
+            uri,
+            self.authority,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let head_only = method == Method::HEAD;
+
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        if self.https {
+            let connector = native_tls::TlsConnector::new().map_err(Error::other)?;
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(&self.host, stream)
+                .await
+                .map_err(Error::other)?;
+            exchange(stream, &head, body, head_only).await
+        } else {
+            exchange(stream, &head, body, head_only).await
+        }
+    }
+}
+
+/// Most bytes a response's status line and headers can take up
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+/// Most bytes a response body can be, double the largest upload the index takes by default so any
+/// stored file fits
+pub const MAX_BODY_BYTES: usize = 256 * 1024 * 1024;
+
+/// Sends a single request over a fresh connection and reads the whole response
+async fn exchange<S>(
+    stream: S,
+    head: &str,
+    body: &[u8],
+    head_only: bool,
+) -> Result<(StatusCode, Bytes)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut response = Vec::new();
+    loop {
+        let line = read_line(&mut stream, MAX_HEAD_BYTES - response.len()).await?;
+        response.extend_from_slice(&line);
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    if parsed.parse(&response).map_err(Error::other)?.is_partial() {
+        return Err(Error::other("malformed response head"));
+    }
+    let status = StatusCode::from_u16(parsed.code.unwrap_or_default()).map_err(Error::other)?;
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+
+    // The server closes the connection after responding, but some don't bother telling TLS
+    // about it so we stop reading as soon as the body is complete if we know its length
+    let body = if head_only {
+        Vec::new()
+    } else if header("transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        read_chunked(&mut stream).await?
+    } else if let Some(len) = header("content-length").and_then(|len| len.parse().ok()) {
+        if len > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.map_err(truncated)?;
+        body
+    } else {
+        let mut body = Vec::new();
+        (&mut stream)
+            .take(MAX_BODY_BYTES as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        body
+    };
+    Ok((status, body.into()))
+}
+
+/// Reads a line up to and including its `\n`, failing if it's longer than `max` bytes
+async fn read_line<R>(reader: &mut R, max: usize) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    reader.take(max as u64).read_until(b'\n', &mut line).await?;
+    if line.last() == Some(&b'\n') {
+        Ok(line)
+    } else if line.len() >= max {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "response head is too long",
+        ))
+    } else {
+        Err(Error::new(ErrorKind::UnexpectedEof, "truncated response"))
+    }
+}
+
+/// Decodes a chunked body as it's read, each chunk only once
+async fn read_chunked<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut decoded = Vec::new();
+    loop {
+        let line = read_line(reader, MAX_HEAD_BYTES).await?;
+        let size = std::str::from_utf8(&line)
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| Error::other("malformed chunk in response"))?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if size > MAX_BODY_BYTES - decoded.len() {
+            return Err(too_large());
+        }
+
+        let start = decoded.len();
+        decoded.resize(start + size, 0);
+        reader
+            .read_exact(&mut decoded[start..])
+            .await
+            .map_err(truncated)?;
+        // Skip the line break ending the chunk
+        reader.read_exact(&mut [0; 2]).await.map_err(truncated)?;
+    }
+}
+
+fn too_large() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("response body is larger than {} bytes", MAX_BODY_BYTES),
+    )
+}
+
+fn truncated(e: Error) -> Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        Error::new(ErrorKind::UnexpectedEof, "truncated response")
+    } else {
+        e
+    }
+}
+
+/// Percent-encodes everything but unreserved characters
+pub fn uri_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
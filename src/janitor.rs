@@ -0,0 +1,224 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use sqlx::AnyPool;
+use tokio::{fs, sync::Mutex, task::JoinHandle};
+
+use crate::{
+    config::{Config, GcConfig},
+    db::{Change, Mod, unix_now},
+    storage::{key_path, parse_artifact_key, parse_key},
+    types::PublishKey,
+    write_behind::WriteBehind,
+};
+
+/// Directory under the downloads path orphaned files are moved to
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// What a tidy-up did, paths are relative to the downloads path
+#[derive(Debug, Default, Serialize)]
+pub struct GcSummary {
+    pub removed_dirs: Vec<String>,
+    /// Files with no version, moved under `quarantine/`
+    pub quarantined: Vec<String>,
+    /// Files with no version, deleted outright
+    pub deleted: Vec<String>,
+    /// Left alone since they changed too recently, they could belong to an upload in progress
+    pub skipped_recent: Vec<String>,
+}
+
+/// Forgets hourly download counts and changes past their retention every hour, the first time
+/// being right away
+pub fn spawn_history_pruner(config: &'static Config, pool: &'static AnyPool) -> JoinHandle<()> {
+    let hours = |days: u64| days.saturating_mul(24).try_into().unwrap_or(i64::MAX);
+    let download_hours = hours(config.download_history_days);
+    let change_hours = hours(config.change_retention_days);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            let hour = unix_now() / 3600;
+            match Mod::prune_download_hours(hour.saturating_sub(download_hours), pool).await {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!("pruned {} hourly download counts", pruned),
+                Err(e) => tracing::error!("couldn't prune hourly download counts: {}", e),
+            }
+            let before = hour.saturating_sub(change_hours).saturating_mul(3600);
+            match Change::prune(before, pool).await {
+                Ok(0) => {}
+                Ok(pruned) => tracing::info!("pruned {} changes", pruned),
+                Err(e) => tracing::error!("couldn't prune changes: {}", e),
+            }
+        }
+    })
+}
+
+/// Disables publish keys unused for `key_expiry_days` every hour, the first time being right away.
+/// Does nothing if it's unset
+pub fn spawn_key_expiry(
+    config: &'static Config,
+    pool: &'static AnyPool,
+    write_behind: &'static WriteBehind,
+) -> Option<JoinHandle<()>> {
+    let days = config.key_expiry_days?;
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            // Uses still in memory would otherwise be missed
+            write_behind.flush(pool).await;
+            match disable_unused_keys(days, pool).await {
+                Ok(0) => {}
+                Ok(disabled) => tracing::info!("disabled {} unused publish keys", disabled),
+                Err(e) => tracing::error!("couldn't disable unused publish keys: {}", e),
+            }
+        }
+    }))
+}
+
+/// Disables publish keys unused for `days`, returning how many were
+pub async fn disable_unused_keys(days: u64, pool: &AnyPool) -> sqlx::Result<u64> {
+    let secs: i64 = days.saturating_mul(86400).try_into().unwrap_or(i64::MAX);
+    PublishKey::disable_unused(unix_now().saturating_sub(secs), pool).await
+}
+
+/// Tidies up the local downloads directory, one run at a time
+pub struct Janitor {
+    path: PathBuf,
+    config: &'static GcConfig,
+    running: Mutex<()>,
+}
+
+impl Janitor {
+    pub fn new(path: PathBuf, config: &'static GcConfig) -> Self {
+        Self {
+            path,
+            config,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Tidies up every `interval_hours` unless it's 0, the first time being right away
+    pub fn spawn(&'static self, pool: &'static AnyPool) -> Option<JoinHandle<()>> {
+        if self.config.interval_hours == 0 {
+            return None;
+        }
+        let period = Duration::from_secs(self.config.interval_hours * 3600);
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match self.run(pool).await {
+                    Ok(summary) => tracing::info!(
+                        "tidied downloads up, removed {} directories and {} orphaned files",
+                        summary.removed_dirs.len(),
+                        summary.quarantined.len() + summary.deleted.len()
+                    ),
+                    Err(e) => tracing::error!("couldn't tidy downloads up: {:#}", e),
+                }
+            }
+        }))
+    }
+
+    /// Moves away or deletes files no version points to, then removes empty directories.
+    /// Anything modified within `min_age_minutes` is left alone
+    pub async fn run(&self, pool: &AnyPool) -> anyhow::Result<GcSummary> {
+        let _running = self.running.lock().await;
+        let mut summary = GcSummary::default();
+        let cutoff = SystemTime::now() - Duration::from_secs(self.config.min_age_minutes * 60);
+
+        let (files, mut dirs) = match walk(&self.path).await {
+            Ok(found) => found,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(summary),
+            Err(e) => return Err(e.into()),
+        };
+
+        for (path, key) in files {
+            // Damaged files verification moved out of the way are kept for inspection
+            if key.ends_with(".corrupt") || !is_orphan(&key, pool).await? {
+                continue;
+            }
+            if fs::metadata(&path).await?.modified()? > cutoff {
+                summary.skipped_recent.push(key);
+                continue;
+            }
+
+            if self.config.delete_orphans {
+                fs::remove_file(&path).await?;
+                tracing::info!("deleted orphaned file {}", key);
+                summary.deleted.push(key);
+            } else {
+                let target = key_path(&self.path.join(QUARANTINE_DIR), &key);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                fs::rename(&path, &target).await?;
+                tracing::info!("quarantined orphaned file {}", key);
+                summary.quarantined.push(key);
+            }
+        }
+
+        // Deepest first, so directories emptied by removing their children go too. Their times
+        // are from before anything was removed, which updates them
+        dirs.sort_by(|(_, a, _), (_, b, _)| {
+            (b.matches('/').count(), a).cmp(&(a.matches('/').count(), b))
+        });
+        for (path, key, modified) in dirs {
+            if modified > cutoff {
+                continue;
+            }
+            match fs::remove_dir(&path).await {
+                Ok(()) => summary.removed_dirs.push(key),
+                // Not empty
+                Err(_) => continue,
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Whether no version or artifact points to the file at this key
+async fn is_orphan(key: &str, pool: &AnyPool) -> sqlx::Result<bool> {
+    if let Some((id, version, name)) = parse_artifact_key(key) {
+        return Ok(Mod::artifact(&id, &version, &name, pool).await?.is_none());
+    }
+    match parse_key(key) {
+        Some((id, version)) => Ok(Mod::get(&id, &version, pool).await?.is_none()),
+        None => Ok(true),
+    }
+}
+
+type Directory = (PathBuf, String, SystemTime);
+
+/// Files under `root` along with their keys, and directories along with their keys and when they
+/// were last modified. The quarantine is left out
+async fn walk(root: &Path) -> std::io::Result<(Vec<(PathBuf, String)>, Vec<Directory>)> {
+    let (mut files, mut dirs) = (Vec::new(), Vec::new());
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            if prefix.is_empty() && name == QUARANTINE_DIR {
+                continue;
+            }
+            let key = format!("{}{}", prefix, name);
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                pending.push((entry.path(), format!("{}/", key)));
+                let modified = entry.metadata().await?.modified()?;
+                dirs.push((entry.path(), key, modified));
+            } else if file_type.is_file() {
+                files.push((entry.path(), key));
+            }
+        }
+    }
+    Ok((files, dirs))
+}
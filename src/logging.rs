@@ -0,0 +1,221 @@
+use std::{
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Span, Subscriber,
+    field::{Empty, Field, Visit, display},
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, FormattedFields, format::FmtSpan, format::Writer,
+    },
+    registry::LookupSpan,
+};
+use warp::{
+    Filter, Rejection,
+    http::{HeaderValue, StatusCode},
+    trace::Info,
+};
+
+use crate::{
+    config::{Config, LogFormat},
+    datetime::DateTime,
+};
+
+/// Longest incoming request id that's passed along instead of replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Installs the global subscriber
+pub fn init(config: &Config) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(config.log_level.as_deref().unwrap_or("info"))
+        .with_span_events(FmtSpan::CLOSE);
+
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .init(),
+    }
+}
+
+/// Span every request is handled in, the request id is recorded once it's known and the status
+/// once it's answered
+pub fn request_span(info: Info<'_>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        request_id = Empty,
+        method = %info.method(),
+        path = %info.path(),
+        version = ?info.version(),
+        referer = Empty,
+        status = Empty,
+        elapsed_ms = Empty,
+    );
+    if let Some(referer) = info.referer() {
+        span.record("referer", display(referer));
+    }
+
+    tracing::debug!(parent: &span, "received request");
+    span
+}
+
+/// Id a request is correlated by, taken from the `X-Request-Id` header or generated
+#[derive(Debug, Clone)]
+pub struct RequestId(pub HeaderValue);
+
+/// Extracts the request id, and records it in the request span
+pub fn request_id() -> impl Filter<Extract = (RequestId,), Error = Rejection> + Clone {
+    warp::header::optional("X-Request-Id").map(|id: Option<HeaderValue>| {
+        let id = id
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.as_bytes().iter().all(u8::is_ascii_graphic)
+            })
+            .unwrap_or_else(|| HeaderValue::from_str(&uuid_v4()).unwrap());
+
+        if let Ok(id) = id.to_str() {
+            Span::current().record("request_id", id);
+        }
+        RequestId(id)
+    })
+}
+
+/// Records how a request was answered in its span, warning about it if it took longer than
+/// `slow_after`
+pub fn record_response(status: StatusCode, started: Instant, slow_after: Option<Duration>) {
+    let elapsed = started.elapsed();
+    let span = Span::current();
+    span.record("status", status.as_u16());
+    span.record("elapsed_ms", elapsed.as_millis() as u64);
+    if slow_after.is_some_and(|slow_after| elapsed > slow_after) {
+        tracing::warn!(
+            status = status.as_u16(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            "slow request"
+        );
+    }
+}
+
+/// Generates a random version 4 UUID
+pub fn uuid_v4() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("no randomness available");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Collects fields into a JSON object
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}
+
+/// Formats span fields as a JSON object, so events can nest them
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// Writes events as one JSON object per line
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        let mut spans = Vec::new();
+        for span in ctx.event_scope().into_iter().flat_map(|s| s.from_root()) {
+            let mut object = Map::new();
+            object.insert("name".to_owned(), span.name().into());
+            if let Some(Ok(Value::Object(fields))) = span
+                .extensions()
+                .get::<FormattedFields<JsonFields>>()
+                .map(|f| serde_json::from_str(&f.fields))
+            {
+                object.extend(fields);
+            }
+            spans.push(Value::Object(object));
+        }
+
+        let metadata = event.metadata();
+        let line = serde_json::json!({
+            "timestamp": DateTime::from_system_time(SystemTime::now()).rfc3339(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        writeln!(writer, "{}", line)
+    }
+}
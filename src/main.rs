@@ -1,43 +1,101 @@
+// The OpenAPI document is one big `json!` literal
+#![recursion_limit = "256"]
+
+mod app;
+mod archive;
+mod backup;
+mod blocklist;
+mod bulk_import;
+mod cli;
+// Not used by the server itself, it's for tools built on top of the index
+#[cfg(feature = "client")]
+#[cfg_attr(not(test), allow(dead_code))]
+mod client;
+mod compression;
 mod config;
+mod confirmations;
+mod datetime;
 mod db;
 mod errors;
+mod feed;
 mod file_repo;
+mod forwarded;
+mod http_client;
+mod janitor;
+mod logging;
+mod maintenance;
+mod mirror;
+mod normalize;
+mod openapi;
+mod qpm;
+mod ratelimit;
+mod response_cache;
 mod routes;
+mod s3;
+mod state;
+mod storage;
+mod types;
+mod verify;
+mod write_behind;
 
-use crate::config::Config;
-use file_repo::FileRepo;
-use std::env;
-use tracing_subscriber::fmt::format::FmtSpan;
-use warp::Filter;
+use crate::{
+    cli::{Cli, Command},
+    config::Config,
+};
+use std::{env, net::SocketAddr};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Config::read(
-        env::args()
-            .nth(1)
-            .unwrap_or_else(|| "config.json".to_owned()),
-    )
-    .await?;
-
-    let file_repo = Box::leak(Box::new(FileRepo::new(config.downloads_path.clone())));
-
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            config
-                .log_level
-                .as_ref()
-                .map(AsRef::as_ref)
-                .unwrap_or("info"),
-        )
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
-
-    let pool = db::connect(&config.database_url).await?;
-
-    warp::serve(routes::handler(pool, config, file_repo).with(warp::trace::request()))
-        .run(([127, 0, 0, 1], config.port))
-        .await;
+    let cli = Cli::parse(env::args()).unwrap_or_else(|e| e.exit());
+    let mut config = Config::read(&cli.config).await?;
+    config.skip_migrations |= cli.skip_migrations;
+    let config: &'static Config = Box::leak(Box::new(config));
+
+    // Everything but serving works on the database and files directly, the server can be down
+    let connect = || db::connect(config);
+    match cli.command {
+        Command::Serve => serve(config).await,
+        Command::AddKey { user, pw } => {
+            cli::print(&cli::add_key(&user, &pw, connect().await?).await?, cli.json)
+        }
+        Command::DeleteKey(selector) => cli::print(
+            &cli::delete_key(&selector, connect().await?).await?,
+            cli.json,
+        ),
+        Command::List => cli::print(&cli::list(connect().await?).await?, cli.json),
+        Command::ImportFile { id, version, path } => {
+            let pool = connect().await?;
+            let storage = app::file_repo(config)?;
+            let imported = cli::import_file(
+                &id,
+                &version,
+                &path,
+                config.allow_build_variants,
+                pool,
+                storage,
+            )
+            .await?;
+            cli::print(&imported, cli.json)
+        }
+        Command::Verify => {
+            let pool = connect().await?;
+            let storage = app::file_repo(config)?;
+            cli::print(&cli::verify(pool, storage).await?, cli.json)
+        }
+    }
+}
+
+async fn serve(config: &'static Config) -> anyhow::Result<()> {
+    config.validate()?;
+
+    logging::init(config);
+
+    let (routes, state) = app::build(config).await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], config.port));
+    let (addr, server) = app::serve(routes, state, addr, app::shutdown_signal()).await?;
+    tracing::info!("listening on {}", addr);
 
+    server.await?;
     Ok(())
 }
 
@@ -0,0 +1,146 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+use sqlx::AnyPool;
+use tokio::sync::{Mutex, OwnedRwLockReadGuard, RwLock};
+
+use crate::db::is_postgres;
+
+/// Which operations to run, they're run in the order listed here
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceRequest {
+    /// Rebuilds the database file, giving back the space deleted rows took
+    #[serde(default)]
+    pub vacuum: bool,
+    /// Checks the database for corruption, only available with SQLite
+    #[serde(default)]
+    pub integrity_check: bool,
+    /// Updates the statistics the query planner goes by
+    #[serde(default)]
+    pub analyze: bool,
+}
+
+impl MaintenanceRequest {
+    /// Why the request can't be run on the pool's database, if it can't
+    pub fn check(&self, pool: &AnyPool) -> Result<(), &'static str> {
+        if !(self.vacuum || self.integrity_check || self.analyze) {
+            Err("no operation was selected")
+        } else if self.integrity_check && is_postgres(pool) {
+            Err("integrity_check is only available with SQLite")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MaintenanceReport {
+    pub operations: Vec<Operation>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Operation {
+    /// `vacuum`, `integrity_check` or `analyze`
+    pub operation: &'static str,
+    pub duration_ms: u64,
+    /// Rows `integrity_check` answered with, just `ok` if nothing's wrong
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Vec<String>>,
+}
+
+/// Runs database maintenance, one run at a time
+#[derive(Default)]
+pub struct Maintenance {
+    running: Mutex<()>,
+    /// Set from when a run starts waiting on writes until it's done, apart from the read-only
+    /// flag admins toggle
+    active: AtomicBool,
+    /// Held shared by every write in progress, and exclusively by a run
+    writers: Arc<RwLock<()>>,
+}
+
+impl Maintenance {
+    /// Whether writes should be refused
+    pub fn is_running(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Counts a write as in progress until the guard is dropped. There's no guard to hold while a
+    /// run is waiting or going, writes are refused meanwhile anyway
+    pub fn writer(&self) -> Option<OwnedRwLockReadGuard<()>> {
+        self.writers.clone().try_read_owned().ok()
+    }
+
+    /// Runs the operations one after another on one connection, once the writes in progress are
+    /// done. New writes are refused meanwhile rather than left waiting on the database
+    pub async fn run(
+        &self,
+        request: &MaintenanceRequest,
+        pool: &AnyPool,
+    ) -> anyhow::Result<MaintenanceReport> {
+        let _running = self.running.lock().await;
+        let _active = Active::set(&self.active);
+        let _writers = self.writers.write().await;
+        let started = Instant::now();
+        let mut conn = pool.acquire().await?;
+        let mut report = MaintenanceReport::default();
+
+        if request.vacuum {
+            let started = Instant::now();
+            sqlx::query("VACUUM").execute(&mut conn).await?;
+            report.operations.push(Operation::done("vacuum", started));
+        }
+        if request.integrity_check {
+            let started = Instant::now();
+            let rows: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+                .fetch_all(&mut conn)
+                .await?;
+            report.operations.push(Operation {
+                result: Some(rows),
+                ..Operation::done("integrity_check", started)
+            });
+        }
+        if request.analyze {
+            let started = Instant::now();
+            sqlx::query("ANALYZE").execute(&mut conn).await?;
+            report.operations.push(Operation::done("analyze", started));
+        }
+
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        Ok(report)
+    }
+}
+
+impl Operation {
+    fn done(operation: &'static str, started: Instant) -> Self {
+        Self {
+            operation,
+            duration_ms: started.elapsed().as_millis() as u64,
+            result: None,
+        }
+    }
+}
+
+/// Keeps the maintenance flag set until dropped, however the run ends
+struct Active<'a>(&'a AtomicBool);
+
+impl<'a> Active<'a> {
+    fn set(active: &'a AtomicBool) -> Self {
+        active.store(true, Ordering::SeqCst);
+        Self(active)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
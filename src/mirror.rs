@@ -0,0 +1,134 @@
+use std::{collections::HashMap, time::Duration};
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use semver::{Version, VersionReq};
+use sqlx::AnyPool;
+use tokio::sync::Mutex;
+use warp::http::{Method, StatusCode};
+
+use crate::{
+    config::Config,
+    db::{Change, Mod},
+    http_client::{Endpoint, uri_encode},
+    storage::Storage,
+};
+
+type Fetch = Shared<BoxFuture<'static, Option<Bytes>>>;
+
+/// Upstream index misses are looked up in, downloads found there are kept locally
+pub struct Mirror {
+    endpoint: Endpoint,
+    timeout: Duration,
+    /// Downloads being fetched, so concurrent requests for the same file share one fetch
+    in_flight: Mutex<HashMap<(String, Version), Fetch>>,
+}
+
+impl Mirror {
+    pub fn new(url: &str, timeout: Duration) -> anyhow::Result<Mirror> {
+        Ok(Mirror {
+            endpoint: Endpoint::parse(url)?,
+            timeout,
+            in_flight: Default::default(),
+        })
+    }
+
+    /// Creates the mirror if an upstream is configured
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<&'static Mirror>> {
+        let Some(url) = &config.upstream_url else {
+            return Ok(None);
+        };
+        let mirror = Mirror::new(url, Duration::from_secs(config.upstream_timeout))?;
+        Ok(Some(Box::leak(Box::new(mirror))))
+    }
+
+    async fn get(&self, path: &str) -> Option<Bytes> {
+        let uri = format!("{}{}", self.endpoint.path(), path);
+        let request = self.endpoint.send(&Method::GET, &uri, &[], &[]);
+        match tokio::time::timeout(self.timeout, request).await {
+            Ok(Ok((StatusCode::OK, body))) => Some(body),
+            Ok(Ok((status, _))) => {
+                tracing::debug!("upstream responded to {} with {}", uri, status);
+                None
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("upstream request to {} failed: {}", uri, e);
+                None
+            }
+            Err(_) => {
+                tracing::warn!("upstream request to {} timed out", uri);
+                None
+            }
+        }
+    }
+
+    /// Resolves a version upstream
+    pub async fn resolve(&self, id: &str, req: &VersionReq, offset: usize) -> Option<Mod> {
+        let body = self
+            .get(&format!(
+                "/{}?req={}&offset={}",
+                id,
+                uri_encode(&req.to_string()),
+                offset
+            ))
+            .await?;
+        serde_json::from_slice::<Mod>(&body)
+            .ok()
+            .filter(|m| m.id == id && req.matches(&m.version))
+    }
+
+    /// Downloads a file upstream and stores it so it's served locally from then on
+    pub async fn download(
+        &'static self,
+        id: &str,
+        ver: &Version,
+        pool: &'static AnyPool,
+        storage: &'static dyn Storage,
+    ) -> Option<Bytes> {
+        let key = (id.to_owned(), ver.clone());
+        let fetch = {
+            let mut in_flight = self.in_flight.lock().await;
+            in_flight
+                .entry(key.clone())
+                .or_insert_with(|| {
+                    let (id, ver) = key.clone();
+                    async move {
+                        let contents = self.get(&format!("/{}/{}", id, ver)).await?;
+                        if let Err(e) = store(&id, &ver, contents.clone(), pool, storage).await {
+                            tracing::error!("couldn't keep {} {} from upstream: {}", id, ver, e);
+                        }
+                        Some(contents)
+                    }
+                    .boxed()
+                    .shared()
+                })
+                .clone()
+        };
+
+        let contents = fetch.clone().await;
+        let mut in_flight = self.in_flight.lock().await;
+        if in_flight.get(&key).is_some_and(|f| f.ptr_eq(&fetch)) {
+            in_flight.remove(&key);
+        }
+        contents
+    }
+}
+
+async fn store(
+    id: &str,
+    ver: &Version,
+    contents: Bytes,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> anyhow::Result<()> {
+    // File first, so the version is never listed without it
+    storage.write_file(id, ver, contents.clone()).await?;
+    // Upstream already decided whether the build can be published
+    let added = Mod::insert(id, ver, true, pool).await?;
+    Mod::record_file(id, ver, &contents, pool).await?;
+    Mod::record_stored_size(id, ver, storage.stored_size(id, ver).await?, pool).await?;
+    if added {
+        Change::record("upload", id, ver, pool).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Result},
+};
+
+use semver::Version;
+use serde::Serialize;
+use sqlx::AnyPool;
+
+use crate::{db::Mod, storage::Storage};
+
+/// What merging ids differing only in case did
+#[derive(Debug, Default, Serialize)]
+pub struct NormalizeReport {
+    pub merged: Vec<MergedPackage>,
+    /// Left alone, they have to be sorted out by hand
+    pub conflicts: Vec<CaseConflict>,
+}
+
+/// A package whose versions were moved under its lowercase id
+#[derive(Debug, Serialize)]
+pub struct MergedPackage {
+    pub id: String,
+    /// Versions moved, with the ids they were under
+    pub moved: Vec<Mod>,
+}
+
+/// A package with the same version under more than one casing of its id
+#[derive(Debug, Serialize)]
+pub struct CaseConflict {
+    pub id: String,
+    /// Every casing the package is stored under
+    pub ids: Vec<String>,
+    /// Versions stored under more than one of them
+    pub versions: Vec<Version>,
+}
+
+/// Merges packages stored under ids that aren't lowercase, from before ids were lowercased, into
+/// the lowercase id that requests for them now look up. Packages are only merged when no version
+/// is under more than one casing, versions differing only in build metadata counting as the same
+/// since readmes and artifacts are kept without it. Files are copied before the database is
+/// changed and removed from under the old id after, so it never names a file that isn't there
+pub async fn normalize_ids(
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> anyhow::Result<NormalizeReport> {
+    let mut packages = BTreeMap::<String, BTreeMap<String, Vec<Version>>>::new();
+    for m in Mod::all(pool).await? {
+        packages
+            .entry(m.id.to_lowercase())
+            .or_default()
+            .entry(m.id)
+            .or_default()
+            .push(m.version);
+    }
+
+    let mut report = NormalizeReport::default();
+    for (id, mut casings) in packages {
+        if casings.keys().all(|casing| *casing == id) {
+            continue;
+        }
+
+        let mut seen = BTreeMap::<(u64, u64, u64), usize>::new();
+        for ver in casings.values().flatten() {
+            *seen.entry((ver.major, ver.minor, ver.patch)).or_default() += 1;
+        }
+        let mut versions: Vec<Version> = casings
+            .values()
+            .flatten()
+            .filter(|ver| seen[&(ver.major, ver.minor, ver.patch)] > 1)
+            .cloned()
+            .collect();
+        if !versions.is_empty() {
+            versions.sort();
+            versions.dedup();
+            tracing::warn!(
+                "{} has versions {:?} under more than one of {:?}, merge it by hand",
+                id,
+                versions,
+                casings.keys()
+            );
+            report.conflicts.push(CaseConflict {
+                ids: casings.into_keys().collect(),
+                id,
+                versions,
+            });
+            continue;
+        }
+
+        casings.remove(&id);
+        let moved: Vec<Mod> = casings
+            .into_iter()
+            .flat_map(|(casing, versions)| {
+                versions.into_iter().map(move |version| Mod {
+                    id: casing.clone(),
+                    version,
+                })
+            })
+            .collect();
+        merge(&id, &moved, pool, storage).await?;
+        tracing::info!(
+            "merged {} versions of {} under its lowercase id",
+            moved.len(),
+            id
+        );
+        report.merged.push(MergedPackage { id, moved });
+    }
+    Ok(report)
+}
+
+async fn merge(
+    id: &str,
+    moved: &[Mod],
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> anyhow::Result<()> {
+    let mut artifacts = Vec::with_capacity(moved.len());
+    for m in moved {
+        match storage.get_file(&m.id, &m.version).await {
+            Ok(contents) => storage.write_file(id, &m.version, contents).await?,
+            // Nothing to move for versions that lost their file
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let names: Vec<String> = Mod::artifacts(&m.id, &m.version, pool)
+            .await?
+            .into_iter()
+            .map(|artifact| artifact.name)
+            .collect();
+        for name in &names {
+            let contents = storage.get_artifact(&m.id, &m.version, name).await?;
+            storage
+                .write_artifact(id, &m.version, name, contents)
+                .await?;
+        }
+        artifacts.push(names);
+    }
+
+    let mut from: Vec<String> = moved.iter().map(|m| m.id.clone()).collect();
+    from.dedup();
+    Mod::merge_ids(&from, id, pool).await?;
+
+    for (m, names) in moved.iter().zip(artifacts) {
+        if let Err(e) = remove_old(id, m, &names, storage).await {
+            tracing::warn!(
+                "couldn't remove the old files of {} {}: {}",
+                m.id,
+                m.version,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Removes a version's files from under its old id, writing the new ones back if that removed
+/// them too, as it does where paths differing only in case name the same file
+async fn remove_old(id: &str, m: &Mod, artifacts: &[String], storage: &dyn Storage) -> Result<()> {
+    match storage.get_file(id, &m.version).await {
+        Ok(contents) => {
+            storage.delete_file(&m.id, &m.version).await?;
+            if !storage.file_exists(id, &m.version).await? {
+                storage.write_file(id, &m.version, contents).await?;
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    for name in artifacts {
+        let contents = storage.get_artifact(id, &m.version, name).await?;
+        storage.delete_artifact(&m.id, &m.version, name).await?;
+        if storage.get_artifact(id, &m.version, name).await.is_err() {
+            storage
+                .write_artifact(id, &m.version, name, contents)
+                .await?;
+        }
+    }
+    Ok(())
+}
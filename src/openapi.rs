@@ -0,0 +1,2139 @@
+use std::sync::LazyLock;
+
+use serde_json::{Value, json};
+
+/// OpenAPI 3 description of every route, kept in sync by hand
+pub static SPEC: LazyLock<Value> = LazyLock::new(spec);
+
+fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "bs-quest-index",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Package ids are case-insensitive. Private packages are left out of \
+                            listings, and only a key covering them can resolve or download them, \
+                            for anyone else they don't exist",
+        },
+        "paths": {
+            "/": {
+                "get": {
+                    "summary": "List package ids",
+                    "description": "Everything is returned unless `page` or `per_page` is given. \
+                                    Poll with `If-None-Match`. `Accept: text/plain` gives an id \
+                                    per line instead of JSON",
+                    "parameters": [
+                        query("page", "integer", "Page to return, starting at 1"),
+                        query("per_page", "integer", "Ids per page, at most 100"),
+                        query(
+                            "game",
+                            "string",
+                            "Only packages with a version made for this game version",
+                        ),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Package ids, sorted",
+                            "headers": {
+                                "X-Total-Count": {
+                                    "description": "Number of packages, when paginating",
+                                    "schema": {"type": "integer"},
+                                },
+                                "Link": {
+                                    "description": "`prev` and `next` pages, when paginating",
+                                    "schema": {"type": "string"},
+                                },
+                                "ETag": {
+                                    "description": "Strong, changes whenever a package is \
+                                                    published, deleted, renamed or hidden, \
+                                                    and stays the same across restarts",
+                                    "schema": {"type": "string"},
+                                },
+                                "Cache-Control": {
+                                    "description": "`max-age=60`",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": list_content(),
+                        },
+                        "304": {"description": "Unchanged since the given `ETag`"},
+                    },
+                },
+            },
+            "/resolve": {
+                "post": {
+                    "summary": "Resolve the latest matching version of several packages",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({
+                            "type": "array",
+                            "maxItems": 100,
+                            "items": schema_ref("BatchEntry"),
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "One result per package, in order",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("BatchResult"),
+                            })),
+                        },
+                        "400": error("Malformed body or too many packages"),
+                    },
+                },
+            },
+            "/feed.atom": {
+                "get": {
+                    "summary": "Most recent uploads as an Atom feed",
+                    "parameters": [
+                        query("package", "string", "Only uploads of this package"),
+                        query("limit", "integer", "Entries to return, at most 100"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Atom feed, newest first",
+                            "content": {"application/atom+xml": {"schema": {"type": "string"}}},
+                        },
+                    },
+                },
+            },
+            "/index.json": {
+                "get": {
+                    "summary": "Snapshot of every version of every package",
+                    "description": "Meant for static mirrors, poll with `If-None-Match`",
+                    "responses": {
+                        "200": {
+                            "description": "Versions by package id, newest first",
+                            "headers": {
+                                "ETag": {
+                                    "description": "Changes when a version is added, removed or \
+                                                    downloaded",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": json_content(json!({
+                                "type": "object",
+                                "additionalProperties": {
+                                    "type": "array",
+                                    "items": schema_ref("IndexVersion"),
+                                },
+                            })),
+                        },
+                        "304": {"description": "Unchanged since the given `ETag`"},
+                    },
+                },
+            },
+            "/stats": {
+                "get": {
+                    "summary": "Disk used by the index",
+                    "responses": {
+                        "200": {
+                            "description": "Usage in total and by package id",
+                            "content": json_content(schema_ref("Stats")),
+                        },
+                    },
+                },
+            },
+            "/popular": {
+                "get": {
+                    "summary": "Public packages with the most downloads recently",
+                    "parameters": [
+                        query(
+                            "window",
+                            "string",
+                            "Hours or days to rank over, like `24h` or `7d`, a week by default. \
+                             At most `download-history-days`",
+                        ),
+                        query("limit", "integer", "Packages to return, at most 100"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Packages by downloads in the window, then by id",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("Popular"),
+                            })),
+                        },
+                        "400": error("The window is malformed or longer than downloads are kept"),
+                    },
+                },
+            },
+            "/changes": {
+                "get": {
+                    "summary": "Versions published, deleted, yanked or given new metadata, in order",
+                    "description": "For mirrors to sync incrementally. Continue from the last \
+                                    change's `seq` while a full page comes back, then from \
+                                    `latest`. Changes to private packages are left out",
+                    "parameters": [
+                        query(
+                            "since",
+                            "integer",
+                            "Sequence number of the last change already seen, everything kept \
+                             if absent",
+                        ),
+                        query("limit", "integer", "Changes to return, at most 1000"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Changes after `since`",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "required": ["changes", "latest"],
+                                "properties": {
+                                    "changes": {
+                                        "type": "array",
+                                        "items": schema_ref("Change"),
+                                    },
+                                    "latest": {
+                                        "type": "integer",
+                                        "description": "Sequence number of the latest change, \
+                                                        0 before anything changed",
+                                    },
+                                },
+                            })),
+                        },
+                        "410": error(
+                            "Changes after `since` were pruned, everything has to be synced \
+                             again from `/index.json`",
+                        ),
+                    },
+                },
+            },
+            "/stats/runtime": {
+                "get": {
+                    "summary": "Counters kept since the index started",
+                    "responses": {
+                        "200": {
+                            "description": "Counters, reset when the index restarts",
+                            "content": json_content(schema_ref("RuntimeStats")),
+                        },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": {
+                        "200": {
+                            "description": "OpenAPI description",
+                            "content": json_content(json!({"type": "object"})),
+                        },
+                    },
+                },
+            },
+            "/{package}": {
+                "parameters": [package()],
+                "get": {
+                    "summary": "Resolve versions of a package",
+                    "parameters": [
+                        query("req", "string", "Version requirement to match, any by default"),
+                        query(
+                            "limit",
+                            "integer",
+                            "Versions to return, 0 for all, 1 by default, at most 100 unless \
+                             configured otherwise",
+                        ),
+                        query("offset", "integer", "Matching versions to skip, newest first"),
+                        {
+                            "name": "shape",
+                            "in": "query",
+                            "description": "`auto` answers with the version on its own when \
+                                            `limit` is 1 and an array otherwise, `array` always \
+                                            with an array, empty when nothing matches",
+                            "schema": {
+                                "type": "string",
+                                "enum": ["auto", "array"],
+                                "default": "auto",
+                            },
+                        },
+                        query(
+                            "game",
+                            "string",
+                            "Only versions made for this game version, versions that aren't \
+                             tagged with any are made for all of them",
+                        ),
+                        query(
+                            "loader",
+                            "string",
+                            "Only versions made for this modloader, like `scotland2`, versions \
+                             that don't declare one are made for any",
+                        ),
+                        query("strict", "boolean", "Whether to refuse unknown parameters"),
+                        {
+                            "name": "If-Modified-Since",
+                            "in": "header",
+                            "description": "Answer with a 304 if the package hasn't changed since \
+                                            this date. Ignored along with `If-None-Match`, or \
+                                            when malformed",
+                            "schema": {"type": "string"},
+                        },
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The latest matching version if `limit` is 1 and \
+                                            `shape` isn't `array`, an array of them otherwise",
+                            "headers": {
+                                "X-Resolved-Id": resolved_id(),
+                                "X-Total-Matches": {
+                                    "description": "Number of matching versions, regardless of \
+                                                    `limit` and `offset`. Left out for versions \
+                                                    found upstream",
+                                    "schema": {"type": "integer"},
+                                },
+                                "ETag": etag(),
+                                "Cache-Control": cache_control(),
+                                "Last-Modified": {
+                                    "description": "When a version was last published, yanked \
+                                                    or deleted, or the package's metadata or \
+                                                    aliases changed. Not sent for versions found \
+                                                    upstream, or for changes made this second",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": json_content(json!({
+                                "oneOf": [
+                                    schema_ref("Resolved"),
+                                    {"type": "array", "items": schema_ref("Resolved")},
+                                ],
+                            })),
+                        },
+                        "304": {
+                            "description": "Unchanged since the given `ETag` or \
+                                            `If-Modified-Since`",
+                        },
+                        "400": error("A parameter is malformed, or unknown with `strict`"),
+                        "404": error(
+                            "No such package, or no matching version when answering with a lone \
+                             version",
+                        ),
+                        "410": error("The package was blocked, with why"),
+                    },
+                },
+            },
+            "/{package}/versions": {
+                "parameters": [package()],
+                "get": {
+                    "summary": "List every version of a package",
+                    "description": "`Accept: text/plain` gives a version per line instead of JSON",
+                    "responses": {
+                        "200": {
+                            "description": "Versions, newest first",
+                            "content": list_content(),
+                        },
+                        "404": error("No such package"),
+                    },
+                },
+            },
+            "/{package}/{version}/meta": {
+                "parameters": [package(), version()],
+                "put": {
+                    "summary": "Replace the metadata of a version",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("Metadata")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The version with its new metadata",
+                            "content": json_content(schema_ref("Resolved")),
+                        },
+                        "400": error("Malformed body, or a field is too long"),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "404": error("No such version"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/{package}/{version}/info": {
+                "parameters": [package(), version()],
+                "get": {
+                    "summary": "Everything known about a version",
+                    "description": "Unlike the download route, the version has to be stored with \
+                                    exactly this version string, build metadata included",
+                    "responses": {
+                        "200": {
+                            "description": "The version, yanked or not",
+                            "headers": {"X-Resolved-Id": resolved_id()},
+                            "content": json_content(schema_ref("VersionInfo")),
+                        },
+                        "404": error("No such version"),
+                        "410": error("The package was blocked, with why"),
+                    },
+                },
+            },
+            "/{package}/{version}/readme": {
+                "parameters": [package(), version()],
+                "get": {
+                    "summary": "The readme attached to a version",
+                    "responses": {
+                        "200": {
+                            "description": "The readme",
+                            "content": {"text/markdown": {"schema": {"type": "string"}}},
+                        },
+                        "404": error("No such version, or it has no readme"),
+                    },
+                },
+                "put": {
+                    "summary": "Attach a readme to a version, replacing any it had",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"text/markdown": {"schema": {"type": "string"}}},
+                    },
+                    "responses": {
+                        "200": {"description": "Attached"},
+                        "400": error("The readme isn't UTF-8"),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "404": error("No such version"),
+                        "413": error("The readme is over 256 KiB"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/{package}/{version}/artifacts": {
+                "parameters": [package(), version()],
+                "get": {
+                    "summary": "Files published with a version",
+                    "description": "The version's own file comes first, without a name",
+                    "responses": {
+                        "200": {
+                            "description": "The files",
+                            "headers": {"X-Resolved-Id": resolved_id()},
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("Artifact"),
+                            })),
+                        },
+                        "404": error("No such version"),
+                    },
+                },
+            },
+            "/{package}/{version}/artifacts/{name}": {
+                "parameters": [
+                    package(),
+                    version(),
+                    {
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "description": "Letters, digits, `.`, `-` and `_`, not starting with `.`",
+                        "schema": {"type": "string", "maxLength": 64},
+                    },
+                ],
+                "get": {
+                    "summary": "Download a named artifact of a version",
+                    "responses": {
+                        "200": {
+                            "description": "The artifact",
+                            "headers": {"X-Resolved-Id": resolved_id()},
+                            "content": {"application/octet-stream": {}},
+                        },
+                        "404": error("No such version or artifact"),
+                    },
+                },
+                "post": {
+                    "summary": "Publish a named artifact alongside a version, like debug symbols",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/octet-stream": {}},
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The same artifact was already published",
+                            "content": json_content(schema_ref("Artifact")),
+                        },
+                        "201": {
+                            "description": "Published",
+                            "content": json_content(schema_ref("Artifact")),
+                        },
+                        "400": error("Invalid artifact name"),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "404": error("No such version"),
+                        "409": {
+                            "description": "A different artifact by that name was already \
+                                            published",
+                            "content": json_content(schema_ref("Artifact")),
+                        },
+                        "413": error("A part of the form, or all of it, is too large"),
+                        "503": read_only(),
+                        "507": error("The upload would go over a storage quota"),
+                    },
+                },
+            },
+            "/{package}/visibility": {
+                "parameters": [package()],
+                "post": {
+                    "summary": "Make a package public or private",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("VisibilityState")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new visibility",
+                            "content": json_content(schema_ref("VisibilityState")),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "404": error("No such package"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/{package}/stats": {
+                "parameters": [package()],
+                "get": {
+                    "summary": "Disk used by a package, and its downloads",
+                    "responses": {
+                        "200": {
+                            "description": "Usage of the package",
+                            "content": json_content(schema_ref("PackageStats")),
+                        },
+                        "404": error("No such package"),
+                    },
+                },
+            },
+            "/{package}/download": {
+                "parameters": [package()],
+                "get": {
+                    "summary": "Download the latest matching version",
+                    "description": "Resolves like `/{package}` with a `limit` of 1, then \
+                                    answers like `/{package}/{version}` with the version found",
+                    "parameters": [
+                        query("req", "string", "Version requirement to match, any by default"),
+                        query("offset", "integer", "Matching versions to skip, newest first"),
+                        query("game", "string", "Only versions made for this game version"),
+                        query("loader", "string", "Only versions made for this modloader"),
+                        query("strict", "boolean", "Whether to refuse unknown parameters"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The qmod file",
+                            "headers": {
+                                "X-Resolved-Id": resolved_id(),
+                                "X-Resolved-Version": {
+                                    "description": "The version downloaded",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": {
+                                "application/zip": {"schema": binary()},
+                                "application/octet-stream": {"schema": binary()},
+                            },
+                        },
+                        "400": error("Malformed parameter"),
+                        "404": error("No such package, or no version matches"),
+                        "410": error("The package was blocked, with why"),
+                        "504": error("Storage took too long to read the file"),
+                    },
+                },
+            },
+            "/{package}/{version}": {
+                "parameters": [package(), version()],
+                "get": {
+                    "summary": "Download a version",
+                    "description": "With `sendfile-header` set the body is left for the proxy in \
+                                    front of the index to fill in. Clients preferring \
+                                    `application/json` in `Accept` get the version as resolve \
+                                    describes it instead. Any build of the version is found \
+                                    if the one asked for isn't there",
+                    "responses": {
+                        "200": {
+                            "description": "The qmod file, or the version's description",
+                            "headers": {"X-Resolved-Id": resolved_id()},
+                            "content": {
+                                "application/zip": {"schema": binary()},
+                                "application/octet-stream": {"schema": binary()},
+                                "application/json": {"schema": schema_ref("Resolved")},
+                            },
+                        },
+                        "404": error("No such version"),
+                        "410": error("The package was blocked, with why"),
+                        "422": error("A version number is too large to be stored"),
+                        "504": error("Storage took too long to read the file"),
+                    },
+                },
+                "post": {
+                    "summary": "Upload a version",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "parameters": [
+                        query(
+                            "force",
+                            "boolean",
+                            "Republish a deleted version, only allowed with an admin key",
+                        ),
+                        query(
+                            "private",
+                            "boolean",
+                            "Make the package private, only when publishing its first version",
+                        ),
+                        query(
+                            "game",
+                            "string",
+                            "Comma separated game versions the version is made for, each must be \
+                             known to the index",
+                        ),
+                        query(
+                            "loader",
+                            "string",
+                            "Modloader the version is made for, as the metadata's `loader`",
+                        ),
+                        query(
+                            "loader_version_req",
+                            "string",
+                            "Loader versions the version works with, as the metadata's \
+                             `loader_version_req`",
+                        ),
+                        {
+                            "name": "Content-Encoding",
+                            "in": "header",
+                            "description": "`gzip` to send the file compressed, it's hashed and \
+                                            stored decompressed. Forms can't be compressed",
+                            "schema": {"type": "string", "enum": ["gzip", "identity"]},
+                        },
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/octet-stream": {"schema": binary()},
+                            "multipart/form-data": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["file"],
+                                    "properties": {
+                                        "file": binary(),
+                                        "metadata": schema_ref("Metadata"),
+                                    },
+                                },
+                                "encoding": {"metadata": {"contentType": "application/json"}},
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The version already exists with the same contents",
+                            "content": json_content(schema_ref("Uploaded")),
+                        },
+                        "201": {
+                            "description": "Uploaded",
+                            "content": json_content(schema_ref("Uploaded")),
+                        },
+                        "400": error(
+                            "The form has no file part, its metadata is invalid, or the gzipped \
+                             body is corrupt",
+                        ),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "409": {
+                            "description": "The version already exists with other contents or is \
+                                            still being published, another build of it exists \
+                                            and `allow-build-variants` isn't set, or the package \
+                                            was renamed",
+                            "content": json_content(json!({
+                                "oneOf": [schema_ref("VersionConflict"), schema_ref("Error")],
+                            })),
+                        },
+                        "410": error(
+                            "The version was deleted and can't be published again, or the \
+                             package was blocked",
+                        ),
+                        "422": error(
+                            "One of the game versions is unknown, a version number is past \
+                             `max-version-number`, or the id is over 255 bytes long",
+                        ),
+                        "413": error(
+                            "A part of the form, or all of it, is too large, or the gzipped body \
+                             decompresses to more than `max-decompressed-bytes`",
+                        ),
+                        "415": error("The body was sent with an encoding other than gzip"),
+                        "429": error(
+                            "Too many versions of the package were published in the last hour",
+                        ),
+                        "503": read_only(),
+                        "507": error("The upload would go over a storage quota"),
+                    },
+                },
+                "delete": {
+                    "summary": "Delete a version",
+                    "security": [{"adminKey": []}],
+                    "parameters": [
+                        {
+                            "name": "If-Match",
+                            "in": "header",
+                            "description": "Only delete the version if its SHA-256 is one of \
+                                            these, or it exists for `*`",
+                            "schema": {"type": "string"},
+                        },
+                        {
+                            "name": "X-Confirm-Delete",
+                            "in": "header",
+                            "description": "Token a delete of a protected version answered with",
+                            "schema": {"type": "string"},
+                        },
+                    ],
+                    "responses": {
+                        "200": {"description": "Deleted"},
+                        "400": error(
+                            "`X-Confirm-Delete` isn't a token issued for the version, or was \
+                             already used or expired",
+                        ),
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("No such version"),
+                        "409": error(
+                            "The version is older than the protection window, repeat the delete \
+                             with `token` in `X-Confirm-Delete` within `expires_in` seconds. \
+                             Yanking needs no confirmation",
+                        ),
+                        "412": error(
+                            "The version doesn't exist or its hash isn't the one given, `sha256` \
+                             has its hash",
+                        ),
+                        "422": error("A version number is too large to be stored"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/publish_key": {
+                "get": {
+                    "summary": "Publish keys without the keys themselves, with when they were \
+                        last used",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "Every key",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("KeyInfo"),
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+                "post": {
+                    "summary": "Add a publish key",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("PublishKey")),
+                    },
+                    "responses": {
+                        "201": {"description": "Added"},
+                        "401": error("Missing or invalid admin key"),
+                        "409": {"description": "The key already exists"},
+                        "422": error("The key or user is empty, too long, or has control characters"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/publish_key/verify": {
+                "get": {
+                    "summary": "Check a key is accepted, and what it may publish",
+                    "description": "Does nothing with the key, besides marking it used",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "Who the key belongs to",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "required": ["user", "scope", "expires_at", "admin"],
+                                "properties": {
+                                    "user": {
+                                        "type": "string",
+                                        "description": "`admin` or `moderator` for admin keys",
+                                    },
+                                    "scope": {"type": "string", "nullable": true},
+                                    "expires_at": {"type": "integer", "nullable": true},
+                                    "admin": {"type": "boolean"},
+                                },
+                            })),
+                        },
+                        "401": error("Missing, invalid, expired or disabled key"),
+                    },
+                },
+            },
+            "/publish_key/enable": {
+                "post": {
+                    "summary": "Enable a user's keys again after they were disabled for going \
+                        unused",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "required": ["user"],
+                            "properties": {"user": {"type": "string"}},
+                        })),
+                    },
+                    "responses": {
+                        "200": {"description": "Enabled"},
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("The user has no keys"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/publish_key/{user}/uploads": {
+                "parameters": [{
+                    "name": "user",
+                    "in": "path",
+                    "required": true,
+                    "schema": {"type": "string"},
+                }],
+                "get": {
+                    "summary": "Versions uploaded by a publish key user, newest first",
+                    "security": [{"adminKey": []}],
+                    "parameters": [
+                        query("page", "integer", "Page to return, starting at 1"),
+                        query("per_page", "integer", "Versions per page, at most 100"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The user's uploads",
+                            "headers": {
+                                "X-Total-Count": {
+                                    "description": "Number of versions the user uploaded",
+                                    "schema": {"type": "integer"},
+                                },
+                            },
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("Publication"),
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+                "post": {
+                    "summary": "Yank every version uploaded by a publish key user",
+                    "security": [{"adminKey": []}],
+                    "parameters": [query("quarantine", "boolean", "Must be `true`")],
+                    "responses": {
+                        "200": {
+                            "description": "The versions that were yanked",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("Mod"),
+                            })),
+                        },
+                        "400": error("`quarantine=true` wasn't given"),
+                        "401": error("Missing or invalid admin key"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/delete_key": {
+                "post": {
+                    "summary": "Delete publish keys by key or by user",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "properties": {
+                                "pw": {"type": "string"},
+                                "user": {"type": "string"},
+                            },
+                        })),
+                    },
+                    "responses": {
+                        "200": {"description": "Deleted"},
+                        "400": {"description": "Neither `pw` nor `user` given"},
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("No such key"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/reconcile": {
+                "post": {
+                    "summary": "Index files found in storage, and optionally unindex missing ones",
+                    "security": [{"adminKey": []}],
+                    "parameters": [
+                        query("prune", "boolean", "Whether to unindex versions without a file"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "What changed",
+                            "content": json_content(schema_ref("ReconcileReport")),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/normalize": {
+                "post": {
+                    "summary": "Merge packages stored under ids with uppercase letters into their \
+                        lowercase ids",
+                    "description": "Ids stored before they were lowercased can't be looked up, \
+                        or split a package in two. Packages with the same version under more \
+                        than one casing are reported rather than merged.",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "What was merged, and what has to be by hand",
+                            "content": json_content(schema_ref("NormalizeReport")),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/maintenance": {
+                "post": {
+                    "summary": "Vacuum, check or analyze the database",
+                    "description": "Operations run one after another on one connection, in \
+                        the order `vacuum`, `integrity_check`, `analyze`. The index is read-only \
+                        until they're done, so writes meanwhile get a 503.",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("MaintenanceRequest")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "How long each operation took",
+                            "content": json_content(schema_ref("MaintenanceReport")),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid admin key"),
+                        "422": error(
+                            "No operation was selected, or `integrity_check` on PostgreSQL"
+                        ),
+                    },
+                },
+            },
+            "/admin/export": {
+                "get": {
+                    "summary": "Export every version, file and hashed publish key",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "A tar archive with a `manifest.json` and the files \
+                                            under `files/`",
+                            "content": {"application/x-tar": {"schema": binary()}},
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/import": {
+                "post": {
+                    "summary": "Restore an exported archive, skipping versions already there",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/x-tar": {"schema": binary()}},
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "What was restored",
+                            "content": json_content(schema_ref("ImportReport")),
+                        },
+                        "400": error("Malformed archive"),
+                        "401": error("Missing or invalid admin key"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/bulk_import": {
+                "post": {
+                    "summary": "Publish many versions at once",
+                    "description": "Files are named `{id}/{version}` or `{id}-{version}.qmod` \
+                                    and published one at a time as they're read, like uploads. \
+                                    Stops at the first file that would go over a storage quota",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/x-tar": {"schema": binary()},
+                            "application/json": {"schema": {
+                                "type": "object",
+                                "required": ["path"],
+                                "properties": {
+                                    "path": {
+                                        "type": "string",
+                                        "description": "Directory to read files from, \
+                                                        relative to `bulk-import-dir`",
+                                    },
+                                },
+                            }},
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "What was published, and what wasn't and why",
+                            "content": json_content(schema_ref("BulkImportReport")),
+                        },
+                        "400": error("Malformed JSON"),
+                        "401": error("Missing or invalid admin key"),
+                        "403": error("The directory is outside of `bulk-import-dir`"),
+                        "404": error("No such directory"),
+                        "422": error("A directory was given but `bulk-import-dir` isn't set"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/audit": {
+                "get": {
+                    "summary": "List uploads, deletions and administrative actions",
+                    "security": [{"adminKey": []}],
+                    "parameters": [
+                        query("limit", "integer", "Entries to return, at most 100"),
+                        query("before", "integer", "Only entries older than this entry id"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Entries, newest first",
+                            "headers": {
+                                "Link": {
+                                    "description": "`next` page, when the page is full",
+                                    "schema": {"type": "string"},
+                                },
+                            },
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("AuditEntry"),
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/migrations": {
+                "get": {
+                    "summary": "List database migrations and whether they're applied",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "Migrations built in and applied, oldest first",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": schema_ref("Migration"),
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/readonly": {
+                "post": {
+                    "summary": "Turn read-only mode on or off",
+                    "description": "Uploads, deletions and key changes are refused while on",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("ReadOnlyState")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new state",
+                            "content": json_content(schema_ref("ReadOnlyState")),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/alias": {
+                "post": {
+                    "summary": "Rename a package",
+                    "description": "Resolves and downloads of the old id are served from the new \
+                                    one, and publishing under the old id is refused",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("Alias")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The alias",
+                            "content": json_content(schema_ref("Alias")),
+                        },
+                        "400": error("Malformed body, or the alias would chain onto another"),
+                        "401": error("Missing or invalid admin key"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/game_versions": {
+                "get": {
+                    "summary": "List the game versions this index knows about",
+                    "responses": {
+                        "200": {
+                            "description": "Game versions, oldest first",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": {"type": "string"},
+                            })),
+                        },
+                    },
+                },
+            },
+            "/admin/game_versions": {
+                "post": {
+                    "summary": "Add a game version uploads can be tagged with",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "required": ["version"],
+                            "properties": {"version": {"type": "string"}},
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Already known, all game versions",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": {"type": "string"},
+                            })),
+                        },
+                        "201": {
+                            "description": "Added, all game versions",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": {"type": "string"},
+                            })),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid admin key"),
+                        "403": error("Admin key lacks the required role"),
+                        "422": error("Not a valid game version"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/backup": {
+                "post": {
+                    "summary": "Copy the database into the backup directory",
+                    "description": "Only available when backups are configured, older copies past \
+                                    `keep` are removed",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "201": {
+                            "description": "Name of the backup file",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": {"file": {"type": "string"}},
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                        "403": error("Admin key lacks the required role"),
+                        "404": error("Backups are not configured"),
+                    },
+                },
+            },
+            "/admin/block": {
+                "post": {
+                    "summary": "Block a package and delete every version of it",
+                    "description": "Resolves, downloads and uploads of the package are answered \
+                                    with a 410 giving the reason until it's unblocked. Deleted \
+                                    versions stay deleted",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("Block")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The block, and the versions deleted",
+                            "content": json_content(json!({
+                                "allOf": [schema_ref("Block")],
+                                "type": "object",
+                                "properties": {
+                                    "deleted": {"type": "array", "items": {"type": "string"}},
+                                },
+                            })),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid admin key"),
+                        "422": error("The reason is empty"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/block/{package}": {
+                "parameters": [package()],
+                "delete": {
+                    "summary": "Unblock a package",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {"description": "Unblocked"},
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("The package isn't blocked"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/approve/{package}": {
+                "parameters": [package()],
+                "post": {
+                    "summary": "Approve a package held for approval",
+                    "description": "With `require_approval_for_new_packages` set, packages first \
+                                    published by a publish key are only seen by admins and the \
+                                    key's user until approved",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {"description": "Approved, the package is listed and resolved"},
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("The package isn't held for approval"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/reject/{package}": {
+                "parameters": [package()],
+                "post": {
+                    "summary": "Reject a package held for approval",
+                    "description": "Yanks every version of it and blocks it, so it stays hidden \
+                                    and can't be published to",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "The versions yanked",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": {"type": "string"},
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                        "404": error("The package isn't held for approval"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/gc": {
+                "post": {
+                    "summary": "Tidy up the downloads directory",
+                    "description": "Moves files no version points to under `quarantine/`, or \
+                                    deletes them if configured to, then removes empty \
+                                    directories. Only available with local storage",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "What was done, paths are relative to the downloads \
+                                            directory",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": {
+                                    "removed_dirs": {"type": "array", "items": {"type": "string"}},
+                                    "quarantined": {"type": "array", "items": {"type": "string"}},
+                                    "deleted": {"type": "array", "items": {"type": "string"}},
+                                    "skipped_recent": {
+                                        "type": "array",
+                                        "items": {"type": "string"},
+                                    },
+                                },
+                            })),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                        "403": error("Admin key lacks the required role"),
+                        "404": error("Storage isn't local"),
+                        "503": read_only(),
+                    },
+                },
+            },
+            "/admin/verify": {
+                "post": {
+                    "summary": "Check every file against the hash recorded at upload",
+                    "description": "Runs in the background, poll `/admin/verify/status` for the \
+                                    report",
+                    "security": [{"adminKey": []}],
+                    "parameters": [
+                        query(
+                            "quarantine",
+                            "boolean",
+                            "Rename mismatched files with a `.corrupt` suffix",
+                        ),
+                    ],
+                    "responses": {
+                        "202": {
+                            "description": "Started",
+                            "content": json_content(schema_ref("VerifyStatus")),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                        "409": {
+                            "description": "A run is going already",
+                            "content": json_content(schema_ref("VerifyStatus")),
+                        },
+                    },
+                },
+            },
+            "/admin/verify/status": {
+                "get": {
+                    "summary": "Progress and report of the latest verification",
+                    "security": [{"adminKey": []}],
+                    "responses": {
+                        "200": {
+                            "description": "Status of the latest run",
+                            "content": json_content(schema_ref("VerifyStatus")),
+                        },
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/admin/cache/purge": {
+                "post": {
+                    "summary": "Drop files cached in memory",
+                    "description": "For after fixing files in storage by hand",
+                    "security": [{"adminKey": []}],
+                    "requestBody": {
+                        "required": false,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "properties": {
+                                "id": {
+                                    "type": "string",
+                                    "description": "Only drop this package's files",
+                                },
+                            },
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "What was dropped",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "required": ["entries", "bytes"],
+                                "properties": {
+                                    "entries": {"type": "integer"},
+                                    "bytes": {"type": "integer"},
+                                },
+                            })),
+                        },
+                        "400": error("Malformed body"),
+                        "401": error("Missing or invalid admin key"),
+                    },
+                },
+            },
+            "/qpm/{package}": {
+                "parameters": [package()],
+                "get": {
+                    "summary": "List every version of a package, for qpm clients",
+                    "description": "Only served when `qpm-compat` is enabled",
+                    "responses": {
+                        "200": {
+                            "description": "Versions, newest first",
+                            "content": json_content(json!({
+                                "type": "array",
+                                "items": {"type": "string"},
+                            })),
+                        },
+                        "404": error("No such package"),
+                    },
+                },
+            },
+            "/qpm/{package}/{version}": {
+                "parameters": [package(), version()],
+                "get": {
+                    "summary": "Get the package config a version was published with",
+                    "description": "Only served when `qpm-compat` is enabled",
+                    "responses": {
+                        "200": {
+                            "description": "The `qpm.shared.json` or `qpm.json` uploaded",
+                            "content": json_content(json!({"type": "object"})),
+                        },
+                        "404": error("No such version"),
+                    },
+                },
+                "post": {
+                    "summary": "Publish a version from its package config",
+                    "description": "Only served when `qpm-compat` is enabled. The key may also be \
+                                    sent in a `QPM_AUTH` header",
+                    "security": [{"publishKey": []}, {"adminKey": []}],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({"type": "object"})),
+                    },
+                    "responses": {
+                        "201": {"description": "Published"},
+                        "400": error("Not a package config, or one for another version"),
+                        "401": error("Missing or invalid key"),
+                        "403": error("The key doesn't cover this package"),
+                        "409": {"description": "The version already exists"},
+                        "410": error(
+                            "The version was deleted and can't be published again, or the \
+                             package was blocked",
+                        ),
+                        "413": error("A part of the form, or all of it, is too large"),
+                        "422": error(
+                            "A version number is past `max-version-number`, or the id is over \
+                             255 bytes long",
+                        ),
+                        "429": error(
+                            "Too many versions of the package were published in the last hour",
+                        ),
+                        "503": read_only(),
+                        "507": error("The upload would go over a storage quota"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "publishKey": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A publish key, the `Bearer` prefix is optional",
+                },
+                "adminKey": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "An admin key, the `Bearer` prefix is optional. Moderator keys can only \
+                        delete versions, other admin routes refuse them with a 403",
+                },
+            },
+            "schemas": {
+                "Mod": {
+                    "type": "object",
+                    "required": ["id", "version"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "version": {"type": "string"},
+                    },
+                },
+                "Metadata": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string"},
+                        "description": {"type": "string"},
+                        "author": {"type": "string"},
+                        "website": {"type": "string"},
+                        "game_version": {"type": "string"},
+                        "loader": {
+                            "type": "string",
+                            "description": "Modloader the version is made for, lowercased",
+                        },
+                        "loader_version_req": {
+                            "type": "string",
+                            "description": "Semver requirement on the loader's version, only \
+                                with a `loader`",
+                        },
+                    },
+                },
+                "Resolved": {
+                    "allOf": [schema_ref("Mod"), schema_ref("Metadata")],
+                    "type": "object",
+                    "properties": {
+                        "download_url": {
+                            "type": "string",
+                            "description": "Absolute if the index is configured with its public \
+                                URL, relative to the index otherwise",
+                        },
+                    },
+                },
+                "PublishKey": {
+                    "type": "object",
+                    "required": ["pw", "user"],
+                    "properties": {
+                        "pw": {"type": "string"},
+                        "user": {"type": "string"},
+                        "scope": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Package id the key may publish, either exact or \
+                                            a prefix ending in `*`",
+                        },
+                        "expires_at": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Unix timestamp after which the key is invalid",
+                        },
+                    },
+                },
+                "IndexVersion": {
+                    "allOf": [schema_ref("Metadata")],
+                    "type": "object",
+                    "required": ["version", "downloads", "download_url"],
+                    "properties": {
+                        "version": {"type": "string"},
+                        "size": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Size of the file in bytes, unknown for old versions",
+                        },
+                        "sha256": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Hex SHA-256 of the file, unknown for old versions",
+                        },
+                        "downloads": {"type": "integer"},
+                        "created_at": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Unix timestamp of the upload",
+                        },
+                        "download_url": {"type": "string"},
+                    },
+                },
+                "VersionInfo": {
+                    "allOf": [schema_ref("Metadata")],
+                    "type": "object",
+                    "required": ["id", "version", "yanked", "downloads", "game_versions"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "version": {"type": "string"},
+                        "sha256": {"type": "string", "nullable": true},
+                        "size": {"type": "integer", "nullable": true},
+                        "created_at": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Unix timestamp of the upload",
+                        },
+                        "yanked": {
+                            "type": "boolean",
+                            "description": "Whether the version is left out of resolves, it can \
+                                            still be downloaded",
+                        },
+                        "yanked_at": {"type": "integer", "nullable": true},
+                        "downloads": {"type": "integer"},
+                        "game_versions": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Game versions the version is tagged as made for",
+                        },
+                        "download_url": {
+                            "type": "string",
+                            "description": "Absolute if the index is configured with its public \
+                                URL, relative to the index otherwise",
+                        },
+                    },
+                },
+                "Publication": {
+                    "type": "object",
+                    "required": ["id", "version"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "version": {"type": "string"},
+                        "created_at": {"type": "integer", "nullable": true},
+                        "yanked_at": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "When the version was left out of resolves",
+                        },
+                    },
+                },
+                "Uploaded": {
+                    "type": "object",
+                    "required": ["id", "version", "download_url"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "version": {"type": "string"},
+                        "size": {"type": "integer", "nullable": true},
+                        "sha256": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Hex SHA-256 of the stored file",
+                        },
+                        "created_at": {"type": "integer", "nullable": true},
+                        "download_url": {
+                            "type": "string",
+                            "description": "Absolute if the index is configured with its public URL \
+                                or a trusted proxy forwarded the scheme and host",
+                        },
+                    },
+                },
+                "VersionConflict": {
+                    "type": "object",
+                    "required": [
+                        "error",
+                        "version",
+                        "file_present",
+                        "hash_matches",
+                        "in_progress",
+                    ],
+                    "properties": {
+                        "error": {"type": "string", "enum": ["conflict"]},
+                        "version": {
+                            "type": "string",
+                            "description": "The version as published, another build of the one \
+                                            uploaded if they differ",
+                        },
+                        "sha256": {"type": "string", "nullable": true},
+                        "published_at": {"type": "string", "format": "date-time", "nullable": true},
+                        "file_present": {
+                            "type": "boolean",
+                            "description": "Whether the version's file is stored",
+                        },
+                        "hash_matches": {
+                            "type": "boolean",
+                            "description": "Whether the stored file is the one uploaded",
+                        },
+                        "in_progress": {
+                            "type": "boolean",
+                            "description": "Whether another upload of the version is still \
+                                            storing its file",
+                        },
+                    },
+                },
+                "Change": {
+                    "type": "object",
+                    "required": ["seq", "action", "id", "version", "timestamp"],
+                    "properties": {
+                        "seq": {"type": "integer"},
+                        "action": {
+                            "type": "string",
+                            "enum": ["upload", "delete", "yank", "metadata"],
+                        },
+                        "id": {"type": "string"},
+                        "version": {"type": "string"},
+                        "timestamp": {
+                            "type": "integer",
+                            "description": "Unix time of the change",
+                        },
+                    },
+                },
+                "Popular": {
+                    "type": "object",
+                    "required": ["id", "downloads", "all_time_downloads"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "downloads": {
+                            "type": "integer",
+                            "description": "Downloads within the window",
+                        },
+                        "all_time_downloads": {"type": "integer"},
+                    },
+                },
+                "Usage": {
+                    "type": "object",
+                    "required": ["versions", "bytes", "stored_bytes"],
+                    "properties": {
+                        "versions": {"type": "integer"},
+                        "bytes": {
+                            "type": "integer",
+                            "description": "Total size of the versions whose size is known",
+                        },
+                        "stored_bytes": {
+                            "type": "integer",
+                            "description": "What those versions take up in storage, less than `bytes` if some are compressed",
+                        },
+                    },
+                },
+                "Stats": {
+                    "allOf": [schema_ref("Usage")],
+                    "type": "object",
+                    "required": ["packages"],
+                    "properties": {
+                        "max_total_bytes": {"type": "integer", "nullable": true},
+                        "max_package_bytes": {"type": "integer", "nullable": true},
+                        "packages": {
+                            "type": "object",
+                            "additionalProperties": schema_ref("Usage"),
+                        },
+                    },
+                },
+                "RuntimeStats": {
+                    "type": "object",
+                    "properties": {
+                        "requests": {"type": "integer"},
+                        "bytes_served": {
+                            "type": "integer",
+                            "description": "Bytes of response bodies whose length was known",
+                        },
+                        "downloads": {"type": "integer"},
+                        "uploads": {"type": "integer"},
+                        "cache_hits": {"type": "integer"},
+                        "cache_misses": {"type": "integer"},
+                        "cache_entries": {
+                            "type": "integer",
+                            "description": "Files cached in memory. Uploads are only cached once \
+                                            downloaded, unless `cache-on-upload` is set",
+                        },
+                        "cache_bytes": {"type": "integer"},
+                        "cache_evictions": {
+                            "type": "integer",
+                            "description": "Files dropped from memory because they expired",
+                        },
+                        "response_cache_hits": {
+                            "type": "integer",
+                            "description": "List and resolve responses answered from memory",
+                        },
+                        "response_cache_misses": {"type": "integer"},
+                        "pending_writes": {
+                            "type": "integer",
+                            "description": "Download counts and key uses not written to the \
+                                database yet",
+                        },
+                        "flushed_writes": {
+                            "type": "integer",
+                            "description": "Statements run writing them",
+                        },
+                        "uptime_secs": {"type": "integer"},
+                    },
+                },
+                "PackageStats": {
+                    "allOf": [schema_ref("Usage")],
+                    "type": "object",
+                    "required": ["id", "downloads"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "downloads": {
+                            "type": "integer",
+                            "description": "Of every version",
+                        },
+                        "max_bytes": {"type": "integer", "nullable": true},
+                    },
+                },
+                "BatchEntry": {
+                    "type": "object",
+                    "required": ["id"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "req": {"type": "string"},
+                    },
+                },
+                "BatchResult": {
+                    "type": "object",
+                    "required": ["id", "version"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "version": {"type": "string", "nullable": true},
+                    },
+                },
+                "ReconcileReport": {
+                    "type": "object",
+                    "properties": {
+                        "added": {"type": "array", "items": schema_ref("Mod")},
+                        "pruned": {"type": "array", "items": schema_ref("Mod")},
+                        "skipped": {"type": "array", "items": {"type": "string"}},
+                    },
+                },
+                "NormalizeReport": {
+                    "type": "object",
+                    "required": ["merged", "conflicts"],
+                    "properties": {
+                        "merged": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["id", "moved"],
+                                "properties": {
+                                    "id": {"type": "string"},
+                                    "moved": {
+                                        "type": "array",
+                                        "items": schema_ref("Mod"),
+                                        "description": "Versions moved, with the ids they were \
+                                            under",
+                                    },
+                                },
+                            },
+                        },
+                        "conflicts": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["id", "ids", "versions"],
+                                "properties": {
+                                    "id": {"type": "string"},
+                                    "ids": {"type": "array", "items": {"type": "string"}},
+                                    "versions": {
+                                        "type": "array",
+                                        "items": {"type": "string"},
+                                        "description": "Versions under more than one of `ids`",
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+                "MaintenanceRequest": {
+                    "type": "object",
+                    "properties": {
+                        "vacuum": {"type": "boolean", "default": false},
+                        "integrity_check": {
+                            "type": "boolean",
+                            "default": false,
+                            "description": "Only available with SQLite",
+                        },
+                        "analyze": {"type": "boolean", "default": false},
+                    },
+                    "additionalProperties": false,
+                },
+                "MaintenanceReport": {
+                    "type": "object",
+                    "required": ["operations", "duration_ms"],
+                    "properties": {
+                        "operations": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["operation", "duration_ms"],
+                                "properties": {
+                                    "operation": {
+                                        "type": "string",
+                                        "enum": ["vacuum", "integrity_check", "analyze"],
+                                    },
+                                    "duration_ms": {"type": "integer"},
+                                    "result": {
+                                        "type": "array",
+                                        "items": {"type": "string"},
+                                        "description": "What `integrity_check` found, just \
+                                            `ok` if nothing's wrong",
+                                    },
+                                },
+                            },
+                        },
+                        "duration_ms": {"type": "integer"},
+                    },
+                },
+                "ImportReport": {
+                    "type": "object",
+                    "properties": {
+                        "added": {"type": "array", "items": schema_ref("Mod")},
+                        "skipped": {"type": "array", "items": schema_ref("Mod")},
+                        "missing": {
+                            "type": "array",
+                            "items": schema_ref("Mod"),
+                            "description": "Versions without a file in the archive",
+                        },
+                        "keys_not_restored": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Users whose keys were only exported hashed",
+                        },
+                    },
+                },
+                "BulkImportReport": {
+                    "type": "object",
+                    "properties": {
+                        "created": {"type": "array", "items": schema_ref("Mod")},
+                        "conflicted": {
+                            "type": "array",
+                            "items": schema_ref("NotImported"),
+                            "description": "Versions that were already published",
+                        },
+                        "failed": {"type": "array", "items": schema_ref("NotImported")},
+                        "stopped": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Why the import stopped before the last file",
+                        },
+                    },
+                },
+                "NotImported": {
+                    "type": "object",
+                    "required": ["name", "reason"],
+                    "properties": {
+                        "name": {"type": "string"},
+                        "reason": {"type": "string"},
+                    },
+                },
+                "AuditEntry": {
+                    "type": "object",
+                    "required": ["id", "created_at", "actor", "action"],
+                    "properties": {
+                        "id": {"type": "integer"},
+                        "created_at": {
+                            "type": "integer",
+                            "description": "Unix timestamp of the action",
+                        },
+                        "actor": {
+                            "type": "string",
+                            "description": "User of the publish key used, or `admin`",
+                        },
+                        "action": {"type": "string"},
+                        "package": {"type": "string", "nullable": true},
+                        "version": {"type": "string", "nullable": true},
+                        "ip": {"type": "string", "nullable": true},
+                    },
+                },
+                "KeyInfo": {
+                    "type": "object",
+                    "required": ["user", "disabled"],
+                    "properties": {
+                        "user": {"type": "string"},
+                        "scope": {"type": "string", "nullable": true},
+                        "expires_at": {"type": "integer", "nullable": true},
+                        "created_at": {"type": "integer", "nullable": true},
+                        "last_used_at": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Unix timestamp, to the hour",
+                        },
+                        "disabled": {
+                            "type": "boolean",
+                            "description": "Disabled for going unused for `key_expiry_days`",
+                        },
+                        "disabled_at": {"type": "integer", "nullable": true},
+                    },
+                },
+                "Migration": {
+                    "type": "object",
+                    "required": ["version", "description", "checksum", "state"],
+                    "properties": {
+                        "version": {"type": "integer"},
+                        "description": {"type": "string"},
+                        "checksum": {
+                            "type": "string",
+                            "description": "Hex SHA-384 of the migration, as applied if it was",
+                        },
+                        "installed_on": {"type": "string", "nullable": true},
+                        "state": {
+                            "type": "string",
+                            "enum": ["applied", "pending", "modified", "unknown"],
+                            "description": "`modified` if applied from a different file than \
+                                the one built in, `unknown` if applied by a newer build",
+                        },
+                    },
+                },
+                "VerifyStatus": verify_status(),
+                "VisibilityState": {
+                    "type": "object",
+                    "required": ["visibility"],
+                    "properties": {
+                        "visibility": {"type": "string", "enum": ["public", "private"]},
+                    },
+                },
+                "Alias": {
+                    "type": "object",
+                    "required": ["from", "to"],
+                    "properties": {
+                        "from": {"type": "string", "description": "Old package id"},
+                        "to": {"type": "string", "description": "New package id"},
+                    },
+                },
+                "Block": {
+                    "type": "object",
+                    "required": ["id", "reason"],
+                    "properties": {
+                        "id": {"type": "string"},
+                        "reason": {"type": "string"},
+                    },
+                },
+                "Artifact": {
+                    "type": "object",
+                    "required": ["name", "download_url"],
+                    "properties": {
+                        "name": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Absent for the version's own file",
+                        },
+                        "size": {"type": "integer", "nullable": true},
+                        "sha256": {"type": "string", "nullable": true},
+                        "created_at": {"type": "integer", "nullable": true},
+                        "download_url": {"type": "string"},
+                    },
+                },
+                "ReadOnlyState": {
+                    "type": "object",
+                    "required": ["enabled"],
+                    "properties": {
+                        "enabled": {"type": "boolean"},
+                    },
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": {
+                        "error": {"type": "string"},
+                        "resource": {
+                            "type": "string",
+                            "description": "What wasn't found",
+                        },
+                        "parameter": {
+                            "type": "string",
+                            "description": "Query parameter that's malformed",
+                        },
+                        "reason": {
+                            "type": "string",
+                            "description": "Why the request is malformed, or why a package \
+                                            was blocked",
+                        },
+                        "retry_after": {
+                            "type": "integer",
+                            "description": "Seconds to wait before trying again",
+                        },
+                        "allow": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Methods allowed on the path",
+                        },
+                        "quota": {
+                            "type": "string",
+                            "enum": ["package", "total"],
+                            "description": "Storage quota an upload would go over",
+                        },
+                        "limit": {
+                            "type": "integer",
+                            "description": "Bytes the quota allows, or versions of a package \
+                                            that can be published within an hour",
+                        },
+                        "count": {
+                            "type": "integer",
+                            "description": "Versions of the package published within the last \
+                                            hour",
+                        },
+                        "to": {
+                            "type": "string",
+                            "description": "Id a package was renamed to",
+                        },
+                        "encoding": {
+                            "type": "string",
+                            "description": "`Content-Encoding` a body was refused for",
+                        },
+                        "detail": {
+                            "type": "string",
+                            "description": "What a write clashed with",
+                        },
+                        "token": {
+                            "type": "string",
+                            "description": "One-time token confirming a delete",
+                        },
+                        "expires_in": {
+                            "type": "integer",
+                            "description": "Seconds the token can be used for",
+                        },
+                        "earliest": {
+                            "type": "integer",
+                            "description": "Sequence number of the earliest change still kept",
+                        },
+                        "resync": {
+                            "type": "string",
+                            "description": "Where to fetch everything from again",
+                        },
+                    },
+                },
+            },
+            "responses": {
+                "TooManyRequests": error("Rate limited or locked out"),
+                "MethodNotAllowed": error("Method not allowed on the path"),
+                "ServiceUnavailable": error("The database is busy"),
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({"application/json": {"schema": schema}})
+}
+
+/// A list of strings, as JSON or a line each in plain text
+fn list_content() -> Value {
+    json!({
+        "application/json": {"schema": {"type": "array", "items": {"type": "string"}}},
+        "text/plain": {"schema": {"type": "string"}},
+    })
+}
+
+fn binary() -> Value {
+    json!({"type": "string", "format": "binary"})
+}
+
+fn error(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": json_content(schema_ref("Error")),
+    })
+}
+
+fn resolved_id() -> Value {
+    json!({
+        "description": "Id the package was renamed to, when requested under its old id",
+        "schema": {"type": "string"},
+    })
+}
+
+fn etag() -> Value {
+    json!({
+        "description": "Changes when the package, or any package for the list, changes. Only \
+                        sent for public packages, not for versions found upstream",
+        "schema": {"type": "string"},
+    })
+}
+
+fn cache_control() -> Value {
+    json!({
+        "description": "`max-age` of the index's own cache of the response, sent along with \
+                        `ETag`",
+        "schema": {"type": "string"},
+    })
+}
+
+fn read_only() -> Value {
+    error("The index is read-only, or the database is busy")
+}
+
+fn query(name: &str, kind: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "schema": {"type": kind},
+    })
+}
+
+/// Schema of `/admin/verify` statuses, kept apart since it's deeply nested
+fn verify_status() -> Value {
+    json!({
+        "type": "object",
+        "required": ["running", "checked", "total", "report"],
+        "properties": {
+            "running": {"type": "boolean"},
+            "started_at": {"type": "integer", "nullable": true},
+            "finished_at": {"type": "integer", "nullable": true},
+            "checked": {"type": "integer"},
+            "total": {"type": "integer"},
+            "error": {
+                "type": "string",
+                "nullable": true,
+                "description": "Why the run stopped early",
+            },
+            "report": {
+                "type": "object",
+                "properties": {
+                    "mismatched": {
+                        "type": "array",
+                        "items": {
+                            "allOf": [schema_ref("Mod")],
+                            "type": "object",
+                            "properties": {
+                                "expected": {"type": "string"},
+                                "actual": {"type": "string"},
+                            },
+                        },
+                    },
+                    "missing": {"type": "array", "items": schema_ref("Mod")},
+                    "unhashed": {
+                        "type": "array",
+                        "items": schema_ref("Mod"),
+                        "description": "Versions uploaded before hashes were recorded",
+                    },
+                    "quarantined": {"type": "array", "items": schema_ref("Mod")},
+                },
+            },
+        },
+    })
+}
+
+fn package() -> Value {
+    json!({
+        "name": "package",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    })
+}
+
+fn version() -> Value {
+    json!({
+        "name": "version",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    })
+}
@@ -0,0 +1,48 @@
+use semver::Version;
+use serde::Deserialize;
+
+use crate::errors::ApiError;
+
+/// `qpm.shared.json`, what qpm clients publish
+#[derive(Debug, Deserialize)]
+struct SharedPackageConfig {
+    config: PackageConfig,
+}
+
+/// `qpm.json`, which older clients publish on its own
+#[derive(Debug, Deserialize)]
+struct PackageConfig {
+    info: PackageInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct PackageInfo {
+    id: String,
+    version: Version,
+}
+
+/// Checks a package config uploaded through the qpm routes describes the version it's stored as
+pub fn check_config(id: &str, ver: &Version, contents: &[u8]) -> Result<(), ApiError> {
+    let info = serde_json::from_slice::<SharedPackageConfig>(contents)
+        .map(|shared| shared.config.info)
+        .or_else(|_| serde_json::from_slice::<PackageConfig>(contents).map(|c| c.info))
+        .map_err(|e| bad_request(format!("not a qpm package config: {}", e)))?;
+
+    if !info.id.eq_ignore_ascii_case(id) {
+        return Err(bad_request(format!(
+            "the package config is for {} instead of {}",
+            info.id, id
+        )));
+    }
+    if &info.version != ver {
+        return Err(bad_request(format!(
+            "the package config is for version {} instead of {}",
+            info.version, ver
+        )));
+    }
+    Ok(())
+}
+
+fn bad_request(reason: String) -> ApiError {
+    ApiError::BadRequest { reason }
+}
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
+use warp::Filter;
+
+use crate::{
+    config::Config,
+    forwarded::{ClientInfo, client_info},
+};
+
+/// Peer address of the connection a request came from, inserted as a request extension by the
+/// server
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Resolves the IP of the client making the request, see [`client_info`]
+pub fn client_ip(
+    config: &'static Config,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Send + Sync + Clone + 'static {
+    client_info(config).map(|client: ClientInfo| client.ip)
+}
+
+/// Tracks failed authentication attempts per client IP and locks clients out after too many
+pub struct AuthLimiter {
+    max_failures: u32,
+    window: Duration,
+    failures: RwLock<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl AuthLimiter {
+    pub fn new(max_failures: u32, window: Duration) -> AuthLimiter {
+        AuthLimiter {
+            max_failures,
+            window,
+            failures: Default::default(),
+        }
+    }
+
+    /// Returns how long the client has to wait before trying again if it's locked out
+    pub async fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let failures = self.failures.read().await;
+        let (count, since) = failures.get(&ip)?;
+
+        let elapsed = since.elapsed();
+        if *count >= self.max_failures && elapsed < self.window {
+            Some(self.window - elapsed)
+        } else {
+            None
+        }
+    }
+
+    pub async fn record_failure(&self, ip: IpAddr) {
+        let mut failures = self.failures.write().await;
+        // Forget about clients whose window expired so the map doesn't grow forever
+        failures.retain(|_, (_, since)| since.elapsed() < self.window);
+
+        failures
+            .entry(ip)
+            .and_modify(|(count, _)| *count += 1)
+            .or_insert((1, Instant::now()));
+    }
+}
+
+/// Token bucket rate limiter keyed by client IP
+pub struct RateLimiter {
+    /// Tokens regained per second
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one is available
+    pub async fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().await;
+        let now = Instant::now();
+        let (tokens, last) = buckets.entry(ip).or_insert((self.burst, now));
+
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / self.rate))
+        } else {
+            // The bucket never refills, so there's no telling when to come back
+            Err(Duration::from_secs(60))
+        }
+    }
+
+    /// Forgets about clients whose bucket has refilled, since they're indistinguishable from
+    /// new ones
+    pub async fn sweep(&self) {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, (tokens, last)| {
+            *tokens + last.elapsed().as_secs_f64() * self.rate < self.burst
+        });
+    }
+
+    pub fn spawn_sweeper(&'static self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                self.sweep().await;
+            }
+        })
+    }
+}
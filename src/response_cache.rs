@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use warp::http::{HeaderMap, HeaderValue};
+
+/// What a cached response answered, queries are normalized so the same question asked in a
+/// different way finds the same entry
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    List {
+        game: Option<String>,
+        page: Option<u32>,
+        per_page: Option<u32>,
+    },
+    Resolve {
+        id: String,
+        req: String,
+        game: Option<String>,
+        loader: Option<String>,
+        limit: usize,
+        offset: usize,
+        /// Whether the versions are answered in an array
+        array: bool,
+    },
+}
+
+impl CacheKey {
+    /// Package whose changes make the response stale, every change does for the list
+    fn package(&self) -> Option<&str> {
+        match self {
+            CacheKey::List { .. } => None,
+            CacheKey::Resolve { id, .. } => Some(id),
+        }
+    }
+}
+
+/// A JSON body before it's compressed for the client, with the headers that went with it
+#[derive(Debug, Clone)]
+pub struct CachedBody {
+    pub body: Bytes,
+    pub headers: HeaderMap,
+}
+
+struct Entry {
+    cached: CachedBody,
+    generation: u64,
+    since: Instant,
+}
+
+/// Counts changes to the index, a package's generation is that of its last change
+#[derive(Default)]
+struct Generations {
+    last: u64,
+    /// Changes that could have touched any package
+    everything: u64,
+    packages: HashMap<String, u64>,
+}
+
+impl Generations {
+    fn of(&self, package: Option<&str>) -> u64 {
+        match package {
+            None => self.last,
+            Some(id) => self
+                .packages
+                .get(id)
+                .copied()
+                .unwrap_or_default()
+                .max(self.everything),
+        }
+    }
+}
+
+/// Responses of the list and resolve routes, kept until the package they're about changes or
+/// they get older than the TTL. Only changes made through this instance are seen, the TTL bounds
+/// how stale answers get when others share the database
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    /// Changes with every start, so ETags handed out before a restart don't match
+    instance: String,
+    /// Whether other instances may change the database too
+    shared: bool,
+    generations: Mutex<Generations>,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize, shared: bool) -> Self {
+        let mut instance = [0; 4];
+        getrandom::getrandom(&mut instance).expect("no randomness available");
+        Self {
+            ttl,
+            max_entries,
+            instance: hex::encode(instance),
+            shared,
+            generations: Default::default(),
+            entries: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    /// How many responses were answered from the cache, and how many weren't
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Generation responses about the package are answered at, or the list's if there's none
+    pub fn generation(&self, package: Option<&str>) -> u64 {
+        self.generations.lock().unwrap().of(package)
+    }
+
+    /// ETag of responses made at the generation. When the database is shared it also rolls over
+    /// with every TTL, since changes other instances make aren't counted
+    pub fn etag(&self, generation: u64) -> HeaderValue {
+        let etag = if self.shared {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let window = now.as_secs() / self.ttl.as_secs().max(1);
+            format!("\"{}-{}-{}\"", self.instance, generation, window)
+        } else {
+            format!("\"{}-{}\"", self.instance, generation)
+        };
+        HeaderValue::from_str(&etag).expect("ETags are hex and digits")
+    }
+
+    /// `Cache-Control` of cached responses, clients can keep them as long as we do
+    pub fn cache_control(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!("max-age={}", self.ttl.as_secs()))
+            .expect("max-age is digits")
+    }
+
+    /// The response to the query if it was made at the given generation and isn't too old
+    pub fn get(&self, key: &CacheKey, generation: u64) -> Option<CachedBody> {
+        let entries = self.entries.lock().unwrap();
+        let found = entries
+            .get(key)
+            .filter(|e| e.generation == generation && e.since.elapsed() < self.ttl)
+            .map(|e| e.cached.clone());
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Keeps a response made at the given generation, unless something changed since
+    pub fn insert(&self, key: CacheKey, generation: u64, cached: CachedBody) {
+        if self.max_entries == 0 || self.generation(key.package()) != generation {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let generations = self.generations.lock().unwrap();
+            entries.retain(|key, e| {
+                e.generation == generations.of(key.package()) && e.since.elapsed() < self.ttl
+            });
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.since)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                cached,
+                generation,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    /// Makes responses about the package stale, along with the list. Without a package every
+    /// response is
+    pub fn invalidate(&self, package: Option<&str>) {
+        let mut generations = self.generations.lock().unwrap();
+        generations.last += 1;
+        let last = generations.last;
+        match package {
+            Some(id) => {
+                generations.packages.insert(id.to_owned(), last);
+            }
+            None => {
+                generations.everything = last;
+                generations.packages.clear();
+            }
+        }
+    }
+}
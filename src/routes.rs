@@ -1,35 +1,414 @@
 use crate::{
-    config::Config,
-    db::{Mod, PublishKey},
-    errors::TryExt,
+    archive::{Archive, TarReader},
+    backup::Backups,
+    blocklist::Blocklist,
+    bulk_import::{BulkImportReport, NotImported, Source, parse_name},
+    compression::{Encoding, ZIP_MAGIC, accept_encoding, content_encoding, gunzip_body},
+    config::{AdminRole, Config},
+    db::{
+        Alias, Approval, Artifact, AuditEntry, Block, Change, GameVersion, Metadata,
+        MigrationStatus, Mod, PackageId, Popular, PublishKey, Published, Resolved, Usage,
+        Visibility, unix_now,
+    },
+    errors::{ApiError, TryExt},
     file_repo::FileRepo,
+    forwarded::{ClientInfo, client_info},
+    http_client::uri_encode,
+    janitor::Janitor,
+    logging::{RequestId, record_response, request_id, request_span},
+    maintenance::MaintenanceRequest,
+    mirror::Mirror,
+    ratelimit::{AuthLimiter, RateLimiter, client_ip},
+    response_cache::{CacheKey, CachedBody, ResponseCache},
+    state::AppState,
+    storage::{Storage, file_key, is_valid_artifact_name},
+    types::version_string,
+    verify::Verifier,
 };
-use bytes::Bytes;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{StreamExt, TryFutureExt, TryStreamExt, future, stream::BoxStream};
 use semver::{Version, VersionReq};
-use serde::Deserialize;
-use sqlx::SqlitePool;
-use tokio::fs;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::AnyPool;
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    fmt::Display,
+    io::ErrorKind,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tower_service::Service;
+use tracing::{Span, field::Empty};
 use warp::{
-    http::{HeaderValue, StatusCode, header::CONTENT_TYPE},
     Filter, Rejection, Reply,
+    cors::Cors,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+        header::{
+            AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG,
+            LAST_MODIFIED, LINK, LOCATION, VARY,
+        },
+    },
+    hyper::body::Body,
+    multipart::FormData,
+    path::FullPath,
+    reply::Response,
 };
 
-#[inline]
-fn one() -> usize {
-    1
-}
-
 #[inline]
 fn any_version() -> VersionReq {
     VersionReq::STAR
 }
 
-#[derive(Debug, Deserialize)]
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const X_RESOLVED_ID: HeaderName = HeaderName::from_static("x-resolved-id");
+const X_TOTAL_MATCHES: HeaderName = HeaderName::from_static("x-total-matches");
+const X_RESOLVED_VERSION: HeaderName = HeaderName::from_static("x-resolved-version");
+
+/// Largest number of packages that can be resolved in a single batch
+const MAX_BATCH_SIZE: usize = 100;
+/// Largest body a batch resolve may send, plenty for a full batch with long ids and ranges
+const MAX_BATCH_BODY_BYTES: u64 = 64 * 1024;
+
+/// Largest page size the package list will return
+const MAX_PER_PAGE: u32 = 100;
+const DEFAULT_PER_PAGE: u32 = 50;
+
+/// Largest number of entries the feed will return
+const MAX_FEED_SIZE: u32 = 100;
+const DEFAULT_FEED_SIZE: u32 = 20;
+
+/// Largest readme that can be attached to a version, in bytes
+const MAX_README_SIZE: usize = 256 * 1024;
+
+/// Largest `Authorization` header looked at, longer ones are refused before any key lookup
+const MAX_AUTH_HEADER_LEN: usize = 1024;
+/// Largest publish key or user name that can be created, in bytes
+const MAX_KEY_LEN: usize = 256;
+
+/// Largest number of packages `/popular` ranks at once
+const MAX_POPULAR_SIZE: u32 = 100;
+const DEFAULT_POPULAR_SIZE: u32 = 20;
+/// Window `/popular` ranks over when none is given, a week
+const DEFAULT_POPULAR_WINDOW: u64 = 7 * 24;
+
+/// Largest version number the database can store, its columns are signed
+const MAX_STORED_VERSION_NUMBER: u64 = i64::MAX as u64;
+
+/// How long clients can reuse the package list before checking it again
+const LIST_CACHE_CONTROL: &str = "max-age=60";
+
+/// Largest number of changes `/changes` returns at once
+const MAX_CHANGES_SIZE: u32 = 1000;
+
+/// Largest number of audit log entries returned at once
+const MAX_AUDIT_SIZE: u32 = 100;
+const DEFAULT_AUDIT_SIZE: u32 = 50;
+
+#[derive(Debug)]
 struct ResolveQuery {
-    #[serde(default = "any_version")]
     req: VersionReq,
-    #[serde(default = "one")]
+    /// Only versions made for this game version
+    game: Option<String>,
+    /// Only versions made for this modloader, or for no loader in particular
+    loader: Option<String>,
+    /// 1 is the latest version, 0 all of them and anything else that many
     limit: usize,
+    offset: usize,
+    shape: Shape,
+}
+
+/// How resolve answers, the latest version on its own or versions in an array
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Object,
+    Array,
+}
+
+impl ResolveQuery {
+    /// Parses the query by hand so a bad parameter can be named, unknown ones are only refused
+    /// with `strict=true`
+    fn parse(params: Vec<(String, String)>, max_limit: usize) -> Result<Self, ApiError> {
+        let mut query = ResolveQuery {
+            req: any_version(),
+            game: None,
+            loader: None,
+            limit: 1,
+            offset: 0,
+            shape: Shape::Object,
+        };
+        let mut array = false;
+        let mut strict = false;
+        let mut unknown = None;
+
+        for (name, value) in params {
+            match name.as_str() {
+                "req" => {
+                    query.req = VersionReq::parse(&value).map_err(|e| invalid("req", e))?;
+                }
+                "game" => query.game = Some(value),
+                "loader" => {
+                    query.loader = Some(loader_name(&value).map_err(|e| invalid("loader", e))?)
+                }
+                "limit" => query.limit = value.parse().map_err(|e| invalid("limit", e))?,
+                "offset" => query.offset = value.parse().map_err(|e| invalid("offset", e))?,
+                "shape" => {
+                    array = match value.as_str() {
+                        "array" => true,
+                        "auto" => false,
+                        _ => return Err(invalid("shape", "expected array or auto")),
+                    }
+                }
+                "strict" => strict = value.parse().map_err(|e| invalid("strict", e))?,
+                _ => unknown = unknown.or(Some(name)),
+            }
+        }
+
+        if let Some(name) = unknown.filter(|_| strict) {
+            return Err(invalid(name, "unknown parameter"));
+        }
+        // 0 is all versions, anything past the cap is asked for explicitly that way
+        if query.limit > max_limit {
+            return Err(invalid(
+                "limit",
+                format!("at most {}, or 0 for every version", max_limit),
+            ));
+        }
+        // Only a limit of 1 answers with a lone object, unless an array is asked for
+        if array || query.limit != 1 {
+            query.shape = Shape::Array;
+        }
+        Ok(query)
+    }
+}
+
+fn invalid(parameter: impl Into<String>, reason: impl Display) -> ApiError {
+    ApiError::Invalid {
+        field: parameter.into(),
+        message: reason.to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchEntry {
+    id: PackageId,
+    #[serde(default = "any_version")]
+    req: VersionReq,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    id: String,
+    version: Option<Version>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    page: Option<u32>,
+    per_page: Option<u32>,
+    /// Only packages with a version for this game version
+    game: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QuarantineQuery {
+    #[serde(default)]
+    quarantine: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UploadQuery {
+    #[serde(default)]
+    force: bool,
+    /// Only taken into account when the package is first published
+    #[serde(default)]
+    private: bool,
+    /// Comma separated game versions the version is made for, each must be known
+    game: Option<String>,
+    /// Modloader the version is made for, as the metadata's `loader`
+    loader: Option<String>,
+    /// Versions of the loader the version works with, as the metadata's `loader_version_req`
+    loader_version_req: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyQuery {
+    /// Whether to move mismatched files out of the way
+    #[serde(default)]
+    quarantine: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedQuery {
+    package: Option<String>,
+    limit: Option<u32>,
+}
+
+/// A version as listed in `/index.json`
+#[derive(Debug, Serialize)]
+struct IndexVersion {
+    #[serde(with = "version_string")]
+    version: Version,
+    size: Option<i64>,
+    sha256: Option<String>,
+    downloads: i64,
+    created_at: Option<i64>,
+    download_url: String,
+    #[serde(flatten)]
+    meta: Metadata,
+}
+
+/// What was recorded for an upload, returned to the publisher
+#[derive(Debug, Serialize)]
+struct Uploaded {
+    id: String,
+    #[serde(with = "version_string")]
+    version: Version,
+    size: Option<i64>,
+    sha256: Option<String>,
+    created_at: Option<i64>,
+    download_url: String,
+}
+
+impl Uploaded {
+    fn new(p: Published, base: &str) -> Self {
+        Self {
+            download_url: download_url(&p.id, &p.version, base),
+            id: p.id,
+            version: p.version,
+            size: p.size,
+            sha256: p.sha256,
+            created_at: p.created_at,
+        }
+    }
+}
+
+/// Where the index is reached from, `public-base-url` if set and otherwise the scheme and host a
+/// trusted proxy forwarded. Empty if neither is known, which leaves URLs relative
+fn base_url(config: &Config, client: Option<&ClientInfo>) -> String {
+    match &config.public_base_url {
+        Some(base) => base.trim_end_matches('/').to_owned(),
+        None => client.and_then(ClientInfo::base_url).unwrap_or_default(),
+    }
+}
+
+/// Where a version can be downloaded from, relative to `base`
+fn download_url(id: &str, ver: &Version, base: &str) -> String {
+    format!("{}/{}/{}", base, uri_encode(id), ver)
+}
+
+/// Where a version's artifact can be downloaded from
+fn artifact_url(id: &str, ver: &Version, name: &str, base: &str) -> String {
+    format!("{}/artifacts/{}", download_url(id, ver, base), name)
+}
+
+/// A file published with a version, for `/{package}/{version}/artifacts`
+#[derive(Debug, Serialize)]
+struct ArtifactEntry {
+    /// Absent for the version's own file
+    name: Option<String>,
+    size: Option<i64>,
+    sha256: Option<String>,
+    created_at: Option<i64>,
+    download_url: String,
+}
+
+impl ArtifactEntry {
+    fn new(id: &str, ver: &Version, artifact: Artifact, base: &str) -> Self {
+        Self {
+            download_url: artifact_url(id, ver, &artifact.name, base),
+            name: Some(artifact.name),
+            size: Some(artifact.size),
+            sha256: Some(artifact.sha256),
+            created_at: Some(artifact.created_at),
+        }
+    }
+}
+
+/// Disk usage of the whole index, for `/stats`
+#[derive(Debug, Serialize)]
+struct Stats {
+    #[serde(flatten)]
+    total: Usage,
+    max_total_bytes: Option<u64>,
+    max_package_bytes: Option<u64>,
+    packages: BTreeMap<String, Usage>,
+}
+
+/// Disk usage of a package, for `/{package}/stats`
+#[derive(Debug, Serialize)]
+struct PackageStats {
+    id: String,
+    #[serde(flatten)]
+    usage: Usage,
+    /// Of every version, including those not written to the database yet
+    downloads: i64,
+    max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PopularQuery {
+    /// Like `24h` or `7d`
+    window: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangesQuery {
+    /// Sequence number of the last change already seen, everything if absent
+    #[serde(default)]
+    since: i64,
+    limit: Option<u32>,
+}
+
+/// A page of `/changes`
+#[derive(Debug, Serialize)]
+struct Changes {
+    changes: Vec<Change>,
+    /// Sequence number of the latest change, to continue from once a page isn't full
+    latest: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    limit: Option<u32>,
+    before: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReconcileQuery {
+    #[serde(default)]
+    prune: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ReconcileReport {
+    added: Vec<Mod>,
+    pruned: Vec<Mod>,
+    skipped: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ReadOnlyState {
+    enabled: bool,
+}
+
+/// Body of `/admin/cache/purge`, everything is purged without an id
+#[derive(Debug, Default, Deserialize)]
+struct PurgeBody {
+    id: Option<PackageId>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct VisibilityState {
+    visibility: Visibility,
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,207 +417,3966 @@ struct OptPublishKey {
     user: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+struct EnableKey {
+    user: String,
+}
+
 pub fn handler(
-    pool: &'static SqlitePool,
-    config: &'static Config,
-    file_repo: &'static FileRepo,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Send + Sync + Clone + 'static {
-    // GET /
+    state: &'static AppState,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Send + Sync + Clone + 'static {
+    let config = state.config;
+    let pool = state.pool;
+    let storage: &'static dyn Storage = state.file_repo;
+    let limiter = &state.auth_limiter;
+    let rate_limiter = state.rate_limiter.as_ref();
+    let read_only = &state.read_only;
+    let blocklist = &state.blocklist;
+    let response_cache = &state.response_cache;
+    let backups = state.backups.as_ref();
+    let janitor = state.janitor.as_ref();
+    let verifier = &state.verifier;
+
+    // GET / Accept: application/json or text/plain
     let list = warp::path::end()
         .and(warp::get())
-        .and_then(move || list(pool));
+        .and(warp::query())
+        .and(warp::header::optional("If-None-Match"))
+        .and(list_format())
+        .and(accept_encoding(config))
+        .and_then(move |query, if_none_match, format, encoding| {
+            list(query, if_none_match, format, encoding, state).map_err(ApiError::into_rejection)
+        });
+
+    // GET /feed.atom?package&limit
+    let feed = warp::path!("feed.atom")
+        .and(warp::get())
+        .and(warp::query())
+        .and_then(move |query| feed(query, state).map_err(ApiError::into_rejection));
+
+    // GET /index.json
+    let index = warp::path!("index.json")
+        .and(warp::get())
+        .and(warp::header::optional("If-None-Match"))
+        .and(accept_encoding(config))
+        .and_then(move |if_none_match, encoding| {
+            index(if_none_match, encoding, state).map_err(ApiError::into_rejection)
+        });
+
+    // GET /stats
+    let stats = warp::path!("stats")
+        .and(warp::get())
+        .and_then(move || stats(pool, config).map_err(ApiError::into_rejection));
 
-    // GET /{package}
-    let resolve = warp::path!(String)
+    // GET /popular?window&limit
+    let popular = warp::path!("popular")
         .and(warp::get())
         .and(warp::query())
-        .and_then(move |id, query| resolve(id, query, pool));
+        .and_then(move |query| popular(query, state).map_err(ApiError::into_rejection));
+
+    // GET /changes?since&limit
+    let changes = warp::path!("changes")
+        .and(warp::get())
+        .and(warp::query())
+        .and_then(move |query| changes(query, pool).map_err(ApiError::into_rejection));
+
+    // GET /stats/runtime
+    let runtime_stats = warp::path!("stats" / "runtime")
+        .and(warp::get())
+        .then(move || async move { warp::reply::json(&state.runtime_stats().await) });
+
+    // GET /openapi.json
+    let openapi = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&*crate::openapi::SPEC));
+
+    // qpackages compatible routes, for qpm clients
+    let qpm_enabled = warp::any()
+        .and_then(move || async move {
+            if config.qpm_compat {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one();
+    // GET /qpm/{package}
+    let qpm_versions = warp::path!("qpm" / PackageId)
+        .and(qpm_enabled)
+        .and(warp::get())
+        .and(viewer(state))
+        .and(accept_encoding(config))
+        .and_then(move |id: PackageId, viewer, encoding| {
+            // qpm only reads JSON
+            versions(id.into(), false, viewer, ListFormat::Json, encoding, pool)
+                .map_err(ApiError::into_rejection)
+        });
+    // GET /qpm/{package}/{version}
+    let qpm_config = warp::path!("qpm" / PackageId / Version)
+        .and(qpm_enabled)
+        .and(warp::get())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, ver, viewer| {
+            qpm_config(id.into(), ver, viewer, pool, storage).map_err(ApiError::into_rejection)
+        });
+    // POST /qpm/{package}/{version} {config}
+    let qpm_upload = writing(
+        state,
+        warp::path!("qpm" / PackageId / Version)
+            .and(qpm_enabled)
+            .and(warp::post())
+            .and(qpm_auth(state))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |id: PackageId, ver: Version, actor, contents: Bytes| {
+                async move {
+                    crate::qpm::check_config(&id, &ver, &contents)?;
+                    let query = UploadQuery {
+                        force: false,
+                        private: false,
+                        game: None,
+                        loader: None,
+                        loader_version_req: None,
+                    };
+                    upload(id.into(), ver, actor, query, contents, None, state).await
+                }
+                .map_err(ApiError::into_rejection)
+            }),
+    );
+    let qpm = qpm_versions.or(qpm_config).or(qpm_upload);
+
+    // GET|HEAD /{package}
+    let resolve = warp::path!(PackageId)
+        .and(get_or_head())
+        .and(
+            warp::query::<Vec<(String, String)>>().and_then(move |params| async move {
+                ResolveQuery::parse(params, config.max_resolve_limit)
+                    .map_err(ApiError::into_rejection)
+            }),
+        )
+        .and(viewer(state))
+        .and(warp::header::optional("If-None-Match"))
+        .and(raw_header("If-Modified-Since"))
+        .and(accept_encoding(config))
+        .and_then(
+            move |id: PackageId,
+                  head,
+                  query,
+                  viewer,
+                  if_none_match,
+                  if_modified_since,
+                  encoding| {
+                resolve(
+                    id.into(),
+                    head,
+                    query,
+                    viewer,
+                    if_none_match,
+                    if_modified_since,
+                    encoding,
+                    state,
+                )
+                .map_err(ApiError::into_rejection)
+            },
+        );
+
+    // POST /resolve [{id, req}]
+    let batch_resolve = warp::path!("resolve")
+        .and(warp::post())
+        .and(viewer(state))
+        .and(accept_encoding(config))
+        .and(limited_body(MAX_BATCH_BODY_BYTES))
+        .and_then(move |viewer, encoding, contents| {
+            batch_resolve(contents, viewer, encoding, pool).map_err(ApiError::into_rejection)
+        });
+
+    // GET|HEAD /{package}/versions Accept: application/json or text/plain
+    let versions = warp::path!(PackageId / "versions")
+        .and(get_or_head())
+        .and(viewer(state))
+        .and(list_format())
+        .and(accept_encoding(config))
+        .and_then(move |id: PackageId, head, viewer, format, encoding| {
+            versions(id.into(), head, viewer, format, encoding, pool)
+                .map_err(ApiError::into_rejection)
+        });
+
+    // GET /{package}/stats
+    let package_stats = warp::path!(PackageId / "stats")
+        .and(warp::get())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, viewer| {
+            package_stats(id.into(), viewer, state).map_err(ApiError::into_rejection)
+        });
+
+    // POST /{package}/visibility {visibility}
+    let set_visibility = writing(
+        state,
+        warp::path!(PackageId / "visibility")
+            .and(warp::post())
+            .and(auth(state))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |id: PackageId, actor, contents| {
+                set_visibility(id.into(), actor, contents, response_cache, pool)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
 
-    // GET /{package}/{version}
-    let download = warp::path!(String / Version)
+    // GET|HEAD /{package}/{version} with Accept: application/json
+    let version_info = warp::path!(PackageId / Version)
+        .and(get_or_head())
+        .and(accepts_json())
+        .and(viewer(state))
+        .and(accept_encoding(config))
+        .and_then(move |id: PackageId, ver, head, viewer, encoding| {
+            version_info(id.into(), ver, head, viewer, encoding, state)
+                .map_err(ApiError::into_rejection)
+        });
+    // GET|HEAD /{package}/{version}/info
+    let exact_info = warp::path!(PackageId / Version / "info")
+        .and(get_or_head())
+        .and(viewer(state))
+        .and(accept_encoding(config))
+        .and_then(move |id: PackageId, ver, head, viewer, encoding| {
+            exact_info(id.into(), ver, head, viewer, encoding, state)
+                .map_err(ApiError::into_rejection)
+        });
+    // GET|HEAD /{package}/{version}
+    let download = warp::path!(PackageId / Version)
+        .and(get_or_head())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, ver, head, viewer| {
+            download(id.into(), ver, head, viewer, state).map_err(ApiError::into_rejection)
+        });
+    // GET|HEAD /{package}/download?req&game&loader&offset
+    // After the exact download, though `download` never parses as a version anyway
+    let download_latest = warp::path!(PackageId / "download")
+        .and(get_or_head())
+        .and(
+            warp::query::<Vec<(String, String)>>().and_then(move |params| async move {
+                ResolveQuery::parse(params, config.max_resolve_limit)
+                    .map_err(ApiError::into_rejection)
+            }),
+        )
+        .and(viewer(state))
+        .and_then(move |id: PackageId, head, query, viewer| {
+            download_latest(id.into(), head, query, viewer, state).map_err(ApiError::into_rejection)
+        });
+    // POST /{package}/version {file} or multipart/form-data {file, metadata}
+    let upload = writing(
+        state,
+        warp::path!(PackageId / Version)
+            .and(warp::post())
+            .and(auth(state))
+            .and(writable(state))
+            .and(warp::query())
+            .and(upload_body(config))
+            .and_then(move |id: PackageId, ver, actor, query, (contents, meta)| {
+                upload(id.into(), ver, actor, query, contents, meta, state)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // PUT /{package}/{version}/meta {metadata}
+    let set_metadata = writing(
+        state,
+        warp::path!(PackageId / Version / "meta")
+            .and(warp::put())
+            .and(auth(state))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |id: PackageId, ver, actor, contents| {
+                set_metadata(id.into(), ver, actor, contents, response_cache, pool)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET|HEAD /{package}/{version}/readme
+    let readme = warp::path!(PackageId / Version / "readme")
+        .and(get_or_head())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, ver, head, viewer| {
+            readme(id.into(), ver, head, viewer, pool).map_err(ApiError::into_rejection)
+        });
+    // PUT /{package}/{version}/readme {markdown}
+    let set_readme = writing(
+        state,
+        warp::path!(PackageId / Version / "readme")
+            .and(warp::put())
+            .and(auth(state))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |id: PackageId, ver, actor, contents| {
+                set_readme(id.into(), ver, actor, contents, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET|HEAD /{package}/{version}/artifacts
+    let artifacts = warp::path!(PackageId / Version / "artifacts")
+        .and(get_or_head())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, ver, head, viewer| {
+            artifacts(id.into(), ver, head, viewer, pool, config).map_err(ApiError::into_rejection)
+        });
+    // GET|HEAD /{package}/{version}/artifacts/{name}
+    let artifact = warp::path!(PackageId / Version / "artifacts" / String)
+        .and(get_or_head())
+        .and(viewer(state))
+        .and_then(move |id: PackageId, ver, name, head, viewer| {
+            artifact(id.into(), ver, name, head, viewer, pool, storage)
+                .map_err(ApiError::into_rejection)
+        });
+    // POST /{package}/{version}/artifacts/{name} {file}
+    let upload_artifact = writing(
+        state,
+        warp::path!(PackageId / Version / "artifacts" / String)
+            .and(warp::post())
+            .and(auth(state))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |id: PackageId, ver, name, actor, contents| {
+                upload_artifact(id.into(), ver, name, actor, contents, pool, config, storage)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // DELETE /{package}/{version} If-Match: {sha256} X-Confirm-Delete: {token}
+    let delete = writing(
+        state,
+        warp::path!(PackageId / Version)
+            .and(warp::delete())
+            .and(auth_admin(AdminRole::Moderator, config, limiter))
+            .and(writable(state))
+            .and(warp::header::optional("If-Match"))
+            .and(raw_header("X-Confirm-Delete"))
+            .and_then(move |id: PackageId, ver, actor, if_match, confirm| {
+                delete(id.into(), ver, actor, if_match, confirm, state)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /publish_key {key}
+    let add_key = writing(
+        state,
+        warp::path!("publish_key")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                add_key(actor, contents, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET /publish_key
+    let list_keys = warp::path!("publish_key")
+        .and(warp::get())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and_then(move |_| list_keys(state).map_err(ApiError::into_rejection));
+    // GET /publish_key/verify
+    let verify_key = warp::path!("publish_key" / "verify")
+        .and(warp::get())
+        .and(auth(state))
+        .and_then(move |actor| verify_key(actor).map_err(ApiError::into_rejection));
+    // POST /publish_key/enable {user}
+    let enable_key = writing(
+        state,
+        warp::path!("publish_key" / "enable")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                enable_key(actor, contents, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET /publish_key/{user}/uploads?page&per_page
+    let key_uploads = warp::path!("publish_key" / String / "uploads")
         .and(warp::get())
-        .and_then(|id, ver| download(id, ver, file_repo));
-    // POST /{package}/version
-    let upload = warp::path!(String / Version)
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and(warp::query())
+        .and_then(move |user, _, query| {
+            key_uploads(user, query, pool).map_err(ApiError::into_rejection)
+        });
+    // POST /publish_key/{user}/uploads?quarantine=true
+    let quarantine_key = writing(
+        state,
+        warp::path!("publish_key" / String / "uploads")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::query())
+            .and_then(move |user, actor, query| {
+                quarantine_key(user, actor, query, response_cache, pool)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /delete_key {key}
+    let delete_key = writing(
+        state,
+        warp::path!("delete_key")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                delete_key(actor, contents, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/reconcile?prune
+    let reconcile = warp::path!("admin" / "reconcile")
+        .and(warp::post())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and(warp::query())
+        .and_then(move |actor, query| {
+            reconcile(actor, query, response_cache, pool, storage).map_err(ApiError::into_rejection)
+        });
+    // POST /admin/maintenance {vacuum, integrity_check, analyze}
+    let maintenance = warp::path!("admin" / "maintenance")
         .and(warp::post())
-        .and(auth(pool))
+        .and(auth_admin(AdminRole::Full, config, limiter))
         .and(warp::body::bytes())
-        .and_then(move |id, ver, contents| upload(id, ver, contents, pool, file_repo));
-    // DELETE /{package}/{version}
-    let delete = warp::path!(String / Version)
-        .and(warp::delete())
-        .and(auth_admin(config))
-        .and_then(move |id, ver| delete(id, ver, pool, config));
-    // POST /publish_key {key}
-    let add_key = warp::path!("publish_key")
+        .and_then(move |actor, contents| {
+            maintenance(actor, contents, state).map_err(ApiError::into_rejection)
+        });
+    // POST /admin/normalize
+    let normalize = writing(
+        state,
+        warp::path!("admin" / "normalize")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and_then(move |actor| {
+                normalize(actor, response_cache, pool, storage).map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET /admin/export
+    let export = warp::path!("admin" / "export")
+        .and(warp::get())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and_then(move |_| {
+            export(&config.downloads_path, pool, state.file_repo).map_err(ApiError::into_rejection)
+        });
+    // POST /admin/import
+    let import = writing(
+        state,
+        warp::path!("admin" / "import")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(limited_body(config.max_import_bytes))
+            .and_then(move |actor, contents| {
+                import(actor, contents, response_cache, pool, storage)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/bulk_import {tar} or {path}
+    let bulk_import = writing(
+        state,
+        warp::path!("admin" / "bulk_import")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::header::optional("Content-Type"))
+            .and(body_stream())
+            .and_then(move |actor, content_type, body| {
+                bulk_import(actor, content_type, body, state).map_err(ApiError::into_rejection)
+            }),
+    );
+    // GET /admin/audit?limit&before
+    let audit = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and(warp::query())
+        .and_then(move |_, query| audit(query, pool).map_err(ApiError::into_rejection));
+    // GET /admin/migrations
+    let migrations = warp::path!("admin" / "migrations")
+        .and(warp::get())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and_then(move |_| migrations(pool).map_err(ApiError::into_rejection));
+    // POST /admin/readonly {enabled}
+    let set_read_only = warp::path!("admin" / "readonly")
         .and(warp::post())
-        .and(auth_admin(config))
+        .and(auth_admin(AdminRole::Full, config, limiter))
         .and(warp::body::bytes())
-        .and_then(move |contents| add_key(contents, pool));
-    // POST /delete_key {key}
-    let delete_key = warp::path!("delete_key")
+        .and_then(move |actor, contents| {
+            set_read_only(actor, contents, read_only, pool).map_err(ApiError::into_rejection)
+        });
+    // GET /game_versions
+    let game_versions = warp::path!("game_versions")
+        .and(warp::get())
+        .and_then(move || game_versions(pool).map_err(ApiError::into_rejection));
+    // POST /admin/game_versions {version}
+    let add_game_version = writing(
+        state,
+        warp::path!("admin" / "game_versions")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                add_game_version(actor, contents, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/backup
+    let backup = warp::path!("admin" / "backup")
+        .and(warp::post())
+        .and(warp::any().and_then(move || async move {
+            backups.or_nf("backups").map_err(ApiError::into_rejection)
+        }))
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and_then(move |backups, actor| {
+            backup(actor, backups, pool).map_err(ApiError::into_rejection)
+        });
+    // POST /admin/gc
+    let gc = writing(
+        state,
+        warp::path!("admin" / "gc")
+            .and(warp::post())
+            .and(warp::any().and_then(move || async move {
+                janitor.or_nf("gc").map_err(ApiError::into_rejection)
+            }))
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and_then(move |janitor, actor| {
+                gc(actor, janitor, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/alias {from, to}
+    let add_alias = writing(
+        state,
+        warp::path!("admin" / "alias")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Full, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                add_alias(actor, contents, response_cache, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/block {id, reason}
+    let block = writing(
+        state,
+        warp::path!("admin" / "block")
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Moderator, config, limiter))
+            .and(writable(state))
+            .and(warp::body::bytes())
+            .and_then(move |actor, contents| {
+                block(actor, contents, blocklist, response_cache, pool, storage)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // DELETE /admin/block/{package}
+    let unblock = writing(
+        state,
+        warp::path!("admin" / "block" / PackageId)
+            .and(warp::delete())
+            .and(auth_admin(AdminRole::Moderator, config, limiter))
+            .and(writable(state))
+            .and_then(move |id: PackageId, actor| {
+                unblock(id.into(), actor, blocklist, response_cache, pool)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/approve/{package}
+    let approve = writing(
+        state,
+        warp::path!("admin" / "approve" / PackageId)
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Moderator, config, limiter))
+            .and(writable(state))
+            .and_then(move |id: PackageId, actor| {
+                approve(id.into(), actor, response_cache, pool).map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/reject/{package}
+    let reject = writing(
+        state,
+        warp::path!("admin" / "reject" / PackageId)
+            .and(warp::post())
+            .and(auth_admin(AdminRole::Moderator, config, limiter))
+            .and(writable(state))
+            .and_then(move |id: PackageId, actor| {
+                reject(id.into(), actor, blocklist, response_cache, pool)
+                    .map_err(ApiError::into_rejection)
+            }),
+    );
+    // POST /admin/verify?quarantine
+    let verify = warp::path!("admin" / "verify")
+        .and(warp::post())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .and(warp::query())
+        .and_then(move |actor, query| {
+            verify(actor, query, verifier, pool, storage).map_err(ApiError::into_rejection)
+        });
+    // GET /admin/verify/status
+    let verify_status = warp::path!("admin" / "verify" / "status")
+        .and(warp::get())
+        .and(auth_admin(AdminRole::Full, config, limiter))
+        .map(move |_| warp::reply::json(&verifier.status()));
+    // POST /admin/cache/purge {id}
+    let purge = warp::path!("admin" / "cache" / "purge")
         .and(warp::post())
-        .and(auth_admin(config))
+        .and(auth_admin(AdminRole::Full, config, limiter))
         .and(warp::body::bytes())
-        .and_then(move |contents| delete_key(contents, pool));
+        .and_then(move |actor, contents| {
+            purge(actor, contents, response_cache, pool, storage).map_err(ApiError::into_rejection)
+        });
 
-    list.or(resolve)
-        .or(download)
-        .or(upload)
+    // Anything else on a known path
+    let list_methods = warp::path::end()
+        .or(warp::path!("feed.atom"))
+        .unify()
+        .or(warp::path!("game_versions"))
+        .unify()
+        .or(warp::path!("openapi.json"))
+        .unify()
+        .or(warp::path!("index.json"))
+        .unify()
+        .or(warp::path!("stats"))
+        .unify()
+        .or(warp::path!("stats" / "runtime"))
+        .unify()
+        .or(warp::path!("popular"))
+        .unify()
+        .or(warp::path!("changes"))
+        .unify()
+        .or(warp::path!("admin" / "export"))
+        .unify()
+        .or(warp::path!("admin" / "audit"))
+        .unify()
+        .or(warp::path!("admin" / "migrations"))
+        .unify()
+        .or(warp::path!("admin" / "verify" / "status"))
+        .unify()
+        .or(warp::path!("publish_key" / "verify"))
+        .unify()
+        .and(allow(&[Method::GET]));
+    let resolve_methods = warp::path!(PackageId)
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD]));
+    let versions_methods = warp::path!(PackageId / "versions")
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD]));
+    let package_stats_methods = warp::path!(PackageId / "stats")
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::GET]));
+    let visibility_methods = warp::path!(PackageId / "visibility")
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::POST]));
+    let version_methods = warp::path!(PackageId / Version)
+        .map(|_, _| ())
+        .untuple_one()
+        .and(allow(&[
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::DELETE,
+        ]));
+    let download_latest_methods = warp::path!(PackageId / "download")
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD]));
+    let metadata_methods = warp::path!(PackageId / Version / "meta")
+        .map(|_, _| ())
+        .untuple_one()
+        .and(allow(&[Method::PUT]));
+    let info_methods = warp::path!(PackageId / Version / "info")
+        .map(|_, _| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD]));
+    let readme_methods = warp::path!(PackageId / Version / "readme")
+        .map(|_, _| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD, Method::PUT]));
+    let artifact_methods = warp::path!(PackageId / Version / "artifacts")
+        .map(|_, _| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::HEAD]))
+        .or(warp::path!(PackageId / Version / "artifacts" / String)
+            .map(|_, _, _| ())
+            .untuple_one()
+            .and(allow(&[Method::GET, Method::HEAD, Method::POST])))
+        .unify();
+    let key_methods = warp::path!("publish_key" / "enable")
+        .or(warp::path!("delete_key"))
+        .unify()
+        .or(warp::path!("admin" / "reconcile"))
+        .unify()
+        .or(warp::path!("admin" / "normalize"))
+        .unify()
+        .or(warp::path!("admin" / "maintenance"))
+        .unify()
+        .or(warp::path!("admin" / "readonly"))
+        .unify()
+        .or(warp::path!("admin" / "import"))
+        .unify()
+        .or(warp::path!("admin" / "bulk_import"))
+        .unify()
+        .or(warp::path!("admin" / "cache" / "purge"))
+        .unify()
+        .or(warp::path!("admin" / "verify"))
+        .unify()
+        .or(warp::path!("admin" / "alias"))
+        .unify()
+        .or(warp::path!("admin" / "backup"))
+        .unify()
+        .or(warp::path!("admin" / "game_versions"))
+        .unify()
+        .or(warp::path!("admin" / "gc"))
+        .unify()
+        .or(warp::path!("admin" / "block"))
+        .unify()
+        .and(allow(&[Method::POST]));
+    let unblock_methods = warp::path!("admin" / "block" / PackageId)
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::DELETE]));
+    let approval_methods = warp::path!("admin" / "approve" / PackageId)
+        .or(warp::path!("admin" / "reject" / PackageId))
+        .unify()
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::POST]));
+    let keys_methods = warp::path!("publish_key").and(allow(&[Method::GET, Method::POST]));
+    let key_uploads_methods = warp::path!("publish_key" / String / "uploads")
+        .map(|_| ())
+        .untuple_one()
+        .and(allow(&[Method::GET, Method::POST]));
+    let qpm_methods = warp::path!("qpm" / PackageId)
+        .map(|_| ())
+        .untuple_one()
+        .and(qpm_enabled)
+        .and(allow(&[Method::GET]))
+        .or(warp::path!("qpm" / PackageId / Version)
+            .map(|_, _| ())
+            .untuple_one()
+            .and(qpm_enabled)
+            .and(allow(&[Method::GET, Method::POST])))
+        .unify();
+
+    // Boxed in small groups, the nested filters otherwise overflow the stack in debug builds.
+    // Every level of nesting is another poll frame for the routes at the bottom of a chain
+    let index_reads = qpm
+        .or(list)
+        .or(batch_resolve)
+        .or(feed)
+        .or(index)
+        .or(stats)
+        .or(popular)
+        .or(changes)
+        .or(runtime_stats)
+        .or(openapi)
+        .boxed();
+    let package_reads = resolve
+        .or(versions)
+        .or(package_stats)
+        .or(set_visibility)
+        .or(version_info)
+        .or(exact_info)
+        .boxed();
+    let file_reads = download
+        .or(download_latest)
+        .or(readme)
+        .or(artifacts)
+        .or(artifact)
+        .or(game_versions)
+        .boxed();
+    let package_writes = upload
+        .or(set_metadata)
+        .or(set_readme)
+        .or(upload_artifact)
         .or(delete)
-        .or(add_key)
+        .boxed();
+    let key_writes = add_key
+        .or(list_keys)
+        .or(verify_key)
+        .or(enable_key)
+        .or(key_uploads)
+        .or(quarantine_key)
         .or(delete_key)
-        .recover(crate::errors::handle_rejection)
-}
+        .boxed();
+    let admin_writes = reconcile
+        .or(normalize)
+        .or(maintenance)
+        .or(set_read_only)
+        .or(export)
+        .or(import)
+        .or(bulk_import)
+        .or(audit)
+        .or(migrations)
+        .or(purge)
+        .boxed();
+    let moderation = add_alias
+        .or(add_game_version)
+        .or(backup)
+        .or(gc)
+        .or(block)
+        .or(unblock)
+        .or(approve)
+        .or(reject)
+        .or(verify)
+        .or(verify_status)
+        .boxed();
+    let reads = index_reads.or(package_reads).or(file_reads).boxed();
+    let writes = package_writes
+        .or(key_writes)
+        .or(admin_writes)
+        .or(moderation)
+        .boxed();
+    let methods = list_methods
+        .or(resolve_methods)
+        .or(versions_methods)
+        .or(package_stats_methods)
+        .or(visibility_methods)
+        .or(version_methods)
+        .or(download_latest_methods)
+        .or(metadata_methods)
+        .or(info_methods)
+        .or(readme_methods)
+        .or(artifact_methods)
+        .or(key_methods)
+        .or(keys_methods)
+        .or(key_uploads_methods)
+        .or(unblock_methods)
+        .or(approval_methods)
+        .or(qpm_methods)
+        .boxed();
+    let routes = canonical_path().or(reads).or(writes).or(methods);
 
-fn auth(
-    pool: &'static SqlitePool,
-) -> impl Filter<Extract = (), Error = Rejection> + Send + Sync + Clone + 'static {
-    warp::header::optional("Authorization")
-        .and_then(move |k: Option<HeaderValue>| async move {
-            let k = match k {
-                Some(k) => k,
-                None => return Err(warp::reject::custom(crate::errors::Unauthorized)),
-            };
+    let routes = rate_limit(config, rate_limiter)
+        .and(routes)
+        .recover(crate::errors::handle_rejection);
+    // Outside of the recovery so errors carry the request id too
+    let slow_after = config.slow_request_ms.map(Duration::from_millis);
+    let routes = warp::any()
+        .map(Instant::now)
+        .and(request_id())
+        .and(routes)
+        .map(move |started, id: RequestId, reply| {
+            let mut response = Reply::into_response(reply);
+            response.headers_mut().insert(X_REQUEST_ID, id.0);
+            record_response(response.status(), started, slow_after);
 
-            if PublishKey::resolve_one(k.to_str().or_ise()?, pool)
-                .await
-                .or_ise()?
-                .is_some()
-            {
-                Ok(())
-            } else {
-                Err(warp::reject::custom(crate::errors::Unauthorized))
+            let counters = &state.counters;
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            if let Some(len) = response.body().size_hint().exact() {
+                counters.bytes_served.fetch_add(len, Ordering::Relaxed);
             }
-        })
-        .untuple_one()
+            response
+        });
+
+    let routes = match cors(config) {
+        Some(cors) => routes.with(cors).map(Reply::into_response).boxed(),
+        None => routes.boxed(),
+    };
+    routes
+        .with(warp::trace(request_span))
+        .map(Reply::into_response)
 }
 
-fn auth_admin(
-    config: &'static Config,
-) -> impl Filter<Extract = (), Error = Rejection> + Send + Sync + Clone + 'static {
-    warp::header::optional("Authorization")
-        .and_then(move |k: Option<HeaderValue>| async move {
-            let k = match k {
-                Some(k) => k,
-                None => return Err(warp::reject::custom(crate::errors::Unauthorized)),
-            };
+fn cors(config: &Config) -> Option<Cors> {
+    let origins = config.cors_allowed_origins.as_ref()?;
 
-            if config.admin_keys.contains(k.to_str().or_ise()?) {
-                Ok(())
-            } else {
-                Err(warp::reject::custom(crate::errors::Unauthorized))
+    let cors = warp::cors()
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE, X_REQUEST_ID])
+        .expose_headers([X_REQUEST_ID, X_TOTAL_MATCHES, ETAG]);
+    let cors = if origins.iter().any(|o| o == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    };
+
+    Some(cors.build())
+}
+
+/// Extracts an upload, either the file as the whole body or a `multipart/form-data` form with
+/// the file in a `file` part and optionally metadata in a `metadata` part
+fn upload_body(
+    config: &'static Config,
+) -> impl Filter<Extract = ((Bytes, Option<Metadata>),), Error = Rejection> + Clone {
+    // Rejecting with not found rather than an error lets the other body be tried
+    let is_form = |form: bool| {
+        warp::header::optional("Content-Type")
+            .and_then(move |content_type: Option<String>| async move {
+                let is_form = content_type.is_some_and(|c| {
+                    c.trim_start()
+                        .to_ascii_lowercase()
+                        .starts_with("multipart/form-data")
+                });
+                if is_form == form {
+                    Ok(())
+                } else {
+                    Err(warp::reject())
+                }
+            })
+            .untuple_one()
+    };
+    // Forms have their own size limits, so only plain bodies can be compressed
+    let form = is_form(true)
+        .and(raw_header("Content-Encoding"))
+        .and_then(|encoding: Option<HeaderValue>| async move {
+            match content_encoding(encoding.as_ref()) {
+                Ok(Encoding::Identity) => Ok(()),
+                Ok(_) => Err(ApiError::UnsupportedEncoding {
+                    encoding: "gzip".to_owned(),
+                }
+                .into_rejection()),
+                Err(e) => Err(e.into_rejection()),
             }
         })
         .untuple_one()
+        .and(warp::multipart::form().max_length(None))
+        .and_then(move |form| read_upload_form(form, config).map_err(ApiError::into_rejection));
+    let raw = is_form(false)
+        .and(raw_header("Content-Encoding"))
+        .and(body_stream())
+        .and_then(move |encoding: Option<HeaderValue>, body| {
+            read_body(encoding, body, config).map_err(ApiError::into_rejection)
+        })
+        .map(|contents| (contents, None));
+    form.or(raw).unify()
 }
 
-#[tracing::instrument(level = "debug", skip(pool))]
-async fn list(pool: &SqlitePool) -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&Mod::list(pool).await.or_ise()?))
-}
-
-#[tracing::instrument(level = "debug", skip(pool))]
-async fn resolve(
-    id: String,
-    query: ResolveQuery,
-    pool: &SqlitePool,
-) -> Result<impl Reply, Rejection> {
-    match query.limit {
-        // 1 => last version, found or not found
-        1 => Ok(warp::reply::json(
-            &Mod::resolve_one(&id, &query.req, pool)
-                .await
-                .or_ise()?
-                .or_nf()?,
-        )),
-        // 0 => all versions
-        0 => Ok(warp::reply::json(
-            &Mod::resolve_all(&id, &query.req, pool).await.or_ise()?,
-        )),
-        // n => n latest versions
-        n => Ok(warp::reply::json(
-            &Mod::resolve_n(&id, &query.req, pool, n).await.or_ise()?,
-        )),
-    }
-}
-
-#[tracing::instrument(level = "debug", skip(file_repo))]
-async fn download(id: String, ver: Version, file_repo: &FileRepo) -> Result<impl Reply, Rejection> {
-    let contents = file_repo.get_file(id, ver).await.or_nf()?;
-    let reply = warp::reply::with_header(
-        contents,
-        CONTENT_TYPE,
-        "application/json; charset=utf-8",
-    );
-    Ok(reply)
+/// Reads a plain upload body, decompressing it if it was sent gzipped
+async fn read_body(
+    encoding: Option<HeaderValue>,
+    mut body: BoxStream<'static, std::io::Result<Bytes>>,
+    config: &Config,
+) -> Result<Bytes, ApiError> {
+    if content_encoding(encoding.as_ref())? == Encoding::Gzip {
+        return gunzip_body(body, config.max_decompressed_bytes).await;
+    }
+    let mut contents = BytesMut::new();
+    while let Some(chunk) = body.try_next().await.map_err(|e| ApiError::BadRequest {
+        reason: e.to_string(),
+    })? {
+        contents.put(chunk);
+    }
+    Ok(contents.freeze())
 }
 
-#[tracing::instrument(level = "debug", skip(pool, file_repo))]
-async fn upload(
-    id: String,
-    ver: Version,
-    contents: Bytes,
-    pool: &SqlitePool,
-    file_repo: &FileRepo,
-) -> Result<impl Reply, Rejection> {
-    if !Mod::insert(&id, &ver, pool).await.or_ise()? {
-        return Ok(warp::reply::with_status("", StatusCode::CONFLICT));
+/// The body as it streams in, for bodies too large to hold in memory
+fn body_stream()
+-> impl Filter<Extract = (BoxStream<'static, std::io::Result<Bytes>>,), Error = Rejection> + Clone {
+    fn to_bytes(mut chunk: impl Buf) -> Bytes {
+        chunk.copy_to_bytes(chunk.remaining())
     }
+    warp::body::stream().map(|body| {
+        TryStreamExt::map_ok(body, to_bytes)
+            .map_err(std::io::Error::other)
+            .boxed()
+    })
+}
 
-    file_repo.write_file(id, ver, contents).await.or_ise()?;
-
-    Ok(warp::reply::with_status("", StatusCode::CREATED))
+/// Reads a whole request body, giving up as soon as it's over `limit` bytes so a huge one is
+/// never buffered
+fn limited_body(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    body_stream().and_then(
+        move |mut body: BoxStream<'static, std::io::Result<Bytes>>| {
+            async move {
+                let mut contents = BytesMut::new();
+                while let Some(chunk) = body.try_next().await.map_err(|e| ApiError::BadRequest {
+                    reason: e.to_string(),
+                })? {
+                    if (contents.len() + chunk.len()) as u64 > limit {
+                        return Err(ApiError::PayloadTooLarge { limit });
+                    }
+                    contents.put(chunk);
+                }
+                Ok(contents.freeze())
+            }
+            .map_err(ApiError::into_rejection)
+        },
+    )
 }
 
-#[tracing::instrument(level = "debug", skip(pool, config))]
-async fn delete(
-    id: String,
-    ver: Version,
-    pool: &SqlitePool,
+/// Reads the parts of an upload form, ignoring the ones that aren't `file` or `metadata`
+async fn read_upload_form(
+    form: FormData,
     config: &Config,
-) -> Result<impl Reply, Rejection> {
-    let mut dir = config
-        .downloads_path
-        .join(&id)
-        .join(format!("{}/{}", ver.major, ver.minor));
+) -> Result<(Bytes, Option<Metadata>), ApiError> {
+    let bad_request = |e: warp::Error| ApiError::BadRequest {
+        reason: e.to_string(),
+    };
+    let too_large = |limit| ApiError::PayloadTooLarge { limit };
 
-    let file = dir.join(ver.patch.to_string());
-    fs::remove_file(file).await.or_nf()?;
-    // Then try to delete our directories, moving upwards
-    for _ in 0..3 {
-        if fs::remove_dir(&dir).await.is_err() {
-            break;
+    let mut form = std::pin::pin!(form);
+    let (mut file, mut metadata) = (None, None);
+    let mut total = 0;
+    while let Some(part) = form.try_next().await.map_err(bad_request)? {
+        let name = part.name().to_owned();
+        let mut contents = BytesMut::new();
+        let mut data = std::pin::pin!(part.stream());
+        while let Some(chunk) = data.try_next().await.map_err(bad_request)? {
+            total += chunk.remaining() as u64;
+            if total > config.max_form_bytes {
+                return Err(too_large(config.max_form_bytes));
+            }
+            if (contents.len() + chunk.remaining()) as u64 > config.max_form_part_bytes {
+                return Err(too_large(config.max_form_part_bytes));
+            }
+            contents.put(chunk);
+        }
+        match name.as_str() {
+            "file" => file = Some(contents.freeze()),
+            "metadata" => metadata = Some(contents.freeze()),
+            _ => {}
         }
-        dir = dir.parent().or_ise()?.to_path_buf();
     }
-    Mod::delete(&id, &ver, pool).await.or_nf()?;
 
-    Ok(warp::reply::with_status("", StatusCode::OK))
+    let file = file.ok_or_else(|| ApiError::BadRequest {
+        reason: "missing file part".to_owned(),
+    })?;
+    let meta = match metadata {
+        Some(metadata) => Some(parse_metadata(&metadata)?),
+        None => None,
+    };
+    Ok((file, meta))
 }
 
-#[tracing::instrument(level = "debug", skip(pool))]
-async fn add_key(contents: Bytes, pool: &SqlitePool) -> Result<impl Reply, Rejection> {
-    let pub_key: PublishKey = serde_json::from_slice(&contents).or_ise()?;
-    if !PublishKey::insert(&pub_key.user, &pub_key.pw, pool)
-        .await
-        .or_ise()?
-    {
-        return Ok(warp::reply::with_status("", StatusCode::CONFLICT));
-    }
-
-    Ok(warp::reply::with_status("", StatusCode::CREATED))
+/// Parses metadata sent by a publisher, refusing fields that are too long and loaders that
+/// can't be matched on
+fn parse_metadata(contents: &[u8]) -> Result<Metadata, ApiError> {
+    let mut meta: Metadata =
+        serde_json::from_slice(contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    meta.check().map_err(|field| ApiError::BadRequest {
+        reason: format!("{} is too long", field),
+    })?;
+    check_loader(&mut meta)?;
+    Ok(meta)
 }
 
-#[tracing::instrument(level = "debug", skip(pool))]
-async fn delete_key(contents: Bytes, pool: &SqlitePool) -> Result<impl Reply, Rejection> {
-    let pub_key: OptPublishKey = serde_json::from_slice(&contents).or_ise()?;
+/// Normalizes the loader a version declares and checks its version requirement parses, so
+/// resolves filtering on it find it and clients can rely on it
+fn check_loader(meta: &mut Metadata) -> Result<(), ApiError> {
+    if let Some(loader) = &meta.loader {
+        let loader = loader_name(loader).map_err(|reason| ApiError::BadRequest {
+            reason: format!("loader {}", reason),
+        })?;
+        meta.loader = Some(loader);
+    }
+    if let Some(req) = &meta.loader_version_req {
+        if meta.loader.is_none() {
+            return Err(ApiError::BadRequest {
+                reason: "loader_version_req needs a loader".to_owned(),
+            });
+        }
+        VersionReq::parse(req).map_err(|e| ApiError::BadRequest {
+            reason: format!("loader_version_req isn't a version requirement: {}", e),
+        })?;
+    }
+    Ok(())
+}
+
+/// A loader's name as it's matched on, lowercase
+fn loader_name(loader: &str) -> Result<String, &'static str> {
+    let loader = loader.trim();
+    if loader.is_empty() {
+        return Err("can't be empty");
+    }
+    if !loader
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        return Err("can only have letters, digits, `-`, `_` and `.`");
+    }
+    Ok(loader.to_ascii_lowercase())
+}
+
+/// Extracts a header's value as is. `warp::header::optional` refuses values that aren't visible
+/// ASCII with a 400, which auth headers should answer with a 401 instead
+fn raw_header(
+    name: &'static str,
+) -> impl Filter<Extract = (Option<HeaderValue>,), Error = Infallible> + Copy {
+    warp::header::value(name)
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+}
+
+/// Extracts the key from an `Authorization` header, accepting both a bare key and the
+/// `Bearer <key>` form. Values that aren't UTF-8 can't be any key, so they're unauthorized
+fn auth_token(k: &HeaderValue) -> Result<&str, ApiError> {
+    if k.len() > MAX_AUTH_HEADER_LEN {
+        return Err(ApiError::BadRequest {
+            reason: format!(
+                "Authorization header is longer than {} bytes",
+                MAX_AUTH_HEADER_LEN
+            ),
+        });
+    }
+    let k = std::str::from_utf8(k.as_bytes())
+        .map_err(|_| ApiError::Unauthorized)?
+        .trim();
+    match k.split_once(char::is_whitespace) {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(token.trim()),
+        Some(_) => Err(ApiError::Unauthorized),
+        None => Ok(k),
+    }
+}
+
+/// Compares two byte strings in constant time with regards to their contents
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Rejects clients that failed to authenticate too many times, and records the failure if the
+/// wrapped check fails
+async fn limit_failures<T>(
+    ip: Option<IpAddr>,
+    limiter: &AuthLimiter,
+    check: impl Future<Output = Result<T, ApiError>>,
+) -> Result<T, ApiError> {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return check.await,
+    };
+
+    if let Some(retry_after) = limiter.locked_out(ip).await {
+        return Err(ApiError::TooManyRequests { retry_after });
+    }
+
+    let result = check.await;
+    if let Err(e) = &result
+        && matches!(e, ApiError::Unauthorized)
+    {
+        limiter.record_failure(ip).await;
+    }
+    result
+}
+
+/// The role of the admin key the token is, if it's one
+fn admin_role(config: &Config, token: &str) -> Option<AdminRole> {
+    // Check every key so the time taken doesn't depend on which one matched
+    config.admin_keys.iter().fold(None, |matched, (key, role)| {
+        if constant_time_eq(key.as_bytes(), token.as_bytes()) {
+            Some(role)
+        } else {
+            matched
+        }
+    })
+}
+
+/// Rejects clients that went over their request budget, admins are exempt
+fn rate_limit(
+    config: &'static Config,
+    limiter: Option<&'static RateLimiter>,
+) -> impl Filter<Extract = (), Error = Rejection> + Send + Sync + Clone + 'static {
+    raw_header("Authorization")
+        .and(client_ip(config))
+        .and_then(move |k: Option<HeaderValue>, ip| async move {
+            let (limiter, ip) = match (limiter, ip) {
+                (Some(limiter), Some(ip)) => (limiter, ip),
+                _ => return Ok(()),
+            };
+            if k.as_ref()
+                .and_then(|k| auth_token(k).ok())
+                .is_some_and(|token| admin_role(config, token).is_some())
+            {
+                return Ok(());
+            }
+
+            limiter
+                .acquire(ip)
+                .await
+                .map_err(|retry_after| ApiError::TooManyRequests { retry_after }.into_rejection())
+        })
+        .untuple_one()
+}
+
+/// Who's making an authenticated request, and from where
+#[derive(Debug)]
+struct Actor {
+    /// Key used, admins don't have one
+    key: Option<PublishKey>,
+    /// Set for admins
+    role: Option<AdminRole>,
+    client: ClientInfo,
+}
+
+impl Actor {
+    fn admin(role: AdminRole, client: ClientInfo) -> Self {
+        Self {
+            key: None,
+            role: Some(role),
+            client,
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        self.role == Some(AdminRole::Full)
+    }
+
+    fn name(&self) -> &str {
+        match (&self.key, self.role) {
+            (Some(key), _) => &key.user,
+            (None, Some(AdminRole::Moderator)) => "moderator",
+            (None, _) => "admin",
+        }
+    }
+
+    /// Whether the actor's key first published a package held for approval
+    fn owns(&self, owner: Option<&str>) -> bool {
+        self.key
+            .as_ref()
+            .is_some_and(|key| owner == Some(key.user.as_str()))
+    }
+
+    /// Moderators don't cover any package, they can only delete versions
+    fn covers(&self, id: &str) -> bool {
+        match &self.key {
+            Some(key) => key.covers(id),
+            None => self.is_admin(),
+        }
+    }
+
+    /// Records something the actor did, failures are only logged so they don't fail the request
+    async fn audit(&self, action: &str, target: Option<(&str, &Version)>, pool: &AnyPool) {
+        let (package, version) = target.unzip();
+        self.record(action, package, version, pool).await;
+    }
+
+    /// Records something the actor did to a package as a whole
+    async fn audit_package(&self, action: &str, id: &str, pool: &AnyPool) {
+        self.record(action, Some(id), None, pool).await;
+    }
+
+    async fn record(
+        &self,
+        action: &str,
+        package: Option<&str>,
+        version: Option<&Version>,
+        pool: &AnyPool,
+    ) {
+        let name = self.name();
+        if let Err(e) =
+            AuditEntry::insert(name, action, package, version, self.client.ip, pool).await
+        {
+            tracing::warn!(
+                "couldn't record {} by {} in the audit log: {}",
+                action,
+                name,
+                e
+            );
+        }
+    }
+}
+
+fn auth(
+    state: &'static AppState,
+) -> impl Filter<Extract = (Actor,), Error = Rejection> + Send + Sync + Clone + 'static {
+    raw_header("Authorization")
+        .and(client_info(state.config))
+        .and_then(move |k, client| authenticate(k, client, state).map_err(ApiError::into_rejection))
+}
+
+/// Like [`auth`], but qpm clients may send their key in a `QPM_AUTH` header instead
+fn qpm_auth(
+    state: &'static AppState,
+) -> impl Filter<Extract = (Actor,), Error = Rejection> + Send + Sync + Clone + 'static {
+    raw_header("QPM_AUTH")
+        .and(raw_header("Authorization"))
+        .map(|qpm: Option<HeaderValue>, k: Option<HeaderValue>| qpm.or(k))
+        .and(client_info(state.config))
+        .and_then(move |k, client| authenticate(k, client, state).map_err(ApiError::into_rejection))
+}
+
+/// Like [`auth`], but anonymous requests are let through without an actor. Credentials that
+/// aren't recognized are treated as none at all and don't count as a failed attempt, since
+/// reading doesn't need them
+fn viewer(
+    state: &'static AppState,
+) -> impl Filter<Extract = (Option<Actor>,), Error = Rejection> + Send + Sync + Clone + 'static {
+    raw_header("Authorization")
+        .and(client_info(state.config))
+        .and_then(move |k: Option<HeaderValue>, client: ClientInfo| {
+            async move {
+                let k = match k {
+                    Some(k) => k,
+                    None => return Ok(None),
+                };
+                // Keys aren't checked at all while locked out, or reads would be a way around it
+                if let Some(ip) = client.ip
+                    && state.auth_limiter.locked_out(ip).await.is_some()
+                {
+                    return Ok(None);
+                }
+                match identify(&k, client, state).await {
+                    Err(ApiError::Unauthorized) => Ok(None),
+                    result => result,
+                }
+            }
+            .map_err(ApiError::into_rejection)
+        })
+}
+
+/// Whether the package is public or the viewer covers it. One held for approval is only seen by
+/// admins and its owner, and a rejected one only by admins
+async fn can_see(id: &str, viewer: Option<&Actor>, pool: &AnyPool) -> Result<bool, ApiError> {
+    match Approval::of(id, pool).await? {
+        (Approval::Approved, _) => {}
+        (Approval::Pending, owner) => {
+            return Ok(
+                viewer.is_some_and(|actor| actor.role.is_some() || actor.owns(owner.as_deref()))
+            );
+        }
+        (Approval::Rejected, _) => return Ok(viewer.is_some_and(|actor| actor.role.is_some())),
+    }
+    if viewer.is_some_and(|actor| actor.covers(id)) {
+        return Ok(true);
+    }
+    Ok(Visibility::of(id, pool).await? == Visibility::Public)
+}
+
+/// Pretends private packages the viewer can't see don't exist, so their ids don't leak
+async fn check_visible(id: &str, viewer: Option<&Actor>, pool: &AnyPool) -> Result<(), ApiError> {
+    if can_see(id, viewer, pool).await? {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound { what: "package" })
+    }
+}
+
+/// The id a package is served from, which differs from the one asked for if it was renamed
+async fn follow_alias(id: String, pool: &AnyPool) -> Result<(String, bool), ApiError> {
+    match Alias::target(&id, pool).await? {
+        Some(target) => Ok((target, true)),
+        None => Ok((id, false)),
+    }
+}
+
+/// Answers requests for a package an admin pulled with why it was
+async fn check_blocked(id: &str, blocklist: &Blocklist, pool: &AnyPool) -> Result<(), ApiError> {
+    match blocklist.reason(id, pool).await? {
+        Some(reason) => Err(ApiError::Blocked { reason }),
+        None => Ok(()),
+    }
+}
+
+/// Tells clients which id they were actually served, so they can update to it
+fn resolved_id(response: &mut Response, id: &str) -> Result<(), ApiError> {
+    response
+        .headers_mut()
+        .insert(X_RESOLVED_ID, HeaderValue::from_str(id).or_ise()?);
+    Ok(())
+}
+
+/// Resolves who a key belongs to, an admin or a publish key user. Anyone else is refused, and
+/// that counts as a failed attempt
+async fn authenticate(
+    k: Option<HeaderValue>,
+    client: ClientInfo,
+    state: &AppState,
+) -> Result<Actor, ApiError> {
+    limit_failures(client.ip, &state.auth_limiter, async move {
+        let k = k.ok_or(ApiError::Unauthorized)?;
+        identify(&k, client, state)
+            .await?
+            .ok_or(ApiError::Unauthorized)
+    })
+    .await
+}
+
+/// Who the key in an `Authorization` header belongs to, if it's an admin key or a usable
+/// publish key
+async fn identify(
+    k: &HeaderValue,
+    client: ClientInfo,
+    state: &AppState,
+) -> Result<Option<Actor>, ApiError> {
+    let token = auth_token(k)?;
+    if let Some(role) = admin_role(state.config, token) {
+        return Ok(Some(Actor::admin(role, client)));
+    }
+
+    match PublishKey::resolve_one(token, state.pool).await? {
+        Some(key) if !key.is_expired() && !key.is_disabled() => {
+            if !key.is_used_recently() {
+                state.write_behind.key_used(&key);
+            }
+            Ok(Some(Actor {
+                key: Some(key),
+                role: None,
+                client,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Only lets through admin keys with at least the required role
+fn auth_admin(
+    required: AdminRole,
+    config: &'static Config,
+    limiter: &'static AuthLimiter,
+) -> impl Filter<Extract = (Actor,), Error = Rejection> + Send + Sync + Clone + 'static {
+    raw_header("Authorization")
+        .and(client_info(config))
+        .and_then(move |k: Option<HeaderValue>, client: ClientInfo| {
+            limit_failures(client.ip, limiter, async move {
+                let k = match k {
+                    Some(k) => k,
+                    None => return Err(ApiError::Unauthorized),
+                };
+                match admin_role(config, auth_token(&k)?) {
+                    Some(role) if role >= required => Ok(Actor::admin(role, client)),
+                    Some(_) => Err(ApiError::Forbidden),
+                    None => Err(ApiError::Unauthorized),
+                }
+            })
+            .map_err(ApiError::into_rejection)
+        })
+}
+
+/// Rejects writes while the index is read-only, or while maintenance runs
+fn writable(
+    state: &'static AppState,
+) -> impl Filter<Extract = (), Error = Rejection> + Send + Sync + Clone + 'static {
+    warp::any()
+        .and_then(move || async move {
+            if state.read_only.load(Ordering::Relaxed) || state.maintenance.is_running() {
+                Err(ApiError::ReadOnly.into_rejection())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Counts `route` as a write in progress for as long as it runs, so maintenance waits for it to be
+/// done. Whether it's let through at all is up to [`writable`]
+fn writing<R: Reply + Send>(
+    state: &'static AppState,
+    route: impl Filter<Extract = (R,), Error = Rejection> + Send + Sync + Clone + 'static,
+) -> impl Filter<Extract = (R,), Error = Rejection> + Send + Sync + Clone + 'static {
+    warp::any()
+        .map(move || state.maintenance.writer())
+        .and(route)
+        .map(|_writer, reply| reply)
+}
+
+/// Redirects paths with a trailing slash or repeated slashes to their canonical form. A 308 keeps
+/// the method and body, so writes are redirected as well
+fn canonical_path() -> impl Filter<Extract = (Response,), Error = Rejection> + Copy {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(|path: FullPath, query: String| async move {
+            let segments: Vec<&str> = path.as_str().split('/').filter(|s| !s.is_empty()).collect();
+            let canonical = format!("/{}", segments.join("/"));
+            if canonical == path.as_str() {
+                return Err(warp::reject());
+            }
+
+            let location = if query.is_empty() {
+                canonical
+            } else {
+                format!("{}?{}", canonical, query)
+            };
+            let mut response = StatusCode::PERMANENT_REDIRECT.into_response();
+            let location = HeaderValue::from_str(&location)
+                .or_ise()
+                .map_err(ApiError::into_rejection)?;
+            response.headers_mut().insert(LOCATION, location);
+            Ok(response)
+        })
+}
+
+/// Rejects requests using a method that isn't allowed, so that known paths answer with a 405
+/// instead of a 404
+fn allow(
+    methods: &'static [Method],
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Copy {
+    warp::method().and_then(move |method: Method| async move {
+        if methods.contains(&method) {
+            Err(warp::reject())
+        } else {
+            Err(ApiError::MethodNotAllowed { allow: methods }.into_rejection())
+        }
+    })
+}
+
+/// Matches both GET and HEAD requests, extracting whether it's a HEAD request
+fn get_or_head() -> impl Filter<Extract = (bool,), Error = Rejection> + Copy {
+    warp::get()
+        .map(|| false)
+        .or(warp::head().map(|| true))
+        .unify()
+}
+
+/// Only matches clients preferring a JSON description over the file itself
+fn accepts_json() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::header::optional("Accept")
+        .and_then(|header: Option<HeaderValue>| async move {
+            match header.as_ref().and_then(|h| h.to_str().ok()) {
+                Some(header) if prefers_json(header) => Ok(()),
+                _ => Err(warp::reject()),
+            }
+        })
+        .untuple_one()
+}
+
+/// Media types in an `Accept` header value, lowercased, with their quality
+fn accept_items(header: &str) -> impl Iterator<Item = (String, f32)> + '_ {
+    header.split(',').map(|item| {
+        let mut parts = item.split(';');
+        let media = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (media, q)
+    })
+}
+
+/// Whether an `Accept` header value ranks JSON at least as high as the file types downloads have
+fn prefers_json(header: &str) -> bool {
+    let (mut json, mut file) = (0.0f32, 0.0f32);
+    for (media, q) in accept_items(header) {
+        match media.as_str() {
+            "application/json" => json = json.max(q),
+            "application/octet-stream" | "application/zip" | "application/*" | "*/*" => {
+                file = file.max(q)
+            }
+            _ => {}
+        }
+    }
+    json > 0.0 && json >= file
+}
+
+/// How a list-like endpoint answers
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListFormat {
+    Json,
+    /// One item per line
+    Text,
+}
+
+impl ListFormat {
+    /// Plain text if the `Accept` header value ranks it above JSON, JSON for anything else rather
+    /// than a 406
+    fn from_accept(header: &str) -> Self {
+        let (mut json, mut text) = (0.0f32, 0.0f32);
+        for (media, q) in accept_items(header) {
+            match media.as_str() {
+                "application/json" | "application/*" | "*/*" => json = json.max(q),
+                "text/plain" | "text/*" => text = text.max(q),
+                _ => {}
+            }
+        }
+        if text > json {
+            ListFormat::Text
+        } else {
+            ListFormat::Json
+        }
+    }
+}
+
+/// Extracts how a list-like endpoint should answer from the `Accept` header
+fn list_format() -> impl Filter<Extract = (ListFormat,), Error = Rejection> + Copy {
+    warp::header::optional("Accept").map(|header: Option<HeaderValue>| {
+        header
+            .as_ref()
+            .and_then(|h| h.to_str().ok())
+            .map_or(ListFormat::Json, ListFormat::from_accept)
+    })
+}
+
+/// Sets the `Content-Length` of a response, and drops its body if it's for a HEAD request
+fn head_response(mut response: Response, head: bool) -> Response {
+    if let Some(len) = response.body().size_hint().exact() {
+        response.headers_mut().insert(CONTENT_LENGTH, len.into());
+    }
+    if head {
+        *response.body_mut() = Default::default();
+    }
+    response
+}
+
+#[tracing::instrument(level = "debug", skip(if_none_match, state))]
+async fn list(
+    query: ListQuery,
+    if_none_match: Option<HeaderValue>,
+    format: ListFormat,
+    encoding: Encoding,
+    state: &AppState,
+) -> Result<Response, ApiError> {
+    // Only paginate when asked to so existing clients still get everything
+    let pages = (query.page.is_some() || query.per_page.is_some()).then(|| {
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE);
+        (page, per_page)
+    });
+    let key = CacheKey::List {
+        game: query.game.clone(),
+        page: pages.map(|(page, _)| page),
+        per_page: pages.map(|(_, per_page)| per_page),
+    };
+
+    // Cheap to compute, and unlike the cache's generation it survives restarts and sees what
+    // other instances change, so launchers checking the list get a 304 without it being built
+    let fingerprint = Mod::list_fingerprint(state.pool).await?;
+    let etag = HeaderValue::from_str(&format!("\"{}\"", fingerprint)).or_ise()?;
+    // The text list gets its own, so a cache holding one representation doesn't revalidate it
+    // with the other's ETag
+    let sent_etag = match format {
+        ListFormat::Json => etag.clone(),
+        ListFormat::Text => HeaderValue::from_str(&format!("\"{}-text\"", fingerprint)).or_ise()?,
+    };
+    let cache_control = HeaderValue::from_static(LIST_CACHE_CONTROL);
+    let vary = HeaderValue::from_static("accept, accept-encoding");
+    if etag_matches(if_none_match.as_ref(), &sent_etag) {
+        let mut response = Response::default();
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        let headers = response.headers_mut();
+        headers.insert(ETAG, sent_etag);
+        headers.insert(CACHE_CONTROL, cache_control);
+        headers.insert(VARY, vary);
+        return Ok(response);
+    }
+
+    // A cached list is only as good as the fingerprint it was made at, so a list older than the
+    // ETag never goes out under it
+    let cache = &state.response_cache;
+    let generation = cache.generation(None);
+    let listed = match cache
+        .get(&key, generation)
+        .filter(|cached| cached.headers.get(ETAG) == Some(&etag))
+    {
+        Some(cached) => cached,
+        None => {
+            let mut listed = list_packages(query.game.as_deref(), pages, state.pool).await?;
+            listed.headers.insert(ETAG, etag);
+            cache.insert(key, generation, listed.clone());
+            listed
+        }
+    };
+    let mut response = match format {
+        ListFormat::Json => json_reply(listed, encoding)?,
+        ListFormat::Text => {
+            // Only the JSON list is cached, it's cheap to turn into lines
+            let ids: Vec<String> = serde_json::from_slice(&listed.body).or_ise()?;
+            let mut response = crate::compression::text_lines(ids, encoding)?;
+            response.headers_mut().extend(listed.headers);
+            response
+        }
+    };
+    let headers = response.headers_mut();
+    headers.insert(ETAG, sent_etag);
+    headers.insert(CACHE_CONTROL, cache_control);
+    headers.insert(VARY, vary);
+    Ok(response)
+}
+
+/// Package ids, a page of them if `pages` has the page and its size
+async fn list_packages(
+    game: Option<&str>,
+    pages: Option<(u32, u32)>,
+    pool: &AnyPool,
+) -> Result<CachedBody, ApiError> {
+    let Some((page, per_page)) = pages else {
+        let ids = Mod::list(game, pool).await?;
+        return Ok(CachedBody {
+            body: serde_json::to_vec(&ids).or_ise()?.into(),
+            headers: HeaderMap::new(),
+        });
+    };
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let total = Mod::count(game, pool).await?;
+    let ids = Mod::list_paged(game, pool, per_page, offset).await?;
+
+    let filter = game
+        .map(|game| format!("&game={}", uri_encode(game)))
+        .unwrap_or_default();
+    let mut links = Vec::new();
+    if page > 1 {
+        links.push(format!(
+            "</?page={}&per_page={}{}>; rel=\"prev\"",
+            page - 1,
+            per_page,
+            filter
+        ));
+    }
+    if offset.saturating_add(per_page) < total {
+        links.push(format!(
+            "</?page={}&per_page={}{}>; rel=\"next\"",
+            page + 1,
+            per_page,
+            filter
+        ));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Total-Count", total.into());
+    if !links.is_empty() {
+        headers.insert(LINK, links.join(", ").parse().or_ise()?);
+    }
+    Ok(CachedBody {
+        body: serde_json::to_vec(&ids).or_ise()?.into(),
+        headers,
+    })
+}
+
+/// Answers from the response cache if it has the query at the current generation, with a 304 if
+/// the client has that already
+fn from_cache(
+    cache: &ResponseCache,
+    key: &CacheKey,
+    generation: u64,
+    if_none_match: Option<&HeaderValue>,
+    if_modified_since: Option<i64>,
+    encoding: Encoding,
+) -> Result<Option<Response>, ApiError> {
+    // Only queries that were answered are, so ETags can't be used to probe for private packages
+    let Some(cached) = cache.get(key, generation) else {
+        return Ok(None);
+    };
+    let etag = cache.etag(generation);
+    let last_modified = cached.headers.get(LAST_MODIFIED).filter(|h| is_settled(h));
+    let unchanged = match if_none_match {
+        Some(_) => etag_matches(if_none_match, &etag),
+        None => not_modified_since(last_modified.and_then(http_date), if_modified_since),
+    };
+    if unchanged {
+        let mut response = Response::default();
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        let headers = response.headers_mut();
+        headers.insert(ETAG, etag);
+        headers.insert(CACHE_CONTROL, cache.cache_control());
+        if let Some(last_modified) = last_modified {
+            headers.insert(LAST_MODIFIED, last_modified.clone());
+        }
+        return Ok(Some(response));
+    }
+    cached_reply(cache, generation, cached, encoding).map(Some)
+}
+
+/// A cacheable JSON reply, with the headers telling clients how long they can keep it
+fn cached_reply(
+    cache: &ResponseCache,
+    generation: u64,
+    cached: CachedBody,
+    encoding: Encoding,
+) -> Result<Response, ApiError> {
+    let mut response = json_reply(cached, encoding)?;
+    let headers = response.headers_mut();
+    headers.insert(ETAG, cache.etag(generation));
+    headers.insert(CACHE_CONTROL, cache.cache_control());
+    Ok(response)
+}
+
+fn json_reply(cached: CachedBody, encoding: Encoding) -> Result<Response, ApiError> {
+    let mut response = crate::compression::json_body(cached.body.into(), encoding)?;
+    let headers = response.headers_mut();
+    headers.extend(cached.headers);
+    if headers.get(LAST_MODIFIED).is_some_and(|h| !is_settled(h)) {
+        headers.remove(LAST_MODIFIED);
+    }
+    Ok(response)
+}
+
+/// Unix time of an HTTP date, like an `If-Modified-Since` header has. Malformed dates are as good
+/// as none
+fn http_date(header: &HeaderValue) -> Option<i64> {
+    let date = httpdate::parse_http_date(header.to_str().ok()?).ok()?;
+    Some(date.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+/// Whether a `Last-Modified` date is in the past. Changes are only known to the second, so one
+/// made this second could still be followed by another the date wouldn't show
+fn is_settled(last_modified: &HeaderValue) -> bool {
+    http_date(last_modified).is_some_and(|time| time < unix_now())
+}
+
+/// Formats unix time as an HTTP date, for `Last-Modified`
+fn http_date_header(time: i64) -> Result<HeaderValue, ApiError> {
+    let time = UNIX_EPOCH + Duration::from_secs(time.max(0) as u64);
+    HeaderValue::from_str(&httpdate::fmt_http_date(time)).or_ise()
+}
+
+/// Whether something last modified at `updated_at` is the same as of `since`
+fn not_modified_since(updated_at: Option<i64>, since: Option<i64>) -> bool {
+    updated_at
+        .zip(since)
+        .is_some_and(|(updated_at, since)| updated_at <= since)
+}
+
+/// Whether an `If-None-Match` header lists the ETag, or is `*`
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &HeaderValue) -> bool {
+    if_none_match
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| {
+            h.split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == etag)
+        })
+}
+
+#[tracing::instrument(level = "debug", skip(state))]
+async fn feed(query: FeedQuery, state: &AppState) -> Result<impl Reply, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_FEED_SIZE)
+        .clamp(1, MAX_FEED_SIZE);
+    let package = query.package.map(|p| p.to_lowercase());
+    let releases = Mod::recent(package.as_deref(), limit, state.pool).await?;
+    let base = base_url(state.config, None);
+
+    Ok(warp::reply::with_header(
+        crate::feed::atom(&releases, package.as_deref(), &base),
+        CONTENT_TYPE,
+        "application/atom+xml; charset=utf-8",
+    ))
+}
+
+#[tracing::instrument(level = "debug", skip(state))]
+async fn popular(query: PopularQuery, state: &AppState) -> Result<impl Reply, ApiError> {
+    let config = state.config;
+    let invalid = |message: String| ApiError::Invalid {
+        field: "window".to_owned(),
+        message,
+    };
+    let hours = match &query.window {
+        Some(window) => window_hours(window).ok_or_else(|| {
+            invalid("expected a number of hours or days, like 24h or 7d".to_owned())
+        })?,
+        None => DEFAULT_POPULAR_WINDOW,
+    };
+    if hours > config.download_history_days * 24 {
+        return Err(invalid(format!(
+            "downloads are only kept for {} days",
+            config.download_history_days
+        )));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_POPULAR_SIZE)
+        .clamp(1, MAX_POPULAR_SIZE);
+
+    // The current hour counts as one of the window's
+    let since = unix_now() / 3600 - (hours as i64 - 1);
+    let pool = state.pool;
+    // Every package is ranked before adding downloads not written yet, which may reorder them
+    let mut popular = Mod::popular(since, u32::MAX, pool).await?;
+    let mut pending = state.write_behind.pending_recent_downloads(since);
+    for p in &mut popular {
+        p.downloads += pending.remove(&p.id).unwrap_or_default();
+        p.all_time_downloads += state.write_behind.pending_downloads(&p.id, None);
+    }
+    // Only downloaded since the last flush
+    for (id, downloads) in pending {
+        if can_see(&id, None, pool).await?
+            && Mod::package_usage(Some(&id), pool).await?.versions > 0
+        {
+            let all_time_downloads =
+                Mod::downloads(&id, pool).await? + state.write_behind.pending_downloads(&id, None);
+            popular.push(Popular {
+                id,
+                downloads,
+                all_time_downloads,
+            });
+        }
+    }
+    popular.sort_by(|a, b| b.downloads.cmp(&a.downloads).then_with(|| a.id.cmp(&b.id)));
+    popular.truncate(limit as usize);
+    Ok(warp::reply::json(&popular))
+}
+
+/// What changed after the `since` sequence number, for mirrors to sync incrementally. Changes to
+/// private packages are left out
+#[tracing::instrument(level = "debug", skip(pool))]
+async fn changes(query: ChangesQuery, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(MAX_CHANGES_SIZE)
+        .clamp(1, MAX_CHANGES_SIZE);
+    let Some((earliest, latest)) = Change::bounds(pool).await? else {
+        return Ok(warp::reply::json(&Changes {
+            changes: Vec::new(),
+            latest: 0,
+        }));
+    };
+    // Anything between `since` and the earliest change kept was pruned
+    if query.since < earliest - 1 {
+        return Err(ApiError::ResyncRequired { earliest });
+    }
+
+    // Bounded by the latest change, so one recorded meanwhile isn't skipped over by a client
+    // moving on to `latest` after a short page
+    let changes = Change::between(query.since, latest, limit, pool).await?;
+    Ok(warp::reply::json(&Changes { changes, latest }))
+}
+
+/// Hours in a window like `24h` or `7d`, none for an empty window
+fn window_hours(window: &str) -> Option<u64> {
+    let hours = match window.strip_suffix('d') {
+        Some(days) => days.parse::<u64>().ok()?.checked_mul(24)?,
+        None => window.strip_suffix('h')?.parse().ok()?,
+    };
+    (hours > 0).then_some(hours)
+}
+
+#[tracing::instrument(level = "debug", skip(state))]
+async fn index(
+    if_none_match: Option<HeaderValue>,
+    encoding: Encoding,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let pool = state.pool;
+    // Cheap to compute, so unchanged snapshots aren't assembled at all
+    let pending = state.write_behind.pending_downloads_total();
+    let etag = format!("\"{}\"", Mod::generation(pending, pool).await?);
+    let etag = HeaderValue::from_str(&etag).or_ise()?;
+    if etag_matches(if_none_match.as_ref(), &etag) {
+        let mut response = Response::default();
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response.headers_mut().insert(ETAG, etag);
+        return Ok(response);
+    }
+
+    let base = base_url(state.config, None);
+    let mut packages: BTreeMap<String, Vec<IndexVersion>> = BTreeMap::new();
+    for p in Mod::published(pool).await? {
+        let download_url = download_url(&p.id, &p.version, &base);
+        let downloads = p.downloads
+            + state
+                .write_behind
+                .pending_downloads(&p.id, Some(&p.version));
+        packages.entry(p.id).or_default().push(IndexVersion {
+            version: p.version,
+            size: p.size,
+            sha256: p.sha256,
+            downloads,
+            created_at: p.created_at,
+            download_url,
+            meta: p.meta,
+        });
+    }
+
+    let mut response = crate::compression::json(&packages, encoding)?;
+    response.headers_mut().insert(ETAG, etag);
+    Ok(response)
+}
+
+#[tracing::instrument(level = "debug", skip(pool))]
+async fn audit(query: AuditQuery, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_SIZE)
+        .clamp(1, MAX_AUDIT_SIZE);
+    let entries = AuditEntry::list(limit, query.before, pool).await?;
+
+    let mut response = warp::reply::json(&entries).into_response();
+    // A full page may not be the last one
+    if let Some(last) = entries.last().filter(|_| entries.len() == limit as usize) {
+        let next = format!(
+            "</admin/audit?before={}&limit={}>; rel=\"next\"",
+            last.id, limit
+        );
+        response.headers_mut().insert(LINK, next.parse().or_ise()?);
+    }
+    Ok(response)
+}
+
+/// Migrations built in and applied, to check what state a deploy left the database in
+#[tracing::instrument(level = "debug", skip(pool))]
+async fn migrations(pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    Ok(warp::reply::json(&MigrationStatus::list(pool).await?))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, viewer, if_none_match, if_modified_since, state),
+    fields(package = %id, matches = Empty)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn resolve(
+    id: String,
+    head: bool,
+    query: ResolveQuery,
+    viewer: Option<Actor>,
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<HeaderValue>,
+    encoding: Encoding,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let (pool, mirror) = (state.pool, state.mirror);
+    let cache = &state.response_cache;
+    let key = CacheKey::Resolve {
+        id: id.clone(),
+        req: query.req.to_string(),
+        game: query.game.clone(),
+        loader: query.loader.clone(),
+        limit: query.limit,
+        offset: query.offset,
+        array: query.shape == Shape::Array,
+    };
+    // Only public packages are cached, so whoever asks can be answered without looking further
+    let generation = cache.generation(Some(&id));
+    let if_modified_since = if_modified_since.as_ref().and_then(http_date);
+    if let Some(response) = from_cache(
+        cache,
+        &key,
+        generation,
+        if_none_match.as_ref(),
+        if_modified_since,
+        encoding,
+    )? {
+        return Ok(head_response(response, head));
+    }
+
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_blocked(&id, &state.blocklist, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    // Upstream can change without the package changing here
+    let updated_at = match mirror {
+        Some(_) => None,
+        None => Mod::updated_at(&id, pool).await?,
+    };
+    let last_modified = updated_at.map(http_date_header).transpose()?;
+    let settled = last_modified.clone().filter(is_settled);
+    if if_none_match.is_none()
+        && not_modified_since(settled.as_ref().and(updated_at), if_modified_since)
+    {
+        let mut response = Response::default();
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        if let Some(last_modified) = settled {
+            response.headers_mut().insert(LAST_MODIFIED, last_modified);
+        }
+        if renamed {
+            resolved_id(&mut response, &id)?;
+        }
+        return Ok(head_response(response, head));
+    }
+
+    let mut mirrored = false;
+    let (mut versions, total) = match query.limit {
+        // 1 => last version, found or not found
+        1 => {
+            let (game, loader) = (query.game.as_deref(), query.loader.as_deref());
+            let found = Mod::resolve_one(&id, &query.req, game, loader, pool, query.offset).await?;
+            let total = match found {
+                Some(_) => Some(Mod::count_matching(&id, &query.req, game, loader, pool).await?),
+                None => None,
+            };
+            // Upstream doesn't know about game versions or loaders, and doesn't say how many
+            // versions match
+            let found = match (found, mirror) {
+                (None, Some(mirror)) if game.is_none() && loader.is_none() => {
+                    mirrored = true;
+                    mirror
+                        .resolve(&id, &query.req, query.offset)
+                        .await
+                        .map(Resolved::from)
+                }
+                (found, _) => found,
+            };
+            Span::current().record("matches", total.unwrap_or(found.is_some() as _));
+            match found {
+                Some(m) => (vec![m], total),
+                // Nothing matching is an empty array like for any other limit
+                None if query.shape == Shape::Array => (Vec::new(), Some(0)),
+                None => return Err(not_found_package_or_version(&id, pool).await),
+            }
+        }
+        // 0 => all versions, n => n latest versions
+        n => {
+            let (versions, total) = Mod::resolve_counted(
+                &id,
+                &query.req,
+                query.game.as_deref(),
+                query.loader.as_deref(),
+                pool,
+                n,
+                query.offset,
+            )
+            .await?;
+            Span::current().record("matches", total);
+            (versions, Some(total))
+        }
+    };
+    // Relative to the index unless a public URL is configured, never to what a request's
+    // forwarding headers say, since the body is cached for everyone
+    let base = base_url(state.config, None);
+    for resolved in &mut versions {
+        resolved.download_url = Some(download_url(&resolved.m.id, &resolved.m.version, &base));
+    }
+    let body = resolve_body(versions, query.shape)?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(total) = total {
+        headers.insert(X_TOTAL_MATCHES, total.into());
+    }
+    if let Some(last_modified) = last_modified {
+        headers.insert(LAST_MODIFIED, last_modified);
+    }
+    let resolved = CachedBody {
+        body: body.into(),
+        headers,
+    };
+    // What upstream says can change without us knowing
+    let mut response = if !mirrored && !renamed && can_see(&id, None, pool).await? {
+        cache.insert(key, generation, resolved.clone());
+        cached_reply(cache, generation, resolved, encoding)?
+    } else {
+        json_reply(resolved, encoding)?
+    };
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+/// Serializes what a resolve found in the shape asked for, a lone object being the only version
+fn resolve_body(mut versions: Vec<Resolved>, shape: Shape) -> Result<Vec<u8>, ApiError> {
+    match shape {
+        Shape::Object => serde_json::to_vec(&versions.pop().or_ise()?),
+        Shape::Array => serde_json::to_vec(&versions),
+    }
+    .or_ise()
+}
+
+#[tracing::instrument(level = "debug", skip(viewer, pool))]
+async fn batch_resolve(
+    contents: Bytes,
+    viewer: Option<Actor>,
+    encoding: Encoding,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    let mut entries: Vec<BatchEntry> =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    if entries.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::BadRequest {
+            reason: format!(
+                "at most {} packages can be resolved at once",
+                MAX_BATCH_SIZE
+            ),
+        });
+    }
+
+    // Only the first entry for a package counts
+    let mut seen = HashSet::new();
+    entries.retain(|e| seen.insert(e.id.clone()));
+
+    let viewer = viewer.as_ref();
+    let results = future::try_join_all(entries.into_iter().map(|e| async move {
+        // Private packages resolve to nothing, as if they didn't exist
+        let version = if can_see(&e.id, viewer, pool).await? {
+            Mod::resolve_one(&e.id, &e.req, None, None, pool, 0)
+                .await?
+                .map(|r| r.m.version)
+        } else {
+            None
+        };
+        Result::<_, ApiError>::Ok(BatchResult {
+            id: e.id.into(),
+            version,
+        })
+    }))
+    .await?;
+
+    crate::compression::json(&results, encoding)
+}
+
+#[tracing::instrument(level = "debug", skip(id, viewer, pool), fields(package = %id))]
+async fn versions(
+    id: String,
+    head: bool,
+    viewer: Option<Actor>,
+    format: ListFormat,
+    encoding: Encoding,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let versions = Mod::versions(&id, pool).await?;
+    if versions.is_empty() {
+        return Err(ApiError::NotFound { what: "package" });
+    }
+
+    let mut response = match format {
+        ListFormat::Json => crate::compression::json(&versions, encoding)?,
+        ListFormat::Text => crate::compression::text_lines(&versions, encoding)?,
+    };
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept, accept-encoding"));
+    Ok(head_response(response, head))
+}
+
+#[tracing::instrument(level = "debug", skip(pool, config))]
+async fn stats(pool: &AnyPool, config: &Config) -> Result<impl Reply, ApiError> {
+    let stats = Stats {
+        total: Mod::package_usage(None, pool).await?,
+        max_total_bytes: config.max_total_bytes,
+        max_package_bytes: config.max_package_bytes,
+        packages: Mod::usage(pool).await?,
+    };
+    Ok(warp::reply::json(&stats))
+}
+
+#[tracing::instrument(level = "debug", skip(id, viewer, state), fields(package = %id))]
+async fn package_stats(
+    id: String,
+    viewer: Option<Actor>,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let pool = state.pool;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let usage = Mod::package_usage(Some(&id), pool).await?;
+    if usage.versions == 0 {
+        return Err(ApiError::NotFound { what: "package" });
+    }
+
+    let downloads =
+        Mod::downloads(&id, pool).await? + state.write_behind.pending_downloads(&id, None);
+    Ok(warp::reply::json(&PackageStats {
+        id,
+        usage,
+        downloads,
+        max_bytes: state.config.max_package_bytes,
+    }))
+}
+
+/// Refuses an upload of `size` bytes to `id` that would go over a storage quota
+async fn check_quota(
+    id: &str,
+    size: usize,
+    pool: &AnyPool,
+    config: &Config,
+) -> Result<(), ApiError> {
+    let size = size as u64;
+    let quotas = [
+        ("package", Some(id), config.max_package_bytes),
+        ("total", None, config.max_total_bytes),
+    ];
+    for (quota, id, limit) in quotas {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let used = Mod::package_usage(id, pool).await?.bytes as u64;
+        if used + size > limit {
+            return Err(ApiError::InsufficientStorage { quota, limit });
+        }
+    }
+    Ok(())
+}
+
+/// Refuses versions with a number past `max`. New versions are held to `max-version-number`,
+/// existing ones only to what can be stored, so lowering it doesn't strand versions published
+/// before
+fn check_version_bounds(ver: &Version, max: u64) -> Result<(), ApiError> {
+    if [ver.major, ver.minor, ver.patch].iter().any(|&n| n > max) {
+        return Err(ApiError::Unprocessable {
+            reason: format!("version numbers can be at most {}", max),
+        });
+    }
+    Ok(())
+}
+
+/// Appends to the feed mirrors follow. The change already happened, so failing to record it is only
+/// logged
+async fn record_change(action: &str, id: &str, ver: &Version, pool: &AnyPool) {
+    if let Err(e) = Change::record(action, id, ver, pool).await {
+        tracing::warn!(
+            "couldn't record {} of {} {} as a change: {}",
+            action,
+            id,
+            ver,
+            e
+        );
+    }
+}
+
+/// Refuses to publish another version of a package that had as many published within the last
+/// hour as allowed, until the earliest of them is an hour old
+async fn check_upload_rate(id: &str, pool: &AnyPool, config: &Config) -> Result<(), ApiError> {
+    const WINDOW: i64 = 3600;
+    let Some(limit) = config.max_uploads_per_package_per_hour else {
+        return Ok(());
+    };
+    let now = unix_now();
+    let (count, earliest) = Mod::recent_uploads(id, now - WINDOW, pool).await?;
+    let count = count as u64;
+    if count < limit {
+        return Ok(());
+    }
+    let wait = earliest.map_or(WINDOW, |earliest| earliest + WINDOW - now);
+    Err(ApiError::UploadLimited {
+        count,
+        limit,
+        retry_after: Duration::from_secs(wait.clamp(1, WINDOW) as u64),
+    })
+}
+
+/// Tells apart a package that doesn't exist at all from one with no matching version
+async fn not_found_package_or_version(id: &str, pool: &AnyPool) -> ApiError {
+    let what = match Mod::resolve_one(id, &VersionReq::STAR, None, None, pool, 0).await {
+        Ok(None) => "package",
+        _ => "version",
+    };
+    ApiError::NotFound { what }
+}
+
+/// Replaces anything but ASCII alphanumerics, dots, dashes and underscores so the name is safe
+/// to use in a quoted header parameter
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, state),
+    fields(package = %id, version = %ver, cache = Empty, bytes = Empty)
+)]
+async fn download(
+    id: String,
+    ver: Version,
+    head: bool,
+    viewer: Option<Actor>,
+    state: &'static AppState,
+) -> Result<impl Reply, ApiError> {
+    check_version_bounds(&ver, MAX_STORED_VERSION_NUMBER)?;
+    let (pool, config, mirror) = (state.pool, state.config, state.mirror);
+    let storage: &'static dyn Storage = state.file_repo;
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_blocked(&id, &state.blocklist, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    // Stray files in storage without a version aren't served, only the mirror can fill those in.
+    // Versions are served whichever way their build metadata is asked for
+    let stored = Mod::find(&id, &ver, pool).await?;
+    let known = stored.is_some();
+    let ver = stored.map_or(ver, |p| p.version);
+    let filename = sanitize_filename(&format!("{}-{}.qmod", id, ver));
+    let mut response = match &config.sendfile_header {
+        Some(header) => sendfile(&id, &ver, known, header, config, pool, storage, mirror).await?,
+        None => {
+            let stored = if known {
+                storage.get_file(&id, &ver).await
+            } else {
+                Err(ErrorKind::NotFound.into())
+            };
+            let contents = match stored {
+                Ok(contents) => Ok(contents),
+                // Upstream failures are the same as the file not being there
+                Err(e) => match mirror {
+                    Some(mirror) => mirror.download(&id, &ver, pool, storage).await.ok_or(e),
+                    None => Err(e),
+                },
+            };
+            let contents = match contents {
+                // Not the file missing, clients should try again later
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e.into()),
+                contents => contents.or_nf("version")?,
+            };
+            Span::current().record("bytes", contents.len());
+
+            let content_type = if contents.starts_with(ZIP_MAGIC) {
+                "application/zip"
+            } else {
+                "application/octet-stream"
+            };
+            let mut response = Response::new(contents.into());
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            response
+        }
+    };
+
+    // The mirror added the version, so resolving finds it now
+    if !known {
+        state.response_cache.invalidate(Some(&id));
+    }
+    if !head {
+        state.counters.downloads.fetch_add(1, Ordering::Relaxed);
+        state.write_behind.count_download(&id, &ver);
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", filename)
+            .parse()
+            .or_ise()?,
+    );
+    headers.insert(VARY, HeaderValue::from_static("accept"));
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+/// Downloads the latest version matching the query, saying which one in `X-Resolved-Version`
+#[tracing::instrument(level = "debug", skip(id, viewer, state), fields(package = %id))]
+async fn download_latest(
+    id: String,
+    head: bool,
+    query: ResolveQuery,
+    viewer: Option<Actor>,
+    state: &'static AppState,
+) -> Result<impl Reply, ApiError> {
+    let pool = state.pool;
+    let (resolved_id, _) = follow_alias(id.clone(), pool).await?;
+    check_blocked(&resolved_id, &state.blocklist, pool).await?;
+    check_visible(&resolved_id, viewer.as_ref(), pool).await?;
+
+    let (game, loader) = (query.game.as_deref(), query.loader.as_deref());
+    let found = Mod::resolve_one(&resolved_id, &query.req, game, loader, pool, query.offset)
+        .await?
+        .map(|r| r.m.version);
+    // Upstream doesn't know about game versions or loaders, as when resolving
+    let found = match (found, state.mirror) {
+        (None, Some(mirror)) if game.is_none() && loader.is_none() => mirror
+            .resolve(&resolved_id, &query.req, query.offset)
+            .await
+            .map(|m| m.version),
+        (found, _) => found,
+    };
+    let Some(ver) = found else {
+        return Err(not_found_package_or_version(&resolved_id, pool).await);
+    };
+
+    // Asked for by the id given, so a renamed package is answered with its new id as well
+    let version = HeaderValue::from_str(&ver.to_string()).or_ise()?;
+    let mut response = download(id, ver, head, viewer, state)
+        .await?
+        .into_response();
+    response.headers_mut().insert(X_RESOLVED_VERSION, version);
+    Ok(response)
+}
+
+/// The version as resolve would describe it, for clients asking for JSON instead of the file
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, state),
+    fields(package = %id, version = %ver)
+)]
+async fn version_info(
+    id: String,
+    ver: Version,
+    head: bool,
+    viewer: Option<Actor>,
+    encoding: Encoding,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let pool = state.pool;
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_blocked(&id, &state.blocklist, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let mut resolved = Mod::find(&id, &ver, pool)
+        .await?
+        .map(Resolved::from)
+        .ok_or("no such version")
+        .or_nf("version")?;
+    let base = base_url(state.config, None);
+    resolved.download_url = Some(download_url(&resolved.m.id, &resolved.m.version, &base));
+    let mut response = crate::compression::json(&resolved, encoding)?;
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept, accept-encoding"));
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+/// Everything known about a version, which unlike [`version_info`] has to be stored with exactly
+/// the version string asked for
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, state),
+    fields(package = %id, version = %ver)
+)]
+async fn exact_info(
+    id: String,
+    ver: Version,
+    head: bool,
+    viewer: Option<Actor>,
+    encoding: Encoding,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let pool = state.pool;
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_blocked(&id, &state.blocklist, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let mut info = Mod::get_exact(&id, &ver, pool).await?.or_nf("version")?;
+    info.downloads += state
+        .write_behind
+        .pending_downloads(&info.id, Some(&info.version));
+    let base = base_url(state.config, None);
+    info.download_url = Some(download_url(&info.id, &info.version, &base));
+    let mut response = crate::compression::json(&info, encoding)?;
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+/// An empty response telling the proxy in front of us to serve the file itself, the cache is
+/// bypassed since the proxy reads the file from storage anyway
+#[allow(clippy::too_many_arguments)]
+async fn sendfile(
+    id: &str,
+    ver: &Version,
+    known: bool,
+    header: &str,
+    config: &Config,
+    pool: &'static AnyPool,
+    storage: &'static dyn Storage,
+    mirror: Option<&'static Mirror>,
+) -> Result<Response, ApiError> {
+    let mut found = known && storage.file_exists(id, ver).await?;
+    if !found && let Some(mirror) = mirror {
+        found = mirror.download(id, ver, pool, storage).await.is_some();
+    }
+    if !found {
+        return Err(ApiError::NotFound { what: "version" });
+    }
+
+    // nginx takes a URI, others like Apache's X-Sendfile take a path
+    let key = if header.eq_ignore_ascii_case("X-Accel-Redirect") {
+        file_key(&uri_encode(id), ver)
+    } else {
+        file_key(id, ver)
+    };
+    let mut response = Response::default();
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_bytes(header.as_bytes()).or_ise()?,
+        format!("{}{}", config.sendfile_prefix, key)
+            .parse()
+            .or_ise()?,
+    );
+    // The file isn't read so it can't be sniffed, qmods are zips
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    Ok(response)
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, actor, contents, cache, pool),
+    fields(package = %id)
+)]
+async fn set_visibility(
+    id: String,
+    actor: Actor,
+    contents: Bytes,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, Some(&actor), pool).await?;
+    if !actor.covers(&id) {
+        return Err(ApiError::Forbidden);
+    }
+    if Mod::versions(&id, pool).await?.is_empty() {
+        return Err(ApiError::NotFound { what: "package" });
+    }
+
+    let state: VisibilityState =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    state.visibility.set(&id, pool).await?;
+    cache.invalidate(Some(&id));
+    let action = match state.visibility {
+        Visibility::Public => "make_public",
+        Visibility::Private => "make_private",
+    };
+    actor.audit_package(action, &id, pool).await;
+
+    Ok(warp::reply::json(&state))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, actor, contents, cache, pool),
+    fields(package = %id, version = %ver)
+)]
+async fn set_metadata(
+    id: String,
+    ver: Version,
+    actor: Actor,
+    contents: Bytes,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, Some(&actor), pool).await?;
+    if !actor.covers(&id) {
+        return Err(ApiError::Forbidden);
+    }
+
+    let meta = parse_metadata(&contents)?;
+    if !Mod::set_metadata(&id, &ver, &meta, pool).await? {
+        return Err(ApiError::NotFound { what: "version" });
+    }
+    cache.invalidate(Some(&id));
+    actor.audit("set_metadata", Some((&id, &ver)), pool).await;
+    record_change("metadata", &id, &ver, pool).await;
+
+    Ok(warp::reply::json(&Resolved {
+        m: Mod { id, version: ver },
+        download_url: None,
+        meta,
+    }))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, pool),
+    fields(package = %id, version = %ver)
+)]
+async fn readme(
+    id: String,
+    ver: Version,
+    head: bool,
+    viewer: Option<Actor>,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let contents = Mod::readme(&id, &ver, pool)
+        .await?
+        .ok_or("no readme")
+        .or_nf("readme")?;
+    let mut response = Response::new(contents.into());
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/markdown; charset=utf-8"),
+    );
+    Ok(head_response(response, head))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, actor, contents, pool),
+    fields(package = %id, version = %ver)
+)]
+async fn set_readme(
+    id: String,
+    ver: Version,
+    actor: Actor,
+    contents: Bytes,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, Some(&actor), pool).await?;
+    if !actor.covers(&id) {
+        return Err(ApiError::Forbidden);
+    }
+
+    if contents.len() > MAX_README_SIZE {
+        return Err(ApiError::PayloadTooLarge {
+            limit: MAX_README_SIZE as u64,
+        });
+    }
+    let contents = std::str::from_utf8(&contents).map_err(|e| ApiError::BadRequest {
+        reason: format!("readmes must be UTF-8: {}", e),
+    })?;
+    if Mod::find(&id, &ver, pool).await?.is_none() {
+        return Err(ApiError::NotFound { what: "version" });
+    }
+
+    Mod::set_readme(&id, &ver, contents, pool).await?;
+    actor.audit("set_readme", Some((&id, &ver)), pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// The version's own file followed by its named artifacts
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, pool, config),
+    fields(package = %id, version = %ver)
+)]
+async fn artifacts(
+    id: String,
+    ver: Version,
+    head: bool,
+    viewer: Option<Actor>,
+    pool: &AnyPool,
+    config: &Config,
+) -> Result<impl Reply, ApiError> {
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let published = Mod::find(&id, &ver, pool).await?.or_nf("version")?;
+    let base = base_url(config, None);
+    let mut entries = vec![ArtifactEntry {
+        name: None,
+        size: published.size,
+        sha256: published.sha256,
+        created_at: published.created_at,
+        download_url: download_url(&id, &published.version, &base),
+    }];
+    for artifact in Mod::artifacts(&id, &ver, pool).await? {
+        entries.push(ArtifactEntry::new(&id, &ver, artifact, &base));
+    }
+
+    let mut response = warp::reply::json(&entries).into_response();
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, pool, storage),
+    fields(package = %id, version = %ver)
+)]
+async fn artifact(
+    id: String,
+    ver: Version,
+    name: String,
+    head: bool,
+    viewer: Option<Actor>,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let (id, renamed) = follow_alias(id, pool).await?;
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    // Names that couldn't have been published aren't looked up
+    if !is_valid_artifact_name(&name) {
+        return Err(ApiError::NotFound { what: "artifact" });
+    }
+    Mod::artifact(&id, &ver, &name, pool)
+        .await?
+        .or_nf("artifact")?;
+    let contents = storage
+        .get_artifact(&id, &ver, &name)
+        .await
+        .or_nf("artifact")?;
+
+    let mut response = Response::new(contents.into());
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", name)).or_ise()?,
+    );
+    if renamed {
+        resolved_id(&mut response, &id)?;
+    }
+    Ok(head_response(response, head))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, actor, contents, pool, config, storage),
+    fields(package = %id, version = %ver)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn upload_artifact(
+    id: String,
+    ver: Version,
+    name: String,
+    actor: Actor,
+    contents: Bytes,
+    pool: &AnyPool,
+    config: &Config,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, Some(&actor), pool).await?;
+    if !actor.covers(&id) {
+        return Err(ApiError::Forbidden);
+    }
+    if !is_valid_artifact_name(&name) {
+        return Err(ApiError::BadRequest {
+            reason: "artifact names are at most 64 letters, digits, '.', '-' or '_', and can't \
+                     start with '.'"
+                .to_owned(),
+        });
+    }
+    Mod::find(&id, &ver, pool).await?.or_nf("version")?;
+    check_quota(&id, contents.len(), pool, config).await?;
+
+    let base = base_url(config, Some(&actor.client));
+    if !Mod::add_artifact(&id, &ver, &name, &contents, pool).await? {
+        // Like versions, artifacts can't be replaced, but publishing the same bytes is a no-op
+        let existing = Mod::artifact(&id, &ver, &name, pool).await?;
+        let sha256 = hex::encode(Sha256::digest(&contents));
+        let status = match &existing {
+            Some(a) if a.sha256 == sha256 => StatusCode::OK,
+            _ => StatusCode::CONFLICT,
+        };
+        let existing = existing.map(|a| ArtifactEntry::new(&id, &ver, a, &base));
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&existing),
+            status,
+        ));
+    }
+    if let Err(e) = storage.write_artifact(&id, &ver, &name, contents).await {
+        if let Err(e) = Mod::remove_artifact(&id, &ver, &name, pool).await {
+            tracing::error!(
+                "couldn't remove {} of {} {} after failing to store it: {}",
+                name,
+                id,
+                ver,
+                e
+            );
+        }
+        return Err(e.into());
+    }
+    actor
+        .audit("upload_artifact", Some((&id, &ver)), pool)
+        .await;
+
+    let artifact = Mod::artifact(&id, &ver, &name, pool)
+        .await?
+        .or_nf("artifact")?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&ArtifactEntry::new(&id, &ver, artifact, &base)),
+        StatusCode::CREATED,
+    ))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, actor, contents, meta, state),
+    fields(package = %id, version = %ver, bytes = contents.len())
+)]
+async fn upload(
+    id: String,
+    ver: Version,
+    actor: Actor,
+    query: UploadQuery,
+    contents: Bytes,
+    meta: Option<Metadata>,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let (status, uploaded) = publish(&id, &ver, &actor, &query, contents, meta, state).await?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&uploaded),
+        status,
+    ))
+}
+
+/// Publishes a version, answering with 201 and what was recorded. A version that was already
+/// there is left alone and answered with 200 if it has the same file, and conflicts otherwise,
+/// unless it was recorded without a file, which is stored then
+async fn publish(
+    id: &str,
+    ver: &Version,
+    actor: &Actor,
+    query: &UploadQuery,
+    contents: Bytes,
+    meta: Option<Metadata>,
+    state: &AppState,
+) -> Result<(StatusCode, Option<Uploaded>), ApiError> {
+    let (pool, config, storage) = (state.pool, state.config, state.file_repo);
+    check_version_bounds(ver, config.max_version_number)?;
+    // Versions are stored by their numbers and build metadata alone, a prerelease would be
+    // recorded as the release
+    if !ver.pre.is_empty() {
+        return Err(ApiError::Unprocessable {
+            reason: "prerelease versions can't be published".to_owned(),
+        });
+    }
+    PackageId::check(id).map_err(|reason| ApiError::Unprocessable { reason })?;
+    check_blocked(id, &state.blocklist, pool).await?;
+    if !actor.covers(id) {
+        return Err(ApiError::Forbidden);
+    }
+    if let Some(to) = Alias::target(id, pool).await? {
+        return Err(ApiError::Renamed { to });
+    }
+    let base = base_url(config, Some(&actor.client));
+
+    // A deleted version may already be installed, so only an admin can knowingly replace it
+    let tombstoned = Mod::is_tombstoned(id, ver, pool).await?;
+    let force = query.force && actor.is_admin();
+    if tombstoned && !force {
+        return Err(ApiError::Gone { what: "version" });
+    }
+
+    if actor.role.is_none() {
+        check_upload_rate(id, pool, config).await?;
+    }
+    check_quota(id, contents.len(), pool, config).await?;
+
+    let game_versions: Vec<String> = query
+        .game
+        .iter()
+        .flat_map(|game| game.split(','))
+        .map(|game| game.trim().to_owned())
+        .filter(|game| !game.is_empty())
+        .collect();
+    for game in &game_versions {
+        if !GameVersion::exists(game, pool).await? {
+            return Err(ApiError::Unprocessable {
+                reason: format!("unknown game version {}", game),
+            });
+        }
+    }
+
+    // The loader can be given either way, but not two different ones
+    let mut meta = meta;
+    if query.loader.is_some() || query.loader_version_req.is_some() {
+        let mut from_query = meta.clone().unwrap_or_default();
+        for (given, field) in [
+            (&query.loader, &mut from_query.loader),
+            (
+                &query.loader_version_req,
+                &mut from_query.loader_version_req,
+            ),
+        ] {
+            if given.is_some() {
+                *field = given.clone();
+            }
+        }
+        check_loader(&mut from_query)?;
+        if let Some(meta) = &meta
+            && (meta.loader.is_some() && meta.loader != from_query.loader
+                || meta.loader_version_req.is_some()
+                    && meta.loader_version_req != from_query.loader_version_req)
+        {
+            return Err(ApiError::BadRequest {
+                reason: "the query and metadata declare different loaders".to_owned(),
+            });
+        }
+        meta = Some(from_query);
+    }
+
+    // Only an admin or the owner can add to a package held for approval
+    if let (Approval::Pending, owner) = Approval::of(id, pool).await?
+        && !actor.is_admin()
+        && !actor.owns(owner.as_deref())
+    {
+        return Err(ApiError::Forbidden);
+    }
+    if Mod::versions(id, pool).await?.is_empty() {
+        if query.private {
+            Visibility::Private.set(id, pool).await?;
+        }
+        // Keys can't claim new ids for themselves, an admin has to approve them first
+        if config.require_approval_for_new_packages && actor.role.is_none() {
+            Approval::hold(id, actor.name(), pool).await?;
+        }
+    }
+
+    let sha256 = hex::encode(Sha256::digest(&contents));
+    // Claimed before the version is recorded, so an upload finding it without a file can tell
+    // whether it's still being stored
+    let claim = Storing::claim(state, id, ver);
+    let (_storing, repair) =
+        if claim.is_some() && Mod::insert(id, ver, config.allow_build_variants, pool).await? {
+            (claim, false)
+        } else {
+            match check_existing(id, ver, &sha256, &base, claim, state).await? {
+                Existing::Same(uploaded) => return Ok((StatusCode::OK, Some(uploaded))),
+                Existing::Repair(storing) => (Some(storing), true),
+            }
+        };
+    if repair {
+        actor.audit("repair", Some((id, ver)), pool).await;
+    } else {
+        Mod::record_publisher(id, ver, actor.name(), pool).await?;
+        Mod::tag_game_versions(id, ver, &game_versions, pool).await?;
+        if let Some(meta) = &meta {
+            Mod::set_metadata(id, ver, meta, pool).await?;
+        }
+        let action = if tombstoned { "republish" } else { "upload" };
+        actor.audit(action, Some((id, ver)), pool).await;
+    }
+
+    // Only the request that inserted the version or is repairing its file gets here, so nothing
+    // else writes the file
+    Mod::record_file(id, ver, &contents, pool).await?;
+    // Forks often publish the same file again, which can be shared rather than stored twice
+    let twin = Mod::with_sha256(&sha256, id, ver, pool).await?;
+    let stored = match &twin {
+        Some(twin) => storage.link_or_write(twin, id, ver, contents).await,
+        None => storage.write_file(id, ver, contents).await,
+    };
+    if let Err(e) = stored {
+        // Leave the version free to be uploaded again rather than listed without a file
+        if let Err(e) = Mod::delete(id, ver, pool).await {
+            tracing::error!(
+                "couldn't remove {} {} after failing to store it: {}",
+                id,
+                ver,
+                e
+            );
+        }
+        state.response_cache.invalidate(Some(id));
+        return Err(e.into());
+    }
+    // Only once the file is there, a failed republish leaves the version deleted for good
+    if tombstoned && !repair {
+        Mod::clear_tombstone(id, ver, pool).await?;
+        tracing::warn!("{} {} was deleted before and is being republished", id, ver);
+    }
+    // Usage falls back to the logical size without it, so it isn't worth failing the upload over
+    match storage.stored_size(id, ver).await {
+        Ok(stored_size) => Mod::record_stored_size(id, ver, stored_size, pool).await?,
+        Err(e) => tracing::warn!("couldn't get the stored size of {} {}: {}", id, ver, e),
+    }
+
+    let uploaded = Mod::get(id, ver, pool)
+        .await?
+        .ok_or("version deleted while uploading")
+        .or_nf("version")?;
+    state.counters.uploads.fetch_add(1, Ordering::Relaxed);
+    state.response_cache.invalidate(Some(id));
+    record_change("upload", id, ver, pool).await;
+    Ok((StatusCode::CREATED, Some(Uploaded::new(uploaded, &base))))
+}
+
+/// Marks a version's file as being stored until dropped
+struct Storing<'a> {
+    storing: &'a Mutex<HashSet<(String, Version)>>,
+    key: (String, Version),
+}
+
+impl<'a> Storing<'a> {
+    /// `None` if another upload is storing the file already
+    fn claim(state: &'a AppState, id: &str, ver: &Version) -> Option<Self> {
+        let key = (id.to_owned(), ver.clone());
+        let claimed = state.storing.lock().unwrap().insert(key.clone());
+        // Built lazily, dropping it would release the other upload's claim
+        claimed.then(|| Storing {
+            storing: &state.storing,
+            key,
+        })
+    }
+}
+
+impl Drop for Storing<'_> {
+    fn drop(&mut self) {
+        self.storing.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// What to do with an upload of a version that's recorded already
+enum Existing<'a> {
+    /// It was published with the same file, so there's nothing to do
+    Same(Uploaded),
+    /// It's recorded without a file, which the upload is stored as
+    Repair(Storing<'a>),
+}
+
+/// Tells a retry of an upload apart from a conflicting one, and repairs versions left recorded
+/// without a file. Conflicts say whether the file is there and matches, or is still being stored
+async fn check_existing<'a>(
+    id: &str,
+    ver: &Version,
+    sha256: &str,
+    base: &str,
+    storing: Option<Storing<'a>>,
+    state: &'a AppState,
+) -> Result<Existing<'a>, ApiError> {
+    let Some(existing) = Mod::find(id, ver, state.pool).await? else {
+        let detail = match storing {
+            Some(_) => "already exists",
+            None => "the version is being published",
+        };
+        return Err(ApiError::Conflict {
+            detail: detail.to_owned(),
+        });
+    };
+    // Whoever claimed the version is storing its file
+    let in_progress = match existing.version == *ver {
+        true => storing.is_none(),
+        false => state
+            .storing
+            .lock()
+            .unwrap()
+            .contains(&(id.to_owned(), existing.version.clone())),
+    };
+    let file_present = !in_progress && state.file_repo.file_exists(id, &existing.version).await?;
+    let hash_matches = file_present && existing.sha256.as_deref() == Some(sha256);
+
+    // Another build of the version is a conflict, whatever its bytes
+    if existing.version == *ver {
+        if hash_matches {
+            // Publishing the same bytes again is a no-op rather than a conflict, so retries are
+            // safe
+            return Ok(Existing::Same(Uploaded::new(existing, base)));
+        }
+        if !file_present && let Some(storing) = storing {
+            tracing::warn!(
+                "{} {} is recorded without a file, storing the one uploaded",
+                id,
+                ver
+            );
+            return Ok(Existing::Repair(storing));
+        }
+    }
+    Err(ApiError::VersionConflict {
+        version: existing.version.to_string(),
+        sha256: existing.sha256,
+        published_at: existing.created_at,
+        file_present,
+        hash_matches,
+        in_progress,
+    })
+}
+
+/// The package config a version was published with through the qpm routes
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, viewer, pool, storage),
+    fields(package = %id, version = %ver)
+)]
+async fn qpm_config(
+    id: String,
+    ver: Version,
+    viewer: Option<Actor>,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    check_visible(&id, viewer.as_ref(), pool).await?;
+
+    let contents = storage.get_file(&id, &ver).await.or_nf("version")?;
+    Ok(warp::reply::with_header(
+        Response::new(contents.into()),
+        CONTENT_TYPE,
+        "application/json",
+    ))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, ver, actor, confirm, state),
+    fields(package = %id, version = %ver)
+)]
+async fn delete(
+    id: String,
+    ver: Version,
+    actor: Actor,
+    if_match: Option<HeaderValue>,
+    confirm: Option<HeaderValue>,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    check_version_bounds(&ver, MAX_STORED_VERSION_NUMBER)?;
+    let (pool, storage) = (state.pool, state.file_repo);
+    if let Some(if_match) = if_match {
+        check_if_match(&if_match, &id, &ver, pool).await?;
+    }
+    check_delete_confirmed(&id, &ver, confirm, state).await?;
+    delete_version(&id, &ver, pool, storage).await?;
+    state.response_cache.invalidate(Some(&id));
+    actor.audit("delete", Some((&id, &ver)), pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// Refuses to delete a version older than the protection window unless `X-Confirm-Delete` has the
+/// token issued for it, answering with a new token otherwise. Versions without a recorded age
+/// count as old
+async fn check_delete_confirmed(
+    id: &str,
+    ver: &Version,
+    confirm: Option<HeaderValue>,
+    state: &AppState,
+) -> Result<(), ApiError> {
+    let Some(hours) = state.config.delete_protection_hours else {
+        return Ok(());
+    };
+    let published = Mod::find(id, ver, state.pool).await?.or_nf("version")?;
+    let window = hours.saturating_mul(3600).try_into().unwrap_or(i64::MAX);
+    if published
+        .created_at
+        .is_some_and(|created_at| unix_now() - created_at < window)
+    {
+        return Ok(());
+    }
+
+    let confirmations = &state.delete_confirmations;
+    match confirm {
+        Some(token) => {
+            let token = token.to_str().unwrap_or_default();
+            if confirmations.redeem(id, ver, token).await {
+                Ok(())
+            } else {
+                Err(ApiError::BadRequest {
+                    reason: "X-Confirm-Delete isn't a token issued for this version, or it was \
+                             already used or expired"
+                        .to_owned(),
+                })
+            }
+        }
+        None => Err(ApiError::ConfirmationRequired {
+            token: confirmations.issue(id, ver).await,
+            expires_in: confirmations.ttl(),
+        }),
+    }
+}
+
+/// Refuses to go on unless the version exists and `If-Match` lists its hash, or is `*`
+async fn check_if_match(
+    if_match: &HeaderValue,
+    id: &str,
+    ver: &Version,
+    pool: &AnyPool,
+) -> Result<(), ApiError> {
+    let sha256 = Mod::sha256(id, ver, pool).await?;
+    let matches = match (if_match.to_str(), &sha256) {
+        (Ok(if_match), Some(current)) => if_match
+            .split(',')
+            .map(|t| t.trim().trim_matches('"'))
+            .any(|t| t == "*" || current.as_ref().is_some_and(|c| t.eq_ignore_ascii_case(c))),
+        _ => false,
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(ApiError::PreconditionFailed {
+            sha256: sha256.flatten(),
+        })
+    }
+}
+
+/// Removes a version along with its files, and keeps it from being published again
+async fn delete_version(
+    id: &str,
+    ver: &Version,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<(), ApiError> {
+    // Artifacts go first, so the version's directories can be removed along with its file. Other
+    // builds of the version share them, so they're kept while there are any
+    let other_builds = Mod::versions(id, pool)
+        .await?
+        .into_iter()
+        .any(|v| v != *ver && v.cmp_precedence(ver).is_eq());
+    let artifacts = if other_builds {
+        Vec::new()
+    } else {
+        Mod::artifacts(id, ver, pool).await?
+    };
+    for artifact in artifacts {
+        match storage.delete_artifact(id, ver, &artifact.name).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    storage.delete_file(id, ver).await.or_nf("version")?;
+    Mod::delete(id, ver, pool).await.or_nf("version")?;
+    Mod::tombstone(id, ver, pool).await?;
+    record_change("delete", id, ver, pool).await;
+    Ok(())
+}
+
+/// What blocking a package removed
+#[derive(Debug, Serialize)]
+struct Blocked {
+    #[serde(flatten)]
+    block: Block,
+    deleted: Vec<Version>,
+}
+
+/// Blocks a package and deletes every version of it
+#[tracing::instrument(
+    level = "debug",
+    skip(actor, contents, blocklist, cache, pool, storage)
+)]
+async fn block(
+    actor: Actor,
+    contents: Bytes,
+    blocklist: &Blocklist,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let mut block: Block = serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+        reason: e.to_string(),
+    })?;
+    block.reason = block.reason.trim().to_owned();
+    if block.reason.is_empty() {
+        return Err(ApiError::Unprocessable {
+            reason: "reason is empty".to_owned(),
+        });
+    }
+
+    // Blocked first, so nothing can be published while the versions are being deleted
+    blocklist.block(&block, pool).await?;
+    cache.invalidate(Some(&block.id));
+    actor.audit_package("block", &block.id, pool).await;
+
+    let deleted = Mod::versions(&block.id, pool).await?;
+    for ver in &deleted {
+        delete_version(&block.id, ver, pool, storage).await?;
+        actor.audit("delete", Some((&block.id, ver)), pool).await;
+    }
+
+    Ok(warp::reply::json(&Blocked { block, deleted }))
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip(id, actor, blocklist, cache, pool),
+    fields(package = %id)
+)]
+async fn unblock(
+    id: String,
+    actor: Actor,
+    blocklist: &Blocklist,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    if !blocklist.unblock(&id, pool).await? {
+        return Err(ApiError::NotFound { what: "block" });
+    }
+    cache.invalidate(Some(&id));
+    actor.audit_package("unblock", &id, pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// Lets a package held for approval be listed and resolved by everyone
+#[tracing::instrument(level = "debug", skip(id, actor, cache, pool), fields(package = %id))]
+async fn approve(
+    id: String,
+    actor: Actor,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    if !Approval::Approved.settle(&id, pool).await? {
+        return Err(ApiError::NotFound {
+            what: "pending package",
+        });
+    }
+    cache.invalidate(Some(&id));
+    actor.audit_package("approve", &id, pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+/// Turns down a package held for approval, yanking its versions and blocking further publishes
+#[tracing::instrument(
+    level = "debug",
+    skip(id, actor, blocklist, cache, pool),
+    fields(package = %id)
+)]
+async fn reject(
+    id: String,
+    actor: Actor,
+    blocklist: &Blocklist,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    if !Approval::Rejected.settle(&id, pool).await? {
+        return Err(ApiError::NotFound {
+            what: "pending package",
+        });
+    }
+    let block = Block {
+        id: id.clone().into(),
+        reason: "rejected by an admin".to_owned(),
+    };
+    blocklist.block(&block, pool).await?;
+    cache.invalidate(Some(&id));
+    actor.audit_package("reject", &id, pool).await;
+
+    let yanked = Mod::yank_package(&id, pool).await?;
+    for ver in &yanked {
+        actor.audit("yank", Some((&id, ver)), pool).await;
+        record_change("yank", &id, ver, pool).await;
+    }
+    Ok(warp::reply::json(&yanked))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, cache, pool, storage))]
+async fn reconcile(
+    actor: Actor,
+    query: ReconcileQuery,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let scan = storage.scan().await?;
+    let mut report = ReconcileReport {
+        skipped: scan.skipped,
+        ..Default::default()
+    };
+
+    for file in scan.files {
+        let added = Mod::insert(&file.id, &file.version, true, pool).await?;
+        Mod::record_size(&file.id, &file.version, file.size, pool).await?;
+        Mod::record_stored_size(&file.id, &file.version, file.size, pool).await?;
+        if added {
+            storage.forget(&file.id, &file.version).await;
+            record_change("upload", &file.id, &file.version, pool).await;
+            report.added.push(Mod {
+                id: file.id,
+                version: file.version,
+            });
+        }
+    }
+
+    if query.prune {
+        for m in Mod::all(pool).await? {
+            if !storage.file_exists(&m.id, &m.version).await? {
+                Mod::delete(&m.id, &m.version, pool).await?;
+                storage.forget(&m.id, &m.version).await;
+                record_change("delete", &m.id, &m.version, pool).await;
+                report.pruned.push(m);
+            }
+        }
+    }
+
+    cache.invalidate(None);
+    let action = if query.prune {
+        "reconcile_prune"
+    } else {
+        "reconcile"
+    };
+    actor.audit(action, None, pool).await;
+
+    Ok(warp::reply::json(&report))
+}
+
+/// Merges packages stored under ids with uppercase letters into their lowercase ids
+#[tracing::instrument(level = "debug", skip(actor, cache, pool, storage))]
+async fn normalize(
+    actor: Actor,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let report = crate::normalize::normalize_ids(pool, storage).await?;
+    for merged in &report.merged {
+        for m in &merged.moved {
+            storage.forget(&m.id, &m.version).await;
+            record_change("delete", &m.id, &m.version, pool).await;
+            record_change("upload", &merged.id, &m.version, pool).await;
+        }
+    }
+    if !report.merged.is_empty() {
+        cache.invalidate(None);
+    }
+    actor.audit("normalize", None, pool).await;
+
+    Ok(warp::reply::json(&report))
+}
+
+#[tracing::instrument(level = "debug", skip(pool, file_repo))]
+async fn export(
+    downloads_path: &Path,
+    pool: &AnyPool,
+    file_repo: &FileRepo,
+) -> Result<Response, ApiError> {
+    // Every export gets its own file so concurrent ones don't overwrite each other
+    let mut path = downloads_path.as_os_str().to_owned();
+    path.push(format!(".export.{}.tar", crate::logging::uuid_v4()));
+    let path = PathBuf::from(path);
+
+    crate::archive::export(&path, pool, file_repo).await?;
+    // warp only streams bodies it reads from files itself, so the archive is served by its file
+    // filter. It's spawned since filters can't run inside another filter's request
+    let mut file = warp::service(warp::fs::file(path.clone()));
+    let mut response = tokio::spawn(async move { file.call(Request::new(String::new())).await })
+        .await
+        .or_ise()?
+        .or_ise()?;
+    // The open file keeps streaming once it's removed
+    tokio::fs::remove_file(&path).await.ok();
+    if !response.status().is_success() {
+        return Err(ApiError::Internal(anyhow::anyhow!(
+            "the export couldn't be read back: {}",
+            response.status()
+        )));
+    }
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
+    headers.insert(
+        CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"index.tar\""),
+    );
+    Ok(response)
+}
+
+#[tracing::instrument(level = "debug", skip(actor, contents, cache, pool, storage))]
+async fn import(
+    actor: Actor,
+    contents: Bytes,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let archive = Archive::parse(&contents).map_err(|e| ApiError::BadRequest {
+        reason: e.to_string(),
+    })?;
+    let report = crate::archive::import(archive, pool, storage).await?;
+    cache.invalidate(None);
+    actor.audit("import", None, pool).await;
+
+    Ok(warp::reply::json(&report))
+}
+
+/// Most bytes of the JSON body naming a directory to bulk import from
+const MAX_BULK_IMPORT_REQUEST: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct BulkImportDir {
+    /// Relative to `bulk-import-dir`
+    path: PathBuf,
+}
+
+/// Publishes every file of a tar archive or a directory as its own upload, as they're read
+#[tracing::instrument(level = "debug", skip(actor, body, state))]
+async fn bulk_import(
+    actor: Actor,
+    content_type: Option<String>,
+    body: BoxStream<'static, std::io::Result<Bytes>>,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let (pool, config) = (state.pool, state.config);
+    let is_json = content_type.is_some_and(|c| {
+        c.trim_start()
+            .to_ascii_lowercase()
+            .starts_with("application/json")
+    });
+    let mut source = if is_json {
+        let mut body = body;
+        let mut request = BytesMut::new();
+        while let Some(chunk) = body.try_next().await.map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })? {
+            if request.len() + chunk.len() > MAX_BULK_IMPORT_REQUEST {
+                return Err(ApiError::PayloadTooLarge {
+                    limit: MAX_BULK_IMPORT_REQUEST as u64,
+                });
+            }
+            request.extend_from_slice(&chunk);
+        }
+        let request: BulkImportDir =
+            serde_json::from_slice(&request).map_err(|e| ApiError::BadRequest {
+                reason: e.to_string(),
+            })?;
+        Source::dir(bulk_import_dir(&request.path, config).await?).await?
+    } else {
+        Source::Tar(TarReader::new(body))
+    };
+
+    let query = UploadQuery::default();
+    let mut report = BulkImportReport::default();
+    loop {
+        // Whatever was imported stays so, the report tells where it stopped
+        let file = match source.next(config.max_form_part_bytes).await {
+            Ok(Some(file)) => file,
+            Ok(None) => break,
+            Err(e) => {
+                report.stopped = Some(e.to_string());
+                break;
+            }
+        };
+        let not_imported = |reason| NotImported {
+            name: file.name.clone(),
+            reason,
+        };
+        let parsed = file.contents.and_then(|contents| {
+            parse_name(&file.name).map(|(id, version)| (id, version, contents))
+        });
+        let (id, version, contents) = match parsed {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                report.failed.push(not_imported(reason));
+                continue;
+            }
+        };
+
+        match publish(&id, &version, &actor, &query, contents, None, state).await {
+            Ok((StatusCode::CREATED, _)) => report.created.push(crate::types::Mod { id, version }),
+            Ok((StatusCode::OK, _)) => report.conflicted.push(not_imported(
+                "already published with the same file".to_owned(),
+            )),
+            Ok(_) | Err(ApiError::VersionConflict { .. }) => report
+                .conflicted
+                .push(not_imported("already published".to_owned())),
+            Err(e @ ApiError::InsufficientStorage { .. }) => {
+                report.stopped = Some(format!("{}: {}", file.name, e));
+                break;
+            }
+            Err(e) => {
+                if let ApiError::Internal(e) = &e {
+                    tracing::error!("couldn't import {}: {:#}", file.name, e);
+                }
+                report.failed.push(not_imported(e.to_string()));
+            }
+        }
+    }
+    actor.audit("bulk_import", None, pool).await;
+
+    Ok(warp::reply::json(&report))
+}
+
+/// Resolves a directory to import from, which has to be within `bulk-import-dir`
+async fn bulk_import_dir(path: &Path, config: &Config) -> Result<PathBuf, ApiError> {
+    let root = config
+        .bulk_import_dir
+        .as_ref()
+        .ok_or_else(|| ApiError::Unprocessable {
+            reason: "importing from a directory needs bulk-import-dir to be set".to_owned(),
+        })?;
+    let root = tokio::fs::canonicalize(root).await?;
+    let dir = tokio::fs::canonicalize(root.join(path))
+        .await
+        .map_err(|_| ApiError::NotFound { what: "directory" })?;
+    if !dir.starts_with(&root) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(dir)
+}
+
+#[tracing::instrument(level = "debug", skip(actor, verifier, pool, storage))]
+async fn verify(
+    actor: Actor,
+    query: VerifyQuery,
+    verifier: &'static Verifier,
+    pool: &'static AnyPool,
+    storage: &'static dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let status = if verifier.start(query.quarantine, pool, storage) {
+        actor.audit("verify", None, pool).await;
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::CONFLICT
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&verifier.status()),
+        status,
+    ))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, cache, pool, storage))]
+async fn purge(
+    actor: Actor,
+    contents: Bytes,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+    storage: &dyn Storage,
+) -> Result<impl Reply, ApiError> {
+    let body: PurgeBody = if contents.is_empty() {
+        PurgeBody::default()
+    } else {
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?
+    };
+    let purged = storage.purge(body.id.as_deref()).await;
+    cache.invalidate(body.id.as_deref());
+    tracing::info!(
+        "purged {} files, {} bytes from the cache",
+        purged.entries,
+        purged.bytes
+    );
+    actor.audit("cache_purge", None, pool).await;
+
+    Ok(warp::reply::json(&purged))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, backups, pool))]
+async fn backup(actor: Actor, backups: &Backups, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let path = backups.run(pool).await?;
+    actor.audit("backup", None, pool).await;
+
+    let file = path.file_name().and_then(|name| name.to_str()).or_ise()?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "file": file })),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Vacuums, checks or analyzes the database while the index is read-only
+#[tracing::instrument(level = "debug", skip(actor, contents, state))]
+async fn maintenance(
+    actor: Actor,
+    contents: Bytes,
+    state: &AppState,
+) -> Result<impl Reply, ApiError> {
+    let request: MaintenanceRequest =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    request
+        .check(state.pool)
+        .map_err(|reason| ApiError::Unprocessable {
+            reason: reason.to_owned(),
+        })?;
+
+    let report = state.maintenance.run(&request, state.pool).await?;
+    actor.audit("maintenance", None, state.pool).await;
+    Ok(warp::reply::json(&report))
+}
+
+#[tracing::instrument(level = "debug", skip(pool))]
+async fn game_versions(pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    Ok(warp::reply::json(&GameVersion::all(pool).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewGameVersion {
+    version: String,
+}
+
+#[tracing::instrument(level = "debug", skip(actor, contents, pool))]
+async fn add_game_version(
+    actor: Actor,
+    contents: Bytes,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    let NewGameVersion { version } =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    let version = version.trim();
+    // Listed in query strings, comma separated
+    if version.is_empty()
+        || version.len() > 32
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+    {
+        return Err(ApiError::Unprocessable {
+            reason: "game versions are at most 32 letters, digits, '.', '-', '_' or '+'".to_owned(),
+        });
+    }
+
+    let status = if GameVersion::insert(version, pool).await? {
+        actor.audit("add_game_version", None, pool).await;
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&GameVersion::all(pool).await?),
+        status,
+    ))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, janitor, pool))]
+async fn gc(actor: Actor, janitor: &Janitor, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let summary = janitor.run(pool).await?;
+    actor.audit("gc", None, pool).await;
+
+    Ok(warp::reply::json(&summary))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, cache, pool))]
+async fn add_alias(
+    actor: Actor,
+    contents: Bytes,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    let alias: Alias = serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+        reason: e.to_string(),
+    })?;
+
+    // Only a single hop is followed, so aliases can't point at or from other aliases
+    let reason = if alias.from == alias.to {
+        Some("a package can't be an alias of itself")
+    } else if Alias::target(&alias.to, pool).await?.is_some() {
+        Some("the new id is itself an alias")
+    } else if Alias::is_target(&alias.from, pool).await? {
+        Some("other aliases point at the old id")
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        return Err(ApiError::BadRequest {
+            reason: reason.to_owned(),
+        });
+    }
+
+    alias.insert(pool).await?;
+    cache.invalidate(Some(&alias.from));
+    actor.audit_package("alias", &alias.from, pool).await;
+
+    Ok(warp::reply::json(&alias))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, read_only, pool))]
+async fn set_read_only(
+    actor: Actor,
+    contents: Bytes,
+    read_only: &AtomicBool,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    let state: ReadOnlyState =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    read_only.store(state.enabled, Ordering::Relaxed);
+    tracing::info!(
+        "read-only mode {}",
+        if state.enabled { "on" } else { "off" }
+    );
+    let action = if state.enabled {
+        "read_only_on"
+    } else {
+        "read_only_off"
+    };
+    actor.audit(action, None, pool).await;
+
+    Ok(warp::reply::json(&state))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, pool))]
+async fn add_key(actor: Actor, contents: Bytes, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let mut pub_key: PublishKey =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    pub_key.pw = pub_key.pw.trim().to_owned();
+    pub_key.user = pub_key.user.trim().to_owned();
+    for (field, value) in [("pw", &pub_key.pw), ("user", &pub_key.user)] {
+        let reason = if value.is_empty() {
+            "is empty"
+        } else if value.chars().any(char::is_control) {
+            "contains control characters"
+        } else if field == "pw" && value.chars().any(char::is_whitespace) {
+            // The Authorization header splits on it, so the key could never be sent
+            "contains whitespace"
+        } else if value.len() > MAX_KEY_LEN {
+            "is too long"
+        } else {
+            continue;
+        };
+        return Err(ApiError::Unprocessable {
+            reason: format!("{} {}", field, reason),
+        });
+    }
+    if !pub_key.insert(pool).await? {
+        return Ok(warp::reply::with_status("", StatusCode::CONFLICT));
+    }
+    actor.audit("add_key", None, pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::CREATED))
+}
+
+/// Keys without the keys themselves, with when they were last used
+#[tracing::instrument(level = "debug", skip(state))]
+async fn list_keys(state: &AppState) -> Result<impl Reply, ApiError> {
+    let mut keys = PublishKey::list(state.pool).await?;
+    for key in &mut keys {
+        if let Some(used) = state.write_behind.pending_key_use(&key.user) {
+            key.last_used_at = Some(used);
+        }
+    }
+    Ok(warp::reply::json(&keys))
+}
+
+/// Who a key belongs to and what it may publish
+#[derive(Debug, Serialize)]
+struct KeyIdentity<'a> {
+    user: &'a str,
+    scope: Option<&'a str>,
+    expires_at: Option<i64>,
+    admin: bool,
+}
+
+/// Tells tools whether their key is still accepted, without doing anything with it
+async fn verify_key(actor: Actor) -> Result<impl Reply, ApiError> {
+    let key = actor.key.as_ref();
+    Ok(warp::reply::json(&KeyIdentity {
+        user: actor.name(),
+        scope: key.and_then(|key| key.scope.as_deref()),
+        expires_at: key.and_then(|key| key.expires_at),
+        admin: actor.role.is_some(),
+    }))
+}
+
+/// Enables a user's keys disabled for going unused
+async fn enable_key(actor: Actor, contents: Bytes, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let EnableKey { user } =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
+    if PublishKey::enable_user(&user, pool).await? == 0 {
+        return Err(ApiError::NotFound { what: "key" });
+    }
+    actor.audit("enable_key", None, pool).await;
+
+    Ok(warp::reply::with_status("", StatusCode::OK))
+}
+
+#[tracing::instrument(level = "debug", skip(pool))]
+async fn key_uploads(
+    user: String,
+    query: ListQuery,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let offset = (page - 1).saturating_mul(per_page);
+
+    let total = Mod::count_published_by(&user, pool).await?;
+    let uploads = Mod::published_by(&user, per_page, offset, pool).await?;
+
+    let mut response = warp::reply::json(&uploads).into_response();
+    response.headers_mut().insert("X-Total-Count", total.into());
+    Ok(response)
+}
+
+/// Yanks everything a user published, for when their key was compromised
+#[tracing::instrument(level = "debug", skip(actor, cache, pool))]
+async fn quarantine_key(
+    user: String,
+    actor: Actor,
+    query: QuarantineQuery,
+    cache: &ResponseCache,
+    pool: &AnyPool,
+) -> Result<impl Reply, ApiError> {
+    if !query.quarantine {
+        return Err(ApiError::BadRequest {
+            reason: "pass quarantine=true to yank every version the user published".to_owned(),
+        });
+    }
+
+    let yanked = Mod::yank_published_by(&user, pool).await?;
+    for m in &yanked {
+        cache.invalidate(Some(&m.id));
+        actor.audit("yank", Some((&m.id, &m.version)), pool).await;
+        record_change("yank", &m.id, &m.version, pool).await;
+    }
+    Ok(warp::reply::json(&yanked))
+}
+
+#[tracing::instrument(level = "debug", skip(actor, pool))]
+async fn delete_key(actor: Actor, contents: Bytes, pool: &AnyPool) -> Result<impl Reply, ApiError> {
+    let pub_key: OptPublishKey =
+        serde_json::from_slice(&contents).map_err(|e| ApiError::BadRequest {
+            reason: e.to_string(),
+        })?;
 
     if let Some(pw) = pub_key.pw {
-        PublishKey::delete_pw(&pw, pool).await.or_nf()?;
+        PublishKey::delete_pw(&pw, pool).await.or_nf("key")?;
+        actor.audit("delete_key", None, pool).await;
         return Ok(warp::reply::with_status("", StatusCode::OK));
     } else if let Some(user) = pub_key.user {
-        PublishKey::delete_user(&user, pool).await.or_nf()?;
+        PublishKey::delete_user(&user, pool).await.or_nf("key")?;
+        actor.audit("delete_key", None, pool).await;
         return Ok(warp::reply::with_status("", StatusCode::OK));
     }
 
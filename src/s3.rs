@@ -0,0 +1,330 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use warp::http::{Method, StatusCode};
+
+use crate::{
+    config::S3Config,
+    datetime::DateTime,
+    http_client::{Endpoint, uri_encode},
+    storage::{Scan, Storage, artifact_key, file_key},
+};
+
+/// Stores files in an S3 compatible bucket, addressed path-style
+pub struct S3Storage {
+    config: &'static S3Config,
+    endpoint: Endpoint,
+}
+
+impl S3Storage {
+    pub fn new(config: &'static S3Config) -> anyhow::Result<S3Storage> {
+        Ok(S3Storage {
+            config,
+            endpoint: Endpoint::parse(&config.endpoint)?,
+        })
+    }
+
+    /// Sends a signed request for an object, or for the bucket itself if `key` is empty
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Bytes,
+    ) -> Result<(StatusCode, Bytes)> {
+        let mut path = format!(
+            "{}/{}",
+            self.endpoint.path(),
+            uri_encode(&self.config.bucket)
+        );
+        if !key.is_empty() {
+            for segment in key.split('/') {
+                path.push('/');
+                path.push_str(&uri_encode(segment));
+            }
+        }
+
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k), uri_encode(v)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let datetime = amz_datetime(SystemTime::now());
+        let authorization = sign(
+            &method,
+            &path,
+            &query,
+            &[
+                ("host", self.endpoint.authority()),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &datetime),
+            ],
+            &payload_hash,
+            &datetime,
+            self.config,
+        );
+
+        let uri = if query.is_empty() {
+            path
+        } else {
+            format!("{}?{}", path, query)
+        };
+        self.endpoint
+            .send(
+                &method,
+                &uri,
+                &[
+                    ("x-amz-content-sha256", &payload_hash),
+                    ("x-amz-date", &datetime),
+                    ("authorization", &authorization),
+                ],
+                &body,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn get_file(&self, id: &str, ver: &Version) -> Result<Bytes> {
+        let (status, body) = self
+            .request(Method::GET, &file_key(id, ver), &[], Bytes::new())
+            .await?;
+        match status {
+            StatusCode::OK => Ok(body),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> Result<()> {
+        let (status, body) = self
+            .request(Method::PUT, &file_key(id, ver), &[], contents)
+            .await?;
+        match status {
+            StatusCode::OK => Ok(()),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn delete_file(&self, id: &str, ver: &Version) -> Result<()> {
+        // Deleting an object that doesn't exist succeeds, so check first
+        if !self.file_exists(id, ver).await? {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        let (status, body) = self
+            .request(Method::DELETE, &file_key(id, ver), &[], Bytes::new())
+            .await?;
+        match status {
+            StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> Result<bool> {
+        let (status, body) = self
+            .request(Method::HEAD, &file_key(id, ver), &[], Bytes::new())
+            .await?;
+        match status {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn get_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<Bytes> {
+        let (status, body) = self
+            .request(Method::GET, &artifact_key(id, ver, name), &[], Bytes::new())
+            .await?;
+        match status {
+            StatusCode::OK => Ok(body),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn write_artifact(
+        &self,
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: Bytes,
+    ) -> Result<()> {
+        let (status, body) = self
+            .request(Method::PUT, &artifact_key(id, ver, name), &[], contents)
+            .await?;
+        match status {
+            StatusCode::OK => Ok(()),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn delete_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<()> {
+        let key = artifact_key(id, ver, name);
+        // Deleting an object that doesn't exist succeeds, so check first
+        let (status, body) = self.request(Method::HEAD, &key, &[], Bytes::new()).await?;
+        match status {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return Err(ErrorKind::NotFound.into()),
+            status => return Err(status_error(status, &body)),
+        }
+
+        let (status, body) = self
+            .request(Method::DELETE, &key, &[], Bytes::new())
+            .await?;
+        match status {
+            StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+            status => Err(status_error(status, &body)),
+        }
+    }
+
+    async fn scan(&self) -> Result<Scan> {
+        let mut scan = Scan::default();
+
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2")];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+
+            let (status, body) = self.request(Method::GET, "", &query, Bytes::new()).await?;
+            if status != StatusCode::OK {
+                return Err(status_error(status, &body));
+            }
+            let xml = std::str::from_utf8(&body).map_err(Error::other)?;
+
+            for contents in xml.split("<Contents>").skip(1) {
+                let key = xml_tag(contents, "Key").map(xml_unescape);
+                let size = xml_tag(contents, "Size").and_then(|s| s.parse().ok());
+                match (key, size) {
+                    (Some(key), Some(size)) => scan.push(key, size),
+                    _ => return Err(Error::other("malformed S3 listing")),
+                }
+            }
+
+            if xml_tag(xml, "IsTruncated") != Some("true") {
+                break;
+            }
+            continuation_token = xml_tag(xml, "NextContinuationToken").map(xml_unescape);
+            if continuation_token.is_none() {
+                return Err(Error::other(
+                    "truncated S3 listing without a continuation token",
+                ));
+            }
+        }
+
+        Ok(scan)
+    }
+}
+
+fn status_error(status: StatusCode, body: &[u8]) -> Error {
+    if status == StatusCode::NOT_FOUND {
+        ErrorKind::NotFound.into()
+    } else {
+        Error::other(format!(
+            "S3 responded with {}: {}",
+            status,
+            String::from_utf8_lossy(body)
+        ))
+    }
+}
+
+/// Computes the `Authorization` header of a request with AWS Signature Version 4. `headers` must
+/// be lowercase and sorted by name, and `path` and `query` already canonical
+pub fn sign(
+    method: &Method,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+    datetime: &str,
+    config: &S3Config,
+) -> String {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, path, query, canonical_headers, signed_headers, payload_hash
+    );
+
+    let date = &datetime[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        datetime,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac_sha256(
+        format!("AWS4{}", config.secret_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let key = hmac_sha256(&key, config.region.as_bytes());
+    let key = hmac_sha256(&key, b"s3");
+    let key = hmac_sha256(&key, b"aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        config.access_key, scope, signed_headers, signature
+    )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Formats a time as `YYYYMMDD'T'HHMMSS'Z'`
+pub fn amz_datetime(time: SystemTime) -> String {
+    let dt = DateTime::from_system_time(time);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    )
+}
+
+fn xml_tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(&xml[start..end])
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use semver::Version;
+use serde::Serialize;
+use sqlx::AnyPool;
+use tokio::task::JoinHandle;
+
+use crate::{
+    backup::Backups,
+    blocklist::Blocklist,
+    config::{Config, StorageKind},
+    confirmations::DeleteConfirmations,
+    db::is_postgres,
+    file_repo::FileRepo,
+    janitor::{Janitor, spawn_history_pruner, spawn_key_expiry},
+    maintenance::Maintenance,
+    mirror::Mirror,
+    ratelimit::{AuthLimiter, RateLimiter},
+    response_cache::ResponseCache,
+    verify::Verifier,
+    write_behind::WriteBehind,
+};
+
+/// How long an admin has to confirm deleting a protected version
+const DELETE_CONFIRMATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Everything the routes share, built once and leaked so handlers can hold on to it
+pub struct AppState {
+    pub config: &'static Config,
+    pub pool: &'static AnyPool,
+    pub file_repo: &'static FileRepo,
+    pub mirror: Option<&'static Mirror>,
+    pub counters: Counters,
+    /// Whether writes are refused, toggled at runtime by admins
+    pub read_only: AtomicBool,
+    pub auth_limiter: AuthLimiter,
+    pub rate_limiter: Option<RateLimiter>,
+    pub blocklist: Blocklist,
+    pub response_cache: ResponseCache,
+    pub delete_confirmations: DeleteConfirmations,
+    pub write_behind: WriteBehind,
+    pub maintenance: Maintenance,
+    /// Versions an upload is storing the file of, so another upload of one can tell it raced it
+    pub storing: Mutex<HashSet<(String, Version)>>,
+    pub backups: Option<Backups>,
+    /// Only local storage has directories to tidy up
+    pub janitor: Option<Janitor>,
+    pub verifier: Verifier,
+    /// Started by `spawn_tasks`, stopped by `stop_tasks`
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    started: Instant,
+}
+
+impl AppState {
+    pub fn new(
+        config: &'static Config,
+        pool: &'static AnyPool,
+        file_repo: &'static FileRepo,
+        mirror: Option<&'static Mirror>,
+    ) -> AppState {
+        AppState {
+            config,
+            pool,
+            file_repo,
+            mirror,
+            counters: Counters::default(),
+            read_only: AtomicBool::new(config.read_only),
+            auth_limiter: AuthLimiter::new(
+                config.auth_max_failures,
+                Duration::from_secs(config.auth_failure_window),
+            ),
+            rate_limiter: config.rate_limit_per_minute.map(|per_minute| {
+                RateLimiter::new(per_minute, config.rate_limit_burst.unwrap_or(per_minute))
+            }),
+            blocklist: Blocklist::default(),
+            response_cache: ResponseCache::new(
+                Duration::from_secs(config.response_cache_ttl_secs),
+                config.response_cache_max_entries,
+                is_postgres(pool),
+            ),
+            delete_confirmations: DeleteConfirmations::new(DELETE_CONFIRMATION_TTL),
+            write_behind: WriteBehind::new(config.write_behind_max_pending),
+            maintenance: Maintenance::default(),
+            storing: Mutex::default(),
+            backups: config.backup.as_ref().map(Backups::new),
+            janitor: (config.storage == StorageKind::Local)
+                .then(|| Janitor::new(config.downloads_path.clone(), &config.gc)),
+            verifier: Verifier::default(),
+            tasks: Mutex::default(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts sweeping caches, backing up, tidying up and flushing in the background, as far as
+    /// the config asks for each
+    pub fn spawn_tasks(&'static self) {
+        let (config, pool) = (self.config, self.pool);
+        let tasks = [
+            self.file_repo.spawn_sweeper(),
+            self.rate_limiter.as_ref().map(RateLimiter::spawn_sweeper),
+            self.backups
+                .as_ref()
+                .and_then(|backups| backups.spawn(pool)),
+            self.janitor
+                .as_ref()
+                .and_then(|janitor| janitor.spawn(pool)),
+            Some(spawn_history_pruner(config, pool)),
+            spawn_key_expiry(config, pool, &self.write_behind),
+            Some(
+                self.write_behind
+                    .spawn(Duration::from_secs(config.write_behind_secs), pool),
+            ),
+        ];
+        self.tasks
+            .lock()
+            .unwrap()
+            .extend(tasks.into_iter().flatten());
+        if config.verify_on_start {
+            self.verifier.start(false, pool, self.file_repo);
+        }
+    }
+
+    /// Stops the background tasks, then writes out the download counts and key uses still in
+    /// memory, which would be lost otherwise
+    pub async fn stop_tasks(&self) {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in &tasks {
+            task.abort();
+        }
+        self.verifier.stop();
+        for task in tasks {
+            task.await.ok();
+        }
+        self.write_behind.flush(self.pool).await;
+    }
+
+    /// Leaks the state so it lives as long as the routes built over it
+    pub fn leak(self) -> &'static AppState {
+        Box::leak(Box::new(self))
+    }
+
+    /// Counters since the process started, for `/stats/runtime`
+    pub async fn runtime_stats(&self) -> RuntimeStats {
+        let cache = self.file_repo.cache_stats().await;
+        let (response_cache_hits, response_cache_misses) = self.response_cache.counts();
+        let (pending_writes, flushed_writes) = self.write_behind.counts();
+        RuntimeStats {
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            bytes_served: self.counters.bytes_served.load(Ordering::Relaxed),
+            downloads: self.counters.downloads.load(Ordering::Relaxed),
+            uploads: self.counters.uploads.load(Ordering::Relaxed),
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+            cache_entries: cache.entries,
+            cache_bytes: cache.bytes,
+            cache_evictions: cache.evictions,
+            response_cache_hits,
+            response_cache_misses,
+            pending_writes,
+            flushed_writes,
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// In-memory counters, only kept until the process exits
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub requests: AtomicU64,
+    /// Bytes of response bodies whose length was known
+    pub bytes_served: AtomicU64,
+    pub downloads: AtomicU64,
+    pub uploads: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeStats {
+    pub requests: u64,
+    pub bytes_served: u64,
+    pub downloads: u64,
+    pub uploads: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Files in memory now, and their size
+    pub cache_entries: u64,
+    pub cache_bytes: u64,
+    /// Files dropped from memory because they expired
+    pub cache_evictions: u64,
+    /// List and resolve responses answered from memory, and ones that weren't
+    pub response_cache_hits: u64,
+    pub response_cache_misses: u64,
+    /// Download counts and key uses not written to the database yet
+    pub pending_writes: u64,
+    /// Statements run writing them
+    pub flushed_writes: u64,
+    pub uptime_secs: u64,
+}
@@ -0,0 +1,450 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use semver::{BuildMetadata, Prerelease, Version};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncReadExt};
+
+/// Longest file or directory name most filesystems take, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+/// Longest path Linux takes, in bytes
+const MAX_PATH_LEN: usize = 4096;
+
+/// A file found in storage
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    pub id: String,
+    pub version: Version,
+    pub size: u64,
+}
+
+/// Files found in storage
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// Files laid out as `{id}/{major}/{minor}/{patch}`
+    pub files: Vec<StoredFile>,
+    /// Keys of anything else
+    pub skipped: Vec<String>,
+}
+
+impl Scan {
+    /// Sorts a file or anything else found at the given key into the scan
+    pub fn push(&mut self, key: String, size: u64) {
+        match parse_key(&key) {
+            Some((id, version)) => self.files.push(StoredFile { id, version, size }),
+            None => self.skipped.push(key),
+        }
+    }
+}
+
+/// What was dropped from a cache
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Purged {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// Where qmod files are kept
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_file(&self, id: &str, ver: &Version) -> Result<Bytes>;
+
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> Result<()>;
+
+    /// Fails with [`ErrorKind::NotFound`] if there's no such file
+    async fn delete_file(&self, id: &str, ver: &Version) -> Result<()>;
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> Result<bool>;
+
+    /// Lists everything in storage
+    async fn scan(&self) -> Result<Scan>;
+
+    /// Hex SHA-256 of a file as stored, never from a cache
+    async fn sha256(&self, id: &str, ver: &Version) -> Result<String> {
+        Ok(hex::encode(Sha256::digest(self.get_file(id, ver).await?)))
+    }
+
+    /// Makes the file of one version the file of another too, without copying it. Fails with
+    /// [`ErrorKind::Unsupported`] if the backend can't
+    async fn link_file(
+        &self,
+        _from_id: &str,
+        _from_ver: &Version,
+        _id: &str,
+        _ver: &Version,
+    ) -> Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Bytes a file takes up in storage, which may be compressed
+    async fn stored_size(&self, id: &str, ver: &Version) -> Result<u64> {
+        Ok(self.get_file(id, ver).await?.len() as u64)
+    }
+
+    /// Moves a damaged file out of the way, keeping it around for inspection
+    async fn quarantine(&self, _id: &str, _ver: &Version) -> Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Reads a named artifact of a version
+    async fn get_artifact(&self, _id: &str, _ver: &Version, _name: &str) -> Result<Bytes> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    async fn write_artifact(
+        &self,
+        _id: &str,
+        _ver: &Version,
+        _name: &str,
+        _contents: Bytes,
+    ) -> Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Fails with [`ErrorKind::NotFound`] if there's no such artifact
+    async fn delete_artifact(&self, _id: &str, _ver: &Version, _name: &str) -> Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// Drops anything cached about a file, for when it changed behind our back
+    async fn forget(&self, _id: &str, _ver: &Version) {}
+
+    /// Drops everything cached, or only what's cached about the package `id`
+    async fn purge(&self, _id: Option<&str>) -> Purged {
+        Purged::default()
+    }
+}
+
+/// Key a file is stored under, `{id}/{major}/{minor}/{patch}` or `{id}/{major}/{minor}/{patch}-{pre}`
+/// for prereleases, followed by `+{build}` for versions with build metadata
+pub fn file_key(id: &str, ver: &Version) -> String {
+    format!("{}/{}/{}/{}", id, ver.major, ver.minor, file_name(ver))
+}
+
+/// Last part of a version's key, the patch number and any prerelease and build metadata
+fn file_name(ver: &Version) -> String {
+    let mut name = ver.patch.to_string();
+    if !ver.pre.is_empty() {
+        name = format!("{}-{}", name, ver.pre);
+    }
+    if !ver.build.is_empty() {
+        name = format!("{}+{}", name, ver.build);
+    }
+    name
+}
+
+/// Builds of a version share its artifacts, so their keys leave build metadata out
+fn without_build(ver: &Version) -> Version {
+    let mut ver = ver.clone();
+    ver.build = BuildMetadata::EMPTY;
+    ver
+}
+
+/// Appended to a version's key to get the directory its artifacts are kept in
+const ARTIFACTS_SUFFIX: &str = ".artifacts";
+
+/// Key a named artifact of a version is stored under, next to the version's own file
+pub fn artifact_key(id: &str, ver: &Version, name: &str) -> String {
+    format!(
+        "{}{}/{}",
+        file_key(id, &without_build(ver)),
+        ARTIFACTS_SUFFIX,
+        name
+    )
+}
+
+/// Whether an artifact name can be used as is in keys and paths, it can't contain slashes or
+/// start with a dot
+pub fn is_valid_artifact_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Parses a key in the `{id}/{major}/{minor}/{patch}.artifacts/{name}` layout
+pub fn parse_artifact_key(key: &str) -> Option<(String, Version, String)> {
+    let (file, name) = key.rsplit_once('/')?;
+    let (id, version) = parse_key(file.strip_suffix(ARTIFACTS_SUFFIX)?)?;
+    is_valid_artifact_name(name).then(|| (id, version, name.to_owned()))
+}
+
+/// Parses a key in the layout of [`file_key`]
+pub fn parse_key(key: &str) -> Option<(String, Version)> {
+    let mut parts = key.split('/');
+    let id = parts.next().filter(|id| !id.is_empty())?;
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let name = parts.next()?;
+    let (name, build) = match name.split_once('+') {
+        Some((name, build)) => (
+            name,
+            BuildMetadata::new(build).ok().filter(|b| !b.is_empty())?,
+        ),
+        None => (name, BuildMetadata::EMPTY),
+    };
+    let (patch, pre) = match name.split_once('-') {
+        Some((patch, pre)) => (patch, Prerelease::new(pre).ok().filter(|p| !p.is_empty())?),
+        None => (name, Prerelease::EMPTY),
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let mut version = Version::new(major, minor, patch.parse().ok()?);
+    version.pre = pre;
+    version.build = build;
+    Some((id.to_owned(), version))
+}
+
+/// Stores files on the local disk
+pub struct LocalStorage {
+    path: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(path: PathBuf) -> LocalStorage {
+        LocalStorage { path }
+    }
+
+    /// Directories a version's file is kept under, `{id}`, `{id}/{major}` and
+    /// `{id}/{major}/{minor}`. Every path is joined one component at a time, so nothing depends
+    /// on the platform's separator
+    pub fn version_dirs(&self, id: &str, ver: &Version) -> [PathBuf; 3] {
+        let id_dir = self.path.join(id);
+        let major_dir = id_dir.join(ver.major.to_string());
+        let minor_dir = major_dir.join(ver.minor.to_string());
+        [id_dir, major_dir, minor_dir]
+    }
+
+    /// Directory a version's file is kept in, `{id}/{major}/{minor}`
+    pub fn dir_for(&self, id: &str, ver: &Version) -> PathBuf {
+        let [_, _, dir] = self.version_dirs(id, ver);
+        dir
+    }
+
+    /// Where a version's file is kept, laid out like its [`file_key`]
+    pub fn version_path(&self, id: &str, ver: &Version) -> PathBuf {
+        self.dir_for(id, ver).join(file_name(ver))
+    }
+
+    /// Directory a version's artifacts are kept in, shared by its builds
+    pub fn artifacts_dir(&self, id: &str, ver: &Version) -> PathBuf {
+        let mut dir = file_name(&without_build(ver));
+        dir.push_str(ARTIFACTS_SUFFIX);
+        self.dir_for(id, ver).join(dir)
+    }
+
+    /// Where a named artifact of a version is kept, laid out like its [`artifact_key`]
+    pub fn artifact_path(&self, id: &str, ver: &Version, name: &str) -> PathBuf {
+        self.artifacts_dir(id, ver).join(name)
+    }
+}
+
+/// Fails with [`ErrorKind::InvalidFilename`] if the filesystem wouldn't take the path, or a part
+/// of its `key` isn't a plain name and would lead somewhere else, so that's found out before any
+/// directory is made for it
+fn check_path(key: &str, path: &Path) -> Result<()> {
+    // A part of `.`, `..` or a root would lead out of where the key belongs
+    let escapes = key.split('/').any(|part| {
+        let mut components = Path::new(part).components();
+        !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == part
+        )
+    });
+    if escapes {
+        return Err(Error::new(
+            ErrorKind::InvalidFilename,
+            "the package id or version can't be stored",
+        ));
+    }
+
+    let too_long = path.as_os_str().len() > MAX_PATH_LEN
+        || path
+            .components()
+            .any(|c| c.as_os_str().len() > MAX_NAME_LEN);
+    match too_long {
+        // The path would tell clients where files are kept
+        true => Err(Error::new(
+            ErrorKind::InvalidFilename,
+            "the package id or version is too long to be stored",
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Removes the given directories deepest first, stopping at the first still in use
+async fn remove_empty_dirs(dirs: &[PathBuf]) {
+    for dir in dirs.iter().rev() {
+        if fs::remove_dir(dir).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Path of a storage key under `root`, joining each `/` separated part on its own
+pub fn key_path(root: &Path, key: &str) -> PathBuf {
+    key.split('/')
+        .fold(root.to_path_buf(), |path, part| path.join(part))
+}
+
+/// Removes a file before it's replaced, since it may be a hard link another version shares and
+/// writing over it would change that version's file too
+async fn remove_link(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn get_file(&self, id: &str, ver: &Version) -> Result<Bytes> {
+        Ok(fs::read(self.version_path(id, ver)).await?.into())
+    }
+
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> Result<()> {
+        let path = self.version_path(id, ver);
+        check_path(&file_key(id, ver), &path)?;
+        // Written next to it first, renaming over the file replaces it rather than writing
+        // through a hard link another version shares, and never leaves it half written
+        let partial = self
+            .dir_for(id, ver)
+            .join(format!(".{}.partial", crate::logging::uuid_v4()));
+        let written = async {
+            fs::create_dir_all(self.dir_for(id, ver)).await?;
+            fs::write(&partial, contents).await?;
+            fs::rename(&partial, &path).await
+        }
+        .await;
+        if written.is_err() {
+            // Neither a partly written file nor directories made just for it are worth keeping,
+            // whatever was there before is left alone
+            fs::remove_file(&partial).await.ok();
+            remove_empty_dirs(&self.version_dirs(id, ver)).await;
+        }
+        written
+    }
+
+    async fn delete_file(&self, id: &str, ver: &Version) -> Result<()> {
+        let path = self.version_path(id, ver);
+        check_path(&file_key(id, ver), &path)?;
+        fs::remove_file(path).await?;
+        // Then try to delete our directories
+        remove_empty_dirs(&self.version_dirs(id, ver)).await;
+        Ok(())
+    }
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> Result<bool> {
+        fs::try_exists(self.version_path(id, ver)).await
+    }
+
+    async fn sha256(&self, id: &str, ver: &Version) -> Result<String> {
+        let mut file = fs::File::open(self.version_path(id, ver)).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await? {
+                0 => break,
+                n => hasher.update(&buf[..n]),
+            }
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    async fn stored_size(&self, id: &str, ver: &Version) -> Result<u64> {
+        Ok(fs::metadata(self.version_path(id, ver)).await?.len())
+    }
+
+    async fn link_file(
+        &self,
+        from_id: &str,
+        from_ver: &Version,
+        id: &str,
+        ver: &Version,
+    ) -> Result<()> {
+        let path = self.version_path(id, ver);
+        check_path(&file_key(id, ver), &path)?;
+        let linked = async {
+            fs::create_dir_all(self.dir_for(id, ver)).await?;
+            remove_link(&path).await?;
+            fs::hard_link(self.version_path(from_id, from_ver), &path).await
+        }
+        .await;
+        if linked.is_err() {
+            remove_empty_dirs(&self.version_dirs(id, ver)).await;
+        }
+        linked
+    }
+
+    async fn get_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<Bytes> {
+        Ok(fs::read(self.artifact_path(id, ver, name)).await?.into())
+    }
+
+    async fn write_artifact(
+        &self,
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: Bytes,
+    ) -> Result<()> {
+        let path = self.artifact_path(id, ver, name);
+        check_path(&artifact_key(id, ver, name), &path)?;
+        fs::create_dir_all(self.artifacts_dir(id, ver)).await?;
+        fs::write(path, contents).await
+    }
+
+    async fn delete_artifact(&self, id: &str, ver: &Version, name: &str) -> Result<()> {
+        let path = self.artifact_path(id, ver, name);
+        check_path(&artifact_key(id, ver, name), &path)?;
+        fs::remove_file(path).await?;
+        // Then the artifacts directory, if that was the last one
+        fs::remove_dir(self.artifacts_dir(id, ver)).await.ok();
+        Ok(())
+    }
+
+    /// Renames the file with a `.corrupt` suffix, which scans skip over
+    async fn quarantine(&self, id: &str, ver: &Version) -> Result<()> {
+        let path = self.version_path(id, ver);
+        check_path(&file_key(id, ver), &path)?;
+        let mut quarantined = path.clone().into_os_string();
+        quarantined.push(".corrupt");
+        fs::rename(path, quarantined).await
+    }
+
+    async fn scan(&self) -> Result<Scan> {
+        let mut scan = Scan::default();
+
+        let mut dirs = vec![(self.path.clone(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound && prefix.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let key = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                let file_type = entry.file_type().await?;
+                if entry.file_name().to_str().is_none() {
+                    scan.skipped.push(key);
+                } else if file_type.is_dir() {
+                    dirs.push((entry.path(), key + "/"));
+                } else if file_type.is_file() {
+                    scan.push(key, entry.metadata().await?.len());
+                } else {
+                    scan.skipped.push(key);
+                }
+            }
+        }
+
+        Ok(scan)
+    }
+}
@@ -0,0 +1,531 @@
+use bytes::Bytes;
+use semver::Version;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::{Context, Layer};
+use warp::http::StatusCode;
+use warp::http::header::CONTENT_TYPE;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::{AdminRole, Config};
+use crate::file_repo::FileRepo;
+use crate::mirror::Mirror;
+use crate::state::AppState;
+use crate::storage::{LocalStorage, Scan, Storage, artifact_key, file_key};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// The composed routes of a test instance
+pub trait Routes:
+    Filter<Extract = (Response,), Error = Rejection> + Send + Sync + Clone + 'static
+{
+}
+
+impl<F> Routes for F where
+    F: Filter<Extract = (Response,), Error = Rejection> + Send + Sync + Clone + 'static
+{
+}
+
+/// Logs to the test output, whichever test gets here first installs the subscriber
+pub fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter("debug")
+        .with_span_events(FmtSpan::CLOSE)
+        .with_test_writer()
+        .try_init()
+        .ok();
+}
+
+/// A span or event seen by [`Capture`], with its fields formatted
+#[derive(Debug, Clone)]
+pub struct Captured {
+    pub name: &'static str,
+    pub fields: HashMap<&'static str, String>,
+}
+
+impl Visit for Captured {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.insert(field.name(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.fields.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// Layer remembering every span and event, for tests about what gets logged
+#[derive(Clone, Default)]
+pub struct Capture {
+    spans: Arc<Mutex<Vec<Captured>>>,
+    /// Where in `spans` each open span is, ids are reused once spans close
+    open: Arc<Mutex<HashMap<Id, usize>>>,
+    events: Arc<Mutex<Vec<Captured>>>,
+}
+
+impl Capture {
+    /// Every span with the given name, in the order they were opened
+    pub fn spans(&self, name: &str) -> Vec<Captured> {
+        let spans = self.spans.lock().unwrap();
+        spans.iter().filter(|s| s.name == name).cloned().collect()
+    }
+
+    /// Every event with the given message
+    pub fn events(&self, message: &str) -> Vec<Captured> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|e| e.fields.get("message").is_some_and(|m| m == message))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Capture {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+        let mut span = Captured {
+            name: attrs.metadata().name(),
+            fields: HashMap::new(),
+        };
+        attrs.record(&mut span);
+        let mut spans = self.spans.lock().unwrap();
+        self.open.lock().unwrap().insert(id.clone(), spans.len());
+        spans.push(span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+        if let Some(&i) = self.open.lock().unwrap().get(id) {
+            values.record(&mut self.spans.lock().unwrap()[i]);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut captured = Captured {
+            name: event.metadata().name(),
+            fields: HashMap::new(),
+        };
+        event.record(&mut captured);
+        self.events.lock().unwrap().push(captured);
+    }
+}
+
+/// Creates an empty directory no other test, or earlier run, uses
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("bs-quest-index-tests")
+        .join(format!("{}-{}", name, crate::logging::uuid_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Config with a database and downloads directory of its own
+pub fn test_config(name: &str) -> Config {
+    let dir = test_dir(name);
+    Config {
+        port: 0,
+        database_url: dir.join("index.db").to_str().unwrap().to_owned(),
+        downloads_path: dir.join("downloads"),
+        log_level: None,
+        log_format: Default::default(),
+        admin_keys: [("admin_password".to_owned(), AdminRole::Full)]
+            .into_iter()
+            .collect(),
+        allow_no_admin: false,
+        auth_max_failures: 10,
+        auth_failure_window: 300,
+        trust_forwarded_for: false,
+        trusted_proxies: Vec::new(),
+        rate_limit_per_minute: None,
+        rate_limit_burst: None,
+        cors_allowed_origins: None,
+        max_resolve_limit: 100,
+        compression: true,
+        missing_file_ttl: 60,
+        cache_ttl_secs: None,
+        cache_on_upload: false,
+        response_cache_ttl_secs: 30,
+        response_cache_max_entries: 1024,
+        write_behind_secs: 5,
+        write_behind_max_pending: 1000,
+        slow_request_ms: None,
+        warm_cache: false,
+        compress_storage: false,
+        storage: Default::default(),
+        s3: None,
+        storage_timeout: 30,
+        busy_timeout: 5,
+        skip_migrations: false,
+        max_connections: 10,
+        upstream_url: None,
+        upstream_timeout: 10,
+        sendfile_header: None,
+        sendfile_prefix: String::new(),
+        public_base_url: None,
+        max_package_bytes: None,
+        max_total_bytes: None,
+        max_uploads_per_package_per_hour: None,
+        max_version_number: 1_000_000_000,
+        max_form_bytes: 128 * 1024 * 1024,
+        max_form_part_bytes: 64 * 1024 * 1024,
+        max_decompressed_bytes: 128 * 1024 * 1024,
+        max_import_bytes: 128 * 1024 * 1024,
+        allow_build_variants: false,
+        require_approval_for_new_packages: false,
+        verify_on_start: false,
+        read_only: false,
+        download_history_days: 30,
+        change_retention_days: 30,
+        key_expiry_days: None,
+        delete_protection_hours: None,
+        bulk_import_dir: None,
+        backup: None,
+        gc: Default::default(),
+        qpm_compat: false,
+    }
+}
+
+pub async fn setup(name: &str) -> impl Routes {
+    setup_with(test_config(name)).await
+}
+
+pub async fn setup_with(config: Config) -> impl Routes {
+    Fixture {
+        config,
+        storage: None,
+    }
+    .build()
+    .await
+}
+
+pub async fn setup_with_storage(config: Config, storage: Box<dyn Storage>) -> impl Routes {
+    init_tracing();
+
+    let config = Box::leak(Box::new(config));
+    let pool = crate::db::connect(config).await.unwrap();
+
+    let file_repo = Box::leak(Box::new(FileRepo::new(
+        storage,
+        Duration::from_secs(config.missing_file_ttl),
+        config.cache_ttl_secs.map(Duration::from_secs),
+        Duration::from_secs(config.storage_timeout),
+        config.cache_on_upload,
+        config.compress_storage,
+    )));
+
+    let mirror = Mirror::from_config(config).unwrap();
+
+    let state = AppState::new(config, pool, file_repo, mirror).leak();
+    state.spawn_tasks();
+    crate::routes::handler(state)
+}
+
+/// Adds a publish key with the admin key
+pub async fn add_key(routes: &impl Routes, user: &str, pw: &str) {
+    let reply = warp::test::request()
+        .path("/publish_key")
+        .method("POST")
+        .header("Authorization", "admin_password")
+        .json(&serde_json::json!({"user": user, "pw": pw}))
+        .reply(routes)
+        .await;
+    assert_eq!(reply.status(), StatusCode::CREATED);
+}
+
+/// Uploads a version, sending `key` if there is one
+pub async fn upload(
+    routes: &impl Routes,
+    id: &str,
+    ver: &str,
+    body: impl AsRef<[u8]>,
+    key: Option<&str>,
+) -> warp::http::Response<Bytes> {
+    let mut request = warp::test::request()
+        .path(&format!("/{}/{}", id, ver))
+        .method("POST")
+        .body(body.as_ref());
+    if let Some(key) = key {
+        request = request.header("Authorization", key);
+    }
+    request.reply(routes).await
+}
+
+/// Checks a reply has the given status and a JSON body, and parses it
+#[track_caller]
+pub fn expect_json<T: DeserializeOwned>(
+    reply: &warp::http::Response<Bytes>,
+    status: StatusCode,
+) -> T {
+    assert_eq!(
+        reply.status(),
+        status,
+        "{}",
+        String::from_utf8_lossy(reply.body())
+    );
+    assert!(
+        reply
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with(JSON_CONTENT_TYPE)),
+        "not JSON: {:?}",
+        reply.headers().get(CONTENT_TYPE)
+    );
+    serde_json::from_slice(reply.body()).unwrap()
+}
+
+/// Serves the routes on a free local port, returning their base URL
+pub async fn serve<F>(routes: F) -> (String, tokio::task::JoinHandle<()>)
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(warp::serve(routes).incoming(listener).run());
+    (endpoint, server)
+}
+
+/// Keeps files in memory, standing in for a remote backend
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: tokio::sync::Mutex<BTreeMap<String, Bytes>>,
+    /// Locked while writing a file, tests hold it to stall uploads midway
+    pub writes: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn get_file(&self, id: &str, ver: &Version) -> std::io::Result<Bytes> {
+        let files = self.files.lock().await;
+        files
+            .get(&file_key(id, ver))
+            .cloned()
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> std::io::Result<()> {
+        let _writing = self.writes.lock().await;
+        self.files.lock().await.insert(file_key(id, ver), contents);
+        Ok(())
+    }
+
+    async fn delete_file(&self, id: &str, ver: &Version) -> std::io::Result<()> {
+        let mut files = self.files.lock().await;
+        files
+            .remove(&file_key(id, ver))
+            .map(|_| ())
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> std::io::Result<bool> {
+        Ok(self.files.lock().await.contains_key(&file_key(id, ver)))
+    }
+
+    async fn get_artifact(&self, id: &str, ver: &Version, name: &str) -> std::io::Result<Bytes> {
+        let files = self.files.lock().await;
+        files
+            .get(&artifact_key(id, ver, name))
+            .cloned()
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    async fn write_artifact(
+        &self,
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: Bytes,
+    ) -> std::io::Result<()> {
+        let key = artifact_key(id, ver, name);
+        self.files.lock().await.insert(key, contents);
+        Ok(())
+    }
+
+    async fn delete_artifact(&self, id: &str, ver: &Version, name: &str) -> std::io::Result<()> {
+        let mut files = self.files.lock().await;
+        files
+            .remove(&artifact_key(id, ver, name))
+            .map(|_| ())
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    async fn scan(&self) -> std::io::Result<Scan> {
+        let mut scan = Scan::default();
+        for (key, contents) in self.files.lock().await.iter() {
+            scan.push(key.clone(), contents.len() as u64);
+        }
+        Ok(scan)
+    }
+}
+
+/// Failures a [`FaultInjectingStorage`] runs into, which tests can change while requests are
+/// being answered
+#[derive(Default)]
+pub struct Faults {
+    /// Writes of files so far, counting failed ones
+    writes: AtomicUsize,
+    /// Number of the write to fail, counting from 1, 0 fails none
+    fail_write: AtomicUsize,
+    read_delay: Mutex<Option<Duration>>,
+    /// Keys of files and artifacts reported missing, whether they're there or not
+    missing: Mutex<HashSet<String>>,
+}
+
+impl Faults {
+    /// Fails the `n`th write from now on, counting from 1
+    pub fn fail_write(&self, n: usize) {
+        let writes = self.writes.load(Ordering::SeqCst);
+        self.fail_write.store(writes + n, Ordering::SeqCst);
+    }
+
+    /// Delays every read of a file or artifact, or stops delaying them with `None`
+    pub fn delay_reads(&self, delay: Option<Duration>) {
+        *self.read_delay.lock().unwrap() = delay;
+    }
+
+    /// Reports the file of a version missing
+    pub fn hide(&self, id: &str, ver: &Version) {
+        self.missing.lock().unwrap().insert(file_key(id, ver));
+    }
+
+    async fn read(&self, key: &str) -> std::io::Result<()> {
+        let delay = *self.read_delay.lock().unwrap();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        self.check(key)
+    }
+
+    fn write(&self) -> std::io::Result<()> {
+        let write = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        match write == self.fail_write.load(Ordering::SeqCst) {
+            true => Err(std::io::Error::other("injected write failure")),
+            false => Ok(()),
+        }
+    }
+
+    fn check(&self, key: &str) -> std::io::Result<()> {
+        match self.missing.lock().unwrap().contains(key) {
+            true => Err(ErrorKind::NotFound.into()),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Keeps files in memory like [`MemoryStorage`], running into the failures set in its [`Faults`]
+pub struct FaultInjectingStorage {
+    files: MemoryStorage,
+    faults: Arc<Faults>,
+}
+
+impl FaultInjectingStorage {
+    pub fn new(faults: Arc<Faults>) -> Self {
+        Self {
+            files: MemoryStorage::default(),
+            faults,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for FaultInjectingStorage {
+    async fn get_file(&self, id: &str, ver: &Version) -> std::io::Result<Bytes> {
+        self.faults.read(&file_key(id, ver)).await?;
+        self.files.get_file(id, ver).await
+    }
+
+    async fn write_file(&self, id: &str, ver: &Version, contents: Bytes) -> std::io::Result<()> {
+        self.faults.write()?;
+        self.files.write_file(id, ver, contents).await
+    }
+
+    async fn delete_file(&self, id: &str, ver: &Version) -> std::io::Result<()> {
+        self.faults.check(&file_key(id, ver))?;
+        self.files.delete_file(id, ver).await
+    }
+
+    async fn file_exists(&self, id: &str, ver: &Version) -> std::io::Result<bool> {
+        match self.faults.check(&file_key(id, ver)) {
+            Ok(()) => self.files.file_exists(id, ver).await,
+            Err(_) => Ok(false),
+        }
+    }
+
+    async fn get_artifact(&self, id: &str, ver: &Version, name: &str) -> std::io::Result<Bytes> {
+        self.faults.read(&artifact_key(id, ver, name)).await?;
+        self.files.get_artifact(id, ver, name).await
+    }
+
+    async fn write_artifact(
+        &self,
+        id: &str,
+        ver: &Version,
+        name: &str,
+        contents: Bytes,
+    ) -> std::io::Result<()> {
+        self.faults.write()?;
+        self.files.write_artifact(id, ver, name, contents).await
+    }
+
+    async fn delete_artifact(&self, id: &str, ver: &Version, name: &str) -> std::io::Result<()> {
+        self.faults.check(&artifact_key(id, ver, name))?;
+        self.files.delete_artifact(id, ver, name).await
+    }
+
+    async fn scan(&self) -> std::io::Result<Scan> {
+        self.files.scan().await
+    }
+}
+
+/// Assembles the routes of a test instance, with a config and storage of its own
+pub struct Fixture {
+    config: Config,
+    storage: Option<Box<dyn Storage>>,
+}
+
+impl Fixture {
+    /// A test instance with [`test_config`], storing files in its downloads directory
+    pub fn new(name: &str) -> Self {
+        Self {
+            config: test_config(name),
+            storage: None,
+        }
+    }
+
+    pub fn config(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Keeps files in memory, running into the given failures
+    pub fn faults(self, faults: &Arc<Faults>) -> Self {
+        self.storage(Box::new(FaultInjectingStorage::new(faults.clone())))
+    }
+
+    /// Path of the SQLite database, to look at what requests left there
+    pub fn database_url(&self) -> String {
+        format!("sqlite://{}", self.config.database_url)
+    }
+
+    pub async fn build(self) -> impl Routes {
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => Box::new(LocalStorage::new(self.config.downloads_path.clone())),
+        };
+        setup_with_storage(self.config, storage).await
+    }
+}
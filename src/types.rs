@@ -0,0 +1,41 @@
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Mod {
+    pub id: String,
+    #[serde(with = "version_string")]
+    pub version: Version,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PublishKey {
+    pub pw: String,
+    pub user: String,
+    /// Package id this key may publish, either exact or a prefix ending in `*`
+    pub scope: Option<String>,
+    /// Unix timestamp (in seconds) after which the key is no longer valid
+    pub expires_at: Option<i64>,
+    /// When the key was last used, to the hour
+    #[serde(skip)]
+    pub last_used_at: Option<i64>,
+    /// When the key was disabled for going unused, it's refused until enabled again
+    #[serde(skip)]
+    pub disabled_at: Option<i64>,
+}
+
+/// (De)serializes a version as its full semver string, prerelease and build metadata included,
+/// whatever `semver`'s own serde support does
+pub mod version_string {
+    use semver::Version;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(version: &Version, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(version)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Version, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        Version::parse(&version).map_err(D::Error::custom)
+    }
+}
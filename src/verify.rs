@@ -0,0 +1,164 @@
+use std::{io::ErrorKind, sync::Mutex};
+
+use serde::Serialize;
+use sqlx::AnyPool;
+use tokio::task::JoinHandle;
+
+use crate::{
+    db::{Mod, unix_now},
+    storage::Storage,
+};
+
+/// A file whose contents don't hash to what was recorded at upload
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    #[serde(flatten)]
+    pub m: Mod,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Outcome of checking every file against its recorded hash
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub mismatched: Vec<Mismatch>,
+    pub missing: Vec<Mod>,
+    /// Versions with no recorded hash to check against
+    pub unhashed: Vec<Mod>,
+    /// Mismatched files moved out of the way
+    pub quarantined: Vec<Mod>,
+}
+
+/// Progress of the latest run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyStatus {
+    pub running: bool,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /// Versions looked at so far, out of `total`
+    pub checked: usize,
+    pub total: usize,
+    /// Why the run stopped early, if it did
+    pub error: Option<String>,
+    pub report: VerifyReport,
+}
+
+/// Runs verifications one at a time in the background
+#[derive(Default)]
+pub struct Verifier {
+    status: Mutex<VerifyStatus>,
+    /// The latest run, kept so it can be stopped on shutdown
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Verifier {
+    pub fn status(&self) -> VerifyStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Starts a run unless one is going already, returns whether it started one
+    pub fn start(
+        &'static self,
+        quarantine: bool,
+        pool: &'static AnyPool,
+        storage: &'static dyn Storage,
+    ) -> bool {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.running {
+                return false;
+            }
+            *status = VerifyStatus {
+                running: true,
+                started_at: Some(unix_now()),
+                ..Default::default()
+            };
+        }
+
+        let task = tokio::spawn(async move {
+            let result = self.run(quarantine, pool, storage).await;
+
+            let mut status = self.status.lock().unwrap();
+            status.running = false;
+            status.finished_at = Some(unix_now());
+            let report = &status.report;
+            match result {
+                Ok(()) => tracing::info!(
+                    "verified {} files, {} mismatched, {} missing",
+                    status.checked,
+                    report.mismatched.len(),
+                    report.missing.len()
+                ),
+                Err(e) => {
+                    tracing::error!("verification stopped: {}", e);
+                    status.error = Some(e.to_string());
+                }
+            }
+        });
+        *self.task.lock().unwrap() = Some(task);
+        true
+    }
+
+    /// Stops the run going on, if there is one
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    /// Checks every file right away, the outcome ends up in `status`
+    pub async fn run(
+        &self,
+        quarantine: bool,
+        pool: &AnyPool,
+        storage: &dyn Storage,
+    ) -> anyhow::Result<()> {
+        let checksums = Mod::checksums(pool).await?;
+        self.status.lock().unwrap().total = checksums.len();
+
+        for (m, expected) in checksums {
+            let Some(expected) = expected else {
+                self.update(|report| report.unhashed.push(m));
+                continue;
+            };
+
+            match storage.sha256(&m.id, &m.version).await {
+                Ok(actual) if actual == expected => self.update(|_| {}),
+                Ok(actual) => {
+                    tracing::warn!("{} {} doesn't match its hash", m.id, m.version);
+                    let quarantined = quarantine
+                        && match storage.quarantine(&m.id, &m.version).await {
+                            Ok(()) => true,
+                            Err(e) => {
+                                tracing::warn!("couldn't quarantine {} {}: {}", m.id, m.version, e);
+                                false
+                            }
+                        };
+                    self.update(|report| {
+                        if quarantined {
+                            report.quarantined.push(m.clone());
+                        }
+                        report.mismatched.push(Mismatch {
+                            m,
+                            expected,
+                            actual,
+                        });
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    tracing::warn!("{} {} has no file in storage", m.id, m.version);
+                    self.update(|report| report.missing.push(m));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a version as checked, recording what was found
+    fn update(&self, f: impl FnOnce(&mut VerifyReport)) {
+        let mut status = self.status.lock().unwrap();
+        status.checked += 1;
+        f(&mut status.report);
+    }
+}
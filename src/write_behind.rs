@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use semver::Version;
+use sqlx::AnyPool;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::{
+    db::{Mod, unix_now},
+    types::PublishKey,
+};
+
+/// Download counts and key uses not written to the database yet. Writing each as it happens
+/// would make every download a write, which SQLite doesn't keep up with under load, so they're
+/// added up in memory and written together every so often
+pub struct WriteBehind {
+    pending: Mutex<Pending>,
+    /// Events after which a flush is started without waiting for the interval
+    max_pending: usize,
+    full: Notify,
+    /// Held while flushing, so flushes don't write the same counts twice
+    flushing: tokio::sync::Mutex<()>,
+    /// Statements flushes ran, to see how much adding counts up saves
+    writes: AtomicU64,
+}
+
+#[derive(Default)]
+struct Pending {
+    downloads: HashMap<(String, Version), i64>,
+    /// Downloads by package and the hour they were in, for `/popular`
+    download_hours: HashMap<(String, i64), i64>,
+    /// When keys were last used, by key and user
+    key_uses: HashMap<(String, String), i64>,
+    /// Events since the last flush started
+    events: usize,
+}
+
+impl WriteBehind {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            pending: Mutex::new(Pending::default()),
+            max_pending,
+            full: Notify::new(),
+            flushing: tokio::sync::Mutex::new(()),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count_download(&self, id: &str, ver: &Version) {
+        self.record(|pending| {
+            *pending
+                .downloads
+                .entry((id.to_owned(), ver.clone()))
+                .or_default() += 1;
+            *pending
+                .download_hours
+                .entry((id.to_owned(), unix_now() / 3600))
+                .or_default() += 1;
+        });
+    }
+
+    pub fn key_used(&self, key: &PublishKey) {
+        self.record(|pending| {
+            pending
+                .key_uses
+                .insert((key.pw.clone(), key.user.clone()), unix_now());
+        });
+    }
+
+    fn record(&self, add: impl FnOnce(&mut Pending)) {
+        let mut pending = self.pending.lock().unwrap();
+        add(&mut pending);
+        pending.events += 1;
+        if pending.events == self.max_pending {
+            self.full.notify_one();
+        }
+    }
+
+    /// Downloads of a version not written yet, or of every version of the package if there's no
+    /// version
+    pub fn pending_downloads(&self, id: &str, ver: Option<&Version>) -> i64 {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .downloads
+            .iter()
+            .filter(|((pending_id, pending_ver), _)| {
+                pending_id == id && ver.is_none_or(|ver| ver == pending_ver)
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Downloads of every version of every package not written yet
+    pub fn pending_downloads_total(&self) -> i64 {
+        self.pending.lock().unwrap().downloads.values().sum()
+    }
+
+    /// Downloads since the start of `since_hour` not written yet, by package
+    pub fn pending_recent_downloads(&self, since_hour: i64) -> HashMap<String, i64> {
+        let pending = self.pending.lock().unwrap();
+        let mut recent = HashMap::<String, i64>::new();
+        for ((id, hour), count) in &pending.download_hours {
+            if *hour >= since_hour {
+                *recent.entry(id.clone()).or_default() += count;
+            }
+        }
+        recent
+    }
+
+    /// When a user's keys were last used, if that's not written yet
+    pub fn pending_key_use(&self, user: &str) -> Option<i64> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .key_uses
+            .iter()
+            .filter(|((_, key_user), _)| key_user == user)
+            .map(|(_, used)| *used)
+            .max()
+    }
+
+    /// Events not written yet, and statements flushes ran, for `/stats/runtime`
+    pub fn counts(&self) -> (u64, u64) {
+        let pending = self.pending.lock().unwrap().events as u64;
+        (pending, self.writes.load(Ordering::Relaxed))
+    }
+
+    /// Writes everything pending. What couldn't be written is kept for the next flush
+    pub async fn flush(&self, pool: &AnyPool) {
+        let _flushing = self.flushing.lock().await;
+        let (downloads, download_hours, key_uses) = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.events = 0;
+            (
+                pending.downloads.clone(),
+                pending.download_hours.clone(),
+                pending.key_uses.clone(),
+            )
+        };
+
+        // Counts are only taken off what's pending once written, so reads adding pending counts
+        // to written ones never miss any
+        for ((id, ver), count) in downloads {
+            let written = Mod::add_downloads(&id, &ver, count, pool).await;
+            self.written(&written, |pending| {
+                subtract(&mut pending.downloads, (id.clone(), ver.clone()), count)
+            });
+            if let Err(e) = written {
+                tracing::warn!("couldn't count downloads of {} {}: {}", id, ver, e);
+            }
+        }
+        for ((id, hour), count) in download_hours {
+            let written = Mod::add_download_hours(&id, hour, count, pool).await;
+            self.written(&written, |pending| {
+                subtract(&mut pending.download_hours, (id.clone(), hour), count)
+            });
+            if let Err(e) = written {
+                tracing::warn!("couldn't count hourly downloads of {}: {}", id, e);
+            }
+        }
+        for ((pw, user), used) in key_uses {
+            let written = PublishKey::mark_used(&pw, &user, used, pool).await;
+            self.written(&written, |pending| {
+                let key = (pw.clone(), user.clone());
+                if pending.key_uses.get(&key) == Some(&used) {
+                    pending.key_uses.remove(&key);
+                }
+            });
+            if let Err(e) = written {
+                tracing::warn!("couldn't mark {}'s key as used: {}", user, e);
+            }
+        }
+    }
+
+    fn written<T>(&self, result: &sqlx::Result<T>, take: impl FnOnce(&mut Pending)) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        if result.is_ok() {
+            take(&mut self.pending.lock().unwrap());
+        }
+    }
+
+    /// Flushes every `interval`, or sooner once `max_pending` events are pending
+    pub fn spawn(&'static self, interval: Duration, pool: &'static AnyPool) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = self.full.notified() => {}
+                }
+                self.flush(pool).await;
+            }
+        })
+    }
+}
+
+fn subtract<K: Eq + Hash>(counts: &mut HashMap<K, i64>, key: K, count: i64) {
+    if let Some(pending) = counts.get_mut(&key) {
+        *pending -= count;
+        if *pending <= 0 {
+            counts.remove(&key);
+        }
+    }
+}